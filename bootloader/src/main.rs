@@ -17,6 +17,7 @@ use uefi::table::boot::{AllocateType, MemoryType};
 use uefi::table::runtime::Time;
 use uefi::Char16;
 
+use rumikan_shared::boot::BootFile;
 use rumikan_shared::graphics::FrameBufferInfo;
 
 use crate::elf64::SegmentType;
@@ -25,12 +26,14 @@ mod elf64;
 
 const MEMORY_MAP_FILE: &str = "\\memmap.csv";
 const KERNEL_FILE: &str = "\\rumikan-kernel";
+/// Optional image shown on the desktop. The first one found is used
+const WALLPAPER_FILES: [&str; 2] = ["\\wallpaper.qoi", "\\wallpaper.bmp"];
 // 4KB
 const PAGE_SIZE: usize = 0x1000;
 // Calculate required buffer size which aligned to the struct size
 const FILE_INFO_BUFFER_LEN: usize = {
     let align = size_of::<FileInfoHeader>();
-    // 15 = "rumikan-kernel".len() + 1 (null character)
+    // 15 = "rumikan-kernel".len() + 1 (null character), the longest file name to read
     let required = align + 15 * size_of::<Char16>();
     align * ((required + (align - 1)) / align)
 };
@@ -57,7 +60,10 @@ fn efi_main(image_handle: uefi::Handle, system_table: SystemTable<Boot>) -> Stat
 
     info!("kernel entry_addr: 0x{:x}", entry_addr);
 
-    let entry_point: extern "sysv64" fn(FrameBufferInfo) -> ! = unsafe { transmute(entry_addr) };
+    let entry_point: extern "sysv64" fn(FrameBufferInfo, BootFile) -> ! =
+        unsafe { transmute(entry_addr) };
+
+    let wallpaper = load_wallpaper_file(bt, fs);
 
     let frame_buffer = get_frame_buffer(bt);
 
@@ -66,7 +72,7 @@ fn efi_main(image_handle: uefi::Handle, system_table: SystemTable<Boot>) -> Stat
         .exit_boot_services(image_handle, &mut mmap_buf)
         .expect_success("Failed to exit boot services");
 
-    entry_point(frame_buffer);
+    entry_point(frame_buffer, wallpaper);
 }
 
 /// Dump memory map as a file in CSV format.
@@ -100,18 +106,7 @@ fn dump_memory_map(bt: &BootServices, fs: &mut SimpleFileSystem) {
 /// Returns the entry-point address of the kernel.
 fn load_kernel_file(bt: &BootServices, fs: &mut SimpleFileSystem) -> u64 {
     let mut file = open_regular_file(fs, KERNEL_FILE, FileMode::Read);
-    let mut buf = [0u8; FILE_INFO_BUFFER_LEN];
-    let info = file
-        .get_info::<FileInfo>(&mut buf)
-        .expect_success("Failed to get file info");
-
-    let pool = bt
-        .allocate_pool(MemoryType::LOADER_DATA, info.file_size() as usize)
-        .expect_success("Failed to allocate pool for load kernel file temporary");
-    unsafe {
-        file.read(from_raw_parts_mut(pool, info.file_size() as usize))
-            .expect_success("Failed to read kernel file");
-    }
+    let (pool, _) = read_to_pool(bt, &mut file);
     let file_header = pool as *const elf64::FileHeader;
     let file_header = unsafe { &*file_header };
 
@@ -154,6 +149,38 @@ fn load_kernel_file(bt: &BootServices, fs: &mut SimpleFileSystem) -> u64 {
     unsafe { *((addr + 24) as *const u64) }
 }
 
+/// Load the wallpaper into the memory, which is left to the kernel.
+/// Returns an empty file if there's none
+fn load_wallpaper_file(bt: &BootServices, fs: &mut SimpleFileSystem) -> BootFile {
+    for &filename in WALLPAPER_FILES.iter() {
+        if let Some(mut file) = try_open_regular_file(fs, filename) {
+            let (pool, size) = read_to_pool(bt, &mut file);
+            info!("wallpaper: {}, {} bytes", filename, size);
+            return BootFile::new(pool, size);
+        }
+    }
+    BootFile::empty()
+}
+
+/// Read the whole file into a pool allocated as LOADER_DATA.
+/// Returns the pool and the file size
+fn read_to_pool(bt: &BootServices, file: &mut RegularFile) -> (*mut u8, usize) {
+    let mut buf = [0u8; FILE_INFO_BUFFER_LEN];
+    let info = file
+        .get_info::<FileInfo>(&mut buf)
+        .expect_success("Failed to get file info");
+    let size = info.file_size() as usize;
+
+    let pool = bt
+        .allocate_pool(MemoryType::LOADER_DATA, size)
+        .expect_success("Failed to allocate pool to read file");
+    unsafe {
+        file.read(from_raw_parts_mut(pool, size))
+            .expect_success("Failed to read file");
+    }
+    (pool, size)
+}
+
 /// Get frame buffer struct which will be passed to kernel entry point
 fn get_frame_buffer(bt: &BootServices) -> FrameBufferInfo {
     let gop = unsafe {
@@ -210,6 +237,20 @@ fn open_regular_file(fs: &mut SimpleFileSystem, filename: &str, mode: FileMode)
     .expect("Unexpected file type")
 }
 
+/// Open specified file as RegularFile to read, or `None` if it doesn't exist
+fn try_open_regular_file(fs: &mut SimpleFileSystem, filename: &str) -> Option<RegularFile> {
+    let file = fs
+        .open_volume()
+        .expect_success("Failed to open volume")
+        .open(filename, FileMode::Read, FileAttribute::empty())
+        .log_warning()
+        .ok()?;
+    match file.into_type().expect_success("Failed to convert file") {
+        FileType::Regular(file) => Some(file),
+        _ => None,
+    }
+}
+
 /// Retrieves the `SimpleFileSystem` protocol associated with
 /// the device the given image was loaded from.
 ///
@@ -0,0 +1,217 @@
+//! Parser of the escape sequences of ECMA-48, as far as the console interprets them.
+
+/// Parameters more than this are ignored
+const MAX_PARAMS: usize = 16;
+const ESC: char = '\x1b';
+
+/// Numeric parameters of a control sequence, where the omitted ones are `None`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Params {
+    values: [Option<u16>; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const EMPTY: Params = Params {
+        values: [None; MAX_PARAMS],
+        len: 0,
+    };
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The parameter at `index`, or `default` if omitted
+    pub fn get(&self, index: usize, default: u16) -> u16 {
+        self.values[..self.len]
+            .get(index)
+            .copied()
+            .flatten()
+            .unwrap_or(default)
+    }
+
+    /// Like [`Params::get`], but 0 also means the default as the cursor movements do
+    pub fn count(&self, index: usize) -> u16 {
+        self.get(index, 1).max(1)
+    }
+
+    fn push(&mut self, value: Option<u16>) {
+        if self.len < MAX_PARAMS {
+            self.values[self.len] = value;
+            self.len += 1;
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Sequence {
+    /// Printable character or a C0 control other than ESC
+    Char(char),
+    /// `ESC final`, e.g. `ESC 7` to save the cursor
+    Escape(char),
+    /// `CSI params final`. Sequences with a private marker such as `?` are not reported
+    Csi(Params, char),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// Skipping a sequence the console doesn't interpret
+    IgnoreCsi,
+}
+
+#[derive(Debug)]
+pub struct Parser {
+    state: State,
+    params: Params,
+    /// The parameter being read
+    current: Option<u16>,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: Params::EMPTY,
+            current: None,
+        }
+    }
+
+    /// Feed a character, which completes a sequence or not
+    pub fn advance(&mut self, c: char) -> Option<Sequence> {
+        match self.state {
+            State::Ground => {
+                if c == ESC {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Sequence::Char(c))
+                }
+            }
+            State::Escape => match c {
+                '[' => {
+                    self.state = State::Csi;
+                    self.params = Params::EMPTY;
+                    self.current = None;
+                    None
+                }
+                // ESC ESC starts over
+                ESC => None,
+                c => {
+                    self.state = State::Ground;
+                    Some(Sequence::Escape(c))
+                }
+            },
+            State::Csi | State::IgnoreCsi => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Sequence> {
+        match c {
+            '0'..='9' => {
+                let digit = c as u16 - '0' as u16;
+                self.current = Some(
+                    self.current
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+                None
+            }
+            ';' => {
+                self.params.push(self.current.take());
+                None
+            }
+            // private markers and intermediate bytes
+            '<'..='?' | ' '..='/' => {
+                self.state = State::IgnoreCsi;
+                None
+            }
+            '@'..='~' => {
+                let ignored = self.state == State::IgnoreCsi;
+                self.state = State::Ground;
+                if ignored {
+                    return None;
+                }
+                if self.current.is_some() || !self.params.is_empty() {
+                    self.params.push(self.current.take());
+                }
+                Some(Sequence::Csi(self.params, c))
+            }
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            // C0 controls are executed in the middle of a sequence
+            c if c.is_control() => Some(Sequence::Char(c)),
+            // broken sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::ansi::{Parser, Sequence};
+
+    fn parse(s: &str) -> Vec<Sequence> {
+        let mut parser = Parser::new();
+        s.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    fn csi(s: &str) -> (Vec<u16>, char) {
+        match parse(s).as_slice() {
+            [Sequence::Csi(params, c)] => {
+                ((0..params.len()).map(|i| params.get(i, 999)).collect(), *c)
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn control_sequences() {
+        assert_eq!(csi("\x1b[m"), (vec![], 'm'));
+        assert_eq!(csi("\x1b[1;31m"), (vec![1, 31], 'm'));
+        assert_eq!(csi("\x1b[38;5;208m"), (vec![38, 5, 208], 'm'));
+        // omitted parameters
+        assert_eq!(csi("\x1b[;5H"), (vec![999, 5], 'H'));
+        assert_eq!(csi("\x1b[3;H"), (vec![3, 999], 'H'));
+        assert_eq!(csi("\x1b[99999A"), (vec![u16::MAX], 'A'));
+
+        assert_eq!(
+            parse("a\x1b7b\x1b8"),
+            vec![
+                Sequence::Char('a'),
+                Sequence::Escape('7'),
+                Sequence::Char('b'),
+                Sequence::Escape('8'),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_sequences() {
+        // private mode to hide the cursor
+        assert_eq!(
+            parse("\x1b[?25lok"),
+            vec![Sequence::Char('o'), Sequence::Char('k')]
+        );
+        // interrupted by another sequence
+        assert_eq!(csi("\x1b[12\x1b[2J"), (vec![2], 'J'));
+        // broken one
+        assert_eq!(parse("\x1b[1あx"), vec![Sequence::Char('x')]);
+        // controls are executed in the middle
+        let parsed = parse("\x1b[1\n2m");
+        assert_eq!(parsed[0], Sequence::Char('\n'));
+        assert_eq!(parsed[1], parse("\x1b[12m")[0]);
+    }
+}
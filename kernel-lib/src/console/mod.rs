@@ -0,0 +1,848 @@
+pub mod ansi;
+
+use core::fmt::{Arguments, Write};
+use core::ops::Range;
+
+use crate::console::ansi::{Params, Parser, Sequence};
+use crate::graphics::fonts;
+use crate::graphics::{CharVec, PixelColor, PixelWriter, Rect, ShadowBuffer};
+use crate::layer::{layer_manager, LayerId};
+
+static mut CONSOLE: Option<Console> = None;
+
+pub fn init_global_console(console: Console) {
+    unsafe { CONSOLE = Some(console) };
+}
+
+/// `None` until [`init_global_console`] is called
+pub fn console() -> Option<&'static mut Console> {
+    unsafe { CONSOLE.as_mut() }
+}
+
+const TAB_WIDTH: usize = 8;
+/// Content of the cell on the right half of a double-width character
+const CONTINUATION: char = '\0';
+
+/// The 16 colors of SGR 30-37 and 90-97, as xterm shows them
+const PALETTE: [PixelColor; 16] = [
+    PixelColor::new(0x00, 0x00, 0x00),
+    PixelColor::new(0xcd, 0x00, 0x00),
+    PixelColor::new(0x00, 0xcd, 0x00),
+    PixelColor::new(0xcd, 0xcd, 0x00),
+    PixelColor::new(0x00, 0x00, 0xee),
+    PixelColor::new(0xcd, 0x00, 0xcd),
+    PixelColor::new(0x00, 0xcd, 0xcd),
+    PixelColor::new(0xe5, 0xe5, 0xe5),
+    PixelColor::new(0x7f, 0x7f, 0x7f),
+    PixelColor::new(0xff, 0x00, 0x00),
+    PixelColor::new(0x00, 0xff, 0x00),
+    PixelColor::new(0xff, 0xff, 0x00),
+    PixelColor::new(0x5c, 0x5c, 0xff),
+    PixelColor::new(0xff, 0x00, 0xff),
+    PixelColor::new(0x00, 0xff, 0xff),
+    PixelColor::new(0xff, 0xff, 0xff),
+];
+
+/// Color of the 256-color palette: the 16 colors, a 6x6x6 cube and 24 grays
+fn indexed_color(index: u8) -> PixelColor {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => PALETTE[index as usize],
+        16..=231 => {
+            let i = index as usize - 16;
+            PixelColor::new(LEVELS[i / 36], LEVELS[i / 6 % 6], LEVELS[i % 6])
+        }
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            PixelColor::new(level, level, level)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Color {
+    /// The color the console is created with
+    Default,
+    Indexed(u8),
+    Rgb(PixelColor),
+}
+
+/// Graphic rendition set by SGR
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    /// Shown with the bright colors
+    bold: bool,
+    inverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        fg: Color::Default,
+        bg: Color::Default,
+        bold: false,
+        inverse: false,
+    };
+}
+
+/// Character cell of the console
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cell {
+    c: char,
+    fg_color: PixelColor,
+    bg_color: PixelColor,
+}
+
+impl Cell {
+    /// Initial value of the storage, which is cleared with the colors of the console anyway
+    pub const BLANK: Cell = Cell {
+        c: ' ',
+        fg_color: PixelColor::new(0, 0, 0),
+        bg_color: PixelColor::new(0, 0, 0),
+    };
+}
+
+/// Text console drawn on a layer, as many characters as the layer fits.
+/// The lines scrolled out of the screen are kept for scrolling back as long as the storage holds.
+/// The escape sequences for the colors, the cursor movements and erasing are interpreted
+pub struct Console {
+    layer: LayerId,
+    cols: usize,
+    rows: usize,
+    /// Size of a cell in pixels, which is of the font selected when the console is created
+    cell_width: usize,
+    cell_height: usize,
+    /// Ring of lines, the last `rows` of which are on the screen
+    cells: &'static mut [Cell],
+    capacity: usize,
+    /// Line of the ring at the top of the screen
+    top: usize,
+    /// Number of lines kept above the screen
+    history: usize,
+    /// Number of lines the view is scrolled back by
+    view_offset: usize,
+    bg_color: PixelColor,
+    fg_color: PixelColor,
+    /// Whether the default background is the transparent color of the layer,
+    /// which the other colors are kept off not to be see-through
+    transparent: bool,
+    attributes: Attributes,
+    cursor_row: usize,
+    cursor_col: usize,
+    /// Cursor and attributes saved by `ESC 7` or `CSI s`
+    saved: (usize, usize, Attributes),
+    parser: Parser,
+    /// Parser of the keys typed on the terminal, see [`Console::on_input`]
+    input: Parser,
+}
+
+impl Console {
+    /// `cells` is the storage of the screen and the scrollback, which must hold the lines of the screen
+    pub fn new(
+        layer: LayerId,
+        cells: &'static mut [Cell],
+        bg_color: PixelColor,
+        fg_color: PixelColor,
+    ) -> Console {
+        let (width, height) = layer_manager()
+            .and_then(|manager| manager.layer(layer).ok())
+            .map(|layer| layer.buffer().resolution())
+            .unwrap_or((0, 0));
+        let (cell_width, cell_height) = fonts::cell_size();
+        let console = Console::with_size(
+            layer,
+            width / cell_width,
+            height / cell_height,
+            cells,
+            bg_color,
+            fg_color,
+        );
+        if let Some(buffer) = console.buffer() {
+            buffer.fill_rect(buffer.bounds(), bg_color);
+        }
+        console.draw();
+        console
+    }
+
+    fn with_size(
+        layer: LayerId,
+        cols: usize,
+        rows: usize,
+        cells: &'static mut [Cell],
+        bg_color: PixelColor,
+        fg_color: PixelColor,
+    ) -> Console {
+        let capacity = cells.len() / cols.max(1);
+        assert!(capacity >= rows);
+        let blank = Cell {
+            c: ' ',
+            fg_color,
+            bg_color,
+        };
+        cells.fill(blank);
+        let (cell_width, cell_height) = fonts::cell_size();
+        Console {
+            layer,
+            cols,
+            rows,
+            cell_width,
+            cell_height,
+            cells,
+            capacity,
+            top: 0,
+            history: 0,
+            view_offset: 0,
+            bg_color,
+            fg_color,
+            transparent: false,
+            attributes: Attributes::DEFAULT,
+            cursor_row: 0,
+            cursor_col: 0,
+            saved: (0, 0, Attributes::DEFAULT),
+            parser: Parser::new(),
+            input: Parser::new(),
+        }
+    }
+
+    /// Number of the columns and the rows
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Default colors of the characters printed from now on, which SGR 0 also goes back to
+    pub fn set_colors(&mut self, fg_color: PixelColor, bg_color: PixelColor) {
+        self.fg_color = fg_color;
+        self.bg_color = bg_color;
+        self.attributes = Attributes::DEFAULT;
+        if self.transparent {
+            self.set_transparent_background();
+        }
+    }
+
+    /// Let the layers below show through the default background from now on.
+    /// The characters and the backgrounds set by SGR are still drawn opaque even in the same color
+    pub fn set_transparent_background(&mut self) {
+        self.transparent = true;
+        if let Some(layer) = layer_manager().and_then(|manager| manager.layer(self.layer).ok()) {
+            layer.set_transparent(Some(self.bg_color));
+        }
+    }
+
+    /// `color` kept off the transparent color, if any
+    fn opaque(&self, color: PixelColor) -> PixelColor {
+        if self.transparent && color == self.bg_color {
+            color.nudged()
+        } else {
+            color
+        }
+    }
+
+    /// Foreground and background colors of the current attributes
+    fn colors(&self) -> (PixelColor, PixelColor) {
+        let Attributes {
+            fg,
+            bg,
+            bold,
+            inverse,
+        } = self.attributes;
+        let fg = match fg {
+            Color::Default => self.fg_color,
+            Color::Indexed(index) if bold && index < 8 => indexed_color(index + 8),
+            Color::Indexed(index) => indexed_color(index),
+            Color::Rgb(color) => color,
+        };
+        let bg = match bg {
+            Color::Default => self.bg_color,
+            Color::Indexed(index) => indexed_color(index),
+            Color::Rgb(color) => color,
+        };
+        if inverse {
+            (self.opaque(bg), self.opaque(fg))
+        } else if self.attributes.bg == Color::Default {
+            (self.opaque(fg), bg)
+        } else {
+            (self.opaque(fg), self.opaque(bg))
+        }
+    }
+
+    fn cell(&self, c: char) -> Cell {
+        let (fg_color, bg_color) = self.colors();
+        Cell {
+            c,
+            fg_color,
+            bg_color,
+        }
+    }
+
+    /// The buffer of the layer, or `None` if the layer manager isn't initialized
+    fn buffer(&self) -> Option<&'static mut ShadowBuffer> {
+        let layer = layer_manager()?.layer(self.layer).ok()?;
+        Some(layer.buffer())
+    }
+
+    fn draw(&self) {
+        if let Some(manager) = layer_manager() {
+            // an error can't be logged since logging prints to the console
+            let _ = manager.draw(self.layer);
+        }
+    }
+
+    /// Line of the ring shown at the row of the view
+    fn line(&self, row: usize) -> &[Cell] {
+        let index = (self.top + self.capacity - self.view_offset + row) % self.capacity;
+        &self.cells[index * self.cols..(index + 1) * self.cols]
+    }
+
+    /// Line of the ring at the row of the screen
+    fn screen_line_mut(&mut self, row: usize) -> &mut [Cell] {
+        let index = (self.top + row) % self.capacity;
+        &mut self.cells[index * self.cols..(index + 1) * self.cols]
+    }
+
+    fn render_cell(&self, buffer: &mut ShadowBuffer, row: usize, col: usize, cell: Cell) {
+        if cell.c == CONTINUATION {
+            // drawn with the left half
+            return;
+        }
+        let (x, y) = (col * self.cell_width, row * self.cell_height);
+        let width = fonts::char_width(cell.c) * self.cell_width;
+        buffer.fill_rect(Rect::new(x, y, width, self.cell_height), cell.bg_color);
+        if cell.c != ' ' {
+            buffer.write_char(x, y, cell.c, cell.fg_color);
+        }
+    }
+
+    /// Render the rows of the view from the cells
+    fn render_rows(&self, buffer: &mut ShadowBuffer, rows: Range<usize>) {
+        for row in rows {
+            for (col, &cell) in self.line(row).iter().enumerate() {
+                self.render_cell(buffer, row, col, cell);
+            }
+        }
+    }
+
+    /// Scroll the view back by `offset` lines from the screen, copying the rows still shown
+    fn scroll_view(&mut self, buffer: &mut ShadowBuffer, offset: usize) {
+        let offset = offset.min(self.history);
+        let delta = offset as isize - self.view_offset as isize;
+        self.view_offset = offset;
+        let shift = delta.unsigned_abs();
+        if shift == 0 {
+            return;
+        }
+        if shift >= self.rows {
+            self.render_rows(buffer, 0..self.rows);
+            return;
+        }
+        let width = self.cols * self.cell_width;
+        let kept = (self.rows - shift) * self.cell_height;
+        if delta > 0 {
+            // older lines come in from the top
+            buffer.copy_rect(Rect::new(0, 0, width, kept), 0, shift * self.cell_height);
+            self.render_rows(buffer, 0..shift);
+        } else {
+            buffer.copy_rect(Rect::new(0, shift * self.cell_height, width, kept), 0, 0);
+            self.render_rows(buffer, self.rows - shift..self.rows);
+        }
+    }
+
+    /// Scroll back by a screen
+    pub fn page_up(&mut self) {
+        self.scroll_back_to(self.view_offset + self.rows);
+    }
+
+    /// Scroll forward by a screen, up to the current screen
+    pub fn page_down(&mut self) {
+        self.scroll_back_to(self.view_offset.saturating_sub(self.rows));
+    }
+
+    fn scroll_back_to(&mut self, offset: usize) {
+        match self.buffer() {
+            Some(buffer) => {
+                self.scroll_view(buffer, offset);
+                self.draw();
+            }
+            // nothing to draw on, but the view follows
+            None => self.view_offset = offset.min(self.history),
+        }
+    }
+
+    /// Handle a character typed on a terminal, e.g. the serial console.
+    /// PgUp and PgDn, with or without the modifiers such as Shift, scroll the view by a screen.
+    /// The other keys are ignored
+    pub fn on_input(&mut self, c: char) {
+        match self.input.advance(c) {
+            Some(Sequence::Csi(params, '~')) if params.get(0, 0) == 5 => self.page_up(),
+            Some(Sequence::Csi(params, '~')) if params.get(0, 0) == 6 => self.page_down(),
+            _ => {}
+        }
+    }
+
+    fn new_line(&mut self, buffer: &mut ShadowBuffer) {
+        self.cursor_col = 0;
+        if self.cursor_row + 1 < self.rows {
+            self.cursor_row += 1;
+            return;
+        }
+        // reached last row. need scroll
+        self.top = (self.top + 1) % self.capacity;
+        self.history = (self.history + 1).min(self.capacity - self.rows);
+        let blank = self.cell(' ');
+        let last = self.rows - 1;
+        self.screen_line_mut(last).fill(blank);
+
+        let width = self.cols * self.cell_width;
+        let rest = Rect::new(0, self.cell_height, width, last * self.cell_height);
+        buffer.copy_rect(rest, 0, 0);
+        buffer.fill_rect(
+            Rect::new(0, rest.height, width, self.cell_height),
+            blank.bg_color,
+        );
+    }
+
+    fn put_char(&mut self, buffer: &mut ShadowBuffer, c: char) {
+        match c {
+            '\n' => self.new_line(buffer),
+            '\r' => self.cursor_col = 0,
+            '\t' => {
+                self.cursor_col = ((self.cursor_col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols)
+            }
+            // moves back without erasing, as terminals do
+            '\x08' => self.cursor_col = self.cursor_col.saturating_sub(1),
+            c => {
+                let width = fonts::char_width(c).min(self.cols);
+                if self.cursor_col + width > self.cols {
+                    self.new_line(buffer);
+                }
+                let cell = self.cell(c);
+                let (row, col) = (self.cursor_row, self.cursor_col);
+                self.split_wide_chars(buffer, row, col..col + width);
+                let line = self.screen_line_mut(row);
+                line[col] = cell;
+                if width == 2 {
+                    line[col + 1] = Cell {
+                        c: CONTINUATION,
+                        ..cell
+                    };
+                }
+                self.render_cell(buffer, row, col, cell);
+                self.cursor_col += width;
+            }
+        }
+    }
+
+    /// Print onto the buffer, going back to the screen if scrolled back
+    fn write(&mut self, buffer: &mut ShadowBuffer, s: impl Iterator<Item = char>) {
+        if self.rows == 0 || self.cols == 0 {
+            return;
+        }
+        self.scroll_view(buffer, 0);
+        for c in s {
+            match self.parser.advance(c) {
+                Some(Sequence::Char(c)) => self.put_char(buffer, c),
+                Some(Sequence::Escape('7')) => self.save_cursor(),
+                Some(Sequence::Escape('8')) => self.restore_cursor(),
+                Some(Sequence::Csi(params, c)) => self.execute(buffer, &params, c),
+                _ => {}
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = (self.cursor_row, self.cursor_col, self.attributes);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col, attributes) = self.saved;
+        self.cursor_row = row.min(self.rows - 1);
+        self.cursor_col = col.min(self.cols);
+        self.attributes = attributes;
+    }
+
+    /// Interpret the control sequence `CSI params c`
+    fn execute(&mut self, buffer: &mut ShadowBuffer, params: &Params, c: char) {
+        // a pending wrap is cancelled by moving the cursor
+        let col = self.cursor_col.min(self.cols - 1);
+        let count = params.count(0) as usize;
+        match c {
+            'A' => self.cursor_row = self.cursor_row.saturating_sub(count),
+            'B' => self.cursor_row = (self.cursor_row + count).min(self.rows - 1),
+            'C' => self.cursor_col = (col + count).min(self.cols - 1),
+            'D' => self.cursor_col = col.saturating_sub(count),
+            'H' | 'f' => {
+                self.cursor_row = (params.count(0) as usize - 1).min(self.rows - 1);
+                self.cursor_col = (params.count(1) as usize - 1).min(self.cols - 1);
+            }
+            'J' => {
+                let (row, cols) = (self.cursor_row, self.cols);
+                match params.get(0, 0) {
+                    0 => {
+                        self.erase(buffer, row, col..cols);
+                        (row + 1..self.rows).for_each(|row| self.erase(buffer, row, 0..cols));
+                    }
+                    1 => {
+                        (0..row).for_each(|row| self.erase(buffer, row, 0..cols));
+                        self.erase(buffer, row, 0..col + 1);
+                    }
+                    2 => (0..self.rows).for_each(|row| self.erase(buffer, row, 0..cols)),
+                    _ => {}
+                }
+            }
+            'K' => {
+                let range = match params.get(0, 0) {
+                    0 => col..self.cols,
+                    1 => 0..col + 1,
+                    2 => 0..self.cols,
+                    _ => return,
+                };
+                self.erase(buffer, self.cursor_row, range);
+            }
+            'm' => self.select_graphic_rendition(params),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    /// Clear the columns of the row of the screen with the current background
+    /// Blank the halves of the double-width characters left outside the columns to overwrite
+    fn split_wide_chars(&mut self, buffer: &mut ShadowBuffer, row: usize, cols: Range<usize>) {
+        let blank = self.cell(' ');
+        let line = self.screen_line_mut(row);
+        let mut split = [None, None];
+        if cols.start > 0 && line[cols.start].c == CONTINUATION {
+            split[0] = Some(cols.start - 1);
+        }
+        if cols.end < line.len() && line[cols.end].c == CONTINUATION {
+            split[1] = Some(cols.end);
+        }
+        for col in split.iter().flatten().copied() {
+            self.screen_line_mut(row)[col] = blank;
+            self.render_cell(buffer, row, col, blank);
+        }
+    }
+
+    fn erase(&mut self, buffer: &mut ShadowBuffer, row: usize, cols: Range<usize>) {
+        self.split_wide_chars(buffer, row, cols.clone());
+        let blank = self.cell(' ');
+        self.screen_line_mut(row)[cols.clone()].fill(blank);
+        buffer.fill_rect(
+            Rect::new(
+                cols.start * self.cell_width,
+                row * self.cell_height,
+                cols.len() * self.cell_width,
+                self.cell_height,
+            ),
+            blank.bg_color,
+        );
+    }
+
+    fn select_graphic_rendition(&mut self, params: &Params) {
+        if params.is_empty() {
+            self.attributes = Attributes::DEFAULT;
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            let attributes = &mut self.attributes;
+            match params.get(i, 0) {
+                0 => *attributes = Attributes::DEFAULT,
+                1 => attributes.bold = true,
+                22 => attributes.bold = false,
+                7 => attributes.inverse = true,
+                27 => attributes.inverse = false,
+                n @ 30..=37 => attributes.fg = Color::Indexed(n as u8 - 30),
+                n @ 90..=97 => attributes.fg = Color::Indexed(n as u8 - 90 + 8),
+                39 => attributes.fg = Color::Default,
+                n @ 40..=47 => attributes.bg = Color::Indexed(n as u8 - 40),
+                n @ 100..=107 => attributes.bg = Color::Indexed(n as u8 - 100 + 8),
+                49 => attributes.bg = Color::Default,
+                n @ 38 | n @ 48 => {
+                    // 5;index or 2;r;g;b
+                    let (color, len) = match params.get(i + 1, 0) {
+                        5 => (Color::Indexed(params.get(i + 2, 0) as u8), 2),
+                        2 => {
+                            let channel = |j| params.get(i + j, 0).min(255) as u8;
+                            (
+                                Color::Rgb(PixelColor::new(channel(2), channel(3), channel(4))),
+                                4,
+                            )
+                        }
+                        _ => (Color::Default, 0),
+                    };
+                    if len > 0 {
+                        if n == 38 {
+                            attributes.fg = color;
+                        } else {
+                            attributes.bg = color;
+                        }
+                    }
+                    i += len;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    pub fn print(&mut self, args: Arguments) {
+        let buffer = match self.buffer() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let mut v = CharVec::new();
+        let truncated_message = if v.write_fmt(args).is_ok() {
+            ""
+        } else {
+            // the line break at the end, if any, is lost with the rest
+            "...(truncated)\n"
+        };
+        self.write(
+            buffer,
+            v.as_slice()
+                .iter()
+                .copied()
+                .chain(truncated_message.chars()),
+        );
+        self.draw();
+    }
+}
+
+pub fn _print(args: Arguments) {
+    if let Some(console) = console() {
+        console.print(args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::{Cell, Console};
+    use crate::graphics::{PixelColor, ShadowBuffer};
+    use crate::layer::LayerId;
+    use rumikan_shared::graphics::PixelFormat;
+
+    const BG: PixelColor = PixelColor::new(0, 0, 0);
+    const FG: PixelColor = PixelColor::new(0xff, 0xff, 0xff);
+    const COLS: usize = 10;
+    const ROWS: usize = 3;
+
+    /// Console of 10x3 keeping 2 more lines, and its buffer
+    fn console() -> (Console, ShadowBuffer) {
+        let cells = Box::leak(vec![Cell::BLANK; COLS * (ROWS + 2)].into_boxed_slice());
+        let console = Console::with_size(LayerId(0), COLS, ROWS, cells, BG, FG);
+        let (width, height) = (COLS * 8, ROWS * 16);
+        let pixels = Box::leak(vec![0u32; width * height].into_boxed_slice());
+        (
+            console,
+            ShadowBuffer::new(pixels, width, height, PixelFormat::Rgb),
+        )
+    }
+
+    /// The characters of the view, read back from the pixels
+    fn screen(buffer: &ShadowBuffer) -> Vec<String> {
+        (0..ROWS)
+            .map(|row| {
+                (0..COLS)
+                    .map(|col| {
+                        // the test font draws the printable characters as boxes
+                        match buffer.read_pixel(col * 8, row * 16) {
+                            Some(FG) => '#',
+                            _ => '.',
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The characters of the view, read from the cells
+    fn text(console: &Console) -> Vec<String> {
+        (0..ROWS)
+            .map(|row| console.line(row).iter().map(|cell| cell.c).collect())
+            .collect()
+    }
+
+    #[test]
+    fn control_characters() {
+        let (mut console, mut buffer) = console();
+        console.write(&mut buffer, "abc\rX\tY\x08Z\n\tab".chars());
+        assert_eq!(
+            text(&console),
+            vec!["Xbc     Z ", "        ab", "          "]
+        );
+        assert_eq!(
+            screen(&buffer),
+            vec!["###.....#.", "........##", ".........."]
+        );
+        // wraps at the end of the line
+        console.write(&mut buffer, "c".chars());
+        assert_eq!(text(&console)[2], "c         ");
+    }
+
+    #[test]
+    fn scroll_back() {
+        let (mut console, mut buffer) = console();
+        console.write(&mut buffer, "0\n1\n2\n3\n4\n5".chars());
+        assert_eq!(
+            text(&console),
+            vec!["3         ", "4         ", "5         "]
+        );
+        assert_eq!(console.history, 2);
+        // rows are copied by pixels when scrolled
+        assert_eq!(
+            screen(&buffer),
+            vec!["#.........", "#.........", "#........."]
+        );
+
+        console.scroll_view(&mut buffer, 1);
+        assert_eq!(
+            text(&console),
+            vec!["2         ", "3         ", "4         "]
+        );
+        // no more than kept
+        console.scroll_view(&mut buffer, 10);
+        assert_eq!(
+            text(&console),
+            vec!["1         ", "2         ", "3         "]
+        );
+
+        // printing goes back to the screen
+        console.write(&mut buffer, " x".chars());
+        assert_eq!(console.view_offset, 0);
+        assert_eq!(
+            text(&console),
+            vec!["3         ", "4         ", "5 x       "]
+        );
+        assert_eq!(
+            screen(&buffer),
+            vec!["#.........", "#.........", "#.#......."]
+        );
+    }
+
+    #[test]
+    fn page_up_down() {
+        let (mut console, mut buffer) = console();
+        console.write(&mut buffer, "0\n1\n2\n3\n4\n5".chars());
+
+        console.page_up();
+        // no more than kept
+        assert_eq!(
+            text(&console),
+            vec!["1         ", "2         ", "3         "]
+        );
+        console.page_down();
+        assert_eq!(
+            text(&console),
+            vec!["3         ", "4         ", "5         "]
+        );
+        console.page_down();
+        assert_eq!(console.view_offset, 0);
+
+        // Shift+PgUp and PgDn as a terminal sends
+        "\x1b[5;2~".chars().for_each(|c| console.on_input(c));
+        assert_eq!(console.view_offset, 2);
+        "x\x1b[A\x1b[6~".chars().for_each(|c| console.on_input(c));
+        assert_eq!(console.view_offset, 0);
+    }
+
+    #[test]
+    fn colors() {
+        let (mut console, mut buffer) = console();
+        console.write(
+            &mut buffer,
+            "\x1b[31ma\x1b[1mb\x1b[0;7mc\x1b[27;38;5;196;48;2;1;2;3md\x1b[m\x1b[92me\x1b[39mf"
+                .chars(),
+        );
+        let colors: Vec<_> = console.line(0)[..6]
+            .iter()
+            .map(|cell| (cell.fg_color, cell.bg_color))
+            .collect();
+        let red = PixelColor::new(0xcd, 0, 0);
+        let bright_red = PixelColor::new(0xff, 0, 0);
+        assert_eq!(
+            colors,
+            vec![
+                (red, BG),
+                (bright_red, BG),
+                (BG, FG),
+                (bright_red, PixelColor::new(1, 2, 3)),
+                (PixelColor::new(0, 0xff, 0), BG),
+                (FG, BG),
+            ]
+        );
+        // pixels of the inverted cell
+        assert_eq!(buffer.read_pixel(2 * 8, 0), Some(BG));
+        assert_eq!(buffer.read_pixel(3 * 8, 0), Some(bright_red));
+    }
+
+    #[test]
+    fn transparent_background() {
+        let (mut console, mut buffer) = console();
+        console.set_transparent_background();
+        console.write(
+            &mut buffer,
+            "a\x1b[40mb\x1b[0;30;7mc\x1b[0;38;2;0;0;0md\x1b[m\x1b[K".chars(),
+        );
+        let colors: Vec<_> = console.line(0)[..5]
+            .iter()
+            .map(|cell| (cell.fg_color, cell.bg_color))
+            .collect();
+        // only the default background is the transparent color
+        let black = BG.nudged();
+        assert_eq!(
+            colors,
+            vec![(FG, BG), (FG, black), (black, black), (black, BG), (FG, BG)]
+        );
+    }
+
+    #[test]
+    fn cursor_movement_and_erase() {
+        let (mut console, mut buffer) = console();
+        console.write(&mut buffer, "0123456789abcdefghij".chars());
+        // pending wrap at the end of the last row
+        console.write(
+            &mut buffer,
+            "\x1b[2;4Hx\x1b[2Cy\x1b[Az\x1b[5Dw\x1b[9Bv".chars(),
+        );
+        assert_eq!(
+            text(&console),
+            vec!["012w456z89", "abcxefyhij", "    v     "]
+        );
+
+        console.write(&mut buffer, "\x1b[1;5H\x1b[1K\x1b[2;3H\x1b[J".chars());
+        assert_eq!(
+            text(&console),
+            vec!["     56z89", "ab        ", "          "]
+        );
+        assert_eq!(screen(&buffer)[0], ".....#####");
+
+        // save and restore
+        console.write(&mut buffer, "\x1b[3;2H\x1b7\x1b[Hq\x1b8r".chars());
+        assert_eq!(
+            text(&console),
+            vec!["q    56z89", "ab        ", " r        "]
+        );
+    }
+
+    #[test]
+    fn double_width() {
+        let (mut console, mut buffer) = console();
+        // wraps before the last column
+        console.write(&mut buffer, "aあb\n012345678漢字".chars());
+        assert_eq!(
+            text(&console),
+            vec!["aあ\0b      ", "012345678 ", "漢\0字\0      "]
+        );
+        assert_eq!(screen(&buffer)[0], "####......");
+
+        // overwriting a half blanks the other
+        console.write(&mut buffer, "\x1b[3;2Hx\x1b[1;2Hy\x1b[3;3H\x1b[K".chars());
+        assert_eq!(
+            text(&console),
+            vec!["ay b      ", "012345678 ", " x        "]
+        );
+        assert_eq!(screen(&buffer)[0], "##.#......");
+        assert_eq!(screen(&buffer)[2], ".#........");
+    }
+}
@@ -14,6 +14,10 @@ impl<E> ErrorContext<E> {
             line,
         }
     }
+
+    pub fn error(&self) -> &E {
+        &self.error
+    }
 }
 
 impl<E> Debug for ErrorContext<E>
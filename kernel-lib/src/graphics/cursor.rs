@@ -0,0 +1,223 @@
+//! Mouse cursor shapes.
+//!
+//! A shape is rows of symbols, each of which picks a color of the palette:
+//! `' '` is transparent, `'@'` the edge, `'#'` the fill and `'+'` the accent.
+//! Changing the palette gives the cursors another theme.
+
+use crate::graphics::PixelColor;
+
+/// Color with the opacity from 0 to 255
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PaletteColor {
+    pub color: PixelColor,
+    pub alpha: u8,
+}
+
+impl PaletteColor {
+    pub const fn new(color: PixelColor, alpha: u8) -> PaletteColor {
+        PaletteColor { color, alpha }
+    }
+}
+
+/// Colors of the edge, the fill and the accent
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Palette {
+    pub edge: PaletteColor,
+    pub fill: PaletteColor,
+    pub accent: PaletteColor,
+}
+
+pub const DEFAULT_PALETTE: Palette = Palette {
+    edge: PaletteColor::new(PixelColor::new(0xff, 0xff, 0xff), 0xff),
+    fill: PaletteColor::new(PixelColor::new(0xff, 0, 0), 0xff),
+    accent: PaletteColor::new(PixelColor::new(0xff, 0xff, 0xff), 0x80),
+};
+
+const TRANSPARENT: PaletteColor = PaletteColor::new(PixelColor::new(0, 0, 0), 0);
+
+/// Bitmap of a cursor and the point in it which the mouse points at
+#[derive(Debug, Copy, Clone)]
+pub struct Cursor {
+    rows: &'static [&'static [u8]],
+    hotspot: (usize, usize),
+    palette: Palette,
+}
+
+impl Cursor {
+    /// The rows are as long as the first one
+    pub const fn new(
+        rows: &'static [&'static [u8]],
+        hotspot: (usize, usize),
+        palette: Palette,
+    ) -> Cursor {
+        Cursor {
+            rows,
+            hotspot,
+            palette,
+        }
+    }
+
+    pub fn with_palette(self, palette: Palette) -> Cursor {
+        Cursor { palette, ..self }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (
+            self.rows.first().map_or(0, |row| row.len()),
+            self.rows.len(),
+        )
+    }
+
+    pub fn hotspot(&self) -> (usize, usize) {
+        self.hotspot
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> PaletteColor {
+        match self.rows.get(y).and_then(|row| row.get(x)) {
+            Some(b'@') => self.palette.edge,
+            Some(b'#') => self.palette.fill,
+            Some(b'+') => self.palette.accent,
+            _ => TRANSPARENT,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CursorShape {
+    Arrow,
+    /// I-beam over the text
+    Text,
+    /// Diagonal arrows to resize from the corner
+    Resize,
+    /// Hourglass while waiting
+    Busy,
+}
+
+impl CursorShape {
+    pub fn cursor(self, palette: Palette) -> Cursor {
+        let (rows, hotspot) = match self {
+            CursorShape::Arrow => (ARROW, (0, 0)),
+            CursorShape::Text => (TEXT, (3, 8)),
+            CursorShape::Resize => (RESIZE, (5, 5)),
+            CursorShape::Busy => (BUSY, (5, 7)),
+        };
+        Cursor::new(rows, hotspot, palette)
+    }
+}
+
+const ARROW: &[&[u8]] = &[
+    b"@               ",
+    b"@@              ",
+    b"@#@             ",
+    b"@##@            ",
+    b"@###@           ",
+    b"@####@          ",
+    b"@#####@         ",
+    b"@######@        ",
+    b"@#######@       ",
+    b"@########@      ",
+    b"@#########@     ",
+    b"@##########@    ",
+    b"@###########@   ",
+    b"@############@  ",
+    b"@######@@@@@@@@ ",
+    b"@######@        ",
+    b"@####@@#@       ",
+    b"@###@ @#@       ",
+    b"@##@   @#@      ",
+    b"@#@    @#@      ",
+    b"@@      @#@     ",
+    b"@       @#@     ",
+    b"         @#@    ",
+    b"         @@@    ",
+];
+
+#[rustfmt::skip]
+const TEXT: &[&[u8]] = &[
+    b"@@@ @@@",
+    b"@##@##@",
+    b"@@@#@@@",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"  @#@  ",
+    b"@@@#@@@",
+    b"@##@##@",
+    b"@@@ @@@",
+];
+
+const RESIZE: &[&[u8]] = &[
+    b"@@@@@@     ",
+    b"@###@      ",
+    b"@##@       ",
+    b"@#@#@      ",
+    b"@@ @#@     ",
+    b"    @#@    ",
+    b"     @#@ @@",
+    b"      @#@#@",
+    b"       @##@",
+    b"      @###@",
+    b"     @@@@@@",
+];
+
+const BUSY: &[&[u8]] = &[
+    b"@@@@@@@@@@@",
+    b"@#########@",
+    b" @#######@ ",
+    b" @#+++++#@ ",
+    b"  @#+++#@  ",
+    b"   @#+#@   ",
+    b"    @#@    ",
+    b"    @#@    ",
+    b"   @###@   ",
+    b"  @##+##@  ",
+    b" @##+++##@ ",
+    b" @#+++++#@ ",
+    b"@#########@",
+    b"@@@@@@@@@@@",
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::cursor::{
+        CursorShape, Palette, PaletteColor, DEFAULT_PALETTE, TRANSPARENT,
+    };
+    use crate::graphics::PixelColor;
+
+    #[test]
+    fn shapes() {
+        for &shape in &[
+            CursorShape::Arrow,
+            CursorShape::Text,
+            CursorShape::Resize,
+            CursorShape::Busy,
+        ] {
+            let cursor = shape.cursor(DEFAULT_PALETTE);
+            let (width, height) = cursor.size();
+            assert!(cursor.rows.iter().all(|row| row.len() == width));
+            let (x, y) = cursor.hotspot();
+            assert!(x < width && y < height);
+        }
+
+        let arrow = CursorShape::Arrow.cursor(DEFAULT_PALETTE);
+        assert_eq!(arrow.size(), (16, 24));
+        assert_eq!(arrow.pixel(0, 0), DEFAULT_PALETTE.edge);
+        assert_eq!(arrow.pixel(1, 2), DEFAULT_PALETTE.fill);
+        assert_eq!(arrow.pixel(15, 0), TRANSPARENT);
+        assert_eq!(arrow.pixel(16, 0), TRANSPARENT);
+
+        let dark = Palette {
+            edge: PaletteColor::new(PixelColor::new(0, 0, 0), 0xff),
+            ..DEFAULT_PALETTE
+        };
+        let busy = CursorShape::Busy.cursor(DEFAULT_PALETTE).with_palette(dark);
+        assert_eq!(busy.pixel(0, 0), dark.edge);
+        assert_eq!(busy.pixel(5, 3), DEFAULT_PALETTE.accent);
+    }
+}
@@ -0,0 +1,437 @@
+//! Bitmap fonts registered at boot.
+//!
+//! The build script converts the fonts into glyph tables of this layout in little endian:
+//!
+//! | offset      | size            | content                                   |
+//! |-------------|-----------------|-------------------------------------------|
+//! | 0           | 4               | magic `RMKF`                              |
+//! | 4           | 2               | glyph width                               |
+//! | 6           | 2               | glyph height                              |
+//! | 8           | 4               | number of the glyphs `n`                  |
+//! | 12          | 4 * n           | code points of the glyphs, ascending      |
+//! | 12 + 4 * n  | bitmap size * n | bitmaps, each row `(width + 7) / 8` bytes |
+//!
+//! Text is laid out in cells, whose size follows the font selected for the screen.
+//! Glyphs of smaller fonts are scaled up by integers,
+//! and a character no font has is drawn as a hollow box rather than disappearing.
+
+use crate::error::ErrorContext;
+use core::cmp::Ordering;
+
+#[derive(Debug)]
+pub enum ErrorType {
+    InvalidFormat,
+    TooManyFonts,
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+const MAGIC: &[u8] = b"RMKF";
+const HEADER_SIZE: usize = 12;
+/// The build script of the kernel has the same limit on the fonts it generates
+const MAX_FONTS: usize = 8;
+/// Cell size until a font is selected, which is of the Shinonome font
+const DEFAULT_CELL_SIZE: (usize, usize) = (8, 16);
+/// The font is made as large as the screen still has this many rows
+const MIN_ROWS: usize = 45;
+
+/// Pairs of a code point and the index of its full-width glyph in little-endian u16,
+/// sorted by the code point. Generated from the mapping of EUC-JP, whose two-byte codes are JIS X 0208
+static JIS_X_0208: &[u8] = include_bytes!("../../resources/jisx0208.bin");
+
+static mut FONT_SET: FontSet = FontSet::new();
+
+/// Glyph table converted by the build script
+#[derive(Debug, Copy, Clone)]
+pub struct Font {
+    width: usize,
+    height: usize,
+    count: usize,
+    data: &'static [u8],
+}
+
+impl Font {
+    pub fn parse(data: &'static [u8]) -> Result<Font> {
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+            return Err(mkerror!(ErrorType::InvalidFormat));
+        }
+        let font = Font {
+            width: u16::from_le_bytes([data[4], data[5]]) as usize,
+            height: u16::from_le_bytes([data[6], data[7]]) as usize,
+            count: u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize,
+            data,
+        };
+        let size = font.bitmaps_offset() + font.count * font.bitmap_size();
+        if font.width == 0 || font.height == 0 || data.len() < size {
+            return Err(mkerror!(ErrorType::InvalidFormat));
+        }
+        Ok(font)
+    }
+
+    pub fn glyph_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn bitmap_size(&self) -> usize {
+        (self.width + 7) / 8 * self.height
+    }
+
+    fn bitmaps_offset(&self) -> usize {
+        HEADER_SIZE + 4 * self.count
+    }
+
+    fn code_point(&self, index: usize) -> u32 {
+        let e = &self.data[HEADER_SIZE + 4 * index..];
+        u32::from_le_bytes([e[0], e[1], e[2], e[3]])
+    }
+
+    /// Glyph of the character enlarged `scale` times
+    pub fn glyph(&self, c: char, scale: usize) -> Option<Glyph> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.code_point(mid).cmp(&(c as u32)) {
+                Ordering::Equal => {
+                    let offset = self.bitmaps_offset() + mid * self.bitmap_size();
+                    return Some(Glyph {
+                        width: self.width,
+                        height: self.height,
+                        scale,
+                        bitmap: &self.data[offset..offset + self.bitmap_size()],
+                    });
+                }
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+}
+
+/// Bitmap of a character. Each row is `(width + 7) / 8` bytes from the MSB on the left
+#[derive(Debug, Copy, Clone)]
+pub struct Glyph {
+    width: usize,
+    height: usize,
+    /// Each dot is drawn as a square of this size
+    scale: usize,
+    bitmap: &'static [u8],
+}
+
+impl Glyph {
+    pub fn width(&self) -> usize {
+        self.width * self.scale
+    }
+
+    pub fn height(&self) -> usize {
+        self.height * self.scale
+    }
+
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        let (x, y) = (x / self.scale, y / self.scale);
+        let stride = (self.width + 7) / 8;
+        self.bitmap[y * stride + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// The registered fonts and the cell size selected among them
+pub struct FontSet {
+    fonts: [Option<Font>; MAX_FONTS],
+    cell_size: (usize, usize),
+}
+
+impl FontSet {
+    pub const fn new() -> FontSet {
+        FontSet {
+            fonts: [None; MAX_FONTS],
+            cell_size: DEFAULT_CELL_SIZE,
+        }
+    }
+
+    /// Fonts registered earlier take precedence for the characters in common
+    pub fn register(&mut self, font: Font) -> Result<()> {
+        let slot = self
+            .fonts
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or_else(|| mkerror!(ErrorType::TooManyFonts))?;
+        *slot = Some(font);
+        Ok(())
+    }
+
+    fn fonts(&self) -> impl Iterator<Item = &Font> {
+        self.fonts.iter().flatten()
+    }
+
+    /// Choose the tallest text font the screen has [`MIN_ROWS`] rows of,
+    /// scaled up while the screen still has as many rows
+    pub fn select_size(&mut self, screen_height: usize) {
+        let text_fonts = || self.fonts().filter(|font| font.glyph('A', 1).is_some());
+        let font = text_fonts()
+            .filter(|font| font.height * MIN_ROWS <= screen_height)
+            .max_by_key(|font| font.height)
+            .or_else(|| text_fonts().min_by_key(|font| font.height));
+        if let Some(font) = font {
+            let scale = (screen_height / (font.height * MIN_ROWS)).max(1);
+            self.cell_size = (font.width * scale, font.height * scale);
+        }
+    }
+
+    pub fn cell_size(&self) -> (usize, usize) {
+        self.cell_size
+    }
+
+    /// Glyph of the character fitting the cell height, from the font scaled up the least
+    pub fn glyph(&self, c: char) -> Option<Glyph> {
+        if c.is_control() {
+            return None;
+        }
+        let height = self.cell_size.1;
+        let glyph = self
+            .fonts()
+            .filter(|font| height % font.height == 0)
+            .filter_map(|font| font.glyph(c, height / font.height))
+            .min_by_key(|glyph| glyph.scale);
+        Some(glyph.unwrap_or_else(|| missing_glyph(char_width(c), height)))
+    }
+}
+
+pub fn register(font: Font) -> Result<()> {
+    unsafe { FONT_SET.register(font) }
+}
+
+/// Select the cell size for the screen. See [`FontSet::select_size`]
+pub fn select_size(screen_height: usize) {
+    unsafe { FONT_SET.select_size(screen_height) }
+}
+
+/// Size of a half-width character, which is the unit of the text layout
+pub fn cell_size() -> (usize, usize) {
+    unsafe { FONT_SET.cell_size() }
+}
+
+/// Glyph of the character, which is `None` for the control characters
+#[cfg(not(test))]
+pub fn get_glyph(c: char) -> Option<Glyph> {
+    unsafe { FONT_SET.glyph(c) }
+}
+
+// the fonts aren't registered in the tests. Printable characters are drawn as a box
+#[cfg(test)]
+pub fn get_glyph(c: char) -> Option<Glyph> {
+    static BOX: [u8; 32] = [0xff; 32];
+    static BLANK: [u8; 16] = [0; 16];
+    let (width, bitmap): (usize, &'static [u8]) = match c {
+        c if c.is_control() => return None,
+        '!'..='~' => (8, &BOX),
+        ' ' | '\u{ff61}'..='\u{ff9f}' => (8, &BLANK),
+        c if jis_x_0208_index(c).is_some() => (16, &BOX),
+        c => return Some(missing_glyph(char_width(c), DEFAULT_CELL_SIZE.1)),
+    };
+    Some(Glyph {
+        width,
+        height: 16,
+        scale: 1,
+        bitmap,
+    })
+}
+
+fn is_halfwidth(c: char) -> bool {
+    // half-width katakana
+    matches!(c, ' '..='~' | '\u{ff61}'..='\u{ff9f}')
+}
+
+/// Index of the full-width glyph, which is `94 * (row - 1) + (cell - 1)` of JIS X 0208
+pub fn jis_x_0208_index(c: char) -> Option<usize> {
+    let entry = |i: usize| {
+        let e = &JIS_X_0208[4 * i..4 * i + 4];
+        (
+            u16::from_le_bytes([e[0], e[1]]) as u32,
+            u16::from_le_bytes([e[2], e[3]]) as usize,
+        )
+    };
+    let (mut low, mut high) = (0, JIS_X_0208.len() / 4);
+    while low < high {
+        let mid = (low + high) / 2;
+        let (code, index) = entry(mid);
+        match code.cmp(&(c as u32)) {
+            Ordering::Equal => return Some(index),
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+        }
+    }
+    None
+}
+
+/// Whether the character is shown in two columns as in East Asian Width
+fn is_wide(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x20000..=0x3fffd)
+}
+
+/// Number of the cells the character takes
+pub fn char_width(c: char) -> usize {
+    if is_halfwidth(c) || c.is_control() {
+        1
+    } else if is_wide(c) || jis_x_0208_index(c).is_some() {
+        2
+    } else {
+        1
+    }
+}
+
+/// Total width of the characters in pixels
+pub fn str_width(s: &str) -> usize {
+    s.chars().map(|c| char_width(c) * cell_size().0).sum()
+}
+
+/// Hollow box for the characters without glyphs, scaled up to the cell height
+fn missing_glyph(width: usize, cell_height: usize) -> Glyph {
+    static NARROW: [u8; 16] = [
+        0, 0, 0x7e, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7e, 0, 0,
+    ];
+    static WIDE: [u8; 32] = [
+        0, 0, 0, 0, 0x7f, 0xfe, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40,
+        0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x7f, 0xfe, 0, 0, 0, 0,
+    ];
+    let (width, bitmap): (usize, &'static [u8]) = if width == 2 {
+        (16, &WIDE)
+    } else {
+        (8, &NARROW)
+    };
+    Glyph {
+        width,
+        height: 16,
+        scale: (cell_height / 16).max(1),
+        bitmap,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::fonts::{
+        char_width, get_glyph, jis_x_0208_index, str_width, Font, FontSet,
+    };
+
+    /// Glyph table whose `i`-th glyph is filled in the rows up to `i`
+    fn table(width: usize, height: usize, chars: &str) -> &'static [u8] {
+        let mut data = b"RMKF".to_vec();
+        data.extend_from_slice(&(width as u16).to_le_bytes());
+        data.extend_from_slice(&(height as u16).to_le_bytes());
+        data.extend_from_slice(&(chars.chars().count() as u32).to_le_bytes());
+        for c in chars.chars() {
+            data.extend_from_slice(&(c as u32).to_le_bytes());
+        }
+        let stride = (width + 7) / 8;
+        for i in 0..chars.chars().count() {
+            for y in 0..height {
+                let row = if y <= i { 0xff } else { 0 };
+                data.extend(std::iter::repeat(row).take(stride));
+            }
+        }
+        Box::leak(data.into_boxed_slice())
+    }
+
+    #[test]
+    fn parse_table() {
+        let font = Font::parse(table(12, 24, "ABC")).unwrap();
+        assert_eq!(font.glyph_size(), (12, 24));
+        let glyph = font.glyph('B', 1).unwrap();
+        assert_eq!((glyph.width(), glyph.height()), (12, 24));
+        assert!(glyph.is_set(11, 1));
+        assert!(!glyph.is_set(0, 2));
+        assert!(font.glyph('D', 1).is_none());
+
+        assert!(Font::parse(b"RMKF").is_err());
+        // truncated
+        assert!(Font::parse(&table(8, 16, "AB")[..40]).is_err());
+        assert!(Font::parse(b"\x72\xb5\x4a\x86\0\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn select_and_scale() {
+        let mut fonts = FontSet::new();
+        fonts
+            .register(Font::parse(table(16, 32, "A")).unwrap())
+            .unwrap();
+        fonts
+            .register(Font::parse(table(8, 16, "AB")).unwrap())
+            .unwrap();
+        // without the alphabets
+        fonts
+            .register(Font::parse(table(16, 16, "あ")).unwrap())
+            .unwrap();
+
+        fonts.select_size(768);
+        assert_eq!(fonts.cell_size(), (8, 16));
+        let b = fonts.glyph('B').unwrap();
+        assert_eq!((b.width(), b.height()), (8, 16));
+
+        // 4K
+        fonts.select_size(2160);
+        assert_eq!(fonts.cell_size(), (16, 32));
+        // from the larger font
+        assert!(!fonts.glyph('A').unwrap().is_set(0, 1));
+        // scaled up
+        let b = fonts.glyph('B').unwrap();
+        assert_eq!((b.width(), b.height()), (16, 32));
+        assert!(b.is_set(15, 3));
+        assert!(!b.is_set(15, 4));
+        assert_eq!(fonts.glyph('あ').unwrap().width(), 32);
+        // missing
+        assert_eq!(fonts.glyph('C').unwrap().height(), 32);
+        assert!(fonts.glyph('\n').is_none());
+
+        // only the small one fits
+        fonts.select_size(1000);
+        assert_eq!(fonts.cell_size(), (8, 16));
+        fonts.select_size(100);
+        assert_eq!(fonts.cell_size(), (8, 16));
+    }
+
+    #[test]
+    fn jis_x_0208() {
+        // row 1 cell 1
+        assert_eq!(jis_x_0208_index('\u{3000}'), Some(0));
+        assert_eq!(jis_x_0208_index('あ'), Some(3 * 94 + 1));
+        // the first and the last kanji of level 1 and 2
+        assert_eq!(jis_x_0208_index('亜'), Some(15 * 94));
+        assert_eq!(jis_x_0208_index('熙'), Some(83 * 94 + 5));
+        assert_eq!(jis_x_0208_index('α'), Some(5 * 94 + 32));
+        assert_eq!(jis_x_0208_index('A'), None);
+        assert_eq!(jis_x_0208_index('한'), None);
+        assert_eq!(jis_x_0208_index('😀'), None);
+    }
+
+    #[test]
+    fn widths() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('ｱ'), 1);
+        assert_eq!(char_width('あ'), 2);
+        assert_eq!(char_width('α'), 2);
+        // not in the fonts
+        assert_eq!(char_width('한'), 2);
+        assert_eq!(char_width('é'), 1);
+        assert_eq!(str_width("aあ"), 24);
+
+        assert_eq!(get_glyph('a').unwrap().width(), 8);
+        assert_eq!(get_glyph('漢').unwrap().width(), 16);
+        let missing = get_glyph('한').unwrap();
+        assert_eq!(missing.width(), 16);
+        assert!(!missing.is_set(8, 8));
+        assert!(missing.is_set(1, 8));
+        assert!(get_glyph('\n').is_none());
+    }
+}
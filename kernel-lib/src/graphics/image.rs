@@ -0,0 +1,611 @@
+//! Decoders of the images for the wallpaper and the icons.
+//!
+//! - BMP: uncompressed 24-bit and 32-bit ones, the latter may have bit fields with alpha
+//! - QOI: "Quite OK Image Format", which is as simple as BMP but compressed
+//!
+//! Without allocator, the pixels are decoded into the buffer given by the caller.
+//! An image larger than the buffer is scaled down by skipping pixels,
+//! or it can be drawn straight onto a [`PixelWriter`] without the buffer.
+
+use crate::error::ErrorContext;
+use crate::graphics::{PixelColor, PixelWriter, Rect};
+
+#[derive(Debug)]
+pub enum ErrorType {
+    UnsupportedFormat,
+    InvalidFormat,
+    /// The buffer can't hold even a pixel of the image of this size
+    TooLarge {
+        width: usize,
+        height: usize,
+    },
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+const BMP_MAGIC: &[u8] = b"BM";
+const BMP_HEADER_SIZE: usize = 54;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const QOI_MAGIC: &[u8] = b"qoif";
+const QOI_HEADER_SIZE: usize = 14;
+const QOI_OP_RGB: u8 = 0xfe;
+const QOI_OP_RGBA: u8 = 0xff;
+const QOI_OP_INDEX: u8 = 0x00;
+const QOI_OP_DIFF: u8 = 0x40;
+const QOI_OP_LUMA: u8 = 0x80;
+const QOI_MASK: u8 = 0xc0;
+
+/// Decoded image whose pixels are `0xAARRGGBB` from the top left
+#[derive(Debug, Copy, Clone)]
+pub struct Image<'a> {
+    width: usize,
+    height: usize,
+    pixels: &'a [u32],
+}
+
+impl<'a> Image<'a> {
+    /// Decode BMP or QOI, told by the magic, into `buffer`.
+    /// The image is scaled down by the smallest integer factor that fits it in `buffer`
+    pub fn decode(data: &[u8], buffer: &'a mut [u32]) -> Result<Image<'a>> {
+        let mut dst = Downscaled::new(buffer);
+        decode_into(data, &mut dst)?;
+        Ok(dst.into_image())
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Color and opacity of the pixel
+    pub fn pixel(&self, x: usize, y: usize) -> (PixelColor, u8) {
+        color_of(self.pixels[y * self.width + x])
+    }
+
+    /// The pixel at `(x, y)` of the image stretched to `dst`, which must contain the point
+    pub fn pixel_at(&self, dst: Rect, x: usize, y: usize) -> (PixelColor, u8) {
+        self.pixel(
+            (x - dst.x) * self.width / dst.width,
+            (y - dst.y) * self.height / dst.height,
+        )
+    }
+
+    /// The largest area in `bounds` keeping the aspect ratio, at the center
+    pub fn fit(&self, bounds: Rect) -> Rect {
+        fit(self.width, self.height, bounds)
+    }
+}
+
+/// Decode BMP or QOI and draw it stretched to [`Image::fit`] in `bounds`, as
+/// [`PixelWriter::draw_image`] does. No buffer is needed for the pixels of the whole image
+pub fn draw_fit<W: PixelWriter + ?Sized>(data: &[u8], writer: &mut W, bounds: Rect) -> Result<()> {
+    decode_into(
+        data,
+        &mut Stretched {
+            writer,
+            bounds,
+            dst: Rect::default(),
+            width: 0,
+            height: 0,
+        },
+    )
+}
+
+fn decode_into(data: &[u8], sink: &mut impl Sink) -> Result<()> {
+    if data.starts_with(BMP_MAGIC) {
+        decode_bmp(data, sink)
+    } else if data.starts_with(QOI_MAGIC) {
+        decode_qoi(data, sink)
+    } else {
+        Err(mkerror!(ErrorType::UnsupportedFormat))
+    }
+}
+
+fn fit(width: usize, height: usize, bounds: Rect) -> Rect {
+    if width == 0 || height == 0 {
+        return Rect::new(bounds.x, bounds.y, 0, 0);
+    }
+    let (fit_width, fit_height) = if bounds.width * height <= bounds.height * width {
+        (bounds.width, height * bounds.width / width)
+    } else {
+        (width * bounds.height / height, bounds.height)
+    };
+    Rect::new(
+        bounds.x + (bounds.width - fit_width) / 2,
+        bounds.y + (bounds.height - fit_height) / 2,
+        fit_width,
+        fit_height,
+    )
+}
+
+fn argb(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+fn color_of(pixel: u32) -> (PixelColor, u8) {
+    let color = PixelColor::new((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8);
+    (color, (pixel >> 24) as u8)
+}
+
+/// Receives the pixels being decoded
+trait Sink {
+    /// Called with the size of the image before the pixels.
+    /// Returns the interval of the pixels it needs in both directions, which the others may skip
+    fn start(&mut self, width: usize, height: usize) -> Result<usize>;
+    /// The pixel at `(x, y)` of the image in `0xAARRGGBB`
+    fn put(&mut self, x: usize, y: usize, pixel: u32);
+}
+
+/// Pixels decoded into the buffer, which are of every `factor` pixels of the image
+struct Downscaled<'a> {
+    buffer: &'a mut [u32],
+    width: usize,
+    height: usize,
+    factor: usize,
+}
+
+impl<'a> Downscaled<'a> {
+    fn new(buffer: &'a mut [u32]) -> Downscaled<'a> {
+        Downscaled {
+            buffer,
+            width: 0,
+            height: 0,
+            factor: 1,
+        }
+    }
+
+    fn into_image(self) -> Image<'a> {
+        let pixels: &'a [u32] = self.buffer;
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels: &pixels[..self.width * self.height],
+        }
+    }
+}
+
+impl Sink for Downscaled<'_> {
+    fn start(&mut self, width: usize, height: usize) -> Result<usize> {
+        let scaled = |factor: usize| {
+            (
+                (width + factor - 1) / factor,
+                (height + factor - 1) / factor,
+            )
+        };
+        let len = self.buffer.len();
+        let fits =
+            |(width, height): (usize, usize)| width.checked_mul(height).map_or(false, |n| n <= len);
+        let mut factor = 1;
+        while !fits(scaled(factor)) {
+            if scaled(factor) == (1, 1) {
+                return Err(mkerror!(ErrorType::TooLarge { width, height }));
+            }
+            factor += 1;
+        }
+        let (scaled_width, scaled_height) = scaled(factor);
+        self.width = scaled_width;
+        self.height = scaled_height;
+        self.factor = factor;
+        Ok(factor)
+    }
+
+    fn put(&mut self, x: usize, y: usize, pixel: u32) {
+        if x % self.factor == 0 && y % self.factor == 0 {
+            self.buffer[y / self.factor * self.width + x / self.factor] = pixel;
+        }
+    }
+}
+
+/// Draws the pixels stretched to the area fitting in `bounds`
+struct Stretched<'w, W: ?Sized> {
+    writer: &'w mut W,
+    bounds: Rect,
+    dst: Rect,
+    width: usize,
+    height: usize,
+}
+
+impl<W: PixelWriter + ?Sized> Sink for Stretched<'_, W> {
+    fn start(&mut self, width: usize, height: usize) -> Result<usize> {
+        self.width = width;
+        self.height = height;
+        self.dst = fit(width, height, self.bounds);
+        Ok(1)
+    }
+
+    /// Draw the pixels of `dst` which [`Image::pixel_at`] maps to the pixel
+    fn put(&mut self, x: usize, y: usize, pixel: u32) {
+        let (color, alpha) = color_of(pixel);
+        if alpha == 0 {
+            return;
+        }
+        // the first and the end of the range mapped to `i`
+        let span = |i: usize, len: usize, dst_len: usize| {
+            (
+                (i * dst_len + len - 1) / len,
+                ((i + 1) * dst_len + len - 1) / len,
+            )
+        };
+        let (x0, x1) = span(x, self.width, self.dst.width);
+        let (y0, y1) = span(y, self.height, self.dst.height);
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let rect = Rect::new(self.dst.x + x0, self.dst.y + y0, x1 - x0, y1 - y0);
+        if alpha == 0xff {
+            self.writer.fill_rect(rect, color);
+        } else {
+            // as the writer draws a translucent pixel of an image
+            let pixels = [pixel];
+            let image = Image {
+                width: 1,
+                height: 1,
+                pixels: &pixels,
+            };
+            self.writer.draw_image(&image, rect);
+        }
+    }
+}
+
+fn u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Extracts a channel of the bit fields, scaled to 8 bits
+#[derive(Debug, Copy, Clone)]
+struct BitField {
+    mask: u32,
+    shift: u32,
+}
+
+impl BitField {
+    fn new(mask: u32) -> BitField {
+        BitField {
+            mask,
+            shift: if mask == 0 { 0 } else { mask.trailing_zeros() },
+        }
+    }
+
+    fn get(&self, pixel: u32, default: u8) -> u8 {
+        if self.mask == 0 {
+            return default;
+        }
+        let max = (self.mask >> self.shift) as u64;
+        (((pixel & self.mask) >> self.shift) as u64 * 255 / max) as u8
+    }
+}
+
+fn decode_bmp(data: &[u8], sink: &mut impl Sink) -> Result<()> {
+    if data.len() < BMP_HEADER_SIZE {
+        return Err(mkerror!(ErrorType::InvalidFormat));
+    }
+    let offset = u32_le(data, 10) as usize;
+    let info_size = u32_le(data, 14) as usize;
+    let (width, height) = (u32_le(data, 18) as i32, u32_le(data, 22) as i32);
+    let bpp = u16_le(data, 28) as usize;
+    let compression = u32_le(data, 30);
+    if width < 0 || height == i32::MIN {
+        return Err(mkerror!(ErrorType::InvalidFormat));
+    }
+    // bottom-up unless the height is negative
+    let (width, bottom_up, height) = (width as usize, height > 0, height.unsigned_abs() as usize);
+
+    let opaque = [0xff0000, 0xff00, 0xff, 0];
+    let [r, g, b, a] = match (bpp, compression) {
+        (24, BI_RGB) | (32, BI_RGB) => opaque,
+        // follow the info header, or in it since version 4, which also has the alpha mask
+        (32, BI_BITFIELDS) if data.len() >= BMP_HEADER_SIZE + 12 => [
+            u32_le(data, 54),
+            u32_le(data, 58),
+            u32_le(data, 62),
+            if info_size >= 56 && data.len() >= 70 {
+                u32_le(data, 66)
+            } else {
+                0
+            },
+        ],
+        _ => return Err(mkerror!(ErrorType::UnsupportedFormat)),
+    };
+    let (r, g, b, a) = (
+        BitField::new(r),
+        BitField::new(g),
+        BitField::new(b),
+        BitField::new(a),
+    );
+
+    // rows are aligned to 4 bytes
+    let stride = (bpp * width + 31) / 32 * 4;
+    let size = stride
+        .checked_mul(height)
+        .and_then(|s| s.checked_add(offset));
+    if size.map_or(true, |size| data.len() < size) {
+        return Err(mkerror!(ErrorType::InvalidFormat));
+    }
+    let factor = sink.start(width, height)?;
+    for y in (0..height).step_by(factor) {
+        let row = if bottom_up { height - 1 - y } else { y };
+        let row = &data[offset + row * stride..];
+        for x in (0..width).step_by(factor) {
+            let pixel = if bpp == 24 {
+                u32_le(&[row[3 * x], row[3 * x + 1], row[3 * x + 2], 0], 0)
+            } else {
+                u32_le(row, 4 * x)
+            };
+            sink.put(
+                x,
+                y,
+                argb(
+                    r.get(pixel, 0),
+                    g.get(pixel, 0),
+                    b.get(pixel, 0),
+                    a.get(pixel, 0xff),
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn decode_qoi(data: &[u8], sink: &mut impl Sink) -> Result<()> {
+    let invalid = || mkerror!(ErrorType::InvalidFormat);
+    if data.len() < QOI_HEADER_SIZE {
+        return Err(invalid());
+    }
+    let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) as usize;
+    let len = width.checked_mul(height).ok_or_else(invalid)?;
+    // every pixel is decoded anyway as it depends on the previous ones
+    sink.start(width, height)?;
+
+    let mut bytes = data[QOI_HEADER_SIZE..].iter().copied();
+    let mut next = || bytes.next().ok_or_else(invalid);
+    let mut index = [[0u8; 4]; 64];
+    let [mut r, mut g, mut b, mut a] = [0u8, 0, 0, 0xff];
+    let mut run = 0;
+    for i in 0..len {
+        if run > 0 {
+            run -= 1;
+        } else {
+            let op = next()?;
+            match op {
+                QOI_OP_RGB => {
+                    r = next()?;
+                    g = next()?;
+                    b = next()?;
+                }
+                QOI_OP_RGBA => {
+                    r = next()?;
+                    g = next()?;
+                    b = next()?;
+                    a = next()?;
+                }
+                _ => match op & QOI_MASK {
+                    QOI_OP_INDEX => {
+                        let [ir, ig, ib, ia] = index[op as usize];
+                        r = ir;
+                        g = ig;
+                        b = ib;
+                        a = ia;
+                    }
+                    QOI_OP_DIFF => {
+                        r = r.wrapping_add((op >> 4) & 3).wrapping_sub(2);
+                        g = g.wrapping_add((op >> 2) & 3).wrapping_sub(2);
+                        b = b.wrapping_add(op & 3).wrapping_sub(2);
+                    }
+                    QOI_OP_LUMA => {
+                        let dg = (op & 0x3f).wrapping_sub(32);
+                        let rb = next()?;
+                        r = r.wrapping_add(dg).wrapping_add(rb >> 4).wrapping_sub(8);
+                        g = g.wrapping_add(dg);
+                        b = b.wrapping_add(dg).wrapping_add(rb & 0xf).wrapping_sub(8);
+                    }
+                    // QOI_OP_RUN, whose length is biased by 1
+                    _ => run = op & 0x3f,
+                },
+            }
+            let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64;
+            index[hash] = [r, g, b, a];
+        }
+        sink.put(i % width, i / width, argb(r, g, b, a));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::image::{draw_fit, ErrorType, Image};
+    use crate::graphics::{PixelColor, PixelWriter, Rect, ShadowBuffer};
+    use rumikan_shared::graphics::PixelFormat;
+
+    const RED: PixelColor = PixelColor::new(0xff, 0, 0);
+    const BLUE: PixelColor = PixelColor::new(0, 0, 0xff);
+
+    fn bmp_header(width: i32, height: i32, bpp: u16, compression: u32, info_size: u32) -> Vec<u8> {
+        let offset = 14 + info_size;
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&info_size.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bpp.to_le_bytes());
+        data.extend_from_slice(&compression.to_le_bytes());
+        data.resize(offset as usize, 0);
+        data
+    }
+
+    #[test]
+    fn bmp() {
+        // 3x2 bottom-up, whose rows are padded to 12 bytes
+        let mut data = bmp_header(3, 2, 24, 0, 40);
+        data.extend_from_slice(&[0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0, 0, 0]);
+        let mut buffer = [0; 6];
+        let image = Image::decode(&data, &mut buffer).unwrap();
+        assert_eq!(image.size(), (3, 2));
+        assert_eq!(image.pixel(0, 0), (RED, 0xff));
+        assert_eq!(image.pixel(2, 0), (PixelColor::new(0xff, 0xff, 0xff), 0xff));
+        assert_eq!(image.pixel(0, 1), (BLUE, 0xff));
+        assert_eq!(image.pixel(2, 1), (RED, 0xff));
+
+        // 2x1 top-down with the alpha in the bit fields of version 4 header
+        let mut data = bmp_header(2, -1, 32, 3, 108);
+        let masks = [0xff00u32, 0xff0000, 0xff000000, 0xff];
+        for (i, mask) in masks.iter().enumerate() {
+            data[54 + 4 * i..58 + 4 * i].copy_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(&0x0000ff80u32.to_le_bytes());
+        data.extend_from_slice(&0xff000000u32.to_le_bytes());
+        let mut buffer = [0; 2];
+        let image = Image::decode(&data, &mut buffer).unwrap();
+        assert_eq!(image.pixel(0, 0), (RED, 0x80));
+        assert_eq!(image.pixel(1, 0), (BLUE, 0));
+
+        // scaled down to fit
+        let mut small = [0; 1];
+        let image = Image::decode(&data, &mut small).unwrap();
+        assert_eq!(image.size(), (1, 1));
+        assert_eq!(image.pixel(0, 0), (RED, 0x80));
+        assert!(matches!(
+            Image::decode(&data, &mut []).unwrap_err().error(),
+            ErrorType::TooLarge {
+                width: 2,
+                height: 1
+            }
+        ));
+        // truncated
+        assert!(Image::decode(&data[..data.len() - 1], &mut buffer).is_err());
+        // 8-bit palette
+        let data = bmp_header(1, 1, 8, 0, 40);
+        assert!(matches!(
+            Image::decode(&data, &mut buffer).unwrap_err().error(),
+            ErrorType::UnsupportedFormat
+        ));
+    }
+
+    #[test]
+    fn qoi() {
+        let mut data = b"qoif".to_vec();
+        data.extend_from_slice(&4u32.to_be_bytes());
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&[4, 0]);
+        // red, run of 2
+        data.extend_from_slice(&[0xfe, 0xff, 0, 0, 0xc1]);
+        // half transparent blue
+        data.extend_from_slice(&[0xff, 0, 0, 0xff, 0x80]);
+        // red again by the index
+        data.push(((0xff * 3 + 0xff * 11) % 64) as u8);
+        // diff: r - 1, g + 1, b + 0
+        data.push(0x40 | 1 << 4 | 3 << 2 | 2);
+        // luma: g + 10, r - g = 2, b - g = -3
+        data.extend_from_slice(&[0x80 | (10 + 32), (2 + 8) << 4 | (8 - 3)]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        let mut buffer = [0; 8];
+        let image = Image::decode(&data, &mut buffer).unwrap();
+        assert_eq!(image.size(), (4, 2));
+        assert_eq!(image.pixel(2, 0), (RED, 0xff));
+        assert_eq!(image.pixel(3, 0), (BLUE, 0x80));
+        assert_eq!(image.pixel(0, 1), (RED, 0xff));
+        assert_eq!(image.pixel(1, 1), (PixelColor::new(0xfe, 1, 0), 0xff));
+        assert_eq!(image.pixel(2, 1), (PixelColor::new(0x0a, 11, 7), 0xff));
+        // every other pixel
+        let mut small = [0; 3];
+        let image = Image::decode(&data, &mut small).unwrap();
+        assert_eq!(image.size(), (2, 1));
+        assert_eq!(image.pixel(0, 0), (RED, 0xff));
+        assert_eq!(image.pixel(1, 0), (RED, 0xff));
+        // no more pixel
+        assert!(Image::decode(&data[..25], &mut buffer).is_err());
+        assert!(Image::decode(b"GIF89a", &mut buffer).is_err());
+    }
+
+    #[test]
+    fn draw_scaled() {
+        // red, blue, and transparent one
+        let buffer = [0xffff0000, 0xff0000ff, 0x00ffffff, 0x80ff0000];
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: &buffer,
+        };
+        assert_eq!(image.fit(Rect::new(0, 0, 8, 4)), Rect::new(2, 0, 4, 4));
+        assert_eq!(image.fit(Rect::new(1, 1, 4, 10)), Rect::new(1, 4, 4, 4));
+
+        let pixels = Box::leak(vec![0u32; 5 * 4].into_boxed_slice());
+        let mut shadow = ShadowBuffer::new(pixels, 5, 4, PixelFormat::Rgb);
+        shadow.draw_image(&image, Rect::new(1, 0, 4, 4));
+        assert_eq!(shadow.read_pixel(0, 0), Some(PixelColor::new(0, 0, 0)));
+        assert_eq!(shadow.read_pixel(2, 1), Some(RED));
+        assert_eq!(shadow.read_pixel(3, 1), Some(BLUE));
+        assert_eq!(shadow.read_pixel(4, 2), Some(PixelColor::new(0x80, 0, 0)));
+        assert_eq!(shadow.read_pixel(1, 3), Some(PixelColor::new(0, 0, 0)));
+        assert_eq!(shadow.dirty_rects(), &[Rect::new(1, 0, 4, 4)]);
+    }
+
+    #[test]
+    fn draw_fit_as_decoded() {
+        // 3x2 with a transparent pixel
+        let mut data = bmp_header(3, -2, 32, 3, 56);
+        let masks = [0xff0000u32, 0xff00, 0xff, 0xff000000];
+        for (i, mask) in masks.iter().enumerate() {
+            data[54 + 4 * i..58 + 4 * i].copy_from_slice(&mask.to_le_bytes());
+        }
+        for pixel in &[
+            0xffff0000u32,
+            0xff0000ff,
+            0x00ffffff,
+            0xff00ff00,
+            0x80ff0000,
+            0xff0000ff,
+        ] {
+            data.extend_from_slice(&pixel.to_le_bytes());
+        }
+        let mut buffer = [0; 6];
+        let image = Image::decode(&data, &mut buffer).unwrap();
+
+        for &bounds in &[
+            Rect::new(0, 0, 7, 5),
+            Rect::new(1, 2, 5, 3),
+            Rect::new(0, 0, 2, 7),
+        ] {
+            let expected = Box::leak(vec![0u32; 7 * 7].into_boxed_slice());
+            let mut expected = ShadowBuffer::new(expected, 7, 7, PixelFormat::Rgb);
+            expected.draw_image(&image, image.fit(bounds));
+            let pixels = Box::leak(vec![0u32; 7 * 7].into_boxed_slice());
+            let mut shadow = ShadowBuffer::new(pixels, 7, 7, PixelFormat::Rgb);
+            draw_fit(&data, &mut shadow, bounds).unwrap();
+            for y in 0..7 {
+                for x in 0..7 {
+                    assert_eq!(
+                        shadow.read_pixel(x, y),
+                        expected.read_pixel(x, y),
+                        "{:?}",
+                        bounds
+                    );
+                }
+            }
+        }
+
+        let pixels = Box::leak(vec![0u32; 4].into_boxed_slice());
+        let mut shadow = ShadowBuffer::new(pixels, 2, 2, PixelFormat::Rgb);
+        let bounds = shadow.bounds();
+        assert!(matches!(
+            draw_fit(b"GIF89a", &mut shadow, bounds)
+                .unwrap_err()
+                .error(),
+            ErrorType::UnsupportedFormat
+        ));
+    }
+}
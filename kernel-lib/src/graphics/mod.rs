@@ -0,0 +1,438 @@
+use core::fmt;
+use core::fmt::{Arguments, Write};
+
+use crate::graphics::image::Image;
+use crate::util::collection::ArrayVec;
+use rumikan_shared::graphics::{FrameBufferInfo, PixelFormat};
+
+pub mod cursor;
+pub mod fonts;
+pub mod image;
+mod shadow;
+
+pub use shadow::ShadowBuffer;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PixelColor {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+impl PixelColor {
+    pub const fn new(r: u8, g: u8, b: u8) -> PixelColor {
+        PixelColor { r, g, b }
+    }
+
+    /// Encode to a 32-bit pixel laid out in `format`
+    pub fn to_native(self, format: PixelFormat) -> u32 {
+        let (lo, hi) = match format {
+            PixelFormat::Rgb => (self.r, self.b),
+            PixelFormat::Bgr => (self.b, self.r),
+        };
+        lo as u32 | (self.g as u32) << 8 | (hi as u32) << 16
+    }
+
+    /// Put over `background` with the opacity from 0 to 255
+    pub fn blend(self, background: PixelColor, alpha: u8) -> PixelColor {
+        let (alpha, rest) = (alpha as u32, 255 - alpha as u32);
+        let mix = |top: u8, bottom: u8| ((top as u32 * alpha + bottom as u32 * rest) / 255) as u8;
+        PixelColor::new(
+            mix(self.r, background.r),
+            mix(self.g, background.g),
+            mix(self.b, background.b),
+        )
+    }
+
+    /// A color next to this one, which looks the same but isn't equal
+    pub fn nudged(self) -> PixelColor {
+        PixelColor::new(self.r, self.g, self.b ^ 1)
+    }
+
+    pub fn from_native(pixel: u32, format: PixelFormat) -> PixelColor {
+        let (lo, g, hi) = (pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8);
+        match format {
+            PixelFormat::Rgb => PixelColor::new(lo, g, hi),
+            PixelFormat::Bgr => PixelColor::new(hi, g, lo),
+        }
+    }
+}
+
+/// Rectangle on a pixel buffer
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    /// The overlapping area, which is empty if they don't overlap
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let (right, bottom) = (
+            self.right().min(other.right()),
+            self.bottom().min(other.bottom()),
+        );
+        if right <= x || bottom <= y {
+            return Rect::default();
+        }
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// The smallest rectangle containing both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
+    /// Whether they overlap or share an edge
+    pub fn touches(&self, other: &Rect) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+}
+
+/// Drawing target. Pixels out of the resolution are clipped
+pub trait PixelWriter {
+    fn resolution(&self) -> (usize, usize);
+
+    fn write_pixel(&mut self, x: usize, y: usize, color: PixelColor);
+
+    fn fill_rect(&mut self, rect: Rect, color: PixelColor);
+
+    fn bounds(&self) -> Rect {
+        let (width, height) = self.resolution();
+        Rect::new(0, 0, width, height)
+    }
+
+    /// Pixels at negative coordinates are clipped as well
+    fn write_pixel_at(&mut self, x: isize, y: isize, color: PixelColor) {
+        if x >= 0 && y >= 0 {
+            self.write_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// Bresenham's line including both ends
+    fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: PixelColor) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.write_pixel_at(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Outline of the rectangle
+    fn draw_rect(&mut self, rect: Rect, color: PixelColor) {
+        if rect.is_empty() {
+            return;
+        }
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = rect;
+        self.fill_rect(Rect::new(x, y, width, 1), color);
+        self.fill_rect(Rect::new(x, rect.bottom() - 1, width, 1), color);
+        self.fill_rect(Rect::new(x, y, 1, height), color);
+        self.fill_rect(Rect::new(rect.right() - 1, y, 1, height), color);
+    }
+
+    /// Midpoint circle algorithm
+    fn draw_circle(&mut self, cx: isize, cy: isize, radius: isize, color: PixelColor) {
+        let (mut x, mut y, mut err) = (radius, 0, 1 - radius);
+        while x >= y {
+            for &(px, py) in &[(x, y), (y, x), (-y, x), (-x, y)] {
+                self.write_pixel_at(cx + px, cy + py, color);
+                self.write_pixel_at(cx - px, cy - py, color);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    fn fill_circle(&mut self, cx: isize, cy: isize, radius: isize, color: PixelColor) {
+        let (mut x, mut y, mut err) = (radius, 0, 1 - radius);
+        while x >= y {
+            for &(half, dy) in &[(x, y), (x, -y), (y, x), (y, -x)] {
+                if let Some(span) = span(cx - half, cx + half, cy + dy) {
+                    self.fill_rect(span, color);
+                }
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Draw the image stretched to `dst` by the nearest neighbor.
+    /// The pixels below can't be read, so the ones less than half opaque are just skipped
+    fn draw_image(&mut self, image: &Image, dst: Rect) {
+        let area = dst.intersection(&self.bounds());
+        for y in area.y..area.bottom() {
+            for x in area.x..area.right() {
+                let (color, alpha) = image.pixel_at(dst, x, y);
+                if alpha >= 0x80 {
+                    self.write_pixel(x, y, color);
+                }
+            }
+        }
+    }
+
+    fn write_char(&mut self, x: usize, y: usize, c: char, color: PixelColor) {
+        if let Some(glyph) = fonts::get_glyph(c) {
+            for dy in 0..glyph.height() {
+                for dx in 0..glyph.width() {
+                    if glyph.is_set(dx, dy) {
+                        self.write_pixel(x + dx, y + dy, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_str(&mut self, x: usize, y: usize, s: &str, color: PixelColor) {
+        let mut x = x;
+        for c in s.chars() {
+            self.write_char(x, y, c, color);
+            x += fonts::char_width(c) * fonts::cell_size().0;
+        }
+    }
+
+    fn write_fmt(&mut self, x: usize, y: usize, args: Arguments, color: PixelColor) -> fmt::Result {
+        let mut v = CharVec::new();
+        v.write_fmt(args)?;
+
+        let mut x = x;
+        for &c in v.as_slice() {
+            self.write_char(x, y, c, color);
+            x += fonts::char_width(c) * fonts::cell_size().0;
+        }
+        Ok(())
+    }
+}
+
+/// The row from `x0` to `x1` at `y`, clipped at the negative coordinates
+fn span(x0: isize, x1: isize, y: isize) -> Option<Rect> {
+    let x0 = x0.max(0);
+    if y < 0 || x1 < x0 {
+        return None;
+    }
+    Some(Rect::new(
+        x0 as usize,
+        y as usize,
+        (x1 - x0 + 1) as usize,
+        1,
+    ))
+}
+
+/// The framebuffer provided by GOP, which is written through to the screen
+#[derive(Clone, Copy, Debug)]
+pub struct FrameBuffer(FrameBufferInfo);
+
+impl FrameBuffer {
+    pub fn new(info: FrameBufferInfo) -> FrameBuffer {
+        FrameBuffer(info)
+    }
+
+    pub fn stride(&self) -> usize {
+        self.0.stride()
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.0.pixel_format()
+    }
+
+    fn row_ptr(&mut self, x: usize, y: usize) -> *mut u32 {
+        unsafe { (self.0.mut_ptr() as *mut u32).add(self.stride() * y + x) }
+    }
+
+    /// Copy `src` of the shadow buffer to the framebuffer at `(x, y)` row by row
+    pub fn blit(&mut self, shadow: &ShadowBuffer, src: Rect, x: usize, y: usize) {
+        let src = src.intersection(&shadow.bounds());
+        let dst = Rect::new(x, y, src.width, src.height).intersection(&self.bounds());
+        for dy in 0..dst.height {
+            let row = &shadow.row(src.y + dy)[src.x..src.x + dst.width];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    row.as_ptr(),
+                    self.row_ptr(dst.x, dst.y + dy),
+                    dst.width,
+                )
+            };
+        }
+    }
+}
+
+impl PixelWriter for FrameBuffer {
+    fn resolution(&self) -> (usize, usize) {
+        self.0.resolution()
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, color: PixelColor) {
+        self.fill_rect(Rect::new(x, y, 1, 1), color);
+    }
+
+    fn fill_rect(&mut self, rect: Rect, color: PixelColor) {
+        let rect = rect.intersection(&self.bounds());
+        let pixel = color.to_native(self.pixel_format());
+        for y in rect.y..rect.bottom() {
+            let row = self.row_ptr(rect.x, y);
+            for dx in 0..rect.width {
+                unsafe { row.add(dx).write_volatile(pixel) };
+            }
+        }
+    }
+}
+
+pub type CharVec = ArrayVec<char, 256>;
+
+impl fmt::Write for CharVec {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.push(c).is_err() {
+                return fmt::Result::Err(fmt::Error);
+            }
+        }
+        fmt::Result::Ok(())
+    }
+}
+
+impl Default for CharVec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::graphics::{CharVec, PixelColor, PixelWriter, Rect, ShadowBuffer};
+    use rumikan_shared::graphics::PixelFormat;
+
+    fn shadow(width: usize, height: usize) -> ShadowBuffer {
+        let pixels = Box::leak(vec![0u32; width * height].into_boxed_slice());
+        ShadowBuffer::new(pixels, width, height, PixelFormat::Rgb)
+    }
+
+    /// Pixels of the color as rows of '#' and '.'
+    fn picture(buf: &ShadowBuffer, color: PixelColor) -> Vec<String> {
+        let (width, height) = buf.resolution();
+        (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        if buf.read_pixel(x, y) == Some(color) {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn primitives() {
+        let white = PixelColor::new(0xff, 0xff, 0xff);
+        let mut buf = shadow(7, 5);
+        buf.draw_line(0, 0, 6, 3, white);
+        buf.draw_line(-2, 4, 2, 4, white);
+        assert_eq!(
+            picture(&buf, white),
+            vec!["#......", ".##....", "...##..", ".....##", "###...."]
+        );
+
+        let mut buf = shadow(7, 5);
+        buf.draw_rect(Rect::new(1, 1, 4, 3), white);
+        assert_eq!(
+            picture(&buf, white),
+            vec![".......", ".####..", ".#..#..", ".####..", "......."]
+        );
+
+        let mut buf = shadow(7, 7);
+        buf.draw_circle(3, 3, 3, white);
+        assert_eq!(
+            picture(&buf, white),
+            vec!["..###..", ".#...#.", "#.....#", "#.....#", "#.....#", ".#...#.", "..###..",]
+        );
+        buf.fill_circle(0, 0, 2, white);
+        assert_eq!(
+            &picture(&buf, white)[..3],
+            &["#####..", "###..#.", "##....#"]
+        );
+    }
+
+    #[test]
+    fn char_vec_write_partial() {
+        let mut v = CharVec::new();
+        for _ in 0..255 {
+            v.push('A').unwrap();
+        }
+        assert!(v.write_str("BCCCCCCC").is_err());
+        // must be written partially even if failed to write entire string
+        assert_eq!(v.as_slice()[255], 'B');
+    }
+}
@@ -0,0 +1,356 @@
+use crate::graphics::fonts;
+use crate::graphics::image::Image;
+use crate::graphics::{FrameBuffer, PixelColor, PixelWriter, Rect};
+use crate::util::collection::ArrayVec;
+use rumikan_shared::graphics::PixelFormat;
+
+/// Number of dirty rectangles tracked separately.
+/// They are merged into their bounding box once exceeded
+pub const MAX_DIRTY_RECTS: usize = 8;
+
+/// Off-screen pixel buffer in the native pixel format of the framebuffer.
+/// Drawing marks the area dirty, and [`ShadowBuffer::flush`] copies only the dirty areas to the screen
+pub struct ShadowBuffer {
+    pixels: &'static mut [u32],
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    dirty: ArrayVec<Rect, MAX_DIRTY_RECTS>,
+}
+
+impl ShadowBuffer {
+    /// `pixels` must hold `width * height` pixels
+    pub fn new(
+        pixels: &'static mut [u32],
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+    ) -> ShadowBuffer {
+        assert!(pixels.len() >= width * height);
+        ShadowBuffer {
+            pixels,
+            width,
+            height,
+            format,
+            dirty: ArrayVec::new(),
+        }
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn row(&self, y: usize) -> &[u32] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn read_pixel(&self, x: usize, y: usize) -> Option<PixelColor> {
+        if x < self.width && y < self.height {
+            Some(PixelColor::from_native(
+                self.pixels[y * self.width + x],
+                self.format,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Move the pixels of `src` to `(x, y)`. The areas may overlap, e.g. to scroll
+    pub fn copy_rect(&mut self, src: Rect, x: usize, y: usize) {
+        let src = src.intersection(&self.bounds());
+        let dst = Rect::new(x, y, src.width, src.height).intersection(&self.bounds());
+        if dst.is_empty() {
+            return;
+        }
+        let width = self.width;
+        let mut copy_row = |dy: usize| {
+            let from = (src.y + dy) * width + src.x;
+            self.pixels
+                .copy_within(from..from + dst.width, (dst.y + dy) * width + dst.x);
+        };
+        // don't overwrite the rows not copied yet
+        if dst.y <= src.y {
+            (0..dst.height).for_each(&mut copy_row);
+        } else {
+            (0..dst.height).rev().for_each(&mut copy_row);
+        }
+        self.mark_dirty(dst);
+    }
+
+    /// Copy `src` of another buffer in the same pixel format to `(x, y)`.
+    /// The pixels of the `transparent` color are skipped
+    pub fn blit(
+        &mut self,
+        other: &ShadowBuffer,
+        src: Rect,
+        x: usize,
+        y: usize,
+        transparent: Option<PixelColor>,
+    ) {
+        let src = src.intersection(&other.bounds());
+        let dst = Rect::new(x, y, src.width, src.height).intersection(&self.bounds());
+        let key = transparent.map(|color| color.to_native(self.format));
+        for dy in 0..dst.height {
+            let from = &other.row(src.y + dy)[src.x..src.x + dst.width];
+            let to = (dst.y + dy) * self.width + dst.x;
+            let to = &mut self.pixels[to..to + dst.width];
+            match key {
+                None => to.copy_from_slice(from),
+                Some(key) => {
+                    for (to, &from) in to.iter_mut().zip(from) {
+                        if from != key {
+                            *to = from;
+                        }
+                    }
+                }
+            }
+        }
+        self.mark_dirty(dst);
+    }
+
+    /// Blend `src` of another buffer in the same pixel format over `(x, y)`.
+    /// `alpha` is the opacity of the source from 0 to 255
+    pub fn blend(&mut self, other: &ShadowBuffer, src: Rect, x: usize, y: usize, alpha: u8) {
+        let src = src.intersection(&other.bounds());
+        let dst = Rect::new(x, y, src.width, src.height).intersection(&self.bounds());
+        let (alpha, rest) = (alpha as u32, 255 - alpha as u32);
+        for dy in 0..dst.height {
+            let from = &other.row(src.y + dy)[src.x..src.x + dst.width];
+            let to = (dst.y + dy) * self.width + dst.x;
+            for (to, &from) in self.pixels[to..to + dst.width].iter_mut().zip(from) {
+                let mut blended = 0;
+                // each channel is a byte in either format
+                for shift in (0..24).step_by(8) {
+                    let channel = ((from >> shift) & 0xff) * alpha + ((*to >> shift) & 0xff) * rest;
+                    blended |= (channel / 255) << shift;
+                }
+                *to = blended;
+            }
+        }
+        self.mark_dirty(dst);
+    }
+
+    /// Copy the pixels of `rect` to `saved` row by row, e.g. before drawing over them
+    pub fn save(&self, rect: Rect, saved: &mut [u32]) {
+        let rect = rect.intersection(&self.bounds());
+        for dy in 0..rect.height {
+            let from = &self.row(rect.y + dy)[rect.x..rect.right()];
+            saved[dy * rect.width..(dy + 1) * rect.width].copy_from_slice(from);
+        }
+    }
+
+    /// Put back the pixels saved by [`ShadowBuffer::save`] from `rect`
+    pub fn restore(&mut self, rect: Rect, saved: &[u32]) {
+        let rect = rect.intersection(&self.bounds());
+        for dy in 0..rect.height {
+            let to = (rect.y + dy) * self.width + rect.x;
+            self.pixels[to..to + rect.width]
+                .copy_from_slice(&saved[dy * rect.width..(dy + 1) * rect.width]);
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// Let the area be copied by the next flush
+    pub fn mark_dirty(&mut self, rect: Rect) {
+        let mut rect = rect.intersection(&self.bounds());
+        if rect.is_empty() {
+            return;
+        }
+        // merge the overlapping ones until none overlaps
+        let mut i = 0;
+        while i < self.dirty.len() {
+            if self.dirty[i].touches(&rect) {
+                rect = rect.union(&self.dirty.swap_remove(i));
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if self.dirty.push(rect).is_err() {
+            let merged = self
+                .dirty
+                .as_slice()
+                .iter()
+                .fold(rect, |acc, dirty| acc.union(dirty));
+            self.dirty = ArrayVec::new();
+            // never fails since the vec is empty
+            let _ = self.dirty.push(merged);
+        }
+    }
+
+    pub fn dirty_rects(&self) -> &[Rect] {
+        self.dirty.as_slice()
+    }
+
+    /// The dirty areas, which are cleared
+    pub fn take_dirty_rects(&mut self) -> ArrayVec<Rect, MAX_DIRTY_RECTS> {
+        core::mem::replace(&mut self.dirty, ArrayVec::new())
+    }
+
+    /// Copy the dirty areas to the framebuffer, where this buffer is placed at `(x, y)`
+    pub fn flush(&mut self, frame_buffer: &mut FrameBuffer, x: usize, y: usize) {
+        for &rect in self.dirty.as_slice() {
+            frame_buffer.blit(self, rect, x + rect.x, y + rect.y);
+        }
+        self.dirty = ArrayVec::new();
+    }
+}
+
+impl PixelWriter for ShadowBuffer {
+    fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, color: PixelColor) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = color.to_native(self.format);
+            self.mark_dirty(Rect::new(x, y, 1, 1));
+        }
+    }
+
+    fn fill_rect(&mut self, rect: Rect, color: PixelColor) {
+        let rect = rect.intersection(&self.bounds());
+        let pixel = color.to_native(self.format);
+        for y in rect.y..rect.bottom() {
+            let begin = y * self.width + rect.x;
+            self.pixels[begin..begin + rect.width].fill(pixel);
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// Blends the translucent pixels, and marks the whole area dirty at once
+    fn draw_image(&mut self, image: &Image, dst: Rect) {
+        let area = dst.intersection(&self.bounds());
+        for y in area.y..area.bottom() {
+            for x in area.x..area.right() {
+                let (color, alpha) = image.pixel_at(dst, x, y);
+                let to = &mut self.pixels[y * self.width + x];
+                let color = match alpha {
+                    0 => continue,
+                    0xff => color,
+                    _ => color.blend(PixelColor::from_native(*to, self.format), alpha),
+                };
+                *to = color.to_native(self.format);
+            }
+        }
+        self.mark_dirty(area);
+    }
+
+    /// Marks the whole glyph dirty at once rather than each pixel
+    fn write_char(&mut self, x: usize, y: usize, c: char, color: PixelColor) {
+        if let Some(glyph) = fonts::get_glyph(c) {
+            let pixel = color.to_native(self.format);
+            for dy in 0..glyph.height() {
+                for dx in 0..glyph.width() {
+                    if glyph.is_set(dx, dy) && x + dx < self.width && y + dy < self.height {
+                        self.pixels[(y + dy) * self.width + x + dx] = pixel;
+                    }
+                }
+            }
+            self.mark_dirty(Rect::new(x, y, glyph.width(), glyph.height()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::{FrameBuffer, PixelColor, PixelWriter, Rect, ShadowBuffer};
+    use rumikan_shared::graphics::{FrameBufferInfo, PixelFormat};
+
+    const RED: PixelColor = PixelColor::new(0xff, 0, 0);
+    const BLUE: PixelColor = PixelColor::new(0, 0, 0xff);
+
+    fn shadow(width: usize, height: usize) -> ShadowBuffer {
+        let pixels = Box::leak(vec![0u32; width * height].into_boxed_slice());
+        ShadowBuffer::new(pixels, width, height, PixelFormat::Bgr)
+    }
+
+    #[test]
+    fn merge_dirty_rects() {
+        let mut buf = shadow(100, 100);
+        buf.fill_rect(Rect::new(0, 0, 10, 10), RED);
+        buf.fill_rect(Rect::new(50, 50, 10, 10), RED);
+        assert_eq!(buf.dirty_rects().len(), 2);
+        // bridges the two
+        buf.fill_rect(Rect::new(5, 5, 50, 50), BLUE);
+        assert_eq!(buf.dirty_rects(), &[Rect::new(0, 0, 60, 60)]);
+        // clipped
+        buf.fill_rect(Rect::new(95, 0, 10, 1), RED);
+        assert_eq!(buf.dirty_rects()[1], Rect::new(95, 0, 5, 1));
+
+        for i in 0..10 {
+            buf.write_pixel(70 + 2 * i, 90, RED);
+        }
+        assert_eq!(buf.dirty_rects(), &[Rect::new(0, 0, 100, 91)]);
+    }
+
+    #[test]
+    fn scroll_up_and_down() {
+        let mut buf = shadow(4, 4);
+        for y in 0..4 {
+            buf.fill_rect(Rect::new(0, y, 4, 1), PixelColor::new(y as u8, 0, 0));
+        }
+        buf.copy_rect(Rect::new(0, 1, 4, 3), 0, 0);
+        let reds: Vec<u8> = (0..4).map(|y| buf.read_pixel(0, y).unwrap().r).collect();
+        assert_eq!(reds, vec![1, 2, 3, 3]);
+
+        buf.copy_rect(Rect::new(0, 0, 4, 3), 0, 1);
+        let reds: Vec<u8> = (0..4).map(|y| buf.read_pixel(3, y).unwrap().r).collect();
+        assert_eq!(reds, vec![1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn flush_dirty_area() {
+        let (width, height, stride) = (8, 4, 10);
+        let mut vram = vec![0u32; stride * height];
+        let mut frame_buffer = FrameBuffer::new(FrameBufferInfo::new(
+            vram.as_mut_ptr() as *mut u8,
+            width,
+            height,
+            stride,
+            PixelFormat::Bgr,
+        ));
+
+        let mut buf = shadow(4, 4);
+        buf.fill_rect(Rect::new(1, 1, 2, 2), RED);
+        buf.flush(&mut frame_buffer, 5, 1);
+        assert!(buf.dirty_rects().is_empty());
+        assert_eq!(vram[2 * stride + 6], 0x00ff_0000);
+        assert_eq!(vram[3 * stride + 7], 0x00ff_0000);
+        // out of the screen
+        assert_eq!(vram[3 * stride + 8], 0);
+        assert_eq!(vram.iter().filter(|&&p| p != 0).count(), 4);
+
+        let mut other = shadow(2, 2);
+        other.fill_rect(Rect::new(0, 0, 2, 2), BLUE);
+        other.write_pixel(0, 0, RED);
+        buf.blit(&other, Rect::new(0, 0, 2, 2), 0, 0, Some(RED));
+        assert_eq!(buf.dirty_rects(), &[Rect::new(0, 0, 2, 2)]);
+        assert_eq!(buf.read_pixel(1, 1), Some(BLUE));
+        // transparent
+        assert_eq!(buf.read_pixel(0, 0), Some(PixelColor::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn blend_half() {
+        let mut buf = shadow(4, 4);
+        buf.fill_rect(buf.bounds(), BLUE);
+        let mut other = shadow(2, 2);
+        other.fill_rect(other.bounds(), RED);
+        buf.blend(&other, other.bounds(), 3, 3, 128);
+        assert_eq!(buf.read_pixel(3, 3), Some(PixelColor::new(128, 0, 127)));
+        assert_eq!(buf.read_pixel(2, 2), Some(BLUE));
+        assert_eq!(buf.dirty_rects()[0], Rect::new(0, 0, 4, 4));
+    }
+
+    #[test]
+    fn native_format() {
+        let color = PixelColor::new(0x12, 0x34, 0x56);
+        assert_eq!(color.to_native(PixelFormat::Rgb), 0x0056_3412);
+        assert_eq!(color.to_native(PixelFormat::Bgr), 0x0012_3456);
+        assert_eq!(
+            PixelColor::from_native(0x0012_3456, PixelFormat::Bgr),
+            color
+        );
+    }
+}
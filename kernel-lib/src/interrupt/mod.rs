@@ -134,12 +134,17 @@ pub struct InterruptFrame {
 #[derive(Debug, Copy, Clone)]
 pub enum InterruptVector {
     XHCI = 0x40,
+    LAPICTimer = 0x41,
+    /// Secondary interrupters of xHC, which are available with MSI-X
+    XHCIInterrupter1 = 0x42,
+    XHCIInterrupter2 = 0x43,
 }
 
 #[derive(Debug)]
 pub enum InterruptEvent {
     Unknown,
-    XHCI,
+    /// Interrupt from the xHC interrupter of the index
+    XHCI(usize),
 }
 
 impl Default for InterruptEvent {
@@ -152,3 +157,32 @@ pub fn notify_end_interrupt() {
     let ptr: u64 = 0xfee000b0;
     unsafe { (ptr as *mut u32).write_volatile(0) }
 }
+
+/// Run `f` with the interrupts disabled, so that the state shared with the interrupt handlers
+/// is never seen half-updated. The interrupt flag is restored after that
+#[cfg(not(test))]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let rflags: u64;
+    unsafe {
+        asm!(
+        "pushfq",
+        "pop {}",
+        "cli",
+        out(reg) rflags
+        );
+    }
+    let ret = f();
+    // IF
+    if rflags & (1 << 9) != 0 {
+        unsafe {
+            asm!("sti");
+        }
+    }
+    ret
+}
+
+/// Interrupts aren't delivered to the tests
+#[cfg(test)]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
@@ -0,0 +1,511 @@
+//! Layers composed into the screen in z-order.
+//!
+//! Each layer draws onto its own [`ShadowBuffer`]. The manager composes the layers
+//! into a back buffer, only in the areas changed by drawing or moving a layer,
+//! and flushes them to the framebuffer.
+//! The mouse cursor is drawn over the layers, saving the pixels under it to put them back.
+
+use crate::error::ErrorContext;
+use crate::graphics::cursor::{Cursor, PaletteColor};
+use crate::graphics::{FrameBuffer, PixelColor, PixelWriter, Rect, ShadowBuffer};
+use crate::util::collection::{ArrayMap, ArrayVec, CollectionError};
+
+#[derive(Debug)]
+pub enum ErrorType {
+    NoSuchLayer(LayerId),
+    CollectionError(CollectionError),
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+const MAX_LAYERS: usize = 16;
+/// Color of the area no layer covers
+const BACKGROUND: PixelColor = PixelColor::new(0, 0, 0);
+/// Larger cursors are cut off at this width and height
+const MAX_CURSOR_SIZE: usize = 64;
+
+static mut LAYER_MANAGER: Option<LayerManager> = None;
+
+pub fn init_global_layer_manager(manager: LayerManager) {
+    unsafe { LAYER_MANAGER = Some(manager) };
+}
+
+/// `None` until [`init_global_layer_manager`] is called
+pub fn layer_manager() -> Option<&'static mut LayerManager> {
+    unsafe { LAYER_MANAGER.as_mut() }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LayerId(pub(crate) u32);
+
+pub struct Layer {
+    buffer: ShadowBuffer,
+    /// Position of the top-left corner on the screen, which may be out of the screen
+    x: isize,
+    y: isize,
+    transparent: Option<PixelColor>,
+}
+
+impl Layer {
+    /// Drawing onto the buffer is shown by [`LayerManager::draw`]
+    pub fn buffer(&mut self) -> &mut ShadowBuffer {
+        &mut self.buffer
+    }
+
+    pub fn position(&self) -> (isize, isize) {
+        (self.x, self.y)
+    }
+
+    /// The pixels of the color let the layers below show through
+    pub fn set_transparent(&mut self, color: Option<PixelColor>) {
+        self.transparent = color;
+    }
+
+    /// The part of `area` on the screen covered by this layer, and its position in the buffer
+    fn overlap(&self, area: &Rect) -> Option<(Rect, usize, usize)> {
+        let (width, height) = self.buffer.resolution();
+        let left = self.x.max(area.x as isize);
+        let top = self.y.max(area.y as isize);
+        let right = (self.x + width as isize).min(area.right() as isize);
+        let bottom = (self.y + height as isize).min(area.bottom() as isize);
+        if right <= left || bottom <= top {
+            return None;
+        }
+        let src = Rect::new(
+            (left - self.x) as usize,
+            (top - self.y) as usize,
+            (right - left) as usize,
+            (bottom - top) as usize,
+        );
+        Some((src, left as usize, top as usize))
+    }
+
+    /// The area on the screen covered by `rect` of the buffer
+    fn to_screen(&self, rect: Rect) -> Rect {
+        clip(
+            self.x + rect.x as isize,
+            self.y + rect.y as isize,
+            rect.width,
+            rect.height,
+        )
+    }
+}
+
+/// The part of the rectangle at `(x, y)` at the non-negative coordinates
+fn clip(x: isize, y: isize, width: usize, height: usize) -> Rect {
+    let (left, top) = (x.max(0), y.max(0));
+    let (right, bottom) = (x + width as isize, y + height as isize);
+    if right <= left || bottom <= top {
+        return Rect::default();
+    }
+    Rect::new(
+        left as usize,
+        top as usize,
+        (right - left) as usize,
+        (bottom - top) as usize,
+    )
+}
+
+/// The cursor over the layers and the pixels under it
+struct CursorOverlay {
+    cursor: Option<Cursor>,
+    /// Position of the hotspot on the screen
+    x: isize,
+    y: isize,
+    /// The area drawn over, whose pixels are saved
+    drawn: Rect,
+    saved: [u32; MAX_CURSOR_SIZE * MAX_CURSOR_SIZE],
+}
+
+pub struct LayerManager {
+    frame_buffer: FrameBuffer,
+    /// Back buffer where the layers are composed
+    screen: ShadowBuffer,
+    layers: ArrayMap<LayerId, Layer, MAX_LAYERS>,
+    /// Visible layers from the bottom
+    z_order: ArrayVec<LayerId, MAX_LAYERS>,
+    next_id: u32,
+    cursor: CursorOverlay,
+}
+
+impl LayerManager {
+    /// `pixels` is the storage of the back buffer. When it can't hold the pixels of the whole screen,
+    /// only the top-left part of the screen that fits is used, which [`LayerManager::screen_size`] tells
+    pub fn new(frame_buffer: FrameBuffer, pixels: &'static mut [u32]) -> LayerManager {
+        let (width, height) = frame_buffer.resolution();
+        let width = width.min(pixels.len());
+        let height = height.min(pixels.len() / width.max(1));
+        LayerManager {
+            frame_buffer,
+            screen: ShadowBuffer::new(pixels, width, height, frame_buffer.pixel_format()),
+            layers: ArrayMap::new(),
+            z_order: ArrayVec::new(),
+            next_id: 0,
+            cursor: CursorOverlay {
+                cursor: None,
+                x: 0,
+                y: 0,
+                drawn: Rect::default(),
+                saved: [0; MAX_CURSOR_SIZE * MAX_CURSOR_SIZE],
+            },
+        }
+    }
+
+    pub fn screen_size(&self) -> (usize, usize) {
+        self.screen.resolution()
+    }
+
+    /// Create a layer at the top-left corner, which is hidden until [`LayerManager::set_z`] is called.
+    /// `pixels` is the storage of its buffer
+    pub fn new_layer(
+        &mut self,
+        pixels: &'static mut [u32],
+        width: usize,
+        height: usize,
+    ) -> Result<LayerId> {
+        let id = LayerId(self.next_id);
+        let layer = Layer {
+            buffer: ShadowBuffer::new(pixels, width, height, self.screen.pixel_format()),
+            x: 0,
+            y: 0,
+            transparent: None,
+        };
+        self.layers
+            .insert(id, layer)
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    pub fn layer(&mut self, id: LayerId) -> Result<&mut Layer> {
+        self.layers
+            .get_mut(&id)
+            .ok_or_else(|| mkerror!(ErrorType::NoSuchLayer(id)))
+    }
+
+    pub fn position(&self, id: LayerId) -> Result<(isize, isize)> {
+        self.layers
+            .get(&id)
+            .map(Layer::position)
+            .ok_or_else(|| mkerror!(ErrorType::NoSuchLayer(id)))
+    }
+
+    /// Show the areas of the layer drawn since the last call
+    pub fn draw(&mut self, id: LayerId) -> Result<()> {
+        let layer = self.layer(id)?;
+        let mut areas = layer.buffer.take_dirty_rects();
+        for area in areas.as_mut_slice() {
+            *area = layer.to_screen(*area);
+        }
+        if self.z_order.as_slice().contains(&id) {
+            for &area in areas.as_slice() {
+                self.compose(area);
+            }
+            self.flush();
+        }
+        Ok(())
+    }
+
+    pub fn move_to(&mut self, id: LayerId, x: isize, y: isize) -> Result<()> {
+        let layer = self.layer(id)?;
+        let old_area = layer.to_screen(layer.buffer.bounds());
+        layer.x = x;
+        layer.y = y;
+        let new_area = layer.to_screen(layer.buffer.bounds());
+        if self.z_order.as_slice().contains(&id) {
+            self.compose(old_area);
+            self.compose(new_area);
+            self.flush();
+        }
+        Ok(())
+    }
+
+    pub fn move_relative(&mut self, id: LayerId, dx: isize, dy: isize) -> Result<()> {
+        let (x, y) = self.layer(id)?.position();
+        self.move_to(id, x + dx, y + dy)
+    }
+
+    /// Place the layer at the height `z` from the bottom, or on the top if it exceeds.
+    /// `None` hides the layer
+    pub fn set_z(&mut self, id: LayerId, z: Option<usize>) -> Result<()> {
+        let layer = self.layer(id)?;
+        let area = layer.to_screen(layer.buffer.bounds());
+        self.z_order.retain(|&other| other != id);
+        if let Some(z) = z {
+            let z = z.min(self.z_order.len());
+            self.z_order
+                .insert(z, id)
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        }
+        self.compose(area);
+        self.flush();
+        Ok(())
+    }
+
+    /// Height of the layer from the bottom, or `None` if hidden
+    pub fn z(&self, id: LayerId) -> Option<usize> {
+        self.z_order
+            .as_slice()
+            .iter()
+            .position(|&other| other == id)
+    }
+
+    /// Visible layers from the bottom
+    pub fn z_order(&self) -> &[LayerId] {
+        self.z_order.as_slice()
+    }
+
+    /// Show the cursor over the layers, or hide it by `None`
+    pub fn set_cursor(&mut self, cursor: Option<Cursor>) {
+        self.hide_cursor();
+        self.cursor.cursor = cursor;
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// Move the hotspot of the cursor to the point of the screen
+    pub fn move_cursor(&mut self, x: isize, y: isize) {
+        self.hide_cursor();
+        self.cursor.x = x;
+        self.cursor.y = y;
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// Position of the hotspot of the cursor
+    pub fn cursor_position(&self) -> (isize, isize) {
+        (self.cursor.x, self.cursor.y)
+    }
+
+    /// Put back the pixels under the cursor
+    fn hide_cursor(&mut self) {
+        self.screen.restore(self.cursor.drawn, &self.cursor.saved);
+        self.cursor.drawn = Rect::default();
+    }
+
+    /// Save the pixels under the cursor, and blend the cursor over them
+    fn show_cursor(&mut self) {
+        let cursor = match self.cursor.cursor {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let (width, height) = cursor.size();
+        let (hx, hy) = cursor.hotspot();
+        let (left, top) = (self.cursor.x - hx as isize, self.cursor.y - hy as isize);
+        let drawn = clip(
+            left,
+            top,
+            width.min(MAX_CURSOR_SIZE),
+            height.min(MAX_CURSOR_SIZE),
+        )
+        .intersection(&self.screen.bounds());
+        self.screen.save(drawn, &mut self.cursor.saved);
+        for y in drawn.y..drawn.bottom() {
+            for x in drawn.x..drawn.right() {
+                let PaletteColor { color, alpha } =
+                    cursor.pixel((x as isize - left) as usize, (y as isize - top) as usize);
+                let color = match alpha {
+                    0 => continue,
+                    0xff => color,
+                    _ => color.blend(self.screen.read_pixel(x, y).unwrap(), alpha),
+                };
+                self.screen.write_pixel(x, y, color);
+            }
+        }
+        self.cursor.drawn = drawn;
+    }
+
+    /// Redraw the area of the back buffer from the bottom layer
+    fn compose(&mut self, area: Rect) {
+        // the layers under the cursor are saved again
+        let under_cursor = !area.intersection(&self.cursor.drawn).is_empty();
+        if under_cursor {
+            self.hide_cursor();
+        }
+        self.screen.fill_rect(area, BACKGROUND);
+        for id in self.z_order.as_slice() {
+            let layer = self.layers.get(id).unwrap();
+            if let Some((src, x, y)) = layer.overlap(&area) {
+                self.screen
+                    .blit(&layer.buffer, src, x, y, layer.transparent);
+            }
+        }
+        if under_cursor {
+            self.show_cursor();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.screen.flush(&mut self.frame_buffer, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::cursor::{Cursor, Palette, PaletteColor};
+    use crate::graphics::{FrameBuffer, PixelColor, PixelWriter, Rect};
+    use crate::layer::LayerManager;
+    use rumikan_shared::graphics::{FrameBufferInfo, PixelFormat};
+
+    const WHITE: PixelColor = PixelColor::new(0xff, 0xff, 0xff);
+    const RED: PixelColor = PixelColor::new(0xff, 0, 0);
+    const KEY: PixelColor = PixelColor::new(0xff, 0, 0xff);
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 8;
+
+    fn pixels(len: usize) -> &'static mut [u32] {
+        Box::leak(vec![0u32; len].into_boxed_slice())
+    }
+
+    /// Layer manager drawing onto the returned memory
+    fn manager() -> (LayerManager, &'static mut [u32]) {
+        let vram = pixels(WIDTH * HEIGHT);
+        let frame_buffer = FrameBuffer::new(FrameBufferInfo::new(
+            vram.as_mut_ptr() as *mut u8,
+            WIDTH,
+            HEIGHT,
+            WIDTH,
+            PixelFormat::Rgb,
+        ));
+        (
+            LayerManager::new(frame_buffer, pixels(WIDTH * HEIGHT)),
+            vram,
+        )
+    }
+
+    fn at(vram: &[u32], x: usize, y: usize) -> PixelColor {
+        PixelColor::from_native(vram[y * WIDTH + x], PixelFormat::Rgb)
+    }
+
+    #[test]
+    fn clamp_screen_to_back_buffer() {
+        let vram = pixels(WIDTH * HEIGHT);
+        let frame_buffer = FrameBuffer::new(FrameBufferInfo::new(
+            vram.as_mut_ptr() as *mut u8,
+            WIDTH,
+            HEIGHT,
+            WIDTH,
+            PixelFormat::Rgb,
+        ));
+        let mut manager = LayerManager::new(frame_buffer, pixels(WIDTH * 3 + 5));
+        assert_eq!(manager.screen_size(), (WIDTH, 3));
+
+        let (width, height) = manager.screen_size();
+        let background = manager
+            .new_layer(pixels(width * height), width, height)
+            .unwrap();
+        let buffer = manager.layer(background).unwrap().buffer();
+        buffer.fill_rect(buffer.bounds(), WHITE);
+        manager.set_z(background, Some(0)).unwrap();
+        assert_eq!(at(vram, WIDTH - 1, 2), WHITE);
+        assert_eq!(vram[3 * WIDTH], 0);
+    }
+
+    #[test]
+    fn compose_in_z_order() {
+        let (mut manager, vram) = manager();
+        let background = manager
+            .new_layer(pixels(WIDTH * HEIGHT), WIDTH, HEIGHT)
+            .unwrap();
+        let buffer = manager.layer(background).unwrap().buffer();
+        buffer.fill_rect(buffer.bounds(), WHITE);
+        manager.set_z(background, Some(0)).unwrap();
+        assert_eq!(at(vram, 15, 7), WHITE);
+
+        // a cursor whose corners are transparent
+        let cursor = manager.new_layer(pixels(4), 2, 2).unwrap();
+        let layer = manager.layer(cursor).unwrap();
+        layer.set_transparent(Some(KEY));
+        let buffer = layer.buffer();
+        buffer.fill_rect(buffer.bounds(), RED);
+        buffer.write_pixel(0, 0, KEY);
+        manager.move_to(cursor, 4, 4).unwrap();
+        // still hidden
+        assert_eq!(at(vram, 5, 5), WHITE);
+        manager.set_z(cursor, Some(usize::MAX)).unwrap();
+        assert_eq!(manager.z(cursor), Some(1));
+        assert_eq!(at(vram, 4, 4), WHITE);
+        assert_eq!(at(vram, 5, 5), RED);
+
+        // the background under the cursor is restored
+        manager.move_relative(cursor, 1, 1).unwrap();
+        assert_eq!(at(vram, 4, 5), WHITE);
+        assert_eq!(at(vram, 6, 6), RED);
+
+        // drawing under the cursor doesn't overwrite it
+        let buffer = manager.layer(background).unwrap().buffer();
+        buffer.fill_rect(Rect::new(0, 0, 8, 8), PixelColor::new(0, 0, 0xff));
+        manager.draw(background).unwrap();
+        assert_eq!(at(vram, 6, 6), RED);
+        assert_eq!(at(vram, 5, 5), PixelColor::new(0, 0, 0xff));
+
+        manager.set_z(cursor, None).unwrap();
+        assert_eq!(manager.z(cursor), None);
+        assert_eq!(at(vram, 6, 6), PixelColor::new(0, 0, 0xff));
+    }
+
+    #[test]
+    fn cursor_saves_pixels_under_it() {
+        let (mut manager, vram) = manager();
+        let background = manager
+            .new_layer(pixels(WIDTH * HEIGHT), WIDTH, HEIGHT)
+            .unwrap();
+        let buffer = manager.layer(background).unwrap().buffer();
+        buffer.fill_rect(buffer.bounds(), WHITE);
+        manager.set_z(background, Some(0)).unwrap();
+
+        let blue = PixelColor::new(0, 0, 0xff);
+        let palette = Palette {
+            edge: PaletteColor::new(RED, 0xff),
+            fill: PaletteColor::new(blue, 0xff),
+            accent: PaletteColor::new(PixelColor::new(0, 0, 0), 0x80),
+        };
+        manager.set_cursor(Some(Cursor::new(&[b"@#", b"+ "], (1, 1), palette)));
+        manager.move_cursor(5, 5);
+        assert_eq!(at(vram, 4, 4), RED);
+        assert_eq!(at(vram, 5, 4), blue);
+        // half transparent
+        assert_eq!(at(vram, 4, 5), PixelColor::new(0x7f, 0x7f, 0x7f));
+        assert_eq!(at(vram, 5, 5), WHITE);
+
+        manager.move_cursor(10, 5);
+        assert_eq!(at(vram, 4, 4), WHITE);
+        assert_eq!(at(vram, 4, 5), WHITE);
+        assert_eq!(at(vram, 9, 4), RED);
+
+        // drawn under the cursor, which stays over it
+        let buffer = manager.layer(background).unwrap().buffer();
+        buffer.fill_rect(Rect::new(8, 0, 8, 8), KEY);
+        manager.draw(background).unwrap();
+        assert_eq!(at(vram, 9, 4), RED);
+        assert_eq!(at(vram, 11, 4), KEY);
+        manager.move_cursor(0, 0);
+        assert_eq!(at(vram, 9, 4), KEY);
+        assert_eq!(at(vram, 9, 5), KEY);
+        // the hotspot is in the corner
+        assert_eq!(at(vram, 0, 0), WHITE);
+        assert_eq!(manager.cursor_position(), (0, 0));
+
+        manager.move_cursor(3, 3);
+        manager.set_cursor(None);
+        assert_eq!(at(vram, 2, 2), WHITE);
+    }
+
+    #[test]
+    fn partially_out_of_screen() {
+        let (mut manager, vram) = manager();
+        let window = manager.new_layer(pixels(16), 4, 4).unwrap();
+        let buffer = manager.layer(window).unwrap().buffer();
+        buffer.fill_rect(buffer.bounds(), RED);
+        manager.set_z(window, Some(0)).unwrap();
+        manager.move_to(window, -2, -3).unwrap();
+        assert_eq!(at(vram, 1, 0), RED);
+        assert_eq!(at(vram, 2, 0), PixelColor::new(0, 0, 0));
+        assert_eq!(at(vram, 0, 1), PixelColor::new(0, 0, 0));
+
+        manager.move_to(window, 14, 6).unwrap();
+        assert_eq!(at(vram, 1, 0), PixelColor::new(0, 0, 0));
+        assert_eq!(at(vram, 15, 7), RED);
+    }
+}
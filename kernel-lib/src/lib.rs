@@ -17,7 +17,11 @@ pub mod console;
 pub mod error;
 pub mod graphics;
 pub mod interrupt;
+pub mod layer;
 pub mod logger;
 pub mod pci;
+pub mod timer;
 pub mod usb;
 pub mod util;
+pub mod widget;
+pub mod window;
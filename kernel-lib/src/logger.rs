@@ -1,6 +1,13 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use log::{Level, LevelFilter, Log, Metadata, Record};
 
 static mut LOGGER: Option<Logger> = None;
+static mut SINK: Option<LogSink> = None;
+/// Set while the records are kept from the sink
+static SINK_MUTED: AtomicBool = AtomicBool::new(false);
+
+/// Receives the log records in addition to the console
+pub type LogSink = fn(core::fmt::Arguments);
 
 struct Logger;
 impl Log for Logger {
@@ -9,16 +16,26 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record) {
-        let level_str = match record.level() {
-            Level::Error => "[ERROR]",
-            Level::Warn => "[WARN ]",
-            Level::Info => "[INFO ]",
-            Level::Debug => "[DEBUG]",
-            Level::Trace => "[TRACE]",
+        // SGR to color the level on the console
+        let (level_str, color) = match record.level() {
+            Level::Error => ("[ERROR]", "\x1b[1;31m"),
+            Level::Warn => ("[WARN ]", "\x1b[1;33m"),
+            Level::Info => ("[INFO ]", "\x1b[32m"),
+            Level::Debug => ("[DEBUG]", "\x1b[36m"),
+            Level::Trace => ("[TRACE]", "\x1b[90m"),
         };
-        crate::console::_print(format_args!("{} ", level_str));
-        crate::console::_print(*record.args());
-        crate::console::_print(format_args!("\n"));
+        crate::console::_print(format_args!(
+            "{}{}\x1b[m {}\n",
+            color,
+            level_str,
+            record.args()
+        ));
+        if SINK_MUTED.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(sink) = unsafe { SINK } {
+            sink(format_args!("{} {}\r\n", level_str, record.args()));
+        }
     }
 
     fn flush(&self) {
@@ -38,3 +55,18 @@ pub fn init_logger(level: LogLevel) {
     .expect("Failed to set logger");
     log::set_max_level(level);
 }
+
+pub fn set_log_sink(sink: LogSink) {
+    unsafe {
+        SINK = Some(sink);
+    }
+}
+
+/// Log only to the console in `f`. Used to report the failures of the sink itself,
+/// which would otherwise feed the sink on and on
+pub fn without_sink<R>(f: impl FnOnce() -> R) -> R {
+    let muted = SINK_MUTED.swap(true, Ordering::Relaxed);
+    let ret = f();
+    SINK_MUTED.store(muted, Ordering::Relaxed);
+    ret
+}
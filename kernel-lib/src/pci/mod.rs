@@ -10,6 +10,7 @@ pub enum ErrorType {
     IndexOutOfRange,
     NotImplemented,
     NoPCIMSI,
+    NoPCIMSIX,
 }
 
 pub type Error = ErrorContext<ErrorType>;
@@ -185,36 +186,68 @@ impl Device {
         vector: InterruptVector,
         num_vector_exponent: u32,
     ) -> Result<()> {
-        let msg_addr: u32 = 0xfee00000 | ((apic_id as u32) << 12);
-        let mut msg_data = ((delivery_mode as u32) << 8) | ((vector as u8) as u32);
-        if trigger_mode == MSITriggerMode::Level {
-            msg_data |= 0xc000;
-        }
+        let (msg_addr, msg_data) = msi_message(apic_id, trigger_mode, delivery_mode, vector);
         self.configure_msi(msg_addr, msg_data, num_vector_exponent)
     }
 
+    /// Enable MSI-X and route the table entries to `vectors` in order.
+    /// Returns the number of entries configured, which is limited by the table size
+    pub fn configure_msix_fixed_destination(
+        &self,
+        apic_id: u8,
+        trigger_mode: MSITriggerMode,
+        delivery_mode: MSIDeliveryMode,
+        vectors: &[InterruptVector],
+    ) -> Result<usize> {
+        let cap_addr = self
+            .find_capability(CapabilityHeader::CAPABILITY_MSIX)
+            .ok_or_else(|| mkerror!(ErrorType::NoPCIMSIX))?;
+        let mut header = self.read_capability_header(cap_addr);
+        let table = self.read_config_reg(cap_addr + 4);
+        let table_base = (self.read_bar((table & 0b111) as u8)? & !0xf) + (table & !0b111) as usize;
+        let table = table_base as *mut MSIXTableEntry;
+
+        // mask all the vectors while updating the table
+        header.set_msix_function_mask(true);
+        header.set_msix_enable(true);
+        self.write_config_reg(cap_addr, header.data);
+
+        let num_vectors = vectors.len().min(header.msix_table_size() as usize + 1);
+        for (i, &vector) in vectors[..num_vectors].iter().enumerate() {
+            let (msg_addr, msg_data) = msi_message(apic_id, trigger_mode, delivery_mode, vector);
+            unsafe {
+                table.add(i).write_volatile(MSIXTableEntry {
+                    msg_addr,
+                    msg_upper_addr: 0,
+                    msg_data,
+                    vector_control: 0,
+                });
+            }
+        }
+
+        header.set_msix_function_mask(false);
+        self.write_config_reg(cap_addr, header.data);
+        Ok(num_vectors)
+    }
+
     fn configure_msi(&self, msg_addr: u32, msg_data: u32, num_vector_exponent: u32) -> Result<()> {
-        let mut cap_addr = (self.read_config_reg(0x34) & 0xff) as u8;
+        let msi_cap_addr = self
+            .find_capability(CapabilityHeader::CAPABILITY_MSI)
+            .ok_or_else(|| mkerror!(ErrorType::NoPCIMSI))?;
+        self.configure_msi_register(msi_cap_addr, msg_addr, msg_data, num_vector_exponent);
+        Ok(())
+    }
 
-        let (mut msi_cap_addr, mut _msix_cap_addr) = (0u8, 0u8);
+    fn find_capability(&self, cap_id: u8) -> Option<u8> {
+        let mut cap_addr = (self.read_config_reg(0x34) & 0xff) as u8;
         while cap_addr != 0 {
-            let header = CapabilityHeader {
-                data: self.read_config_reg(cap_addr),
-            };
-            match header.cap_id() {
-                CapabilityHeader::CAPABILITY_MSI => msi_cap_addr = cap_addr,
-                CapabilityHeader::CAPABILITY_MSIX => _msix_cap_addr = cap_addr,
-                _ => {}
+            let header = self.read_capability_header(cap_addr);
+            if header.cap_id() == cap_id {
+                return Some(cap_addr);
             }
             cap_addr = header.next_ptr();
         }
-
-        if msi_cap_addr != 0 {
-            self.configure_msi_register(msi_cap_addr, msg_addr, msg_data, num_vector_exponent);
-            Ok(())
-        } else {
-            Err(mkerror!(ErrorType::NoPCIMSI))
-        }
+        None
     }
 
     fn configure_msi_register(
@@ -307,13 +340,13 @@ impl Device {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum MSIDeliveryMode {
     Fixed = 0b000,
 }
 
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum MSITriggerMode {
     Level = 1,
 }
@@ -335,6 +368,9 @@ impl CapabilityHeader {
     setbits!(pub set_multi_msg_enable: u8; data; 20; 3);
     getbit!(pub addr_64_capable; data; 23);
     getbit!(pub per_vector_mask_capable; data; 24);
+    getbits!(pub msix_table_size: u16; data; 16; 11);
+    setbit!(pub set_msix_function_mask; data; 30);
+    setbit!(pub set_msix_enable; data; 31);
 }
 
 #[repr(C)]
@@ -348,6 +384,31 @@ pub struct MSICapability {
     pub pending_bits: u32,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+struct MSIXTableEntry {
+    msg_addr: u32,
+    msg_upper_addr: u32,
+    msg_data: u32,
+    /// Bit 0 masks the vector
+    vector_control: u32,
+}
+
+/// Message address and data which deliver `vector` to the local APIC
+fn msi_message(
+    apic_id: u8,
+    trigger_mode: MSITriggerMode,
+    delivery_mode: MSIDeliveryMode,
+    vector: InterruptVector,
+) -> (u32, u32) {
+    let msg_addr: u32 = 0xfee00000 | ((apic_id as u32) << 12);
+    let mut msg_data = ((delivery_mode as u32) << 8) | ((vector as u8) as u32);
+    if trigger_mode == MSITriggerMode::Level {
+        msg_data |= 0xc000;
+    }
+    (msg_addr, msg_data)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub struct ClassCode {
     pub base: u8,
@@ -405,7 +466,8 @@ fn in32(addr: u16) -> u32 {
 
 #[cfg(test)]
 mod tests {
-    use crate::pci::ClassCode;
+    use crate::interrupt::InterruptVector;
+    use crate::pci::{msi_message, ClassCode, MSIDeliveryMode, MSITriggerMode};
 
     #[test]
     fn class_code_equality() {
@@ -423,4 +485,17 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn msi_message_to_local_apic() {
+        assert_eq!(
+            msi_message(
+                3,
+                MSITriggerMode::Level,
+                MSIDeliveryMode::Fixed,
+                InterruptVector::XHCI
+            ),
+            (0xfee0_3000, 0xc040)
+        );
+    }
 }
@@ -0,0 +1,104 @@
+use crate::interrupt::InterruptVector;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const LVT_TIMER: usize = 0xfee0_0320;
+const INITIAL_COUNT: usize = 0xfee0_0380;
+const CURRENT_COUNT: usize = 0xfee0_0390;
+const DIVIDE_CONFIGURATION: usize = 0xfee0_03e0;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Bit 0 gates PIT channel 2 and bit 5 reads its output
+const PIT_CHANNEL2_CONTROL: u16 = 0x61;
+const PIT_FREQUENCY: u32 = 1_193_182;
+/// Length of the period to measure the local APIC timer with PIT
+const CALIBRATION_MS: u32 = 10;
+
+/// The timer is calibrated so that a tick is 1ms
+pub const TICKS_PER_SECOND: u64 = 1000;
+
+static TICK: AtomicU64 = AtomicU64::new(0);
+/// Set on a timer interrupt and cleared by [`take_pending_tick`],
+/// so the ticks elapsed while the kernel is busy are handled at once
+static TICK_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Start the local APIC timer which interrupts at `vector` every tick
+pub fn initialize_lapic_timer(vector: InterruptVector) {
+    let count_per_ms = measure_lapic_count(CALIBRATION_MS) / CALIBRATION_MS;
+    let count_per_tick = count_per_ms * 1000 / TICKS_PER_SECOND as u32;
+    debug!("Local APIC timer: {} counts per tick", count_per_tick);
+    unsafe {
+        // periodic mode, not masked
+        (LVT_TIMER as *mut u32).write_volatile((1 << 17) | vector as u32);
+        (INITIAL_COUNT as *mut u32).write_volatile(count_per_tick.max(1));
+    }
+}
+
+/// Count down the local APIC timer while PIT measures `ms` milliseconds.
+/// Returns how much it has counted down
+fn measure_lapic_count(ms: u32) -> u32 {
+    unsafe {
+        // divide by 1
+        (DIVIDE_CONFIGURATION as *mut u32).write_volatile(0b1011);
+        // one-shot mode, masked
+        (LVT_TIMER as *mut u32).write_volatile(1 << 16);
+    }
+
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    let pit_count = PIT_FREQUENCY * ms / 1000;
+    let control = in8(PIT_CHANNEL2_CONTROL);
+    // disable the speaker and stop the count until it's loaded
+    out8(PIT_CHANNEL2_CONTROL, control & !0b11);
+    out8(PIT_COMMAND, 0b1011_0000);
+    out8(PIT_CHANNEL2, pit_count as u8);
+    out8(PIT_CHANNEL2, (pit_count >> 8) as u8);
+
+    out8(PIT_CHANNEL2_CONTROL, (control & !0b10) | 0b1);
+    unsafe {
+        (INITIAL_COUNT as *mut u32).write_volatile(u32::MAX);
+    }
+    while in8(PIT_CHANNEL2_CONTROL) & (1 << 5) == 0 {}
+    let current = unsafe { (CURRENT_COUNT as *const u32).read_volatile() };
+
+    unsafe {
+        (INITIAL_COUNT as *mut u32).write_volatile(0);
+    }
+    out8(PIT_CHANNEL2_CONTROL, control);
+    u32::MAX - current
+}
+
+/// Must be called on every timer interrupt
+pub fn on_interrupt() {
+    TICK.fetch_add(1, Ordering::Relaxed);
+    TICK_PENDING.store(true, Ordering::Release);
+}
+
+/// Whether any tick has elapsed since the last call
+pub fn take_pending_tick() -> bool {
+    TICK_PENDING.swap(false, Ordering::Acquire)
+}
+
+/// Ticks elapsed since the timer started
+pub fn current_tick() -> u64 {
+    TICK.load(Ordering::Relaxed)
+}
+
+fn out8(addr: u16, data: u8) {
+    unsafe {
+        asm!(
+        "out dx, al",
+        in("dx") addr, in("al") data
+        );
+    }
+}
+
+fn in8(addr: u16) -> u8 {
+    unsafe {
+        let data: u8;
+        asm!(
+        "in al, dx",
+        out("al") data, in("dx") addr
+        );
+        data
+    }
+}
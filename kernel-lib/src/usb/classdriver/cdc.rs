@@ -0,0 +1,433 @@
+use crate::interrupt::without_interrupts;
+use crate::logger::without_sink;
+use crate::usb::classdriver::ClassDriver;
+use crate::usb::descriptor::InterfaceDescriptor;
+use crate::usb::devmgr::{self, TransferCompletion, TransferResult, UsbDevice};
+use crate::usb::endpoint::{EndpointConfig, EndpointId, EndpointType};
+use crate::usb::mem::{allocate, free};
+use crate::usb::trb::{RequestType, SetupData};
+use crate::util::collection::ArrayQueue;
+use core::fmt::Write;
+
+const RX_CAPACITY: usize = 1024;
+const TX_CAPACITY: usize = 4096;
+/// Length of a bulk transfer, which is a multiple of the max packet size of any speed
+const BULK_BUF_LEN: usize = 512;
+const NOTIFICATION_BUF_LEN: usize = 16;
+/// A TRB data buffer must not span a 64KiB boundary
+const BUF_BOUNDARY: usize = 64 * 1024;
+const MAX_DRIVERS: usize = 2;
+
+/// The drivers, which are kept out of the DMA pool as xHC doesn't access them
+static mut DRIVERS: [Option<CdcAcmDriver>; MAX_DRIVERS] = [None, None];
+/// The driver which the byte stream is connected to
+static mut ACTIVE_DRIVER: Option<*mut CdcAcmDriver> = None;
+
+/// Queue `bytes` to be sent to the attached CDC-ACM device.
+/// Returns the number of bytes queued, which is 0 if no device is ready.
+/// This can be called from the interrupt handlers, e.g. to log
+pub fn write(bytes: &[u8]) -> usize {
+    without_interrupts(|| {
+        let driver = match unsafe { ACTIVE_DRIVER } {
+            Some(driver) => unsafe { &mut *driver },
+            None => return 0,
+        };
+        bytes
+            .iter()
+            .take_while(|&&b| driver.tx.push(b).is_ok())
+            .count()
+    })
+}
+
+/// Take the bytes received from the attached CDC-ACM device into `buf`.
+/// Returns the number of bytes taken.
+pub fn read(buf: &mut [u8]) -> usize {
+    let driver = match unsafe { ACTIVE_DRIVER } {
+        Some(driver) => unsafe { &mut *driver },
+        None => return 0,
+    };
+    let mut len = 0;
+    while len < buf.len() {
+        match driver.rx.poll() {
+            Some(b) => buf[len] = b,
+            None => break,
+        }
+        len += 1;
+    }
+    len
+}
+
+pub fn is_ready() -> bool {
+    unsafe { ACTIVE_DRIVER }.is_some()
+}
+
+/// Writes to the attached CDC-ACM device. Bytes overflowing the queue are dropped
+#[derive(Debug)]
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Print to the attached CDC-ACM device, which can be used as a log sink
+pub fn print(args: core::fmt::Arguments) {
+    let _ = SerialWriter.write_fmt(args);
+}
+
+/// Data of SET_LINE_CODING request
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    pub stop_bits: u8,
+    pub parity: u8,
+    pub data_bits: u8,
+}
+
+impl LineCoding {
+    pub const STOP_BITS_1: u8 = 0;
+    pub const STOP_BITS_2: u8 = 2;
+    pub const PARITY_NONE: u8 = 0;
+    pub const PARITY_ODD: u8 = 1;
+    pub const PARITY_EVEN: u8 = 2;
+
+    pub fn to_bytes(self) -> [u8; 7] {
+        let baud_rate = self.baud_rate.to_le_bytes();
+        [
+            baud_rate[0],
+            baud_rate[1],
+            baud_rate[2],
+            baud_rate[3],
+            self.stop_bits,
+            self.parity,
+            self.data_bits,
+        ]
+    }
+}
+
+impl Default for LineCoding {
+    /// 115200 8N1
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            stop_bits: Self::STOP_BITS_1,
+            parity: Self::PARITY_NONE,
+            data_bits: 8,
+        }
+    }
+}
+
+/// Driver for the Abstract Control Model of Communications Device Class.
+/// It's bound to the communication interface and takes over the data interface following it.
+#[derive(Debug)]
+pub struct CdcAcmDriver {
+    interface_index: u8,
+    data_interface_index: Option<u8>,
+    endpoint_interrupt_in: EndpointId,
+    endpoint_bulk_in: EndpointId,
+    endpoint_bulk_out: EndpointId,
+    control_buf: *mut u8,
+    notification_buf: *mut u8,
+    in_buf: *mut u8,
+    out_buf: *mut u8,
+    rx: ArrayQueue<u8, RX_CAPACITY>,
+    tx: ArrayQueue<u8, TX_CAPACITY>,
+    /// Whether a bulk OUT transfer is in flight
+    sending: bool,
+}
+
+impl CdcAcmDriver {
+    pub const CLASS_COMMUNICATIONS: u8 = 0x02;
+    pub const SUB_CLASS_ACM: u8 = 0x02;
+    pub const CLASS_DATA: u8 = 0x0a;
+
+    /// DTR and RTS of SET_CONTROL_LINE_STATE
+    const CONTROL_LINE_STATE: u16 = 0b11;
+
+    /// Place a new driver in a free slot. Returns `None` if all slots are in use
+    pub fn allocate(interface_index: u8) -> Option<*mut CdcAcmDriver> {
+        let slot = unsafe { DRIVERS.iter_mut() }.find(|slot| slot.is_none())?;
+        Some(slot.insert(Self::new(interface_index)) as *mut Self)
+    }
+
+    fn new(interface_index: u8) -> Self {
+        let alloc = |len| {
+            allocate::<u8>(len, Some(64), Some(BUF_BOUNDARY))
+                .expect("Failed to allocate memory for driver")
+        };
+        Self {
+            interface_index,
+            data_interface_index: None,
+            endpoint_interrupt_in: EndpointId::new(0),
+            endpoint_bulk_in: EndpointId::new(0),
+            endpoint_bulk_out: EndpointId::new(0),
+            control_buf: alloc(8),
+            notification_buf: alloc(NOTIFICATION_BUF_LEN),
+            in_buf: alloc(BULK_BUF_LEN),
+            out_buf: alloc(BULK_BUF_LEN),
+            rx: ArrayQueue::new(),
+            tx: ArrayQueue::new(),
+            sending: false,
+        }
+    }
+
+    pub fn interface_index(&self) -> u8 {
+        self.interface_index
+    }
+
+    pub fn endpoint_interrupt_in(&self) -> EndpointId {
+        self.endpoint_interrupt_in
+    }
+
+    pub fn notification_buffer(&self) -> *const () {
+        self.notification_buf as *const ()
+    }
+
+    pub fn notification_packet_size(&self) -> usize {
+        NOTIFICATION_BUF_LEN
+    }
+
+    /// Take the data interface, which is the first one following the communication interface
+    pub fn claim_interface(&mut self, desc: &InterfaceDescriptor) -> bool {
+        if self.data_interface_index.is_none() && desc.interface_class() == Self::CLASS_DATA {
+            self.data_interface_index = Some(desc.interface_number());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_endpoint(&mut self, config: &EndpointConfig) {
+        match (config.endpoint_type, config.endpoint_id.is_in()) {
+            (EndpointType::Interrupt, true) => self.endpoint_interrupt_in = config.endpoint_id,
+            (EndpointType::Bulk, true) => self.endpoint_bulk_in = config.endpoint_id,
+            (EndpointType::Bulk, false) => self.endpoint_bulk_out = config.endpoint_id,
+            _ => {}
+        }
+    }
+
+    /// Configure the line, then start receiving
+    pub fn start(&mut self, dev: &mut UsbDevice, driver: ClassDriver) -> devmgr::Result<()> {
+        let line_coding = LineCoding::default().to_bytes();
+        for (i, &b) in line_coding.iter().enumerate() {
+            unsafe { self.control_buf.add(i).write(b) };
+        }
+        let setup_data = self
+            .class_request(RequestType::DIRECTION_HOST_TO_DEVICE)
+            .with_request(SetupData::REQUEST_SET_LINE_CODING)
+            .with_value(0)
+            .with_length(line_coding.len() as u16);
+        dev.submit_control(
+            setup_data,
+            Some(self.control_buf as *mut ()),
+            TransferCompletion::ClassDriver(driver),
+        )
+    }
+
+    pub fn on_transfer_completed(
+        &mut self,
+        dev: &mut UsbDevice,
+        driver: ClassDriver,
+        result: &TransferResult,
+    ) -> devmgr::Result<()> {
+        match result.setup {
+            Some(setup) => {
+                if !result.is_success() {
+                    // the line can be used without them, e.g. if the device doesn't support them
+                    warn!(
+                        "CDC-ACM request {} failed: {:?}",
+                        setup.request(),
+                        result.completion_code
+                    );
+                }
+                match setup.request() {
+                    SetupData::REQUEST_SET_LINE_CODING => self.set_control_line_state(dev, driver),
+                    SetupData::REQUEST_SET_CONTROL_LINE_STATE => self.on_line_ready(dev, driver),
+                    _ => Err(mkerror!(devmgr::ErrorType::NotImplemented)),
+                }
+            }
+            None if result.endpoint_id == self.endpoint_bulk_in => {
+                if result.is_success() {
+                    let data =
+                        unsafe { core::slice::from_raw_parts(self.in_buf, result.length as usize) };
+                    // bytes are dropped while nobody reads them
+                    for &b in data {
+                        if self.rx.push(b).is_err() {
+                            break;
+                        }
+                    }
+                } else if result.completion_code.halts_endpoint() {
+                    // receiving resumes once the endpoint is recovered
+                    return Err(mkerror!(devmgr::ErrorType::TransferFailed(
+                        result.completion_code
+                    )));
+                }
+                self.receive(dev, driver)
+            }
+            None if result.endpoint_id == self.endpoint_bulk_out => {
+                self.sending = false;
+                if !result.is_success() {
+                    // the bytes are lost. Sending resumes on the next flush
+                    without_sink(|| {
+                        warn!(
+                            "Failed to send to CDC-ACM device: {:?}",
+                            result.completion_code
+                        )
+                    });
+                    return Ok(());
+                }
+                self.flush(dev, driver)
+            }
+            None => Err(mkerror!(devmgr::ErrorType::NotImplemented)),
+        }
+    }
+
+    pub fn on_endpoint_recovered(
+        &mut self,
+        dev: &mut UsbDevice,
+        driver: ClassDriver,
+        endpoint_id: EndpointId,
+    ) -> devmgr::Result<()> {
+        if endpoint_id == self.endpoint_bulk_in {
+            self.receive(dev, driver)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn on_interrupt_completed(&self, ep_id: EndpointId, len: u32) {
+        if ep_id == self.endpoint_interrupt_in {
+            debug!("CDC-ACM notification received. len = {}", len);
+        }
+    }
+
+    /// Send the queued bytes unless the previous transfer is in flight
+    pub fn flush(&mut self, dev: &mut UsbDevice, driver: ClassDriver) -> devmgr::Result<()> {
+        if self.sending || !self.is_active() {
+            return Ok(());
+        }
+        let tx = &mut self.tx;
+        let out_buf = self.out_buf;
+        let len = without_interrupts(|| {
+            let mut len = 0;
+            while len < BULK_BUF_LEN {
+                match tx.poll() {
+                    Some(b) => unsafe { out_buf.add(len).write(b) },
+                    None => break,
+                }
+                len += 1;
+            }
+            len
+        });
+        if len == 0 {
+            return Ok(());
+        }
+        dev.bulk_out(
+            self.endpoint_bulk_out,
+            self.out_buf as *const (),
+            len as u32,
+            TransferCompletion::ClassDriver(driver),
+        )?;
+        self.sending = true;
+        Ok(())
+    }
+
+    /// Release the buffers and the slot. The driver must not be used after this.
+    pub fn free(driver: *mut CdcAcmDriver) {
+        let slot = unsafe { DRIVERS.iter_mut() }
+            .find(|slot| matches!(slot, Some(d) if core::ptr::eq(d, driver)));
+        let this = match slot.and_then(Option::take) {
+            Some(this) => this,
+            None => return,
+        };
+        without_interrupts(|| unsafe {
+            if ACTIVE_DRIVER == Some(driver) {
+                ACTIVE_DRIVER = None;
+            }
+        });
+        free(this.control_buf);
+        free(this.notification_buf);
+        free(this.in_buf);
+        free(this.out_buf);
+    }
+
+    fn set_control_line_state(
+        &mut self,
+        dev: &mut UsbDevice,
+        driver: ClassDriver,
+    ) -> devmgr::Result<()> {
+        let setup_data = self
+            .class_request(RequestType::DIRECTION_HOST_TO_DEVICE)
+            .with_request(SetupData::REQUEST_SET_CONTROL_LINE_STATE)
+            .with_value(Self::CONTROL_LINE_STATE)
+            .with_length(0);
+        dev.submit_control(setup_data, None, TransferCompletion::ClassDriver(driver))
+    }
+
+    fn on_line_ready(&mut self, dev: &mut UsbDevice, driver: ClassDriver) -> devmgr::Result<()> {
+        if self.data_interface_index.is_none() {
+            warn!("CDC-ACM device has no data interface");
+            return Ok(());
+        }
+        info!("CDC-ACM device is ready");
+        without_interrupts(|| unsafe {
+            if ACTIVE_DRIVER.is_none() {
+                ACTIVE_DRIVER = Some(self as *mut Self);
+            }
+        });
+        if self.endpoint_interrupt_in != EndpointId::new(0) {
+            dev.interrupt_in(
+                self.endpoint_interrupt_in,
+                self.notification_buffer(),
+                NOTIFICATION_BUF_LEN as u32,
+            )?;
+        }
+        self.receive(dev, driver)
+    }
+
+    fn receive(&mut self, dev: &mut UsbDevice, driver: ClassDriver) -> devmgr::Result<()> {
+        dev.bulk_in(
+            self.endpoint_bulk_in,
+            self.in_buf as *mut (),
+            BULK_BUF_LEN as u32,
+            TransferCompletion::ClassDriver(driver),
+        )
+    }
+
+    fn is_active(&self) -> bool {
+        unsafe { ACTIVE_DRIVER == Some(self as *const Self as *mut Self) }
+    }
+
+    fn class_request(&self, direction: bool) -> SetupData {
+        SetupData::new()
+            .with_request_type(
+                RequestType::new()
+                    .with_direction(direction)
+                    .with_type(RequestType::TYPE_CLASS)
+                    .with_recipient(RequestType::RECIPIENT_INTERFACE),
+            )
+            .with_index(self.interface_index as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::classdriver::cdc::LineCoding;
+
+    #[test]
+    fn line_coding_bytes() {
+        assert_eq!(
+            LineCoding::default().to_bytes(),
+            [0x00, 0xc2, 0x01, 0x00, 0, 0, 8]
+        );
+        let coding = LineCoding {
+            baud_rate: 9600,
+            stop_bits: LineCoding::STOP_BITS_2,
+            parity: LineCoding::PARITY_EVEN,
+            data_bits: 7,
+        };
+        assert_eq!(coding.to_bytes(), [0x80, 0x25, 0x00, 0x00, 2, 2, 7]);
+    }
+}
@@ -1,7 +1,12 @@
+pub mod cdc;
+
 use crate::error::ErrorContext;
+use crate::usb::classdriver::cdc::CdcAcmDriver;
 use crate::usb::descriptor::InterfaceDescriptor;
+use crate::usb::devmgr::{self, TransferCompletion, TransferResult, UsbDevice};
 use crate::usb::endpoint::{EndpointConfig, EndpointId, EndpointType};
-use crate::usb::mem::allocate;
+use crate::usb::mem::{allocate, free};
+use crate::usb::trb::{RequestType, SetupData};
 use core::mem::size_of;
 
 #[derive(Debug)]
@@ -12,7 +17,26 @@ pub enum ErrorType {
 pub type Error = ErrorContext<ErrorType>;
 pub type Result<T> = core::result::Result<T, Error>;
 
-pub type MouseObserver = fn((i8, i8));
+/// Report of a boot protocol mouse
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct MouseEvent {
+    /// Bitmap of the buttons being pressed
+    pub buttons: u8,
+    pub dx: i8,
+    pub dy: i8,
+}
+
+impl MouseEvent {
+    pub const BUTTON_LEFT: u8 = 1 << 0;
+    pub const BUTTON_RIGHT: u8 = 1 << 1;
+    pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+    pub fn is_pressed(&self, button: u8) -> bool {
+        self.buttons & button != 0
+    }
+}
+
+pub type MouseObserver = fn(MouseEvent);
 static mut DEFAULT_OBSERVER: Option<MouseObserver> = None;
 
 pub fn set_default_mouse_observer(observer: MouseObserver) {
@@ -21,68 +45,181 @@ pub fn set_default_mouse_observer(observer: MouseObserver) {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ClassDriver {
     HidMouse(*mut HidMouseDriver),
+    CdcAcm(*mut CdcAcmDriver),
 }
 
 impl ClassDriver {
+    /// Whether a driver can be bound to the interface
+    pub fn supports(desc: &InterfaceDescriptor) -> bool {
+        Self::is_hid_mouse(desc)
+            || (desc.interface_class() == CdcAcmDriver::CLASS_COMMUNICATIONS
+                && desc.interface_sub_class() == CdcAcmDriver::SUB_CLASS_ACM)
+    }
+
     pub fn new(desc: &InterfaceDescriptor) -> Option<Self> {
-        if desc.interface_class() == 3 && desc.interface_sub_class() == 1 {
-            if desc.interface_protocol() == 1 {
-                return None;
-            } else if desc.interface_protocol() == 2 {
-                let driver_ptr: *mut HidMouseDriver =
-                    allocate(size_of::<HidMouseDriver>(), None, None)
-                        .expect("Failed to allocate memory for driver");
-                unsafe {
-                    driver_ptr.write(HidMouseDriver {
-                        interface_index: desc.interface_number(),
-                        endpoint_interrupt_in: EndpointId::new(0),
-                        buf: allocate(1024, None, None)
-                            .expect("Failed to allocate memory for driver"),
-                    });
-                }
-                return Some(ClassDriver::HidMouse(driver_ptr));
+        if !Self::supports(desc) {
+            return None;
+        }
+        if Self::is_hid_mouse(desc) {
+            let driver_ptr: *mut HidMouseDriver = allocate(size_of::<HidMouseDriver>(), None, None)
+                .expect("Failed to allocate memory for driver");
+            unsafe {
+                driver_ptr.write(HidMouseDriver {
+                    interface_index: desc.interface_number(),
+                    endpoint_interrupt_in: EndpointId::new(0),
+                    buf: allocate(1024, None, None).expect("Failed to allocate memory for driver"),
+                });
             }
+            Some(ClassDriver::HidMouse(driver_ptr))
+        } else {
+            let driver_ptr = CdcAcmDriver::allocate(desc.interface_number());
+            if driver_ptr.is_none() {
+                warn!("No more CDC-ACM devices can be used");
+            }
+            driver_ptr.map(ClassDriver::CdcAcm)
+        }
+    }
+
+    /// HID boot interface mouse
+    fn is_hid_mouse(desc: &InterfaceDescriptor) -> bool {
+        desc.interface_class() == 3
+            && desc.interface_sub_class() == 1
+            && desc.interface_protocol() == 2
+    }
+
+    /// Whether the driver also takes the interface following the one it's bound to
+    pub fn claim_interface(&mut self, desc: &InterfaceDescriptor) -> bool {
+        match *self {
+            ClassDriver::HidMouse(_) => false,
+            ClassDriver::CdcAcm(driver) => unsafe { &mut *driver }.claim_interface(desc),
+        }
+    }
+
+    /// Release the memory of the driver. The driver must not be used after this.
+    pub fn free(self) {
+        match self {
+            ClassDriver::HidMouse(driver) => {
+                free(unsafe { &*driver }.buf as *mut ());
+                free(driver);
+            }
+            ClassDriver::CdcAcm(driver) => CdcAcmDriver::free(driver),
         }
-        None
     }
 
     pub fn on_interrupt_completed(&self, ep_id: EndpointId, len: u32) -> Result<()> {
         match *self {
             ClassDriver::HidMouse(driver) => unsafe { &*driver }.on_interrupt_completed(ep_id, len),
+            ClassDriver::CdcAcm(driver) => unsafe { &*driver }.on_interrupt_completed(ep_id, len),
         }
         Ok(())
     }
 
+    /// Called when the endpoints are configured and the driver can start transfers
+    pub fn start(&self, dev: &mut UsbDevice) -> devmgr::Result<()> {
+        match *self {
+            ClassDriver::HidMouse(_) => {
+                let setup_data = SetupData::new()
+                    .with_request_type(
+                        RequestType::new()
+                            .with_direction(RequestType::DIRECTION_HOST_TO_DEVICE)
+                            .with_type(RequestType::TYPE_CLASS)
+                            .with_recipient(RequestType::RECIPIENT_INTERFACE),
+                    )
+                    .with_request(SetupData::REQUEST_SET_PROTOCOL)
+                    .with_value(0)
+                    .with_index(self.interface_index() as u16)
+                    .with_length(0);
+                dev.submit_control(setup_data, None, TransferCompletion::ClassDriver(*self))
+            }
+            ClassDriver::CdcAcm(driver) => unsafe { &mut *driver }.start(dev, *self),
+        }
+    }
+
+    /// Called when a halted endpoint of the driver other than the interrupt IN is recovered
+    pub fn on_endpoint_recovered(
+        &self,
+        dev: &mut UsbDevice,
+        endpoint_id: EndpointId,
+    ) -> devmgr::Result<()> {
+        match *self {
+            ClassDriver::HidMouse(_) => Ok(()),
+            ClassDriver::CdcAcm(driver) => {
+                unsafe { &mut *driver }.on_endpoint_recovered(dev, *self, endpoint_id)
+            }
+        }
+    }
+
+    /// Called periodically to send the data queued by the driver
+    pub fn flush(&self, dev: &mut UsbDevice) -> devmgr::Result<()> {
+        match *self {
+            ClassDriver::HidMouse(_) => Ok(()),
+            ClassDriver::CdcAcm(driver) => unsafe { &mut *driver }.flush(dev, *self),
+        }
+    }
+
+    /// Called when a transfer issued by the driver finishes
+    pub fn on_transfer_completed(
+        &self,
+        dev: &mut UsbDevice,
+        result: &TransferResult,
+    ) -> devmgr::Result<()> {
+        if let ClassDriver::CdcAcm(driver) = *self {
+            return unsafe { &mut *driver }.on_transfer_completed(dev, *self, result);
+        }
+        if !result.is_success() {
+            return Err(mkerror!(devmgr::ErrorType::TransferFailed(
+                result.completion_code
+            )));
+        }
+        match (*self, result.setup) {
+            (ClassDriver::HidMouse(_), Some(setup))
+                if setup.request() == SetupData::REQUEST_SET_PROTOCOL =>
+            {
+                dev.interrupt_in(
+                    self.endpoint_interrupt_in(),
+                    self.buffer(),
+                    self.in_packet_size() as u32,
+                )
+            }
+            _ => Err(mkerror!(devmgr::ErrorType::NotImplemented)),
+        }
+    }
+
     pub fn set_endpoint(&mut self, config: &EndpointConfig) {
         match *self {
             ClassDriver::HidMouse(driver) => unsafe { &mut *driver }.set_endpoint(config),
+            ClassDriver::CdcAcm(driver) => unsafe { &mut *driver }.set_endpoint(config),
         }
     }
 
     pub fn interface_index(&self) -> u8 {
         match *self {
             ClassDriver::HidMouse(driver) => unsafe { &*driver }.interface_index,
+            ClassDriver::CdcAcm(driver) => unsafe { &*driver }.interface_index(),
         }
     }
 
     pub fn buffer(&self) -> *const () {
         match *self {
             ClassDriver::HidMouse(driver) => unsafe { &*driver }.buf,
+            ClassDriver::CdcAcm(driver) => unsafe { &*driver }.notification_buffer(),
         }
     }
 
     pub fn in_packet_size(&self) -> usize {
         match self {
             ClassDriver::HidMouse(_) => HidMouseDriver::IN_PACKET_SIZE,
+            ClassDriver::CdcAcm(driver) => unsafe { &**driver }.notification_packet_size(),
         }
     }
 
     pub fn endpoint_interrupt_in(&self) -> EndpointId {
         match *self {
             ClassDriver::HidMouse(driver) => unsafe { &*driver }.endpoint_interrupt_in,
+            ClassDriver::CdcAcm(driver) => unsafe { &*driver }.endpoint_interrupt_in(),
         }
     }
 }
@@ -105,14 +242,18 @@ impl HidMouseDriver {
 
     pub fn on_interrupt_completed(&self, ep_id: EndpointId, _len: u32) {
         if ep_id.is_in() {
-            let (x, y) = unsafe {
+            let event = unsafe {
                 let ptr = self.buf as *const u8;
-                (ptr.add(1).read() as i8, ptr.add(2).read() as i8)
+                MouseEvent {
+                    buttons: ptr.read(),
+                    dx: ptr.add(1).read() as i8,
+                    dy: ptr.add(2).read() as i8,
+                }
             };
-            debug!("event received. (x, y) = ({}, {})", x, y);
+            debug!("event received. {:?}", event);
             unsafe {
                 if let Some(observer) = DEFAULT_OBSERVER {
-                    observer((x, y));
+                    observer(event);
                 }
             }
         }
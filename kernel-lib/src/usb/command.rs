@@ -0,0 +1,189 @@
+use crate::error::ErrorContext;
+use crate::timer::{current_tick, TICKS_PER_SECOND};
+use crate::usb::endpoint::EndpointId;
+use crate::usb::trb::ring::Ring;
+use crate::usb::trb::{CommandCompletionEventTrb, CompletionCode, Trb};
+use crate::usb::xhci::{Accessor, DoorbellRegister, OperationalRegisters};
+use crate::usb::SlotId;
+use crate::util::collection::ArrayMap;
+
+const COMMAND_RING_LEN: usize = 32;
+/// A TRB is occupied by Link TRB, and another one is kept empty
+/// so that the enqueue pointer never catches up with the dequeue pointer
+const MAX_PENDING_COMMANDS: usize = COMMAND_RING_LEN - 2;
+/// The command being executed is aborted if no command completes within this period
+pub(super) const COMMAND_TIMEOUT_TICKS: u64 = TICKS_PER_SECOND;
+
+#[derive(Debug)]
+pub enum ErrorType {
+    RingFull,
+    UnknownCommand(u64),
+    TrbError(crate::usb::trb::Error),
+    CollectionError(crate::util::collection::CollectionError),
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Issued command with the context to resume the work on its completion
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    EnableSlot {
+        port_id: u8,
+    },
+    AddressDevice {
+        port_id: u8,
+        slot_id: SlotId,
+    },
+    ConfigureEndpoint {
+        port_id: u8,
+        slot_id: SlotId,
+    },
+    DisableSlot {
+        slot_id: SlotId,
+    },
+    ResetEndpoint {
+        slot_id: SlotId,
+        endpoint_id: EndpointId,
+    },
+    SetTrDequeuePointer {
+        slot_id: SlotId,
+        endpoint_id: EndpointId,
+    },
+    /// Configure Endpoint Command to switch an alternate setting
+    Reconfigure {
+        slot_id: SlotId,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CommandCompletion {
+    pub command: Command,
+    pub completion_code: CompletionCode,
+    /// Slot ID in the event, which is the enabled slot for Enable Slot Command
+    pub slot_id: SlotId,
+}
+
+impl CommandCompletion {
+    pub fn is_success(&self) -> bool {
+        self.completion_code == CompletionCode::Success
+    }
+}
+
+/// Command ring which keeps track of the commands until they complete
+#[derive(Debug)]
+pub struct CommandRing {
+    ring: Ring,
+    pending: ArrayMap<u64, Command, MAX_PENDING_COMMANDS>,
+    /// The tick when a command was issued to the idle ring or the last command completed
+    last_progress: u64,
+    aborting: bool,
+    operational: Accessor<OperationalRegisters>,
+    doorbell: Accessor<DoorbellRegister>,
+}
+
+impl CommandRing {
+    pub fn new() -> Self {
+        Self {
+            ring: Ring::new(),
+            pending: ArrayMap::new(),
+            last_progress: 0,
+            aborting: false,
+            operational: Accessor::null(),
+            doorbell: Accessor::null(),
+        }
+    }
+
+    pub fn initialize(
+        &mut self,
+        operational: Accessor<OperationalRegisters>,
+        doorbell: Accessor<DoorbellRegister>,
+    ) -> Result<()> {
+        self.operational = operational;
+        self.doorbell = doorbell;
+        self.ring
+            .initialize(COMMAND_RING_LEN)
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
+
+        let ptr = self.ring.buffer_pointer();
+        self.operational.as_mut().crcr.update(|c| {
+            c.set_ring_cycle_state(true);
+            c.set_command_stop(false);
+            c.set_command_abort(false);
+            c.set_command_ring_pointer(ptr);
+        });
+        Ok(())
+    }
+
+    /// Issue the command unless the ring is full
+    pub fn push<T: Trb>(&mut self, trb: T, command: Command) -> Result<()> {
+        if self.pending.len() >= MAX_PENDING_COMMANDS {
+            return Err(mkerror!(ErrorType::RingFull));
+        }
+        if self.pending.is_empty() {
+            self.last_progress = current_tick();
+        }
+
+        let ptr = self
+            .ring
+            .push(trb)
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+            .ptr;
+        self.pending
+            .insert(ptr, command)
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        self.doorbell.as_mut().ring(0, 0);
+        Ok(())
+    }
+
+    /// Look up the command which the event is for.
+    /// Returns `None` if the event doesn't complete any command
+    pub fn on_completion(
+        &mut self,
+        trb: &CommandCompletionEventTrb,
+    ) -> Result<Option<CommandCompletion>> {
+        self.last_progress = current_tick();
+        if trb.completion_code() == CompletionCode::CommandRingStopped {
+            debug!("Command ring has been stopped");
+            self.aborting = false;
+            if !self.pending.is_empty() {
+                // resume the commands following the aborted one
+                self.doorbell.as_mut().ring(0, 0);
+            }
+            return Ok(None);
+        }
+
+        let ptr = trb.issuer_pointer();
+        self.ring.on_trb_completed(ptr);
+        let command = self
+            .pending
+            .remove(&ptr)
+            .ok_or_else(|| mkerror!(ErrorType::UnknownCommand(ptr)))?;
+        Ok(Some(CommandCompletion {
+            command,
+            completion_code: trb.completion_code(),
+            slot_id: trb.slot_id(),
+        }))
+    }
+
+    /// Abort the command being executed if it has been stuck.
+    /// The command completes with Command Aborted and the ring is resumed after that
+    pub fn check_timeout(&mut self) {
+        if self.aborting
+            || self.pending.is_empty()
+            || current_tick() - self.last_progress < COMMAND_TIMEOUT_TICKS
+        {
+            return;
+        }
+        self.last_progress = current_tick();
+        let crcr = &mut self.operational.as_mut().crcr;
+        if !crcr.read().command_ring_running() {
+            // xHC has missed the doorbell somehow
+            self.doorbell.as_mut().ring(0, 0);
+            return;
+        }
+        warn!("Command timed out. Aborting");
+        self.aborting = true;
+        crcr.update(|c| c.set_command_abort(true));
+    }
+}
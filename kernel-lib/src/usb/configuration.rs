@@ -0,0 +1,214 @@
+//! Configurations of a device, parsed from a configuration descriptor
+//! and the interface and endpoint descriptors following it
+
+use crate::error::ErrorContext;
+use crate::usb::classdriver::ClassDriver;
+use crate::usb::descriptor::{
+    self, descriptors, DescriptorType, DeviceDescriptor, InterfaceDescriptor,
+};
+use crate::usb::endpoint::EndpointConfig;
+use crate::util::collection::{ArrayVec, CollectionError};
+
+#[derive(Debug)]
+pub enum ErrorType {
+    /// The buffer doesn't start with a configuration descriptor
+    NotConfiguration,
+    /// An endpoint descriptor appears before any interface descriptor
+    NoInterface,
+    DescriptorError(descriptor::Error),
+    CollectionError(CollectionError),
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Maximum number of alternate settings of all interfaces in a configuration
+const MAX_ALT_SETTINGS: usize = 16;
+/// Maximum number of endpoints of all alternate settings in a configuration
+const MAX_ENDPOINTS: usize = 32;
+
+/// Policy to pick the configuration of a device. The first configuration accepted is set
+pub type ConfigurationSelector = fn(&DeviceDescriptor, &Configuration) -> bool;
+static mut SELECTOR: ConfigurationSelector = has_supported_interface;
+
+/// Replace the policy for the devices enumerated after this
+pub fn set_configuration_selector(selector: ConfigurationSelector) {
+    unsafe {
+        SELECTOR = selector;
+    }
+}
+
+pub fn configuration_selector() -> ConfigurationSelector {
+    unsafe { SELECTOR }
+}
+
+/// The default policy, which accepts a configuration if a class driver supports any of its interfaces
+pub fn has_supported_interface(_: &DeviceDescriptor, config: &Configuration) -> bool {
+    config
+        .alt_settings
+        .as_slice()
+        .iter()
+        .any(|alt| alt.alternate_setting() == 0 && ClassDriver::supports(alt.descriptor()))
+}
+
+/// An alternate setting of an interface
+#[derive(Debug, Copy, Clone)]
+pub struct AltSetting {
+    descriptor: InterfaceDescriptor,
+    /// Range of the endpoints in [`Configuration::endpoints`]
+    endpoints_begin: usize,
+    endpoints_end: usize,
+}
+
+impl AltSetting {
+    pub fn descriptor(&self) -> &InterfaceDescriptor {
+        &self.descriptor
+    }
+
+    pub fn interface_number(&self) -> u8 {
+        self.descriptor.interface_number()
+    }
+
+    pub fn alternate_setting(&self) -> u8 {
+        self.descriptor.alternate_setting()
+    }
+}
+
+/// Tree of the interfaces, their alternate settings and the endpoints of each setting
+#[derive(Debug)]
+pub struct Configuration {
+    index: u8,
+    value: u8,
+    /// In the order of the descriptors
+    alt_settings: ArrayVec<AltSetting, MAX_ALT_SETTINGS>,
+    endpoints: ArrayVec<EndpointConfig, MAX_ENDPOINTS>,
+}
+
+impl Configuration {
+    /// `buf` is the configuration descriptor of `index` followed by the others.
+    /// Descriptors beyond wTotalLength and the unknown ones are ignored
+    pub fn parse(index: u8, buf: &[u8]) -> Result<Self> {
+        let config_desc = match DescriptorType::parse(buf) {
+            Ok(DescriptorType::Configuration(config_desc)) => config_desc,
+            Ok(_) => return Err(mkerror!(ErrorType::NotConfiguration)),
+            Err(e) => return Err(mkerror!(ErrorType::DescriptorError(e))),
+        };
+        let len = buf.len().min(config_desc.total_length() as usize);
+        let mut config = Self {
+            index,
+            value: config_desc.configuration_value(),
+            alt_settings: ArrayVec::new(),
+            endpoints: ArrayVec::new(),
+        };
+        for desc in descriptors(&buf[..len]) {
+            let desc = match desc {
+                Ok(desc) => desc,
+                Err(e) if matches!(e.error(), descriptor::ErrorType::Unknown { .. }) => continue,
+                Err(e) => return Err(mkerror!(ErrorType::DescriptorError(e))),
+            };
+            match desc {
+                DescriptorType::Interface(interface_desc) => {
+                    let begin = config.endpoints.len();
+                    config
+                        .alt_settings
+                        .push(AltSetting {
+                            descriptor: interface_desc,
+                            endpoints_begin: begin,
+                            endpoints_end: begin,
+                        })
+                        .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+                }
+                DescriptorType::Endpoint(ep_desc) => {
+                    let last = config
+                        .alt_settings
+                        .len()
+                        .checked_sub(1)
+                        .ok_or_else(|| mkerror!(ErrorType::NoInterface))?;
+                    config
+                        .endpoints
+                        .push(EndpointConfig::from(&ep_desc))
+                        .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+                    config.alt_settings[last].endpoints_end += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// The index to request the descriptor with
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// bConfigurationValue, which is passed to SET_CONFIGURATION
+    pub fn configuration_value(&self) -> u8 {
+        self.value
+    }
+
+    /// Numbers of the interfaces in the order of appearance
+    pub fn interfaces(&self) -> impl Iterator<Item = u8> + '_ {
+        let alt_settings = self.alt_settings.as_slice();
+        alt_settings
+            .iter()
+            .enumerate()
+            .filter(move |(i, alt)| {
+                alt_settings[..*i]
+                    .iter()
+                    .all(|prev| prev.interface_number() != alt.interface_number())
+            })
+            .map(|(_, alt)| alt.interface_number())
+    }
+
+    pub fn alt_settings(&self, interface: u8) -> impl Iterator<Item = &AltSetting> + '_ {
+        self.alt_settings
+            .as_slice()
+            .iter()
+            .filter(move |alt| alt.interface_number() == interface)
+    }
+
+    pub fn alt_setting(&self, interface: u8, alternate: u8) -> Option<&AltSetting> {
+        self.alt_settings(interface)
+            .find(|alt| alt.alternate_setting() == alternate)
+    }
+
+    pub fn endpoints(&self, alt: &AltSetting) -> &[EndpointConfig] {
+        &self.endpoints.as_slice()[alt.endpoints_begin..alt.endpoints_end]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::configuration::Configuration;
+    use crate::usb::endpoint::EndpointId;
+
+    #[test]
+    fn alternate_settings() {
+        let buf: [u8; 55] = [
+            9, 2, 55, 0, 2, 3, 0, 0x80, 50, // configuration 3
+            9, 4, 0, 0, 1, 3, 1, 1, 0, // interface 0: keyboard
+            7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN
+            9, 4, 1, 0, 0, 1, 2, 0, 0, // interface 1: audio streaming without endpoints
+            9, 4, 1, 1, 1, 1, 2, 0, 0, // interface 1, alternate setting 1
+            5, 0x24, 1, 1, 0, // class specific
+            7, 5, 0x02, 1, 0xc0, 0, 1, // endpoint 2 OUT
+        ];
+        let config = Configuration::parse(1, &buf).unwrap();
+        assert_eq!(config.index(), 1);
+        assert_eq!(config.configuration_value(), 3);
+        assert_eq!(config.interfaces().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(config.alt_settings(1).count(), 2);
+
+        let keyboard = config.alt_setting(0, 0).unwrap();
+        assert_eq!(keyboard.descriptor().interface_class(), 3);
+        assert_eq!(config.endpoints(keyboard).len(), 1);
+        assert!(config
+            .endpoints(config.alt_setting(1, 0).unwrap())
+            .is_empty());
+        let streaming = config.endpoints(config.alt_setting(1, 1).unwrap());
+        assert_eq!(streaming.len(), 1);
+        assert_eq!(streaming[0].endpoint_id, EndpointId::new(4));
+        assert_eq!(streaming[0].max_packet_size, 192);
+        assert!(config.alt_setting(1, 2).is_none());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::usb::endpoint::EndpointId;
+use crate::usb::mem::{allocate, free};
 use crate::usb::port::PortSpeed;
 use bit_field::{BitArray, BitField};
 use core::convert::TryFrom;
@@ -37,27 +38,88 @@ impl EndpointContext {
     setbits!(pub set_error_count: u8; data; 33; 2);
     setbits!(pub set_endpoint_type: u8; data; 35; 3);
     setbits!(pub set_max_burst_size: u8; data; 40; 8);
+    getbits!(pub max_packet_size: u16; data; 48; 16);
     setbits!(pub set_max_packet_size: u16; data; 48; 16);
     setbit!(pub set_dequeue_cycle_state; data; 64);
     setbits!(_set_transfer_ring_buffer: u64; data; 68; 60);
     setbits!(pub set_average_trb_length: u16; data; 128; 16);
+    setbits!(pub set_max_esit_payload_lo: u16; data; 144; 16);
 
     pub fn set_transfer_ring_buffer(&mut self, ptr: u64) {
         self._set_transfer_ring_buffer(ptr >> 4);
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Default)]
+/// Size of each context in device and input contexts, which is specified by HCCPARAMS1.CSZ.
+/// Only the first 32 bytes are used in 64-byte contexts
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ContextSize {
+    Bytes32,
+    Bytes64,
+}
+
+impl ContextSize {
+    pub fn new(csz: bool) -> Self {
+        if csz {
+            ContextSize::Bytes64
+        } else {
+            ContextSize::Bytes32
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            ContextSize::Bytes32 => 32,
+            ContextSize::Bytes64 => 64,
+        }
+    }
+}
+
+/// Output device context which consists of a slot context and 31 endpoint contexts
+#[derive(Debug)]
 pub struct DeviceContext {
-    pub slot_context: SlotContext,
-    pub endpoint_contexts: [EndpointContext; 31],
+    ptr: *mut u8,
+    context_size: ContextSize,
+}
+
+impl DeviceContext {
+    const NUM_CONTEXTS: usize = 32;
+
+    pub fn allocate(context_size: ContextSize) -> crate::usb::mem::Result<Self> {
+        let ptr = allocate(
+            Self::NUM_CONTEXTS * context_size.bytes(),
+            Some(64),
+            Some(4096),
+        )?;
+        Ok(Self { ptr, context_size })
+    }
+
+    pub fn free(&self) {
+        free(self.ptr);
+    }
+
+    pub fn ptr(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    pub fn slot_context(&self) -> &SlotContext {
+        unsafe { &*(self.ptr as *const SlotContext) }
+    }
+
+    pub fn endpoint_context(&self, dci: EndpointId) -> &EndpointContext {
+        unsafe {
+            &*(self
+                .ptr
+                .add(dci.address() as usize * self.context_size.bytes())
+                as *const EndpointContext)
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct InputControlContext {
-    _drop_context_flags: u32,
+    drop_context_flags: u32,
     add_context_flags: u32,
     _reserved1: [u32; 5],
     _configuration_value: u8,
@@ -66,21 +128,131 @@ pub struct InputControlContext {
     _reserved2: u8,
 }
 
-#[repr(C)]
-#[derive(Debug, Default)]
+/// Input context which consists of an input control context and a device context
+#[derive(Debug)]
 pub struct InputContext {
-    pub input_control_context: InputControlContext,
-    pub slot_context: SlotContext,
-    endpoint_contexts: [EndpointContext; 31],
+    ptr: *mut u8,
+    context_size: ContextSize,
 }
+
 impl InputContext {
+    const NUM_CONTEXTS: usize = 33;
+
+    pub fn allocate(context_size: ContextSize) -> crate::usb::mem::Result<Self> {
+        let ptr = allocate(
+            Self::NUM_CONTEXTS * context_size.bytes(),
+            Some(64),
+            Some(4096),
+        )?;
+        Ok(Self { ptr, context_size })
+    }
+
+    pub fn free(&self) {
+        free(self.ptr);
+    }
+
+    pub fn ptr(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    pub fn input_control_context(&mut self) -> &mut InputControlContext {
+        unsafe { &mut *(self.ptr as *mut InputControlContext) }
+    }
+
+    /// Slot context without marking it to be added
+    pub fn slot_context(&mut self) -> &mut SlotContext {
+        unsafe { &mut *(self.context_ptr(1) as *mut SlotContext) }
+    }
+
     pub fn enable_slot_context(&mut self) -> &mut SlotContext {
-        self.input_control_context.add_context_flags |= 1;
-        &mut self.slot_context
+        self.input_control_context().add_context_flags |= 1;
+        self.slot_context()
     }
 
     pub fn enable_endpoint(&mut self, dci: EndpointId) -> &mut EndpointContext {
-        self.input_control_context.add_context_flags |= 1 << dci.address();
-        &mut self.endpoint_contexts[dci.address() as usize - 1]
+        self.input_control_context().add_context_flags |= 1 << dci.address();
+        unsafe { &mut *(self.context_ptr(1 + dci.address() as usize) as *mut EndpointContext) }
+    }
+
+    /// Mark the endpoint to be dropped by Configure Endpoint Command
+    pub fn disable_endpoint(&mut self, dci: EndpointId) {
+        self.input_control_context().drop_context_flags |= 1 << dci.address();
+    }
+
+    fn context_ptr(&self, index: usize) -> *mut u8 {
+        unsafe { self.ptr.add(index * self.context_size.bytes()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::context::{
+        ContextSize, DeviceContext, EndpointContext, InputContext, InputControlContext, SlotContext,
+    };
+    use crate::usb::endpoint::EndpointId;
+    use crate::usb::mem::exclusive_pool;
+    use core::mem::size_of;
+
+    fn read_u32(ptr: u64, offset: usize) -> u32 {
+        unsafe { (ptr as *const u32).add(offset / 4).read() }
+    }
+
+    #[test]
+    fn context_layout() {
+        assert_eq!(size_of::<SlotContext>(), 32);
+        assert_eq!(size_of::<EndpointContext>(), 32);
+        assert_eq!(size_of::<InputControlContext>(), 32);
+    }
+
+    #[test]
+    fn input_context_32() {
+        let _pool = exclusive_pool();
+
+        let mut ctx = InputContext::allocate(ContextSize::Bytes32).unwrap();
+        ctx.enable_slot_context().set_root_hub_port_num(42);
+        ctx.enable_endpoint(EndpointId::new(3))
+            .set_max_packet_size(512);
+
+        // add context flags
+        assert_eq!(read_u32(ctx.ptr(), 4), 0b1001);
+        // slot context follows input control context
+        assert_eq!(read_u32(ctx.ptr(), 32 + 4), 42 << 16);
+        // endpoint context of DCI 3
+        assert_eq!(read_u32(ctx.ptr(), 32 * 4 + 4), 512 << 16);
+        assert_eq!(ctx.slot_context().root_hub_port_num(), 42);
+    }
+
+    #[test]
+    fn input_context_64() {
+        let _pool = exclusive_pool();
+
+        let mut ctx = InputContext::allocate(ContextSize::Bytes64).unwrap();
+        ctx.enable_slot_context().set_root_hub_port_num(42);
+        ctx.enable_endpoint(EndpointId::new(31))
+            .set_max_packet_size(512);
+
+        assert_eq!(read_u32(ctx.ptr(), 4), 1 | (1 << 31));
+        assert_eq!(read_u32(ctx.ptr(), 64 + 4), 42 << 16);
+        assert_eq!(read_u32(ctx.ptr(), 64 * 32 + 4), 512 << 16);
+        // the whole context is in a page
+        assert_eq!(ctx.ptr() % 64, 0);
+        assert_eq!(ctx.ptr() / 4096, (ctx.ptr() + 64 * 33 - 1) / 4096);
+    }
+
+    #[test]
+    fn device_context_64() {
+        let _pool = exclusive_pool();
+
+        let ctx = DeviceContext::allocate(ContextSize::Bytes64).unwrap();
+        unsafe {
+            let ptr = ctx.ptr() as *mut u32;
+            ptr.add(1).write(7 << 16);
+            ptr.add(64 * 2 / 4 + 1).write(64 << 16);
+        }
+        assert_eq!(ctx.slot_context().root_hub_port_num(), 7);
+        assert_eq!(
+            ctx.endpoint_context(EndpointId::new(2)).max_packet_size(),
+            64
+        );
     }
 }
@@ -1,87 +1,218 @@
+//! USB descriptors parsed from the data stage of GET_DESCRIPTOR.
+//!
+//! Every descriptor is checked against both its bLength and the buffer,
+//! so that a broken device can't make the parser loop or read out of bounds.
+
+use crate::error::ErrorContext;
 use bit_field::BitField;
+use core::convert::TryInto;
+use core::fmt::{Debug, Display, Formatter};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorType {
+    /// bLength is 0, after which no more descriptors can be found
+    ZeroLength { offset: usize },
+    /// The descriptor doesn't fit in the buffer, or bLength is shorter than its fixed fields
+    Truncated { offset: usize, descriptor_type: u8 },
+    /// bDescriptorType isn't known. The descriptors following it are still readable
+    Unknown { offset: usize, descriptor_type: u8 },
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
 
-#[derive(Debug)]
-pub struct Descriptor(*const u8);
-impl Descriptor {
-    pub fn new(buf: *const u8) -> Descriptor {
-        Descriptor(buf)
+/// bDescriptorType of the class-specific descriptors
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+#[derive(Debug, Copy, Clone)]
+pub enum DescriptorType<'a> {
+    Device(DeviceDescriptor),
+    Configuration(ConfigurationDescriptor),
+    String(StringDescriptor<'a>),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    InterfaceAssociation(InterfaceAssociationDescriptor),
+    Bos(BosDescriptor),
+    DeviceCapability(DeviceCapabilityDescriptor<'a>),
+    SuperSpeedEndpointCompanion(SuperSpeedEndpointCompanionDescriptor),
+    Hid(HidDescriptor),
+    ClassSpecific(ClassSpecificDescriptor<'a>),
+}
+
+impl<'a> DescriptorType<'a> {
+    /// Parse the descriptor at the head of `buf`
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        Self::parse_at(buf, 0)
     }
 
-    pub fn specialize(&self) -> DescriptorType {
-        match unsafe { self.0.add(1).read() } {
+    /// `offset` is the position of `buf` in the whole data, which is reported on errors
+    fn parse_at(buf: &'a [u8], offset: usize) -> Result<Self> {
+        let descriptor_type = buf.get(1).copied().unwrap_or(0);
+        let truncated = || {
+            mkerror!(ErrorType::Truncated {
+                offset,
+                descriptor_type
+            })
+        };
+        let len = *buf.first().ok_or_else(truncated)? as usize;
+        if len == 0 {
+            return Err(mkerror!(ErrorType::ZeroLength { offset }));
+        }
+        if len < 2 || len > buf.len() {
+            return Err(truncated());
+        }
+        let desc = &buf[..len];
+        let fixed = |size: usize| desc.get(..size).ok_or_else(truncated);
+
+        Ok(match descriptor_type {
             DeviceDescriptor::TYPE => {
-                DescriptorType::Device(unsafe { (self.0 as *const DeviceDescriptor).read() })
+                Self::Device(DeviceDescriptor(fixed(18)?.try_into().unwrap()))
+            }
+            ConfigurationDescriptor::TYPE => {
+                Self::Configuration(ConfigurationDescriptor(fixed(9)?.try_into().unwrap()))
             }
-            ConfigurationDescriptor::TYPE => DescriptorType::Configuration(unsafe {
-                (self.0 as *const ConfigurationDescriptor).read()
-            }),
+            StringDescriptor::TYPE => Self::String(StringDescriptor(&desc[2..])),
             InterfaceDescriptor::TYPE => {
-                DescriptorType::Interface(unsafe { (self.0 as *const InterfaceDescriptor).read() })
+                Self::Interface(InterfaceDescriptor(fixed(9)?.try_into().unwrap()))
             }
             EndpointDescriptor::TYPE => {
-                DescriptorType::Endpoint(unsafe { (self.0 as *const EndpointDescriptor).read() })
+                Self::Endpoint(EndpointDescriptor(fixed(7)?.try_into().unwrap()))
             }
-            HidDescriptor::TYPE => {
-                DescriptorType::Hid(unsafe { (self.0 as *const HidDescriptor).read() })
+            InterfaceAssociationDescriptor::TYPE => Self::InterfaceAssociation(
+                InterfaceAssociationDescriptor(fixed(8)?.try_into().unwrap()),
+            ),
+            BosDescriptor::TYPE => Self::Bos(BosDescriptor(fixed(5)?.try_into().unwrap())),
+            DeviceCapabilityDescriptor::TYPE => {
+                fixed(3)?;
+                Self::DeviceCapability(DeviceCapabilityDescriptor(desc))
             }
-            _ => DescriptorType::Unsupported,
-        }
+            SuperSpeedEndpointCompanionDescriptor::TYPE => Self::SuperSpeedEndpointCompanion(
+                SuperSpeedEndpointCompanionDescriptor(fixed(6)?.try_into().unwrap()),
+            ),
+            HidDescriptor::TYPE => Self::Hid(HidDescriptor(fixed(6)?.try_into().unwrap())),
+            CS_INTERFACE | CS_ENDPOINT => {
+                fixed(3)?;
+                Self::ClassSpecific(ClassSpecificDescriptor(desc))
+            }
+            _ => {
+                return Err(mkerror!(ErrorType::Unknown {
+                    offset,
+                    descriptor_type
+                }))
+            }
+        })
     }
+}
 
-    pub fn iter(&self, len: usize) -> DescriptorIter {
-        DescriptorIter {
-            ptr: self.0,
-            len,
-            offset: 0,
-        }
+/// Iterate the descriptors packed in a buffer, e.g. a configuration descriptor
+/// and the interface and endpoint descriptors following it
+pub fn descriptors(buf: &[u8]) -> DescriptorIter {
+    DescriptorIter {
+        buf,
+        offset: 0,
+        done: false,
     }
 }
 
-pub struct DescriptorIter {
-    ptr: *const u8,
-    len: usize,
+pub struct DescriptorIter<'a> {
+    buf: &'a [u8],
     offset: usize,
+    /// Whether the rest of the buffer can't be split into descriptors
+    done: bool,
 }
-impl Iterator for DescriptorIter {
-    type Item = DescriptorType;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.offset < self.len {
-            let ptr = self.ptr;
-            let desc_len = unsafe { *ptr } as usize;
-
-            self.offset += desc_len;
-            self.ptr = unsafe { ptr.add(desc_len) };
+impl<'a> Iterator for DescriptorIter<'a> {
+    type Item = Result<DescriptorType<'a>>;
 
-            Some(Descriptor(self.ptr).specialize())
-        } else {
-            None
+    /// Unknown descriptors are reported and skipped.
+    /// The iteration ends after a zero-length or truncated descriptor
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.buf.len() {
+            return None;
         }
+        let rest = &self.buf[self.offset..];
+        let result = DescriptorType::parse_at(rest, self.offset);
+        match result.as_ref().map_err(|e| e.error()) {
+            Ok(_) | Err(ErrorType::Unknown { .. }) => self.offset += rest[0] as usize,
+            Err(_) => self.done = true,
+        }
+        Some(result)
     }
 }
 
-#[derive(Debug)]
-pub enum DescriptorType {
-    Unsupported,
-    Device(DeviceDescriptor),
-    Configuration(ConfigurationDescriptor),
-    Interface(InterfaceDescriptor),
-    Endpoint(EndpointDescriptor),
-    Hid(HidDescriptor),
-}
-
 #[repr(transparent)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct DeviceDescriptor([u8; 18]);
 impl DeviceDescriptor {
     pub const TYPE: u8 = 1;
+
+    /// bcdUSB
+    pub fn usb_release(&self) -> u16 {
+        u16::from_le_bytes([self.0[2], self.0[3]])
+    }
+
+    pub fn device_class(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn device_sub_class(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn device_protocol(&self) -> u8 {
+        self.0[6]
+    }
+
+    pub fn max_packet_size0(&self) -> u8 {
+        self.0[7]
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        u16::from_le_bytes([self.0[8], self.0[9]])
+    }
+
+    pub fn product_id(&self) -> u16 {
+        u16::from_le_bytes([self.0[10], self.0[11]])
+    }
+
+    /// bcdDevice
+    pub fn device_release(&self) -> u16 {
+        u16::from_le_bytes([self.0[12], self.0[13]])
+    }
+
+    pub fn manufacturer_index(&self) -> u8 {
+        self.0[14]
+    }
+
+    pub fn product_index(&self) -> u8 {
+        self.0[15]
+    }
+
+    pub fn serial_number_index(&self) -> u8 {
+        self.0[16]
+    }
+
+    pub fn num_configurations(&self) -> u8 {
+        self.0[17]
+    }
 }
 
 #[repr(transparent)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct ConfigurationDescriptor([u8; 9]);
 impl ConfigurationDescriptor {
     pub const TYPE: u8 = 2;
+    pub const LENGTH: usize = 9;
+
+    /// wTotalLength, the length of the descriptors including the ones following this
+    pub fn total_length(&self) -> u16 {
+        u16::from_le_bytes([self.0[2], self.0[3]])
+    }
+
+    pub fn num_interfaces(&self) -> u8 {
+        self.0[4]
+    }
 
     pub fn configuration_value(&self) -> u8 {
         self.0[5]
@@ -89,7 +220,7 @@ impl ConfigurationDescriptor {
 }
 
 #[repr(transparent)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct InterfaceDescriptor([u8; 9]);
 impl InterfaceDescriptor {
     pub const TYPE: u8 = 4;
@@ -98,6 +229,10 @@ impl InterfaceDescriptor {
         self.0[2]
     }
 
+    pub fn alternate_setting(&self) -> u8 {
+        self.0[3]
+    }
+
     pub fn num_endpoints(&self) -> u8 {
         self.0[4]
     }
@@ -115,43 +250,470 @@ impl InterfaceDescriptor {
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct EndpointDescriptor {
-    _length: u8,
-    _descriptor_type: u8,
-    endpoint_address: u8,
-    attributes: u8,
-    max_packet_size: u16,
-    interval: u8,
-}
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct EndpointDescriptor([u8; 7]);
 impl EndpointDescriptor {
     pub const TYPE: u8 = 5;
 
     pub fn endpoint_address_number(&self) -> u8 {
-        self.endpoint_address.get_bits(0..4)
+        self.0[2].get_bits(0..4)
     }
 
     pub fn endpoint_address_dir_in(&self) -> bool {
-        self.endpoint_address.get_bit(7)
+        self.0[2].get_bit(7)
     }
 
     pub fn attributes_transfer_type(&self) -> u8 {
-        self.attributes.get_bits(0..2)
+        self.0[3].get_bits(0..2)
     }
 
     pub fn max_packet_size(&self) -> u16 {
-        self.max_packet_size
+        u16::from_le_bytes([self.0[4], self.0[5]])
     }
 
     pub fn interval(&self) -> u8 {
-        self.interval
+        self.0[6]
+    }
+}
+
+/// Groups the interfaces of a function, e.g. the two interfaces of CDC-ACM
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct InterfaceAssociationDescriptor([u8; 8]);
+impl InterfaceAssociationDescriptor {
+    pub const TYPE: u8 = 11;
+
+    pub fn first_interface(&self) -> u8 {
+        self.0[2]
+    }
+
+    pub fn interface_count(&self) -> u8 {
+        self.0[3]
+    }
+
+    pub fn function_class(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn function_sub_class(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn function_protocol(&self) -> u8 {
+        self.0[6]
+    }
+}
+
+/// Binary Device Object Store, followed by the device capability descriptors
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct BosDescriptor([u8; 5]);
+impl BosDescriptor {
+    pub const TYPE: u8 = 15;
+
+    pub fn total_length(&self) -> u16 {
+        u16::from_le_bytes([self.0[2], self.0[3]])
+    }
+
+    pub fn num_device_capabilities(&self) -> u8 {
+        self.0[4]
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceCapabilityDescriptor<'a>(&'a [u8]);
+impl<'a> DeviceCapabilityDescriptor<'a> {
+    pub const TYPE: u8 = 16;
+    pub const USB_2_0_EXTENSION: u8 = 2;
+    pub const SUPERSPEED_USB: u8 = 3;
+
+    /// bDevCapabilityType
+    pub fn capability_type(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// Capability-dependent fields following bDevCapabilityType
+    pub fn body(&self) -> &'a [u8] {
+        &self.0[3..]
+    }
+}
+
+/// Follows an endpoint descriptor of a SuperSpeed device
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct SuperSpeedEndpointCompanionDescriptor([u8; 6]);
+impl SuperSpeedEndpointCompanionDescriptor {
+    pub const TYPE: u8 = 48;
+
+    /// Number of packets in a burst minus 1
+    pub fn max_burst(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// bmAttributes, MaxStreams for bulk endpoints and Mult for isochronous ones
+    pub fn attributes(&self) -> u8 {
+        self.0[3]
+    }
+
+    pub fn bytes_per_interval(&self) -> u16 {
+        u16::from_le_bytes([self.0[4], self.0[5]])
     }
 }
 
 #[repr(transparent)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct HidDescriptor([u8; 6]);
 impl HidDescriptor {
     pub const TYPE: u8 = 33;
+
+    /// bcdHID
+    pub fn hid_release(&self) -> u16 {
+        u16::from_le_bytes([self.0[2], self.0[3]])
+    }
+}
+
+/// CS_INTERFACE or CS_ENDPOINT descriptor, whose layout is defined by each class
+#[derive(Debug, Copy, Clone)]
+pub struct ClassSpecificDescriptor<'a>(&'a [u8]);
+impl<'a> ClassSpecificDescriptor<'a> {
+    pub fn is_interface(&self) -> bool {
+        self.0[1] == CS_INTERFACE
+    }
+
+    /// bDescriptorSubtype
+    pub fn subtype(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// Fields following bDescriptorSubtype
+    pub fn body(&self) -> &'a [u8] {
+        &self.0[3..]
+    }
+}
+
+/// String descriptor, whose body is an array of UTF-16LE code units.
+/// The descriptor of index 0 holds the language IDs instead.
+#[derive(Debug, Copy, Clone)]
+pub struct StringDescriptor<'a>(&'a [u8]);
+impl<'a> StringDescriptor<'a> {
+    pub const TYPE: u8 = 3;
+
+    /// `buf` is the data stage of GET_DESCRIPTOR which may be shorter than bLength
+    pub fn new(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < 2 || buf[1] != Self::TYPE {
+            return None;
+        }
+        let len = (buf[0] as usize).min(buf.len());
+        Some(StringDescriptor(&buf[2..len.max(2)]))
+    }
+
+    pub fn code_units(&self) -> impl Iterator<Item = u16> + 'a {
+        self.0
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+    }
+
+    /// The first language ID, which is used to request strings
+    pub fn first_language_id(&self) -> Option<u16> {
+        self.code_units().next()
+    }
+
+    pub fn to_usb_string(self) -> UsbString {
+        UsbString::from_utf16(self.code_units())
+    }
+}
+
+/// Fixed-sized UTF-8 string decoded from [`StringDescriptor`]
+#[derive(Copy, Clone)]
+pub struct UsbString {
+    buf: [u8; UsbString::CAPACITY],
+    len: usize,
+}
+
+impl UsbString {
+    const CAPACITY: usize = 128;
+
+    pub const fn empty() -> Self {
+        Self {
+            buf: [0; Self::CAPACITY],
+            len: 0,
+        }
+    }
+
+    /// Invalid sequences are replaced with U+FFFD, and characters which don't fit are dropped
+    pub fn from_utf16<I: IntoIterator<Item = u16>>(units: I) -> Self {
+        let mut s = Self::empty();
+        for c in core::char::decode_utf16(units) {
+            let c = c.unwrap_or(core::char::REPLACEMENT_CHARACTER);
+            if s.len + c.len_utf8() > Self::CAPACITY {
+                break;
+            }
+            c.encode_utf8(&mut s.buf[s.len..]);
+            s.len += c.len_utf8();
+        }
+        s
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only whole characters are written into the buffer
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Display for UsbString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Debug for UsbString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::configuration::Configuration;
+    use crate::usb::descriptor::{
+        descriptors, DescriptorType, DeviceCapabilityDescriptor, DeviceDescriptor, ErrorType,
+        StringDescriptor, UsbString,
+    };
+
+    #[test]
+    fn device_descriptor_fields() {
+        let desc = DeviceDescriptor([
+            18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x27, 0x06, 0x01, 0x00, 0x00, 0x01, 1, 2, 3, 1,
+        ]);
+        assert_eq!(desc.usb_release(), 0x0200);
+        assert_eq!(desc.max_packet_size0(), 64);
+        assert_eq!(desc.vendor_id(), 0x0627);
+        assert_eq!(desc.product_id(), 0x0001);
+        assert_eq!(desc.device_release(), 0x0100);
+        assert_eq!(desc.manufacturer_index(), 1);
+        assert_eq!(desc.product_index(), 2);
+        assert_eq!(desc.serial_number_index(), 3);
+        assert_eq!(desc.num_configurations(), 1);
+    }
+
+    #[test]
+    fn iterate_configuration() {
+        // CDC-ACM communication interface with a functional descriptor,
+        // followed by an endpoint which is out of wTotalLength
+        let buf: [u8; 30] = [
+            9, 2, 23, 0, 1, 1, 0, 0x80, 50, // configuration
+            9, 4, 0, 0, 1, 2, 2, 1, 0, // interface
+            5, 0x24, 0, 0x10, 0x01, // header functional descriptor
+            7, 5, 0x83, 3, 8, 0, 16, // endpoint
+        ];
+        let mut iter = descriptors(&buf[..23]);
+        assert!(matches!(
+            iter.next(),
+            Some(Ok(DescriptorType::Configuration(_)))
+        ));
+        assert!(matches!(
+            iter.next(),
+            Some(Ok(DescriptorType::Interface(_)))
+        ));
+        match iter.next() {
+            Some(Ok(DescriptorType::ClassSpecific(desc))) => {
+                assert!(desc.is_interface());
+                assert_eq!(desc.subtype(), 0);
+                assert_eq!(desc.body(), &[0x10, 0x01]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(iter.next().is_none());
+
+        let endpoint = descriptors(&buf).last().unwrap().unwrap();
+        match endpoint {
+            DescriptorType::Endpoint(desc) => {
+                assert_eq!(desc.endpoint_address_number(), 3);
+                assert!(desc.endpoint_address_dir_in());
+                assert_eq!(desc.attributes_transfer_type(), 3);
+                assert_eq!(desc.max_packet_size(), 8);
+                assert_eq!(desc.interval(), 16);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_descriptors() {
+        let buf = [
+            8, 11, 0, 2, 2, 2, 1, 0, // interface association
+            5, 15, 22, 0, 2, // BOS
+            7, 16, 2, 0x02, 0, 0, 0, // USB 2.0 extension
+            10, 16, 3, 0, 0x0e, 0, 1, 10, 0xff, 0x07, // SuperSpeed USB
+            6, 48, 15, 0, 0, 0, // SuperSpeed endpoint companion
+            9, 33, 0x11, 0x01, 0, 1, 34, 52, 0, // HID
+        ];
+        let descs: Vec<_> = descriptors(&buf).map(Result::unwrap).collect();
+        assert_eq!(descs.len(), 6);
+        match descs[0] {
+            DescriptorType::InterfaceAssociation(desc) => {
+                assert_eq!(desc.first_interface(), 0);
+                assert_eq!(desc.interface_count(), 2);
+                assert_eq!(desc.function_class(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match descs[1] {
+            DescriptorType::Bos(desc) => {
+                assert_eq!(desc.total_length(), 22);
+                assert_eq!(desc.num_device_capabilities(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match descs[3] {
+            DescriptorType::DeviceCapability(desc) => {
+                assert_eq!(
+                    desc.capability_type(),
+                    DeviceCapabilityDescriptor::SUPERSPEED_USB
+                );
+                assert_eq!(desc.body().len(), 7);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match descs[4] {
+            DescriptorType::SuperSpeedEndpointCompanion(desc) => assert_eq!(desc.max_burst(), 15),
+            other => panic!("unexpected {:?}", other),
+        }
+        match descs[5] {
+            DescriptorType::Hid(desc) => assert_eq!(desc.hid_release(), 0x0111),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn errors(buf: &[u8]) -> Vec<ErrorType> {
+        descriptors(buf)
+            .filter_map(|desc| desc.err())
+            .map(|e| *e.error())
+            .collect()
+    }
+
+    #[test]
+    fn malformed_descriptors() {
+        // bLength = 0 would make the iteration loop forever
+        assert_eq!(
+            errors(&[9, 4, 0, 0, 1, 3, 1, 2, 0, 0, 5, 0x81]),
+            vec![ErrorType::ZeroLength { offset: 9 }]
+        );
+        // bLength beyond the buffer
+        assert_eq!(
+            errors(&[7, 5, 0x81, 3, 8]),
+            vec![ErrorType::Truncated {
+                offset: 0,
+                descriptor_type: 5
+            }]
+        );
+        // bLength shorter than the fields
+        assert_eq!(
+            errors(&[4, 1, 0, 2, 4, 5, 0x81, 3]),
+            vec![ErrorType::Truncated {
+                offset: 0,
+                descriptor_type: 1
+            }]
+        );
+        assert_eq!(
+            errors(&[1]),
+            vec![ErrorType::Truncated {
+                offset: 0,
+                descriptor_type: 0
+            }]
+        );
+        // the descriptors after the unknown one are still parsed
+        let buf = [3, 0xff, 0, 7, 5, 0x81, 3, 8, 0, 10];
+        assert_eq!(
+            errors(&buf),
+            vec![ErrorType::Unknown {
+                offset: 0,
+                descriptor_type: 0xff
+            }]
+        );
+        assert!(matches!(
+            descriptors(&buf).nth(1),
+            Some(Ok(DescriptorType::Endpoint(_)))
+        ));
+    }
+
+    /// xorshift, to generate the same inputs in every run
+    struct Random(u32);
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let mut random = Random(0x1234_5678);
+        for _ in 0..10000 {
+            let len = random.next() as usize % 64;
+            let buf: Vec<u8> = (0..len).map(|_| random.next() as u8).collect();
+            // every item consumes at least a byte, or ends the iteration
+            assert!(descriptors(&buf).count() <= len);
+        }
+    }
+
+    #[test]
+    fn fuzz_mutated_configuration() {
+        let valid = [
+            9, 2, 48, 0, 2, 1, 0, 0x80, 50, // configuration
+            8, 11, 0, 2, 2, 2, 1, 0, // interface association
+            9, 4, 0, 0, 1, 2, 2, 1, 0, // interface
+            5, 0x24, 0, 0x10, 0x01, // header functional descriptor
+            7, 5, 0x83, 3, 8, 0, 16, // endpoint
+            3, 0xff, 0, // vendor specific
+            7, 5, 0x02, 2, 0x00, 0x02, 0, // endpoint
+        ];
+        let mut random = Random(0x9abc_def0);
+        for _ in 0..10000 {
+            let mut buf = valid.to_vec();
+            for _ in 0..=random.next() % 4 {
+                let i = random.next() as usize % buf.len();
+                buf[i] = random.next() as u8;
+            }
+            buf.truncate(random.next() as usize % (buf.len() + 1));
+            let _ = Configuration::parse(0, &buf);
+            assert!(descriptors(&buf).count() <= buf.len());
+        }
+    }
+
+    #[test]
+    fn language_ids() {
+        let desc = StringDescriptor::new(&[4, 3, 0x09, 0x04]).unwrap();
+        assert_eq!(desc.first_language_id(), Some(0x0409));
+
+        let desc = StringDescriptor::new(&[2, 3]).unwrap();
+        assert_eq!(desc.first_language_id(), None);
+
+        assert!(StringDescriptor::new(&[4, 1, 0x09, 0x04]).is_none());
+    }
+
+    #[test]
+    fn decode_string() {
+        // "QEMU" followed by garbage beyond bLength
+        let desc =
+            StringDescriptor::new(&[10, 3, b'Q', 0, b'E', 0, b'M', 0, b'U', 0, 0xff]).unwrap();
+        assert_eq!(desc.to_usb_string().as_str(), "QEMU");
+
+        // "マウス🐭" including a surrogate pair
+        let s = UsbString::from_utf16([0x30de, 0x30a6, 0x30b9, 0xd83d, 0xdc2d].iter().copied());
+        assert_eq!(s.as_str(), "マウス🐭");
+
+        let s = UsbString::from_utf16([0x41, 0xd800, 0x42].iter().copied());
+        assert_eq!(s.as_str(), "A\u{fffd}B");
+
+        let s = UsbString::from_utf16(core::iter::repeat(0x3042).take(100));
+        assert_eq!(s.as_str().chars().count(), 42);
+    }
 }
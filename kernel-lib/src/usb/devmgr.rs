@@ -1,37 +1,47 @@
 use crate::error::ErrorContext;
+use crate::logger::without_sink;
 use crate::usb::classdriver::ClassDriver;
-use crate::usb::context::{DeviceContext, InputContext, InputControlContext};
+use crate::usb::configuration::{self, Configuration};
+use crate::usb::context::{ContextSize, DeviceContext, InputContext, InputControlContext};
 use crate::usb::descriptor::{
-    ConfigurationDescriptor, Descriptor, DescriptorType, DeviceDescriptor,
+    ConfigurationDescriptor, DescriptorType, DeviceDescriptor, StringDescriptor, UsbString,
 };
 use crate::usb::endpoint::{EndpointConfig, EndpointId, EndpointNumber, EndpointType};
-use crate::usb::mem::{allocate, allocate_array};
+use crate::usb::inventory::{self, DeviceInfo};
+use crate::usb::mem::{allocate, free};
 use crate::usb::port::{Port, PortSpeed};
 use crate::usb::trb::ring::Ring;
 use crate::usb::trb::{
-    DataStageTrb, NormalTrb, RequestType, SetupData, SetupStageTrb, StatusStageTrb,
-    TransferEventTrb,
+    CompletionCode, DataStageTrb, IsochTrb, NormalTrb, RequestType, SetupData, SetupStageTrb,
+    StatusStageTrb, TransferEventTrb,
 };
 use crate::usb::xhci::{Accessor, DoorbellRegister};
 use crate::usb::SlotId;
 use crate::util::collection::{ArrayMap, ArrayVec};
+use core::fmt::{Debug, Formatter};
 use core::mem::size_of;
-use core::ptr::{null, null_mut};
+use core::ptr::null_mut;
+use core::slice::from_raw_parts;
 
 #[derive(Debug)]
 pub enum ErrorType {
     AllocError(crate::usb::mem::Error),
-    TransferFailed(u8),
+    TransferFailed(CompletionCode),
     NoWaiter,
-    NoCorrespondingSetupStage,
     NotImplemented,
     ClassDriverError(crate::usb::classdriver::Error),
     InvalidPhase,
     InvalidEndpointNumber,
+    InvalidEndpointType,
+    InvalidTransferLength,
     TransferRingNotSet,
     UnknownXHCISpeedID,
     CollectionError(crate::util::collection::CollectionError),
     TrbError(crate::usb::trb::Error),
+    DescriptorError(crate::usb::descriptor::Error),
+    ConfigurationError(configuration::Error),
+    /// The interface isn't bound to a class driver, or doesn't have the alternate setting
+    InvalidInterface,
 }
 
 pub type Error = ErrorContext<ErrorType>;
@@ -40,18 +50,32 @@ pub type Result<T> = core::result::Result<T, Error>;
 const NUM_DEVICE_SLOTS: usize = 8;
 const DEVICES_CAPACITY: usize = NUM_DEVICE_SLOTS + 1;
 
+/// A TRB data buffer must not span a 64KiB boundary
+const TRB_BUFFER_BOUNDARY: u64 = 64 * 1024;
+/// Maximum number of TRBs a single bulk or isochronous TD can be split into
+const MAX_TRBS_PER_TD: usize = 16;
+/// An interrupt endpoint is no longer re-armed after this number of errors in a row
+const MAX_CONSECUTIVE_ERRORS: u8 = 8;
+/// Maximum number of interfaces bound to class drivers in a device
+const MAX_INTERFACES: usize = 8;
+
 pub struct DeviceManager {
     max_slots: usize,
-    device_contexts: *mut *mut DeviceContext,
+    context_size: ContextSize,
+    /// DCBAA. The first entry points to the scratchpad buffer array
+    device_contexts: *mut u64,
     devices: ArrayMap<SlotId, UsbDevice, DEVICES_CAPACITY>,
+    num_interrupters: usize,
 }
 
 impl DeviceManager {
     pub fn new() -> DeviceManager {
         DeviceManager {
             max_slots: NUM_DEVICE_SLOTS,
+            context_size: ContextSize::Bytes32,
             device_contexts: null_mut(),
             devices: ArrayMap::new(),
+            num_interrupters: 1,
         }
     }
 
@@ -59,122 +83,235 @@ impl DeviceManager {
         self.max_slots
     }
 
+    /// Transfers of devices allocated after this are distributed over the interrupters
+    pub fn set_num_interrupters(&mut self, num_interrupters: usize) {
+        self.num_interrupters = num_interrupters;
+    }
+
     pub fn dcbaa_ptr(&self) -> u64 {
         self.device_contexts as u64
     }
 
-    pub fn initialize(&mut self) -> Result<()> {
-        let ctx_ptr = allocate::<*mut DeviceContext>(
-            size_of::<*mut DeviceContext>() * (self.max_slots + 1),
+    pub fn initialize(&mut self, context_size: ContextSize) -> Result<()> {
+        let ctx_ptr = allocate::<u64>(
+            size_of::<u64>() * (self.max_slots + 1),
             Some(64),
             Some(4096),
         )
         .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
         self.device_contexts = ctx_ptr;
+        self.context_size = context_size;
 
         Ok(())
     }
 
+    /// Allocate the scratchpad buffers xHC requests and register them to DCBAA.
+    /// Each buffer is a page of `page_size`, which is taken from PAGESIZE register.
+    pub fn initialize_scratchpad_buffers(
+        &mut self,
+        num_buffers: usize,
+        page_size: usize,
+    ) -> Result<()> {
+        if num_buffers == 0 {
+            return Ok(());
+        }
+        let array = allocate::<u64>(size_of::<u64>() * num_buffers, Some(64), Some(page_size))
+            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+        for i in 0..num_buffers {
+            match allocate::<u8>(page_size, Some(page_size), Some(page_size)) {
+                Ok(buf) => unsafe { array.add(i).write(buf as u64) },
+                Err(e) => {
+                    for j in 0..i {
+                        free(unsafe { array.add(j).read() } as *mut u8);
+                    }
+                    free(array);
+                    return Err(mkerror!(ErrorType::AllocError(e)));
+                }
+            }
+        }
+        unsafe {
+            self.device_contexts.write(array as u64);
+        }
+        Ok(())
+    }
+
     pub fn allocate_device(
         &mut self,
         slot_id: SlotId,
         dbreg: Accessor<DoorbellRegister>,
     ) -> Result<()> {
-        let device_context = allocate_array::<DeviceContext>(1, Some(64), Some(4096))
+        let device_context = DeviceContext::allocate(self.context_size)
             .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
-        let input_context = allocate_array::<InputContext>(1, Some(64), Some(4096))
+        let input_context = InputContext::allocate(self.context_size)
+            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+        let device_context_ptr = device_context.ptr();
+        let data_buf = allocate::<()>(UsbDevice::DATA_BUF_LEN, None, None)
             .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
-        let data_buf =
-            allocate::<()>(256, None, None).map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
 
         let dev = UsbDevice {
             class_drivers: ArrayMap::new(),
+            configuration: None,
+            interfaces: ArrayMap::new(),
+            reconfiguration: None,
             transfer_rings: ArrayMap::new(),
             dbreg,
             data_buf,
+            data_buf_len: UsbDevice::DATA_BUF_LEN,
             ep_configs: ArrayVec::new(),
-            setup_stage_map: ArrayMap::new(),
-            event_waiters: ArrayMap::new(),
+            transfer_waiters: ArrayMap::new(),
+            halted_endpoints: ArrayMap::new(),
+            error_counts: ArrayMap::new(),
             device_context,
             input_context,
+            port_id: 0,
+            slot_id,
             is_initialized: false,
-            initialize_phase: 0,
+            initialize_phase: InitializePhase::NotStarted,
+            device_desc: None,
+            lang_id: 0,
+            strings: [UsbString::empty(); 3],
+            num_interrupters: self.num_interrupters,
         };
 
         unsafe {
             self.device_contexts
                 .add(slot_id.value() as usize)
-                .write(device_context);
+                .write(device_context_ptr);
         }
 
-        self.devices
+        if let Some(mut prev) = self
+            .devices
             .insert(slot_id, dev)
-            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))
-            .map(|_| ())
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?
+        {
+            prev.free();
+        }
+        Ok(())
     }
 
     pub fn find_by_slot(&mut self, slot_id: SlotId) -> Option<&mut UsbDevice> {
         self.devices.get_mut(&slot_id)
     }
+
+    pub fn find_slot_by_port(&mut self, port_id: u8) -> Option<SlotId> {
+        self.devices
+            .iter_mut()
+            .find(|(_, dev)| dev.port_id == port_id)
+            .map(|(&slot_id, _)| slot_id)
+    }
+
+    pub fn find_port_by_slot(&self, slot_id: SlotId) -> Option<u8> {
+        self.devices.get(&slot_id).map(|dev| dev.port_id)
+    }
+
+    /// Must be called periodically to let the class drivers make progress
+    pub fn on_timer(&mut self) {
+        for (slot_id, dev) in self.devices.iter_mut() {
+            if !dev.is_initialized {
+                continue;
+            }
+            if let Err(err) = dev.flush_class_drivers() {
+                // the class drivers flushed may be the log sink
+                without_sink(|| error!("Failed to flush slot {}: {:?}", slot_id.value(), err));
+            }
+        }
+    }
+
+    /// Release all resources of the device in the slot.
+    /// The slot must have been disabled so that xHC doesn't access them anymore.
+    pub fn remove_device(&mut self, slot_id: SlotId) {
+        unsafe {
+            self.device_contexts.add(slot_id.value() as usize).write(0);
+        }
+        if let Some(mut dev) = self.devices.remove(&slot_id) {
+            dev.free();
+        }
+        inventory::unregister(slot_id);
+    }
 }
 
 #[derive(Debug)]
 pub struct UsbDevice {
     class_drivers: ArrayMap<EndpointNumber, ClassDriver, { EndpointNumber::MAX as usize }>,
+    /// The configuration set to the device
+    configuration: Option<Configuration>,
+    /// Interfaces bound to the class drivers
+    interfaces: ArrayMap<u8, ClaimedInterface, MAX_INTERFACES>,
+    /// The interface being switched to another alternate setting
+    reconfiguration: Option<Reconfiguration>,
     transfer_rings: ArrayMap<EndpointId, Ring, { EndpointId::MAX as usize }>,
     dbreg: Accessor<DoorbellRegister>,
-    data_buf: *const (),
+    /// Buffer of the descriptors, which grows to hold a long configuration
+    data_buf: *mut (),
+    data_buf_len: usize,
     ep_configs: ArrayVec<EndpointConfig, { EndpointNumber::MAX as usize }>,
-    setup_stage_map: ArrayMap<u64, SetupStageTrb, 16>,
-    event_waiters: ArrayMap<SetupData, ClassDriver, 4>,
-    device_context: *mut DeviceContext,
-    input_context: *mut InputContext,
+    transfer_waiters: ArrayMap<u64, PendingTransfer, 8>,
+    halted_endpoints: ArrayMap<EndpointId, HaltedEndpoint, { EndpointId::MAX as usize }>,
+    /// Number of errors in a row on each interrupt endpoint
+    error_counts: ArrayMap<EndpointId, u8, { EndpointId::MAX as usize }>,
+    device_context: DeviceContext,
+    input_context: InputContext,
+    port_id: u8,
+    slot_id: SlotId,
     is_initialized: bool,
-    initialize_phase: u8,
+    initialize_phase: InitializePhase,
+    device_desc: Option<DeviceDescriptor>,
+    lang_id: u16,
+    /// Manufacturer, product and serial number
+    strings: [UsbString; 3],
+    num_interrupters: usize,
 }
 
 impl UsbDevice {
-    const DATA_BUF_LEN: u32 = 256;
+    const DATA_BUF_LEN: usize = 256;
 
     pub fn device_context(&self) -> &DeviceContext {
-        unsafe { self.device_context.as_ref().unwrap() }
+        &self.device_context
     }
 
     pub fn input_context_ptr(&self) -> u64 {
-        self.input_context as u64
+        self.input_context.ptr()
     }
 
     pub fn is_initialized(&self) -> bool {
         self.is_initialized
     }
 
+    /// Available after the device descriptor is fetched in the initialization
+    pub fn device_descriptor(&self) -> Option<DeviceDescriptor> {
+        self.device_desc
+    }
+
+    /// The root hub port which the device is attached to
+    pub fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
     pub fn start_initialize(&mut self) -> Result<()> {
         self.is_initialized = false;
-        self.initialize_phase = 1;
-        self.get_descriptor(
-            EndpointId::DEFAULT_CONTROL_PIPE_ID,
-            DeviceDescriptor::TYPE,
-            0,
-            Some(self.data_buf),
-            Self::DATA_BUF_LEN,
-        )
+        self.initialize_phase = InitializePhase::DeviceDescriptor;
+        self.get_descriptor(DeviceDescriptor::TYPE, 0, 0, self.data_buf_len as u16)
     }
 
     pub fn address_device(&mut self, port: Port) -> Result<()> {
         let ep0 = EndpointId::DEFAULT_CONTROL_PIPE_ID;
         let tr_ptr = self.alloc_transfer_ring(ep0, 32)?.buffer_pointer();
-        let slot_ctx = unsafe { self.input_context.as_mut().unwrap() }.enable_slot_context();
-        let ep0_ctx = unsafe { self.input_context.as_mut().unwrap() }.enable_endpoint(ep0);
+        let port_speed = port
+            .port_speed()
+            .ok_or_else(|| mkerror!(ErrorType::UnknownXHCISpeedID))?;
+        self.port_id = port.port_num();
 
+        let slot_ctx = self.input_context.enable_slot_context();
         slot_ctx.set_route_string(0);
         slot_ctx.set_root_hub_port_num(port.port_num());
         slot_ctx.set_context_entries(1);
-        slot_ctx.set_speed(port.port_speed().unwrap());
+        slot_ctx.set_speed(port_speed);
 
+        let ep0_ctx = self.input_context.enable_endpoint(ep0);
         ep0_ctx.set_endpoint_type(4); // Control Endpoint. Bidi
-        ep0_ctx.set_max_packet_size(match slot_ctx.speed() {
-            Ok(PortSpeed::SuperSpeed) => 512,
-            Ok(PortSpeed::HighSpeed) => 64,
+        ep0_ctx.set_max_packet_size(match port_speed {
+            PortSpeed::SuperSpeed => 512,
+            PortSpeed::HighSpeed => 64,
             _ => 8,
         });
         ep0_ctx.set_max_burst_size(0);
@@ -189,126 +326,512 @@ impl UsbDevice {
     }
 
     pub fn configure_endpoints(&mut self, port: Port) -> Result<()> {
-        let input_ctx = unsafe { &mut *self.input_context };
-        let device_ctx = unsafe { &mut *self.device_context };
-
-        input_ctx.input_control_context = InputControlContext::default();
-        input_ctx.slot_context = device_ctx.slot_context;
-        let slot_ctx = input_ctx.enable_slot_context();
-
-        slot_ctx.set_context_entries(EndpointId::MAX);
+        self.prepare_input_context();
+        self.input_context
+            .slot_context()
+            .set_context_entries(EndpointId::MAX);
         let port_speed = port
             .port_speed()
             .ok_or_else(|| mkerror!(ErrorType::UnknownXHCISpeedID))?;
 
         for i in 0..self.ep_configs.len() {
             let ep_config = self.ep_configs[i];
-
-            input_ctx.enable_endpoint(ep_config.endpoint_id);
-            let ep_ctx = input_ctx.enable_endpoint(ep_config.endpoint_id);
-            match ep_config.endpoint_type {
-                EndpointType::Control => ep_ctx.set_endpoint_type(4),
-                EndpointType::Isochronous => {
-                    ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 5 } else { 1 })
-                }
-                EndpointType::Bulk => {
-                    ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 6 } else { 2 })
-                }
-                EndpointType::Interrupt => {
-                    ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 7 } else { 3 })
-                }
-            }
-
-            ep_ctx.set_max_packet_size(ep_config.max_packet_size as u16);
-            ep_ctx.set_interval(
-                port_speed.convert_interval(ep_config.endpoint_type, ep_config.interval) as u8,
-            );
-            ep_ctx.set_average_trb_length(1);
-            let tr = self.alloc_transfer_ring(ep_config.endpoint_id, 32)?;
-
-            ep_ctx.set_transfer_ring_buffer(tr.buffer_pointer());
-            ep_ctx.set_dequeue_cycle_state(true);
-            ep_ctx.set_max_primary_streams(0);
-            ep_ctx.set_mult(0);
-            ep_ctx.set_error_count(3);
+            self.enable_endpoint(ep_config, port_speed)?;
         }
         Ok(())
     }
 
     pub fn on_transfer_event_received(&mut self, trb: &TransferEventTrb) -> Result<()> {
         let residual_length = trb.transfer_length();
+        let code = trb.completion_code();
+        if let Some(ring) = self.transfer_rings.get_mut(&trb.endpoint_id()) {
+            ring.on_trb_completed(trb.issuer_pointer());
+        }
 
-        if !(trb.completion_code() == 1 || trb.completion_code() == 13) {
-            return Err(mkerror!(ErrorType::TransferFailed(trb.completion_code())));
+        if let Some(finished) = self.update_transfer_waiters(trb) {
+            return match finished {
+                Some((pending, result)) => {
+                    if code.halts_endpoint() {
+                        let last_ptr = pending.trbs[pending.trbs.len() - 1].0;
+                        self.on_endpoint_halted(pending.endpoint_id, last_ptr, code)?;
+                    }
+                    self.notify_completion(pending.waiter, result)
+                }
+                None => Ok(()),
+            };
         }
 
         if let Some(normal_trb) = trb.issuer_trb().specialize::<NormalTrb>() {
+            if !code.is_success() {
+                return self.on_interrupt_failed(trb.endpoint_id(), trb.issuer_pointer(), code);
+            }
             let transfer_length = normal_trb.transfer_length() - residual_length;
             return self.on_interrupt_completed(trb.endpoint_id(), transfer_length);
         }
+        if !code.is_success() {
+            return Err(mkerror!(ErrorType::TransferFailed(code)));
+        }
+        Err(mkerror!(ErrorType::NoWaiter))
+    }
+
+    /// Take a halted endpoint which Reset Endpoint Command hasn't been issued for yet
+    pub fn next_halted_endpoint(&mut self) -> Option<EndpointId> {
+        for (&endpoint_id, halted) in self.halted_endpoints.iter_mut() {
+            if !halted.resetting {
+                halted.resetting = true;
+                return Some(endpoint_id);
+            }
+        }
+        None
+    }
 
-        let setup_stage_trb = self
-            .setup_stage_map
-            .remove(&trb.issuer_pointer())
-            .ok_or_else(|| mkerror!(ErrorType::NoCorrespondingSetupStage))?;
+    /// The dequeue pointer and its cycle state to skip the TD which has halted the endpoint
+    pub fn recovery_dequeue_pointer(&self, endpoint_id: EndpointId) -> Option<(u64, bool)> {
+        self.halted_endpoints
+            .get(&endpoint_id)
+            .map(|halted| halted.dequeue_pointer)
+    }
 
-        let setup_data = setup_stage_trb.setup_data();
-        if let Some(data_stage_trb) = trb.issuer_trb().specialize::<DataStageTrb>() {
-            let transfer_length = data_stage_trb.trb_transfer_length() - residual_length;
-            self.on_control_completed(
-                setup_data,
-                data_stage_trb.data_buffer_pointer() as *const (),
-                transfer_length,
-            )
-        } else if trb.issuer_trb().specialize::<StatusStageTrb>().is_some() {
-            self.on_control_completed(setup_data, null(), 0)
+    /// Must be called when the TR Dequeue Pointer of the halted endpoint is set.
+    /// Restarts the endpoint and re-arms it if the class driver is waiting for it
+    pub fn on_endpoint_recovered(&mut self, endpoint_id: EndpointId) -> Result<()> {
+        let halted = self
+            .halted_endpoints
+            .remove(&endpoint_id)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        debug!("Endpoint {:?} has been recovered", endpoint_id);
+        self.dbreg.as_mut().ring(endpoint_id.address(), 0);
+
+        if endpoint_id == EndpointId::DEFAULT_CONTROL_PIPE_ID {
+            // the device clears the stall of the control pipe by itself on the next request
+            return Ok(());
+        }
+        if halted.code == CompletionCode::StallError {
+            self.clear_endpoint_halt(endpoint_id)
         } else {
-            Err(mkerror!(ErrorType::NotImplemented))
+            self.rearm_endpoint(endpoint_id)
         }
     }
 
+    /// Give up recovering the endpoint since the command for it failed
+    pub fn on_endpoint_recovery_failed(&mut self, endpoint_id: EndpointId) {
+        self.halted_endpoints.remove(&endpoint_id);
+    }
+
     pub fn on_endpoints_configured(&mut self) -> Result<()> {
-        for i in 0..self.ep_configs.len() {
-            let conf = self.ep_configs[i];
-            let driver = self
-                .class_drivers
-                .get_mut(&conf.endpoint_id.number())
-                .unwrap();
-            let setup_data = SetupData::new()
-                .with_request_type(
-                    RequestType::new()
-                        .with_direction(RequestType::DIRECTION_HOST_TO_DEVICE)
-                        .with_type(RequestType::TYPE_CLASS)
-                        .with_recipient(RequestType::RECIPIENT_INTERFACE),
-                )
-                .with_request(SetupData::REQUEST_SET_PROTOCOL)
-                .with_value(0)
-                .with_index(driver.interface_index() as u16)
-                .with_length(0);
-            let driver = *driver;
-            self.control_out(
-                EndpointId::DEFAULT_CONTROL_PIPE_ID,
-                setup_data,
-                None,
-                0,
-                Some(driver),
-            )?;
+        for &driver in self.bound_drivers().as_slice() {
+            driver.start(self)?;
+        }
+        Ok(())
+    }
+
+    /// Switch the interface bound to a class driver to the alternate setting by SET_INTERFACE.
+    /// The endpoints of the current setting are replaced with the new ones by Configure Endpoint Command,
+    /// and the transfers of the driver are restarted on them
+    pub fn set_interface(&mut self, interface: u8, alternate: u8) -> Result<()> {
+        if self.reconfiguration.is_some() {
+            return Err(mkerror!(ErrorType::InvalidPhase));
+        }
+        let exists = self
+            .configuration
+            .as_ref()
+            .and_then(|config| config.alt_setting(interface, alternate))
+            .is_some();
+        if !exists || self.interfaces.get(&interface).is_none() {
+            return Err(mkerror!(ErrorType::InvalidInterface));
+        }
+
+        let setup_data = SetupData::new()
+            .with_request_type(
+                RequestType::new()
+                    .with_direction(RequestType::DIRECTION_HOST_TO_DEVICE)
+                    .with_type(RequestType::TYPE_STANDARD)
+                    .with_recipient(RequestType::RECIPIENT_INTERFACE),
+            )
+            .with_request(SetupData::REQUEST_SET_INTERFACE)
+            .with_value(alternate as u16)
+            .with_index(interface as u16)
+            .with_length(0);
+        self.push_control(setup_data, None, Waiter::SetInterface)?;
+        self.reconfiguration = Some(Reconfiguration {
+            interface,
+            alternate,
+            step: ReconfigurationStep::SetInterface,
+            command_requested: false,
+        });
+        Ok(())
+    }
+
+    /// Whether Configure Endpoint Command has to be issued with the input context
+    /// to switch the alternate setting. Returns `true` only once for each request
+    pub fn take_reconfiguration_request(&mut self) -> bool {
+        match self.reconfiguration.as_mut() {
+            Some(reconfiguration) if reconfiguration.command_requested => {
+                reconfiguration.command_requested = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Must be called when Configure Endpoint Command for the reconfiguration completes
+    pub fn on_endpoints_reconfigured(&mut self) -> Result<()> {
+        let reconfiguration = self
+            .reconfiguration
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        match reconfiguration.step {
+            ReconfigurationStep::DropEndpoints => self.add_endpoints(),
+            ReconfigurationStep::AddEndpoints => self.on_interface_set(),
+            ReconfigurationStep::SetInterface => Err(mkerror!(ErrorType::InvalidPhase)),
+        }
+    }
+
+    /// Give up switching the alternate setting since the command failed
+    pub fn on_reconfiguration_failed(&mut self) {
+        self.reconfiguration = None;
+    }
+
+    /// Queue a control transfer to the default control pipe.
+    /// The data stage is issued if `buf` is given, and its direction and length follow `setup`.
+    /// `completion` is notified with the setup data and the actual length of the data stage.
+    pub fn submit_control(
+        &mut self,
+        setup: SetupData,
+        buf: Option<*mut ()>,
+        completion: TransferCompletion,
+    ) -> Result<()> {
+        self.push_control(setup, buf, Waiter::Caller(completion))
+    }
+
+    /// Queue an interrupt transfer. The class driver of the endpoint is notified on completion
+    pub fn interrupt_in(
+        &mut self,
+        endpoint_id: EndpointId,
+        buf: *const (),
+        len: u32,
+    ) -> Result<()> {
+        let tr = if let Some(ring) = self.transfer_rings.get_mut(&endpoint_id) {
+            ring
+        } else {
+            return Err(mkerror!(ErrorType::TransferRingNotSet));
+        };
+
+        let normal_trb = NormalTrb::new()
+            .with_pointer(buf as u64)
+            .with_transfer_length(len)
+            .with_interrupter_target(interrupter_target(
+                self.num_interrupters,
+                EndpointType::Interrupt,
+            ))
+            .with_interrupt_on_short_packet(true)
+            .with_interrupt_on_completion(true);
+
+        tr.push(normal_trb)
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
+        self.dbreg.as_mut().ring(endpoint_id.address(), 0);
+        Ok(())
+    }
+
+    /// Queue a bulk IN transfer into `buf`.
+    /// A buffer which doesn't fit in a single TRB is split into chained Normal TRBs,
+    /// and `completion` is notified once with the total length when the whole TD finishes.
+    pub fn bulk_in(
+        &mut self,
+        endpoint_id: EndpointId,
+        buf: *mut (),
+        len: u32,
+        completion: TransferCompletion,
+    ) -> Result<()> {
+        if !endpoint_id.is_in() {
+            return Err(mkerror!(ErrorType::InvalidEndpointNumber));
         }
+        self.push_td(endpoint_id, EndpointType::Bulk, buf, len, None, completion)
+    }
+
+    /// Queue a bulk OUT transfer from `buf`. See [`UsbDevice::bulk_in`]
+    pub fn bulk_out(
+        &mut self,
+        endpoint_id: EndpointId,
+        buf: *const (),
+        len: u32,
+        completion: TransferCompletion,
+    ) -> Result<()> {
+        if endpoint_id.is_in() {
+            return Err(mkerror!(ErrorType::InvalidEndpointNumber));
+        }
+        self.push_td(endpoint_id, EndpointType::Bulk, buf, len, None, completion)
+    }
+
+    /// Queue an isochronous TD which carries the data of single service interval,
+    /// up to the max packet size. The direction follows `endpoint_id`.
+    pub fn isochronous_transfer(
+        &mut self,
+        endpoint_id: EndpointId,
+        buf: *mut (),
+        len: u32,
+        frame: IsochFrame,
+        completion: TransferCompletion,
+    ) -> Result<()> {
+        self.push_td(
+            endpoint_id,
+            EndpointType::Isochronous,
+            buf,
+            len,
+            Some(frame),
+            completion,
+        )
+    }
+
+    fn push_td(
+        &mut self,
+        endpoint_id: EndpointId,
+        endpoint_type: EndpointType,
+        buf: *const (),
+        len: u32,
+        frame: Option<IsochFrame>,
+        completion: TransferCompletion,
+    ) -> Result<()> {
+        let max_packet_size = match self.endpoint_config(endpoint_id) {
+            Some(conf) if conf.endpoint_type == endpoint_type => conf.max_packet_size.max(1) as u32,
+            Some(_) => return Err(mkerror!(ErrorType::InvalidEndpointType)),
+            None => return Err(mkerror!(ErrorType::InvalidEndpointNumber)),
+        };
+        let chunks = split_td(buf as u64, len)?;
+        let interrupter = interrupter_target(self.num_interrupters, endpoint_type);
+        let tr = if let Some(ring) = self.transfer_rings.get_mut(&endpoint_id) {
+            ring
+        } else {
+            return Err(mkerror!(ErrorType::TransferRingNotSet));
+        };
+
+        // a TD must not be cut off in the middle because the ring is full
+        tr.reserve(chunks.len())
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
+
+        let mut trbs = ArrayVec::<(u64, u32), MAX_TRBS_PER_TD>::new();
+        let mut remaining = len;
+        for (i, &(ptr, chunk_len)) in chunks.as_slice().iter().enumerate() {
+            remaining -= chunk_len;
+            let is_last = i == chunks.len() - 1;
+            let td_size = td_size(remaining, max_packet_size);
+
+            let trb_ptr = match frame {
+                Some(frame) if i == 0 => {
+                    let (burst_count, last_burst_packet_count) =
+                        isoch_burst_counts(len, max_packet_size)?;
+                    let trb = IsochTrb::new()
+                        .with_pointer(ptr)
+                        .with_transfer_length(chunk_len)
+                        .with_td_size(td_size)
+                        .with_interrupter_target(interrupter)
+                        .with_chain_bit(!is_last)
+                        .with_interrupt_on_short_packet(endpoint_id.is_in())
+                        .with_interrupt_on_completion(is_last)
+                        .with_transfer_burst_count(burst_count)
+                        .with_transfer_last_burst_packet_count(last_burst_packet_count);
+                    let trb = match frame {
+                        IsochFrame::Asap => trb.with_start_isoch_asap(true),
+                        IsochFrame::At(frame_id) => trb.with_frame_id(frame_id),
+                    };
+                    tr.push(trb)
+                        .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+                        .ptr
+                }
+                _ => {
+                    let trb = NormalTrb::new()
+                        .with_pointer(ptr)
+                        .with_transfer_length(chunk_len)
+                        .with_td_size(td_size)
+                        .with_interrupter_target(interrupter)
+                        .with_chain_bit(!is_last)
+                        .with_interrupt_on_short_packet(endpoint_id.is_in())
+                        .with_interrupt_on_completion(is_last);
+                    tr.push(trb)
+                        .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+                        .ptr
+                }
+            };
+            trbs.push((trb_ptr, chunk_len))
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        }
+
+        let last_ptr = trbs[trbs.len() - 1].0;
+        self.transfer_waiters
+            .insert(
+                last_ptr,
+                PendingTransfer {
+                    endpoint_id,
+                    setup: None,
+                    buf,
+                    trbs,
+                    short_length: None,
+                    waiter: Waiter::Caller(completion),
+                },
+            )
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+
+        self.dbreg.as_mut().ring(endpoint_id.address(), 0);
+        Ok(())
+    }
+
+    /// Look up the bulk or isochronous TD which the event belongs to.
+    /// Returns `None` if the event isn't for such TD, or `Some(None)` if the TD isn't finished yet.
+    fn update_transfer_waiters(
+        &mut self,
+        trb: &TransferEventTrb,
+    ) -> Option<Option<(PendingTransfer, TransferResult)>> {
+        let issuer = trb.issuer_pointer();
+        let success = trb.completion_code().is_success();
+
+        let mut finished = None;
+        for (&last_ptr, pending) in self.transfer_waiters.iter_mut() {
+            let trbs = pending.trbs.as_slice();
+            if let Some(pos) = trbs.iter().position(|&(ptr, _)| ptr == issuer) {
+                let preceding: u32 = trbs[..pos].iter().map(|&(_, len)| len).sum();
+                let length = preceding + trbs[pos].1.saturating_sub(trb.transfer_length());
+                if issuer != last_ptr && success {
+                    // short packet in the middle of the TD.
+                    // xHC still reports the last TRB of the TD later because of IOC
+                    pending.short_length = Some(length);
+                    return Some(None);
+                }
+                finished = Some((last_ptr, pending.short_length.unwrap_or(length)));
+                break;
+            }
+        }
+
+        let (last_ptr, length) = finished?;
+        let pending = self
+            .transfer_waiters
+            .remove(&last_ptr)
+            .expect("Existence is guaranteed here");
+        let result = TransferResult {
+            endpoint_id: pending.endpoint_id,
+            completion_code: trb.completion_code(),
+            setup: pending.setup,
+            buf: pending.buf,
+            length,
+        };
+        Some(Some((pending, result)))
+    }
+
+    fn notify_completion(&mut self, waiter: Waiter, result: TransferResult) -> Result<()> {
+        match waiter {
+            Waiter::Initializer => self.on_control_completed(&result),
+            Waiter::ClearHalt(_) if !result.is_success() => {
+                Err(mkerror!(ErrorType::TransferFailed(result.completion_code)))
+            }
+            Waiter::ClearHalt(endpoint_id) => self.rearm_endpoint(endpoint_id),
+            Waiter::SetInterface if !result.is_success() => {
+                self.reconfiguration = None;
+                Err(mkerror!(ErrorType::TransferFailed(result.completion_code)))
+            }
+            Waiter::SetInterface => self.drop_endpoints(),
+            Waiter::Caller(TransferCompletion::None) => Ok(()),
+            Waiter::Caller(TransferCompletion::Callback(callback)) => {
+                callback(self, &result);
+                Ok(())
+            }
+            Waiter::Caller(TransferCompletion::ClassDriver(driver)) => {
+                driver.on_transfer_completed(self, &result)
+            }
+        }
+    }
+
+    /// Remember the endpoint to be reset. The TD ending with `last_ptr` is skipped
+    fn on_endpoint_halted(
+        &mut self,
+        endpoint_id: EndpointId,
+        last_ptr: u64,
+        code: CompletionCode,
+    ) -> Result<()> {
+        warn!("Endpoint {:?} has been halted: {:?}", endpoint_id, code);
+        let ring = self
+            .transfer_rings
+            .get_mut(&endpoint_id)
+            .ok_or_else(|| mkerror!(ErrorType::TransferRingNotSet))?;
+        let dequeue_pointer = ring.dequeue_pointer_after(last_ptr);
+        ring.on_trb_completed(last_ptr);
+        self.halted_endpoints
+            .insert(
+                endpoint_id,
+                HaltedEndpoint {
+                    code,
+                    dequeue_pointer,
+                    resetting: false,
+                },
+            )
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
         Ok(())
     }
 
+    fn on_interrupt_failed(
+        &mut self,
+        endpoint_id: EndpointId,
+        trb_ptr: u64,
+        code: CompletionCode,
+    ) -> Result<()> {
+        let count = self.error_counts.get(&endpoint_id).copied().unwrap_or(0) + 1;
+        self.error_counts
+            .insert(endpoint_id, count)
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        if count > MAX_CONSECUTIVE_ERRORS {
+            error!("Too many errors on endpoint {:?}. Giving up", endpoint_id);
+            return Err(mkerror!(ErrorType::TransferFailed(code)));
+        }
+
+        if code.halts_endpoint() {
+            // re-armed after the endpoint is recovered
+            self.on_endpoint_halted(endpoint_id, trb_ptr, code)
+        } else {
+            warn!("Interrupt transfer failed: {:?}", code);
+            self.rearm_endpoint(endpoint_id)
+        }
+    }
+
+    /// Queue the transfer again if the class driver is waiting for the endpoint
+    fn rearm_endpoint(&mut self, endpoint_id: EndpointId) -> Result<()> {
+        match self.class_drivers.get(&endpoint_id.number()) {
+            Some(&driver) if driver.endpoint_interrupt_in() == endpoint_id => {
+                self.interrupt_in(endpoint_id, driver.buffer(), driver.in_packet_size() as u32)
+            }
+            Some(&driver) => driver.on_endpoint_recovered(self, endpoint_id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Clear the halt on the device side by CLEAR_FEATURE(ENDPOINT_HALT)
+    fn clear_endpoint_halt(&mut self, endpoint_id: EndpointId) -> Result<()> {
+        let setup_data = SetupData::new()
+            .with_request_type(
+                RequestType::new()
+                    .with_direction(RequestType::DIRECTION_HOST_TO_DEVICE)
+                    .with_type(RequestType::TYPE_STANDARD)
+                    .with_recipient(RequestType::RECIPIENT_ENDPOINT),
+            )
+            .with_request(SetupData::REQUEST_CLEAR_FEATURE)
+            .with_value(SetupData::FEATURE_ENDPOINT_HALT)
+            .with_index(endpoint_id.endpoint_address() as u16)
+            .with_length(0);
+        self.push_control(setup_data, None, Waiter::ClearHalt(endpoint_id))
+    }
+
+    fn endpoint_config(&self, endpoint_id: EndpointId) -> Option<&EndpointConfig> {
+        self.ep_configs
+            .as_slice()
+            .iter()
+            .find(|conf| conf.endpoint_id == endpoint_id)
+    }
+
     fn on_interrupt_completed(&mut self, endpoint_id: EndpointId, len: u32) -> Result<()> {
+        self.error_counts.remove(&endpoint_id);
         if let Some(driver) = self.class_drivers.get(&endpoint_id.number()) {
             if endpoint_id.is_in() {
                 let driver = *driver;
                 driver
                     .on_interrupt_completed(endpoint_id, len)
                     .map_err(|e| mkerror!(ErrorType::ClassDriverError(e)))?;
-                self.interrupt_in(
-                    driver.endpoint_interrupt_in(),
-                    driver.buffer(),
-                    driver.in_packet_size() as u32,
-                )
+                self.rearm_endpoint(endpoint_id)
             } else {
                 Ok(())
             }
@@ -317,109 +840,251 @@ impl UsbDevice {
         }
     }
 
-    fn on_control_completed(
-        &mut self,
-        setup_data: SetupData,
-        buf: *const (),
-        len: u32,
-    ) -> Result<()> {
-        if self.is_initialized {
-            if let Some(waiter) = self.event_waiters.get_mut(&setup_data) {
-                let waiter = *waiter;
-                return self.interrupt_in(
-                    waiter.endpoint_interrupt_in(),
-                    waiter.buffer(),
-                    waiter.in_packet_size() as u32,
+    fn on_control_completed(&mut self, result: &TransferResult) -> Result<()> {
+        let setup_data = result
+            .setup
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        let buf = result.buf;
+        match self.initialize_phase {
+            // strings are optional so the device is still usable without them
+            InitializePhase::LanguageIds if result.is_success() => {
+                let desc = StringDescriptor::new(unsafe {
+                    from_raw_parts(buf as *const u8, result.length as usize)
+                });
+                match desc.and_then(|desc| desc.first_language_id()) {
+                    Some(lang_id) => {
+                        self.lang_id = lang_id;
+                        self.request_string(0)
+                    }
+                    None => self.on_strings_received(),
+                }
+            }
+            InitializePhase::String(i) if result.is_success() => {
+                if let Some(desc) = StringDescriptor::new(unsafe {
+                    from_raw_parts(buf as *const u8, result.length as usize)
+                }) {
+                    self.strings[i] = desc.to_usb_string();
+                }
+                self.request_string(i + 1)
+            }
+            InitializePhase::LanguageIds | InitializePhase::String(_) => {
+                debug!(
+                    "Failed to get a string descriptor: {:?}",
+                    result.completion_code
                 );
+                self.on_strings_received()
             }
-            return Err(mkerror!(ErrorType::NoWaiter));
-        }
-        match self.initialize_phase {
-            1 => {
+            _ if !result.is_success() => {
+                Err(mkerror!(ErrorType::TransferFailed(result.completion_code)))
+            }
+            InitializePhase::DeviceDescriptor => {
                 if setup_data.request() == SetupData::REQUEST_GET_DESCRIPTOR {
-                    if let DescriptorType::Device(_) =
-                        Descriptor::new(buf as *const u8).specialize()
-                    {
-                        return self.initialize_phase1();
+                    let desc = DescriptorType::parse(unsafe {
+                        from_raw_parts(buf as *const u8, result.length as usize)
+                    })
+                    .map_err(|e| mkerror!(ErrorType::DescriptorError(e)))?;
+                    if let DescriptorType::Device(desc) = desc {
+                        return self.on_device_descriptor_received(desc);
                     }
                 }
                 Err(mkerror!(ErrorType::InvalidPhase))
             }
-            2 => {
+            InitializePhase::ConfigurationHeader(index) => {
                 if setup_data.request() == SetupData::REQUEST_GET_DESCRIPTOR {
-                    let desc = Descriptor::new(buf as *const u8);
-                    if let DescriptorType::Configuration(config_desc) = desc.specialize() {
-                        return self.initialize_phase2(desc, config_desc, len);
+                    let desc = DescriptorType::parse(unsafe {
+                        from_raw_parts(buf as *const u8, result.length as usize)
+                    })
+                    .map_err(|e| mkerror!(ErrorType::DescriptorError(e)))?;
+                    if let DescriptorType::Configuration(desc) = desc {
+                        return self.request_whole_configuration(index, desc.total_length());
                     }
                 }
                 Err(mkerror!(ErrorType::InvalidPhase))
             }
-            3 => {
+            InitializePhase::ConfigurationDescriptor(index) => {
+                if setup_data.request() == SetupData::REQUEST_GET_DESCRIPTOR {
+                    let config = Configuration::parse(index, unsafe {
+                        from_raw_parts(buf as *const u8, result.length as usize)
+                    })
+                    .map_err(|e| mkerror!(ErrorType::ConfigurationError(e)))?;
+                    return self.on_configuration_received(config);
+                }
+                Err(mkerror!(ErrorType::InvalidPhase))
+            }
+            InitializePhase::SetConfiguration => {
                 if setup_data.request() == SetupData::REQUEST_SET_CONFIGURATION {
-                    return self.initialize_phase3();
+                    return self.on_configuration_set();
                 }
                 Err(mkerror!(ErrorType::InvalidPhase))
             }
-            _ => Err(mkerror!(ErrorType::NotImplemented)),
+            _ => Err(mkerror!(ErrorType::InvalidPhase)),
+        }
+    }
+
+    fn on_device_descriptor_received(&mut self, desc: DeviceDescriptor) -> Result<()> {
+        self.device_desc = Some(desc);
+        if self.string_indices().iter().all(|&i| i == 0) {
+            return self.on_strings_received();
         }
+        self.initialize_phase = InitializePhase::LanguageIds;
+        self.get_descriptor(StringDescriptor::TYPE, 0, 0, self.data_buf_len as u16)
     }
 
-    fn initialize_phase1(&mut self) -> Result<()> {
-        self.initialize_phase = 2;
+    /// Request the first available string from `strings[from]`
+    fn request_string(&mut self, from: usize) -> Result<()> {
+        let indices = self.string_indices();
+        match (from..indices.len()).find(|&i| indices[i] != 0) {
+            Some(i) => {
+                self.initialize_phase = InitializePhase::String(i);
+                self.get_descriptor(
+                    StringDescriptor::TYPE,
+                    indices[i],
+                    self.lang_id,
+                    self.data_buf_len as u16,
+                )
+            }
+            None => self.on_strings_received(),
+        }
+    }
+
+    fn on_strings_received(&mut self) -> Result<()> {
+        let info = self.info();
+        info!(
+            "USB device attached: slot {}, port {}, {:04x}:{:04x} {:?} {} {}",
+            info.slot_id.value(),
+            info.port_id,
+            info.vendor_id,
+            info.product_id,
+            info.speed,
+            info.manufacturer,
+            info.product
+        );
+        inventory::register(info);
+
+        self.request_configuration(0)
+    }
+
+    /// Fetch the configuration descriptor alone first to know the length of the whole
+    fn request_configuration(&mut self, index: u8) -> Result<()> {
+        self.initialize_phase = InitializePhase::ConfigurationHeader(index);
         self.get_descriptor(
-            EndpointId::DEFAULT_CONTROL_PIPE_ID,
             ConfigurationDescriptor::TYPE,
+            index,
             0,
-            Some(self.data_buf),
-            Self::DATA_BUF_LEN,
+            ConfigurationDescriptor::LENGTH as u16,
         )
     }
 
-    fn initialize_phase2(
-        &mut self,
-        desc: Descriptor,
-        config_desc: ConfigurationDescriptor,
-        len: u32,
-    ) -> Result<()> {
-        let mut iter = desc.iter(len as usize);
-
-        let mut class_driver_found = false;
-        while let Some(DescriptorType::Interface(interface_desc)) = iter.next() {
-            if let Some(class_driver) = ClassDriver::new(&interface_desc) {
-                class_driver_found = true;
-                let mut num_endpoints = 0;
-                while num_endpoints < interface_desc.num_endpoints() {
-                    match iter.next() {
-                        Some(DescriptorType::Endpoint(ep_desc)) => {
-                            num_endpoints += 1;
-                            let conf = EndpointConfig::from(&ep_desc);
-                            self.class_drivers
-                                .insert(conf.endpoint_id.number(), class_driver)
-                                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
-                            self.ep_configs
-                                .push(conf)
-                                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
-                        }
-                        Some(DescriptorType::Hid(_)) => {
-                            // noop
-                        }
-                        _ => {}
-                    }
+    /// Fetch the configuration descriptor followed by the interface and endpoint descriptors
+    fn request_whole_configuration(&mut self, index: u8, total_length: u16) -> Result<()> {
+        self.reserve_data_buf(total_length as usize)?;
+        self.initialize_phase = InitializePhase::ConfigurationDescriptor(index);
+        self.get_descriptor(ConfigurationDescriptor::TYPE, index, 0, total_length)
+    }
+
+    /// Make the data buffer hold `len` bytes at least
+    fn reserve_data_buf(&mut self, len: usize) -> Result<()> {
+        if len <= self.data_buf_len {
+            return Ok(());
+        }
+        let buf = allocate::<()>(len, None, Some(TRB_BUFFER_BOUNDARY as usize))
+            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+        free(self.data_buf);
+        self.data_buf = buf;
+        self.data_buf_len = len;
+        Ok(())
+    }
+
+    fn string_indices(&self) -> [u8; 3] {
+        self.device_desc
+            .map(|desc| {
+                [
+                    desc.manufacturer_index(),
+                    desc.product_index(),
+                    desc.serial_number_index(),
+                ]
+            })
+            .unwrap_or([0; 3])
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let desc = self.device_desc;
+        DeviceInfo {
+            slot_id: self.slot_id,
+            port_id: self.port_id,
+            speed: self.device_context.slot_context().speed().ok(),
+            vendor_id: desc.map(|d| d.vendor_id()).unwrap_or(0),
+            product_id: desc.map(|d| d.product_id()).unwrap_or(0),
+            class: desc.map(|d| d.device_class()).unwrap_or(0),
+            manufacturer: self.strings[0],
+            product: self.strings[1],
+            serial_number: self.strings[2],
+        }
+    }
+
+    /// Set the configuration if the selector accepts it, or request the next one
+    fn on_configuration_received(&mut self, config: Configuration) -> Result<()> {
+        let accepted = self.device_desc.map_or(false, |desc| {
+            configuration::configuration_selector()(&desc, &config)
+        });
+        if !accepted {
+            let next = config.index() + 1;
+            if next < self.device_desc.map_or(0, |desc| desc.num_configurations()) {
+                return self.request_configuration(next);
+            }
+            debug!(
+                "No configuration of slot {} is usable",
+                self.slot_id.value()
+            );
+            return Ok(());
+        }
+
+        let mut class_driver: Option<ClassDriver> = None;
+        for interface in config.interfaces() {
+            let alt = match config.alt_setting(interface, 0) {
+                Some(alt) => alt,
+                None => continue,
+            };
+            let claimed = match class_driver.as_mut() {
+                // only a single driver is supported for a device
+                Some(driver) => driver.claim_interface(alt.descriptor()),
+                None => {
+                    class_driver = ClassDriver::new(alt.descriptor());
+                    class_driver.is_some()
                 }
-                break;
+            };
+            if !claimed {
+                continue;
+            }
+            let driver = class_driver.unwrap();
+            self.interfaces
+                .insert(
+                    interface,
+                    ClaimedInterface {
+                        driver,
+                        alternate: 0,
+                    },
+                )
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+            for &conf in config.endpoints(alt) {
+                self.class_drivers
+                    .insert(conf.endpoint_id.number(), driver)
+                    .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+                self.ep_configs
+                    .push(conf)
+                    .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
             }
         }
-        if !class_driver_found {
+        if class_driver.is_none() {
             return Ok(());
         }
-        self.initialize_phase = 3;
-        self.set_configuration(
-            EndpointId::DEFAULT_CONTROL_PIPE_ID,
-            config_desc.configuration_value(),
-        )
+        let config_value = config.configuration_value();
+        self.configuration = Some(config);
+        self.initialize_phase = InitializePhase::SetConfiguration;
+        self.set_configuration(config_value)
     }
 
-    fn initialize_phase3(&mut self) -> Result<()> {
+    fn on_configuration_set(&mut self) -> Result<()> {
         for i in 0..self.ep_configs.len() {
             let config = self.ep_configs[i];
             let driver = self
@@ -428,18 +1093,18 @@ impl UsbDevice {
                 .unwrap();
             driver.set_endpoint(&config);
         }
-        self.initialize_phase = 4;
+        self.initialize_phase = InitializePhase::Completed;
         self.is_initialized = true;
         Ok(())
     }
 
+    /// `lang_id` is used only for string descriptors. `length` must fit in the data buffer
     fn get_descriptor(
         &mut self,
-        endpoint_id: EndpointId,
         desc_type: u8,
         desc_index: u8,
-        buf: Option<*const ()>,
-        len: u32,
+        lang_id: u16,
+        length: u16,
     ) -> Result<()> {
         let setup_data = SetupData::new()
             .with_request_type(
@@ -450,12 +1115,12 @@ impl UsbDevice {
             )
             .with_request(SetupData::REQUEST_GET_DESCRIPTOR)
             .with_value(((desc_type as u16) << 8) | (desc_index as u16))
-            .with_index(0)
-            .with_length(len as u16);
-        self.control_in(endpoint_id, setup_data, buf, len, None)
+            .with_index(lang_id)
+            .with_length(length);
+        self.push_control(setup_data, Some(self.data_buf), Waiter::Initializer)
     }
 
-    fn set_configuration(&mut self, endpoint_id: EndpointId, config_value: u8) -> Result<()> {
+    fn set_configuration(&mut self, config_value: u8) -> Result<()> {
         let setup_data = SetupData::new()
             .with_request_type(
                 RequestType::new()
@@ -467,25 +1132,24 @@ impl UsbDevice {
             .with_value(config_value as u16)
             .with_index(0)
             .with_length(0);
-        self.control_out(endpoint_id, setup_data, None, 0, None)
+        self.push_control(setup_data, None, Waiter::Initializer)
     }
 
-    fn control_in(
+    fn push_control(
         &mut self,
-        endpoint_id: EndpointId,
         setup_data: SetupData,
-        buf: Option<*const ()>,
-        len: u32,
-        issuer: Option<ClassDriver>,
+        buf: Option<*mut ()>,
+        waiter: Waiter,
     ) -> Result<()> {
-        if let Some(driver) = issuer {
-            self.event_waiters
-                .insert(setup_data, driver)
-                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
-        }
-
-        if EndpointNumber::MAX_ENDPOINT < endpoint_id.number() {
-            return Err(mkerror!(ErrorType::InvalidEndpointNumber));
+        let endpoint_id = EndpointId::DEFAULT_CONTROL_PIPE_ID;
+        let len = setup_data.length() as u32;
+        let buf = buf.filter(|_| len > 0);
+        let direction_in = setup_data.is_device_to_host();
+        if let Some(buf) = buf {
+            // the data stage is a single TRB
+            if split_td(buf as u64, len)?.len() > 1 {
+                return Err(mkerror!(ErrorType::InvalidTransferLength));
+            }
         }
 
         let tr = if let Some(ring) = self.transfer_rings.get_mut(&endpoint_id) {
@@ -494,124 +1158,301 @@ impl UsbDevice {
             return Err(mkerror!(ErrorType::TransferRingNotSet));
         };
 
-        match buf {
-            Some(buf) => {
-                let setup_stage = tr
-                    .push(setup_data.trb(SetupStageTrb::TRANSFER_TYPE_IN_DATA_STAGE))
-                    .trb;
-                let data = DataStageTrb::new()
-                    .with_direction_in(true)
-                    .with_data_buffer_pointer(buf as u64)
-                    .with_trb_transfer_length(len)
-                    .with_interrupt_on_completion(true);
-
-                let data_stage_ptr = tr.push(data).ptr;
-                tr.push(StatusStageTrb::new());
-
-                self.setup_stage_map
-                    .insert(data_stage_ptr, setup_stage)
-                    .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
-            }
-            None => {
-                let setup_stage = tr
-                    .push(setup_data.trb(SetupStageTrb::TRANSFER_TYPE_NO_DATA_STAGE))
-                    .trb;
-                let status_trb_ptr = tr
-                    .push(
-                        StatusStageTrb::new()
-                            .with_direction_in(true)
-                            .with_interrupt_on_completion(true),
-                    )
-                    .ptr;
-                self.setup_stage_map
-                    .insert(status_trb_ptr, setup_stage)
-                    .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
-            }
+        tr.reserve(if buf.is_some() { 3 } else { 2 })
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
+
+        let mut trbs = ArrayVec::<(u64, u32), MAX_TRBS_PER_TD>::new();
+        let transfer_type = match buf {
+            None => SetupStageTrb::TRANSFER_TYPE_NO_DATA_STAGE,
+            Some(_) if direction_in => SetupStageTrb::TRANSFER_TYPE_IN_DATA_STAGE,
+            Some(_) => SetupStageTrb::TRANSFER_TYPE_OUT_DATA_STAGE,
+        };
+        let setup_ptr = tr
+            .push(setup_data.trb(transfer_type))
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+            .ptr;
+        trbs.push((setup_ptr, 0))
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+
+        if let Some(buf) = buf {
+            let data = DataStageTrb::new()
+                .with_direction_in(direction_in)
+                .with_data_buffer_pointer(buf as u64)
+                .with_trb_transfer_length(len)
+                .with_interrupt_on_short_packet(direction_in);
+            let data_ptr = tr
+                .push(data)
+                .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+                .ptr;
+            trbs.push((data_ptr, len))
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
         }
 
+        // the status stage is in the opposite direction of the data stage, or IN if no data stage
+        let status = StatusStageTrb::new()
+            .with_direction_in(buf.is_none() || !direction_in)
+            .with_interrupt_on_completion(true);
+        let status_ptr = tr
+            .push(status)
+            .map_err(|e| mkerror!(ErrorType::TrbError(e)))?
+            .ptr;
+        trbs.push((status_ptr, 0))
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+
+        self.transfer_waiters
+            .insert(
+                status_ptr,
+                PendingTransfer {
+                    endpoint_id,
+                    setup: Some(setup_data),
+                    buf: buf.unwrap_or(null_mut()),
+                    trbs,
+                    short_length: None,
+                    waiter,
+                },
+            )
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+
         self.dbreg.as_mut().ring(endpoint_id.address(), 0);
         Ok(())
     }
 
-    fn control_out(
-        &mut self,
-        endpoint_id: EndpointId,
-        setup_data: SetupData,
-        buf: Option<*const ()>,
-        len: u32,
-        issuer: Option<ClassDriver>,
-    ) -> Result<()> {
-        if let Some(driver) = issuer {
-            self.event_waiters
-                .insert(setup_data, driver)
-                .map_err(ErrorType::CollectionError)
-                .map_err(|e| mkerror!(e))?;
+    /// Let the class drivers send the data queued since the last call
+    pub fn flush_class_drivers(&mut self) -> Result<()> {
+        for &driver in self.bound_drivers().as_slice() {
+            driver.flush(self)?;
         }
+        Ok(())
+    }
 
-        if EndpointNumber::MAX_ENDPOINT < endpoint_id.number() {
-            return Err(mkerror!(ErrorType::InvalidEndpointNumber));
+    /// Each class driver bound to the interfaces
+    fn bound_drivers(&mut self) -> ArrayVec<ClassDriver, MAX_INTERFACES> {
+        let mut drivers = ArrayVec::<ClassDriver, MAX_INTERFACES>::new();
+        for (_, interface) in self.interfaces.iter_mut() {
+            if !drivers.as_slice().contains(&interface.driver) {
+                // capacity is same as interfaces so never fails
+                let _ = drivers.push(interface.driver);
+            }
         }
+        drivers
+    }
 
-        let tr = if let Some(ring) = self.transfer_rings.get_mut(&endpoint_id) {
-            ring
-        } else {
-            return Err(mkerror!(ErrorType::TransferRingNotSet));
-        };
+    /// Set up the input context to update the device context from its current state
+    fn prepare_input_context(&mut self) {
+        *self.input_context.input_control_context() = InputControlContext::default();
+        *self.input_context.slot_context() = *self.device_context.slot_context();
+        self.input_context.enable_slot_context();
+    }
 
-        match buf {
-            Some(buf) => {
-                let setup_stage = tr
-                    .push(setup_data.trb(SetupStageTrb::TRANSFER_TYPE_OUT_DATA_STAGE))
-                    .trb;
-                let data = DataStageTrb::new()
-                    .with_data_buffer_pointer(buf as u64)
-                    .with_trb_transfer_length(len)
-                    .with_direction_in(true)
-                    .with_interrupt_on_completion(true);
-
-                let data_stage_ptr = tr.push(data).ptr;
-                tr.push(StatusStageTrb::new());
-
-                self.setup_stage_map
-                    .insert(data_stage_ptr, setup_stage)
-                    .map_err(ErrorType::CollectionError)
-                    .map_err(|e| mkerror!(e))?;
-            }
-            None => {
-                let setup_stage = tr
-                    .push(setup_data.trb(SetupStageTrb::TRANSFER_TYPE_NO_DATA_STAGE))
-                    .trb;
-                let status_trb_ptr = tr
-                    .push(StatusStageTrb::new().with_interrupt_on_completion(true))
-                    .ptr;
-                self.setup_stage_map
-                    .insert(status_trb_ptr, setup_stage)
-                    .map_err(ErrorType::CollectionError)
-                    .map_err(|e| mkerror!(e))?;
+    /// Allocate the transfer ring and set up the context of the endpoint to be added
+    fn enable_endpoint(&mut self, ep_config: EndpointConfig, port_speed: PortSpeed) -> Result<()> {
+        let tr_ptr = self
+            .alloc_transfer_ring(ep_config.endpoint_id, 32)?
+            .buffer_pointer();
+
+        let ep_ctx = self.input_context.enable_endpoint(ep_config.endpoint_id);
+        match ep_config.endpoint_type {
+            EndpointType::Control => ep_ctx.set_endpoint_type(4),
+            EndpointType::Isochronous => {
+                ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 5 } else { 1 })
+            }
+            EndpointType::Bulk => {
+                ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 6 } else { 2 })
+            }
+            EndpointType::Interrupt => {
+                ep_ctx.set_endpoint_type(if ep_config.endpoint_id.is_in() { 7 } else { 3 })
             }
         }
-        self.dbreg.as_mut().ring(endpoint_id.address(), 0);
+
+        ep_ctx.set_max_packet_size(ep_config.max_packet_size as u16);
+        match ep_config.endpoint_type {
+            EndpointType::Interrupt | EndpointType::Isochronous => {
+                ep_ctx.set_interval(
+                    port_speed.convert_interval(ep_config.endpoint_type, ep_config.interval) as u8,
+                );
+                ep_ctx.set_max_esit_payload_lo(ep_config.max_packet_size as u16);
+            }
+            // bInterval of bulk and control endpoints doesn't mean a polling interval
+            EndpointType::Bulk | EndpointType::Control => ep_ctx.set_interval(0),
+        }
+        ep_ctx.set_average_trb_length(match ep_config.endpoint_type {
+            EndpointType::Bulk | EndpointType::Isochronous => 3072,
+            _ => 1,
+        });
+        ep_ctx.set_transfer_ring_buffer(tr_ptr);
+        ep_ctx.set_dequeue_cycle_state(true);
+        ep_ctx.set_max_primary_streams(0);
+        ep_ctx.set_mult(0);
+        // isochronous endpoints never retry
+        ep_ctx.set_error_count(if ep_config.endpoint_type == EndpointType::Isochronous {
+            0
+        } else {
+            3
+        });
         Ok(())
     }
 
-    fn interrupt_in(&mut self, endpoint_id: EndpointId, buf: *const (), len: u32) -> Result<()> {
-        let tr = if let Some(ring) = self.transfer_rings.get_mut(&endpoint_id) {
-            ring
-        } else {
-            return Err(mkerror!(ErrorType::TransferRingNotSet));
-        };
+    /// Endpoints of the alternate setting in the current configuration
+    fn endpoints_of(
+        &self,
+        interface: u8,
+        alternate: u8,
+    ) -> Result<ArrayVec<EndpointConfig, { EndpointNumber::MAX as usize }>> {
+        let config = self
+            .configuration
+            .as_ref()
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        let alt = config
+            .alt_setting(interface, alternate)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidInterface))?;
+        let mut endpoints = ArrayVec::new();
+        for &conf in config.endpoints(alt) {
+            endpoints
+                .push(conf)
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        }
+        Ok(endpoints)
+    }
 
-        let normal_trb = NormalTrb::new()
-            .with_pointer(buf as u64)
-            .with_transfer_length(len)
-            .with_interrupt_on_short_packet(true)
-            .with_interrupt_on_completion(true);
+    /// The interface being switched and its current setting
+    fn reconfiguring_interface(&self) -> Result<(Reconfiguration, ClaimedInterface)> {
+        let reconfiguration = self
+            .reconfiguration
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        let claimed = *self
+            .interfaces
+            .get(&reconfiguration.interface)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidInterface))?;
+        Ok((reconfiguration, claimed))
+    }
 
-        tr.push(normal_trb);
-        self.dbreg.as_mut().ring(endpoint_id.address(), 0);
+    fn request_reconfiguration(&mut self, step: ReconfigurationStep) -> Result<()> {
+        let reconfiguration = self
+            .reconfiguration
+            .as_mut()
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPhase))?;
+        reconfiguration.step = step;
+        reconfiguration.command_requested = true;
         Ok(())
     }
 
+    /// Let xHC drop the endpoints of the current alternate setting
+    fn drop_endpoints(&mut self) -> Result<()> {
+        let (reconfiguration, claimed) = self.reconfiguring_interface()?;
+        let endpoints = self.endpoints_of(reconfiguration.interface, claimed.alternate)?;
+        if endpoints.len() == 0 {
+            return self.add_endpoints();
+        }
+        self.prepare_input_context();
+        for conf in endpoints.as_slice() {
+            self.input_context.disable_endpoint(conf.endpoint_id);
+        }
+        self.request_reconfiguration(ReconfigurationStep::DropEndpoints)
+    }
+
+    /// Release the dropped endpoints and let xHC add the ones of the new alternate setting
+    fn add_endpoints(&mut self) -> Result<()> {
+        let (reconfiguration, claimed) = self.reconfiguring_interface()?;
+        for conf in self
+            .endpoints_of(reconfiguration.interface, claimed.alternate)?
+            .as_slice()
+        {
+            self.release_endpoint(conf.endpoint_id);
+        }
+
+        let endpoints = self.endpoints_of(reconfiguration.interface, reconfiguration.alternate)?;
+        if endpoints.len() == 0 {
+            return self.on_interface_set();
+        }
+        let port_speed = self
+            .device_context
+            .slot_context()
+            .speed()
+            .map_err(|_| mkerror!(ErrorType::UnknownXHCISpeedID))?;
+        self.prepare_input_context();
+        for &conf in endpoints.as_slice() {
+            self.class_drivers
+                .insert(conf.endpoint_id.number(), claimed.driver)
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+            self.ep_configs
+                .push(conf)
+                .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+            self.enable_endpoint(conf, port_speed)?;
+        }
+        self.request_reconfiguration(ReconfigurationStep::AddEndpoints)
+    }
+
+    /// Forget the endpoint which xHC has dropped
+    fn release_endpoint(&mut self, endpoint_id: EndpointId) {
+        self.ep_configs
+            .retain(|conf| conf.endpoint_id != endpoint_id);
+        let number = endpoint_id.number();
+        if !self
+            .ep_configs
+            .as_slice()
+            .iter()
+            .any(|conf| conf.endpoint_id.number() == number)
+        {
+            self.class_drivers.remove(&number);
+        }
+        if let Some(mut ring) = self.transfer_rings.remove(&endpoint_id) {
+            ring.free();
+        }
+        self.halted_endpoints.remove(&endpoint_id);
+        self.error_counts.remove(&endpoint_id);
+
+        // the TDs on the endpoint never complete
+        let mut stale = ArrayVec::<u64, 8>::new();
+        for (&last_ptr, pending) in self.transfer_waiters.iter_mut() {
+            if pending.endpoint_id == endpoint_id {
+                // capacity is same as transfer_waiters so never fails
+                let _ = stale.push(last_ptr);
+            }
+        }
+        for last_ptr in stale.as_slice() {
+            self.transfer_waiters.remove(last_ptr);
+        }
+    }
+
+    /// The new alternate setting is ready. The driver restarts its transfers on the endpoints
+    fn on_interface_set(&mut self) -> Result<()> {
+        let (reconfiguration, _) = self.reconfiguring_interface()?;
+        self.reconfiguration = None;
+        let interface = self
+            .interfaces
+            .get_mut(&reconfiguration.interface)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidInterface))?;
+        interface.alternate = reconfiguration.alternate;
+        let mut driver = interface.driver;
+        debug!(
+            "Interface {} of slot {} has been switched to alternate setting {}",
+            reconfiguration.interface,
+            self.slot_id.value(),
+            reconfiguration.alternate
+        );
+
+        let endpoints = self.endpoints_of(reconfiguration.interface, reconfiguration.alternate)?;
+        for conf in endpoints.as_slice() {
+            driver.set_endpoint(conf);
+        }
+        for conf in endpoints.as_slice() {
+            self.rearm_endpoint(conf.endpoint_id)?;
+        }
+        Ok(())
+    }
+
+    fn free(&mut self) {
+        for driver in self.bound_drivers().as_slice() {
+            driver.free();
+        }
+        for (_, ring) in self.transfer_rings.iter_mut() {
+            ring.free();
+        }
+        self.device_context.free();
+        self.input_context.free();
+        free(self.data_buf);
+    }
+
     fn alloc_transfer_ring(
         &mut self,
         endpoint_id: EndpointId,
@@ -621,9 +1462,13 @@ impl UsbDevice {
         ring.initialize(buf_size)
             .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
 
-        self.transfer_rings
+        if let Some(mut prev) = self
+            .transfer_rings
             .insert(endpoint_id, ring)
-            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?
+        {
+            prev.free();
+        }
         Ok(self
             .transfer_rings
             .get_mut(&endpoint_id)
@@ -631,24 +1476,246 @@ impl UsbDevice {
     }
 }
 
+/// Result of a transfer which is passed to [`TransferCompletion`]
+#[derive(Debug, Copy, Clone)]
+pub struct TransferResult {
+    pub endpoint_id: EndpointId,
+    pub completion_code: CompletionCode,
+    /// The request if the transfer is a control transfer
+    pub setup: Option<SetupData>,
+    pub buf: *const (),
+    /// Actual number of bytes transferred
+    pub length: u32,
+}
+
+impl TransferResult {
+    pub fn is_success(&self) -> bool {
+        self.completion_code.is_success()
+    }
+}
+
+pub type TransferCallback = fn(&mut UsbDevice, &TransferResult);
+
+/// Specifies who will be notified when a transfer finishes
+#[derive(Copy, Clone)]
+pub enum TransferCompletion {
+    None,
+    Callback(TransferCallback),
+    ClassDriver(ClassDriver),
+}
+
+impl Debug for TransferCompletion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransferCompletion::None => f.write_str("None"),
+            TransferCompletion::Callback(callback) => {
+                f.write_fmt(format_args!("Callback({:p})", *callback as *const ()))
+            }
+            TransferCompletion::ClassDriver(driver) => {
+                f.write_fmt(format_args!("ClassDriver({:?})", driver))
+            }
+        }
+    }
+}
+
+/// The frame which an isochronous TD is scheduled at
+#[derive(Debug, Copy, Clone)]
+pub enum IsochFrame {
+    /// Let xHC start the TD as soon as possible
+    Asap,
+    /// Frame ID to start the TD. See [`crate::usb::Xhc::current_frame_index`]
+    At(u16),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum InitializePhase {
+    NotStarted,
+    DeviceDescriptor,
+    LanguageIds,
+    /// Index of [`UsbDevice::strings`]
+    String(usize),
+    /// Index of the configuration, whose descriptor alone is being fetched
+    ConfigurationHeader(u8),
+    /// Index of the configuration
+    ConfigurationDescriptor(u8),
+    SetConfiguration,
+    Completed,
+}
+
+/// Who is notified when a transfer finishes
+#[derive(Debug, Copy, Clone)]
+enum Waiter {
+    /// The device itself which is fetching descriptors and setting the configuration
+    Initializer,
+    Caller(TransferCompletion),
+    /// The endpoint is re-armed once the halt is cleared
+    ClearHalt(EndpointId),
+    /// The endpoints are switched once the interface accepts the alternate setting
+    SetInterface,
+}
+
+/// Interface bound to a class driver
+#[derive(Debug, Copy, Clone)]
+struct ClaimedInterface {
+    driver: ClassDriver,
+    /// Current alternate setting
+    alternate: u8,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ReconfigurationStep {
+    SetInterface,
+    DropEndpoints,
+    AddEndpoints,
+}
+
+/// Switching an interface to another alternate setting
+#[derive(Debug, Copy, Clone)]
+struct Reconfiguration {
+    interface: u8,
+    alternate: u8,
+    step: ReconfigurationStep,
+    /// Whether the input context is ready for Configure Endpoint Command
+    command_requested: bool,
+}
+
+#[derive(Debug)]
+struct HaltedEndpoint {
+    code: CompletionCode,
+    dequeue_pointer: (u64, bool),
+    /// Whether Reset Endpoint Command has been issued
+    resetting: bool,
+}
+
+#[derive(Debug)]
+struct PendingTransfer {
+    endpoint_id: EndpointId,
+    setup: Option<SetupData>,
+    buf: *const (),
+    /// Pointers to TRBs composing the TD and their transfer length
+    trbs: ArrayVec<(u64, u32), MAX_TRBS_PER_TD>,
+    short_length: Option<u32>,
+    waiter: Waiter,
+}
+
+/// Interrupter which receives the events of transfers on the endpoint type.
+/// Interrupt endpoints get their own interrupter so that bulk and isochronous transfers
+/// of high-rate devices don't delay them. Control transfers share the primary interrupter.
+fn interrupter_target(num_interrupters: usize, endpoint_type: EndpointType) -> u16 {
+    let last = num_interrupters.saturating_sub(1) as u16;
+    match endpoint_type {
+        EndpointType::Control => 0,
+        EndpointType::Interrupt => last.min(1),
+        EndpointType::Bulk | EndpointType::Isochronous => last.min(2),
+    }
+}
+
+/// Split the buffer into chunks which don't cross [`TRB_BUFFER_BOUNDARY`]
+fn split_td(ptr: u64, len: u32) -> Result<ArrayVec<(u64, u32), MAX_TRBS_PER_TD>> {
+    let mut chunks = ArrayVec::new();
+    let mut offset = 0u32;
+    loop {
+        let chunk_ptr = ptr + offset as u64;
+        let chunk_len = ((len - offset) as u64)
+            .min(TRB_BUFFER_BOUNDARY - chunk_ptr % TRB_BUFFER_BOUNDARY)
+            as u32;
+        chunks
+            .push((chunk_ptr, chunk_len))
+            .map_err(|_| mkerror!(ErrorType::InvalidTransferLength))?;
+        offset += chunk_len;
+        if offset == len {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// Number of packets remaining after the TRB, which is to be set to the TD Size field
+fn td_size(remaining: u32, max_packet_size: u32) -> u8 {
+    ((remaining + max_packet_size - 1) / max_packet_size).min(31) as u8
+}
+
+/// Calculate TBC and TLBPC of Isoch TRB.
+/// Endpoints are configured with Mult and Max Burst Size of 0, so a TD is a single packet.
+fn isoch_burst_counts(len: u32, max_packet_size: u32) -> Result<(u8, u8)> {
+    let packet_count = ((len + max_packet_size - 1) / max_packet_size).max(1);
+    if packet_count > 1 {
+        return Err(mkerror!(ErrorType::InvalidTransferLength));
+    }
+    Ok(((packet_count - 1) as u8, 0))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::usb::context::InputContext;
-    use crate::usb::mem::{allocate_array, free_all};
+    use crate::usb::context::ContextSize;
+    use crate::usb::devmgr::{
+        interrupter_target, isoch_burst_counts, split_td, td_size, DeviceManager,
+    };
+    use crate::usb::endpoint::EndpointType;
+    use crate::usb::mem::exclusive_pool;
 
     #[test]
-    fn enable_slot_context() {
-        free_all();
-
-        let input_context_ptr = allocate_array::<InputContext>(1, Some(64), Some(4096)).unwrap();
-        let input_context = unsafe { input_context_ptr.as_mut().unwrap() };
-        input_context.slot_context.set_root_hub_port_num(42);
-
+    fn split_td_at_64k_boundary() {
+        let chunks = split_td(0x1_0000 - 0x100, 0x2_0000).unwrap();
         assert_eq!(
-            unsafe { input_context_ptr.read() }
-                .slot_context
-                .root_hub_port_num(),
-            42
+            chunks.as_slice(),
+            &[
+                (0x1_0000 - 0x100, 0x100),
+                (0x1_0000, 0x1_0000),
+                (0x2_0000, 0x1_0000 - 0x100)
+            ]
         );
+
+        let chunks = split_td(0x1000, 0).unwrap();
+        assert_eq!(chunks.as_slice(), &[(0x1000, 0)]);
+
+        assert!(split_td(0, 0x10_0000).is_ok());
+        assert!(split_td(0, 0x10_0001).is_err());
+    }
+
+    #[test]
+    fn td_size_of_remaining_packets() {
+        assert_eq!(td_size(0, 512), 0);
+        assert_eq!(td_size(1, 512), 1);
+        assert_eq!(td_size(1024, 512), 2);
+        assert_eq!(td_size(1025, 512), 3);
+        assert_eq!(td_size(0x10_0000, 512), 31);
+    }
+
+    #[test]
+    fn interrupter_target_by_endpoint_type() {
+        assert_eq!(interrupter_target(1, EndpointType::Interrupt), 0);
+        assert_eq!(interrupter_target(1, EndpointType::Bulk), 0);
+        assert_eq!(interrupter_target(2, EndpointType::Interrupt), 1);
+        assert_eq!(interrupter_target(2, EndpointType::Isochronous), 1);
+        assert_eq!(interrupter_target(3, EndpointType::Control), 0);
+        assert_eq!(interrupter_target(3, EndpointType::Interrupt), 1);
+        assert_eq!(interrupter_target(3, EndpointType::Bulk), 2);
+    }
+
+    #[test]
+    fn isoch_burst_count() {
+        assert_eq!(isoch_burst_counts(0, 1024).unwrap(), (0, 0));
+        assert_eq!(isoch_burst_counts(1024, 1024).unwrap(), (0, 0));
+        assert!(isoch_burst_counts(1025, 1024).is_err());
+        assert!(isoch_burst_counts(3000, 1024).is_err());
+    }
+
+    #[test]
+    fn scratchpad_buffers_in_dcbaa() {
+        let _pool = exclusive_pool();
+
+        let mut manager = DeviceManager::new();
+        manager.initialize(ContextSize::Bytes64).unwrap();
+        manager.initialize_scratchpad_buffers(3, 4096).unwrap();
+
+        let dcbaa = manager.dcbaa_ptr() as *const u64;
+        let array = unsafe { dcbaa.read() } as *const u64;
+        assert_eq!(array as u64 % 64, 0);
+        for i in 0..3 {
+            let buf = unsafe { array.add(i).read() };
+            assert_ne!(buf, 0);
+            assert_eq!(buf % 4096, 0);
+        }
+        assert_eq!(unsafe { dcbaa.add(1).read() }, 0);
     }
 }
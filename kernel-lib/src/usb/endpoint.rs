@@ -36,6 +36,11 @@ impl EndpointId {
     pub fn address(&self) -> u8 {
         self.0
     }
+
+    /// bEndpointAddress form, which is used as wIndex of requests to the endpoint
+    pub fn endpoint_address(&self) -> u8 {
+        (self.0 >> 1) | ((self.is_in() as u8) << 7)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
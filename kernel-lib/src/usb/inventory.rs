@@ -0,0 +1,87 @@
+use crate::usb::descriptor::UsbString;
+use crate::usb::port::PortSpeed;
+use crate::usb::SlotId;
+
+const CAPACITY: usize = 16;
+
+static mut INVENTORY: [Option<DeviceInfo>; CAPACITY] = [None; CAPACITY];
+
+/// Summary of an attached device
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfo {
+    pub slot_id: SlotId,
+    pub port_id: u8,
+    pub speed: Option<PortSpeed>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub class: u8,
+    pub manufacturer: UsbString,
+    pub product: UsbString,
+    pub serial_number: UsbString,
+}
+
+/// Devices which have been enumerated and not detached yet
+pub fn inventory() -> impl Iterator<Item = &'static DeviceInfo> {
+    unsafe { INVENTORY.iter().flatten() }
+}
+
+pub fn register(info: DeviceInfo) {
+    let inventory = unsafe { &mut INVENTORY };
+    let pos = inventory
+        .iter()
+        .position(|entry| matches!(entry, Some(e) if e.slot_id == info.slot_id))
+        .or_else(|| inventory.iter().position(Option::is_none));
+    match pos {
+        Some(pos) => inventory[pos] = Some(info),
+        None => warn!("Too many devices to keep track of: {:?}", info),
+    }
+}
+
+pub fn unregister(slot_id: SlotId) {
+    for entry in unsafe { INVENTORY.iter_mut() } {
+        if matches!(entry, Some(e) if e.slot_id == slot_id) {
+            *entry = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::descriptor::UsbString;
+    use crate::usb::inventory::{inventory, register, unregister, DeviceInfo};
+    use crate::usb::SlotId;
+
+    fn info(slot_id: u8, product: &str) -> DeviceInfo {
+        DeviceInfo {
+            slot_id: SlotId::new(slot_id),
+            port_id: slot_id,
+            speed: None,
+            vendor_id: 0x0627,
+            product_id: 0x0001,
+            class: 0,
+            manufacturer: UsbString::empty(),
+            product: UsbString::from_utf16(product.encode_utf16()),
+            serial_number: UsbString::empty(),
+        }
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        register(info(1, "mouse"));
+        register(info(2, "keyboard"));
+        register(info(1, "tablet"));
+        assert_eq!(
+            inventory()
+                .map(|i| (i.slot_id.value(), i.product.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "tablet"), (2, "keyboard")]
+        );
+
+        unregister(SlotId::new(1));
+        unregister(SlotId::new(3));
+        assert_eq!(
+            inventory().map(|i| i.slot_id.value()).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+}
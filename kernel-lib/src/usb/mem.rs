@@ -1,63 +1,52 @@
 use crate::error::ErrorContext;
 use core::mem::size_of;
+use core::ptr::null_mut;
 
 const MEMORY_POOL_BYTES: usize = 4096 * 32;
-const MEMORY_POOL_ALIGNMENT_COUNT: usize = MEMORY_POOL_BYTES / 64;
+const PAGE_SIZE: usize = 4096;
+/// The smallest block. Every block is aligned to this size at least
+const MIN_BLOCK_BYTES: usize = 64;
+/// Blocks are sized from 64B (order 0) to 2MiB (order 15)
+const NUM_ORDERS: usize = 16;
+const MAX_REGIONS: usize = 8;
 
-static mut MEMORY_POOL: [Alignment; MEMORY_POOL_ALIGNMENT_COUNT] =
-    [Alignment([0; 64]); MEMORY_POOL_ALIGNMENT_COUNT];
-static mut OFFSET: usize = 0;
+static mut MEMORY_POOL: MemoryPool = MemoryPool([0; MEMORY_POOL_BYTES]);
+static mut MEMORY_POOL_ORDER_MAP: [u8; MEMORY_POOL_BYTES / MIN_BLOCK_BYTES] =
+    [0; MEMORY_POOL_BYTES / MIN_BLOCK_BYTES];
+static mut ALLOCATOR: BuddyAllocator = BuddyAllocator::new();
 
-#[derive(Copy, Clone)]
-#[repr(C, align(64))]
-struct Alignment([u8; 64]);
+#[repr(C, align(4096))]
+struct MemoryPool([u8; MEMORY_POOL_BYTES]);
 
 #[derive(Debug)]
 pub enum ErrorType {
     OutOfMemory,
+    TooLarge,
+    InvalidRegion,
+    TooManyRegions,
 }
 
 pub type Error = ErrorContext<ErrorType>;
 pub type Result<T> = core::result::Result<T, Error>;
 
-#[cfg(test)]
-pub fn current_offset() -> usize {
-    unsafe { OFFSET }
-}
-
-#[cfg(test)]
-pub fn free_all() {
-    unsafe {
-        OFFSET = 0;
-    }
+/// Usage statistics of the memory available for xHC
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemoryStats {
+    pub total_bytes: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+    pub num_regions: usize,
 }
 
+/// Allocate memory which satisfies the constraints xHC imposes on its data structures.
+/// The memory is zero-filled.
 pub fn allocate<T>(
     bytes: usize,
     alignment: Option<usize>,
     boundary: Option<usize>,
 ) -> Result<*mut T> {
-    let mut offset = unsafe { OFFSET };
-    if let Some(alignment) = alignment {
-        offset = ceil(offset, alignment);
-    }
-    if let Some(boundary) = boundary {
-        let next_boundary = ceil(offset, boundary);
-        if offset + bytes > next_boundary {
-            offset = next_boundary;
-        }
-    }
-
-    if offset + bytes <= MEMORY_POOL_BYTES {
-        unsafe {
-            OFFSET = offset + bytes;
-            let base_ptr: *mut [Alignment; MEMORY_POOL_ALIGNMENT_COUNT] = &mut MEMORY_POOL;
-            let ptr = (base_ptr as *mut u8).add(offset);
-            Ok(ptr as *mut T)
-        }
-    } else {
-        Err(mkerror!(ErrorType::OutOfMemory))
-    }
+    allocator().allocate(bytes, alignment, boundary)
 }
 
 pub fn allocate_array<T: Default>(
@@ -73,13 +62,313 @@ pub fn allocate_array<T: Default>(
     })
 }
 
+/// Give the memory obtained by [`allocate`] back
+pub fn free<T>(ptr: *mut T) {
+    if !allocator().free(ptr as usize) {
+        warn!("Tried to free unknown memory: {:p}", ptr);
+    }
+}
+
+/// Make the region available for allocation in addition to the built-in pool.
+/// `base` must be page-aligned, and the region is owned by the allocator from now on.
+/// A part of the region is used to manage the allocation.
+pub fn add_region(base: *mut u8, bytes: usize) -> Result<()> {
+    let base = base as usize;
+    if base == 0 || base % PAGE_SIZE != 0 {
+        return Err(mkerror!(ErrorType::InvalidRegion));
+    }
+    let order_map_bytes = ceil(bytes / MIN_BLOCK_BYTES, PAGE_SIZE);
+    if bytes < order_map_bytes + PAGE_SIZE {
+        return Err(mkerror!(ErrorType::InvalidRegion));
+    }
+    unsafe {
+        (base as *mut u8).write_bytes(0, order_map_bytes);
+    }
+    allocator().add_region(
+        base + order_map_bytes,
+        bytes - order_map_bytes,
+        base as *mut u8,
+    )
+}
+
+pub fn stats() -> MemoryStats {
+    allocator().stats()
+}
+
+fn allocator() -> &'static mut BuddyAllocator {
+    let allocator = unsafe { &mut ALLOCATOR };
+    if !allocator.initialized {
+        allocator.initialized = true;
+        unsafe {
+            let base: *mut MemoryPool = &mut MEMORY_POOL;
+            let order_map: *mut [u8; MEMORY_POOL_BYTES / MIN_BLOCK_BYTES] =
+                &mut MEMORY_POOL_ORDER_MAP;
+            allocator
+                .add_region(base as usize, MEMORY_POOL_BYTES, order_map as *mut u8)
+                .expect("Built-in memory pool must be valid");
+        }
+    }
+    allocator
+}
+
+#[cfg(test)]
+static TEST_LOCK: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Guard to use the allocator exclusively in a test
+#[cfg(test)]
+pub struct ExclusivePool;
+
+#[cfg(test)]
+impl Drop for ExclusivePool {
+    fn drop(&mut self) {
+        TEST_LOCK.store(false, core::sync::atomic::Ordering::Release);
+    }
+}
+
+/// Take the allocator exclusively and release everything allocated so far.
+/// Tests allocating memory must hold the guard since the allocator is a global state.
+#[cfg(test)]
+pub fn exclusive_pool() -> ExclusivePool {
+    use core::sync::atomic::Ordering;
+    while TEST_LOCK
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        std::thread::yield_now();
+    }
+    unsafe {
+        ALLOCATOR = BuddyAllocator::new();
+        MEMORY_POOL_ORDER_MAP = [0; MEMORY_POOL_BYTES / MIN_BLOCK_BYTES];
+    }
+    ExclusivePool
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Region {
+    base: usize,
+    bytes: usize,
+    /// `order + 1` of the allocated block starting at each [`MIN_BLOCK_BYTES`] unit, or 0
+    order_map: *mut u8,
+}
+
+impl Region {
+    const EMPTY: Self = Self {
+        base: 0,
+        bytes: 0,
+        order_map: null_mut(),
+    };
+
+    fn contains(&self, addr: usize) -> bool {
+        self.base <= addr && addr < self.base + self.bytes
+    }
+
+    fn order_map_entry(&self, addr: usize) -> *mut u8 {
+        unsafe { self.order_map.add((addr - self.base) / MIN_BLOCK_BYTES) }
+    }
+}
+
+/// Buddy allocator over a set of regions.
+/// Free blocks are linked through their first word.
+#[derive(Debug)]
+struct BuddyAllocator {
+    initialized: bool,
+    regions: [Region; MAX_REGIONS],
+    num_regions: usize,
+    free_lists: [usize; NUM_ORDERS],
+    total_bytes: usize,
+    used_bytes: usize,
+}
+
+impl BuddyAllocator {
+    const fn new() -> Self {
+        Self {
+            initialized: false,
+            regions: [Region::EMPTY; MAX_REGIONS],
+            num_regions: 0,
+            free_lists: [0; NUM_ORDERS],
+            total_bytes: 0,
+            used_bytes: 0,
+        }
+    }
+
+    fn add_region(&mut self, base: usize, bytes: usize, order_map: *mut u8) -> Result<()> {
+        if self.num_regions == MAX_REGIONS {
+            return Err(mkerror!(ErrorType::TooManyRegions));
+        }
+        let bytes = bytes & !(MIN_BLOCK_BYTES - 1);
+        self.regions[self.num_regions] = Region {
+            base,
+            bytes,
+            order_map,
+        };
+        self.num_regions += 1;
+
+        // split into the largest blocks from the beginning, so every block is aligned to its size
+        // relative to the base
+        let mut offset = 0;
+        while offset < bytes {
+            let order = (0..NUM_ORDERS)
+                .rev()
+                .find(|&order| block_bytes(order) <= bytes - offset)
+                .expect("Remaining bytes are multiple of MIN_BLOCK_BYTES");
+            self.push_free(base + offset, order);
+            offset += block_bytes(order);
+        }
+        self.total_bytes += bytes;
+        Ok(())
+    }
+
+    fn allocate<T>(
+        &mut self,
+        bytes: usize,
+        alignment: Option<usize>,
+        boundary: Option<usize>,
+    ) -> Result<*mut T> {
+        if let Some(boundary) = boundary {
+            if bytes > boundary {
+                return Err(mkerror!(ErrorType::TooLarge));
+            }
+        }
+        let order = (0..NUM_ORDERS)
+            .find(|&order| block_bytes(order) >= bytes.max(alignment.unwrap_or(1)))
+            .ok_or_else(|| mkerror!(ErrorType::TooLarge))?;
+
+        let fits = |addr: usize| {
+            alignment.map(|a| addr % a == 0).unwrap_or(true)
+                && boundary
+                    .map(|b| bytes == 0 || addr / b == (addr + bytes - 1) / b)
+                    .unwrap_or(true)
+        };
+
+        for free_order in order..NUM_ORDERS {
+            let mut block = self.free_lists[free_order];
+            while block != 0 {
+                let found = (0..(1 << (free_order - order)))
+                    .map(|i| block + i * block_bytes(order))
+                    .find(|&addr| fits(addr));
+                if let Some(addr) = found {
+                    self.take(block, free_order, addr, order);
+                    unsafe {
+                        (addr as *mut u8).write_bytes(0, bytes);
+                    }
+                    return Ok(addr as *mut T);
+                }
+                block = unsafe { (block as *const usize).read() };
+            }
+        }
+        Err(mkerror!(ErrorType::OutOfMemory))
+    }
+
+    /// Take the free block of `block_order` out, and split it until `addr` of `order` is left
+    fn take(&mut self, block: usize, block_order: usize, addr: usize, order: usize) {
+        self.remove_free(block, block_order);
+        let (mut current, mut current_order) = (block, block_order);
+        while current_order > order {
+            current_order -= 1;
+            let half = current + block_bytes(current_order);
+            if addr >= half {
+                self.push_free(current, current_order);
+                current = half;
+            } else {
+                self.push_free(half, current_order);
+            }
+        }
+
+        let region = self.region_of(addr).expect("Block must be in a region");
+        unsafe {
+            region.order_map_entry(addr).write(order as u8 + 1);
+        }
+        self.used_bytes += block_bytes(order);
+    }
+
+    /// Returns false if `addr` isn't a block allocated by this allocator
+    fn free(&mut self, addr: usize) -> bool {
+        let region = match self.region_of(addr) {
+            Some(region) if (addr - region.base) % MIN_BLOCK_BYTES == 0 => region,
+            _ => return false,
+        };
+        let mut order = match unsafe { region.order_map_entry(addr).read() } {
+            0 => return false,
+            entry => entry as usize - 1,
+        };
+        unsafe {
+            region.order_map_entry(addr).write(0);
+        }
+        self.used_bytes -= block_bytes(order);
+
+        let mut offset = addr - region.base;
+        while order < NUM_ORDERS - 1 {
+            let buddy_offset = offset ^ block_bytes(order);
+            if buddy_offset + block_bytes(order) > region.bytes
+                || !self.remove_free(region.base + buddy_offset, order)
+            {
+                break;
+            }
+            offset = offset.min(buddy_offset);
+            order += 1;
+        }
+        self.push_free(region.base + offset, order);
+        true
+    }
+
+    fn stats(&self) -> MemoryStats {
+        let largest_free_block = (0..NUM_ORDERS)
+            .rev()
+            .find(|&order| self.free_lists[order] != 0)
+            .map(block_bytes)
+            .unwrap_or(0);
+        MemoryStats {
+            total_bytes: self.total_bytes,
+            used_bytes: self.used_bytes,
+            free_bytes: self.total_bytes - self.used_bytes,
+            largest_free_block,
+            num_regions: self.num_regions,
+        }
+    }
+
+    fn region_of(&self, addr: usize) -> Option<Region> {
+        self.regions[..self.num_regions]
+            .iter()
+            .copied()
+            .find(|region| region.contains(addr))
+    }
+
+    fn push_free(&mut self, addr: usize, order: usize) {
+        unsafe {
+            (addr as *mut usize).write(self.free_lists[order]);
+        }
+        self.free_lists[order] = addr;
+    }
+
+    /// Returns false if the block isn't in the free list
+    fn remove_free(&mut self, addr: usize, order: usize) -> bool {
+        let mut link: *mut usize = &mut self.free_lists[order];
+        unsafe {
+            while *link != 0 {
+                if *link == addr {
+                    *link = (addr as *const usize).read();
+                    return true;
+                }
+                link = *link as *mut usize;
+            }
+        }
+        false
+    }
+}
+
+fn block_bytes(order: usize) -> usize {
+    MIN_BLOCK_BYTES << order
+}
+
 fn ceil(value: usize, alignment: usize) -> usize {
     (value + alignment - 1) & !(alignment - 1)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::usb::mem::{allocate, allocate_array, ceil, current_offset, free_all};
+    use crate::usb::mem::{
+        add_region, allocate, allocate_array, ceil, exclusive_pool, free, stats, MEMORY_POOL_BYTES,
+    };
     use core::slice::from_raw_parts_mut;
 
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -94,9 +383,12 @@ mod tests {
         }
     }
 
+    #[repr(C, align(4096))]
+    struct Page([u8; 4096]);
+
     #[test]
     fn allocate_without_alignment() {
-        free_all();
+        let _pool = exclusive_pool();
 
         for _ in 0..32 {
             assert!(allocate::<()>(4096, None, None).is_ok());
@@ -105,20 +397,45 @@ mod tests {
     }
 
     #[test]
-    fn test_contiguous_layout() {
-        free_all();
+    fn allocate_and_free_reuses_memory() {
+        let _pool = exclusive_pool();
+
+        let first = allocate::<u8>(100, None, None).unwrap();
+        free(first);
+        let second = allocate::<u8>(100, None, None).unwrap();
+        assert_eq!(first, second);
+        free(second);
 
-        let first_ptr = allocate::<()>(1, None, None).unwrap() as u64;
-        for i in 1..(4096 * 32) {
-            let ptr = allocate::<()>(1, None, None).unwrap() as u64;
-            assert_eq!(ptr - first_ptr, i);
+        let mut ptrs = [core::ptr::null_mut::<u8>(); 32];
+        for _ in 0..3 {
+            for ptr in ptrs.iter_mut() {
+                *ptr = allocate::<u8>(4096, None, None).unwrap();
+            }
+            assert!(allocate::<u8>(1, None, None).is_err());
+            for &ptr in ptrs.iter() {
+                free(ptr);
+            }
         }
-        assert!(allocate::<()>(1, None, None).is_err());
+        assert_eq!(stats().used_bytes, 0);
+        assert_eq!(stats().largest_free_block, MEMORY_POOL_BYTES);
+    }
+
+    #[test]
+    fn allocated_memory_is_zeroed() {
+        let _pool = exclusive_pool();
+
+        let ptr = allocate::<u8>(256, None, None).unwrap();
+        unsafe { ptr.write_bytes(0xff, 256) };
+        free(ptr);
+
+        let ptr = allocate::<u8>(256, None, None).unwrap();
+        let buf = unsafe { from_raw_parts_mut(ptr, 256) };
+        assert!(buf.iter().all(|&b| b == 0));
     }
 
     #[test]
     fn test_base_alignment() {
-        free_all();
+        let _pool = exclusive_pool();
 
         let ptr = allocate::<()>(1, None, None).unwrap() as u64;
         assert_eq!(ptr % 64, 0);
@@ -126,21 +443,69 @@ mod tests {
 
     #[test]
     fn allocate_alignment() {
-        free_all();
+        let _pool = exclusive_pool();
+
+        for _ in 0..8 {
+            let ptr = allocate::<()>(100, Some(4096), Some(4096)).unwrap() as usize;
+            assert_eq!(ptr % 4096, 0);
+            let ptr = allocate::<()>(1, Some(64), None).unwrap() as usize;
+            assert_eq!(ptr % 64, 0);
+        }
+    }
+
+    #[test]
+    fn allocate_never_crosses_boundary() {
+        let _pool = exclusive_pool();
+
+        for _ in 0..64 {
+            let ptr = allocate::<()>(1000, Some(64), Some(1024)).unwrap() as usize;
+            assert_eq!(ptr / 1024, (ptr + 1000 - 1) / 1024);
+        }
+        assert!(allocate::<()>(2048, None, Some(1024)).is_err());
+    }
+
+    #[test]
+    fn usage_stats() {
+        let _pool = exclusive_pool();
 
-        assert!(allocate::<()>(1, Some(64), Some(4096)).is_ok());
-        assert_eq!(current_offset(), 1);
+        assert_eq!(stats().total_bytes, MEMORY_POOL_BYTES);
+        assert_eq!(stats().num_regions, 1);
 
-        assert!(allocate::<()>(1, Some(64), Some(4096)).is_ok());
-        assert_eq!(current_offset(), 64 + 1);
+        let ptr = allocate::<()>(100, None, None).unwrap();
+        assert_eq!(stats().used_bytes, 128);
+        assert_eq!(stats().free_bytes, MEMORY_POOL_BYTES - 128);
+        assert_eq!(stats().largest_free_block, MEMORY_POOL_BYTES / 2);
 
-        assert!(allocate::<()>(4090, Some(64), Some(4096)).is_ok());
-        assert_eq!(current_offset(), 4096 + 4090);
+        free(ptr);
+        assert_eq!(stats().used_bytes, 0);
+        assert_eq!(stats().largest_free_block, MEMORY_POOL_BYTES);
+    }
+
+    #[test]
+    fn grow_from_additional_region() {
+        let _pool = exclusive_pool();
+
+        let region = Box::leak(Box::new([
+            Page([0; 4096]),
+            Page([0; 4096]),
+            Page([0; 4096]),
+        ]));
+        let region = region.as_mut_ptr() as *mut u8;
+        assert!(add_region(unsafe { region.add(1) }, 4096 * 2).is_err());
+        assert!(add_region(region, 4096).is_err());
+        add_region(region, 4096 * 3).unwrap();
+        // first page is used to manage the region
+        assert_eq!(stats().total_bytes, MEMORY_POOL_BYTES + 4096 * 2);
+
+        for _ in 0..(32 + 2) {
+            assert!(allocate::<()>(4096, Some(4096), None).is_ok());
+        }
+        assert!(allocate::<()>(4096, None, None).is_err());
     }
 
     #[test]
     fn allocate_array_test_struct() {
-        free_all();
+        let _pool = exclusive_pool();
 
         let array: *mut TestStruct = allocate_array(2, None, None).unwrap();
         let array = unsafe { from_raw_parts_mut(array, 2) };
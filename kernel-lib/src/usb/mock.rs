@@ -0,0 +1,772 @@
+//! Simulated xHC to test the USB stack on the host.
+//!
+//! [`MockXhc`] owns an in-memory register file which [`Xhc`](crate::usb::Xhc) is pointed at.
+//! Register writes are forwarded to the mock active in the thread, which reacts as the hardware does:
+//! it resets ports, consumes the command and transfer rings when the doorbells are rung
+//! and puts the events into the event rings.
+
+use crate::usb::trb::{
+    AddressDeviceCommandTrb, CommandCompletionEventTrb, ConfigureEndpointCommandTrb, DataStageTrb,
+    DisableSlotCommandTrb, EnableSlotCommandTrb, IsochTrb, LinkTrb, NormalTrb,
+    PortStatusChangeEventTrb, ResetEndpointCommandTrb, SetTrDequeuePointerCommandTrb, SetupData,
+    SetupStageTrb, StatusStageTrb, TransferEventTrb, Trb,
+};
+use bit_field::BitField;
+use std::cell::Cell;
+use std::ptr::null_mut;
+
+thread_local! {
+    /// The mock whose registers are accessed in this thread
+    static ACTIVE: Cell<*mut MockXhc> = Cell::new(null_mut());
+}
+
+/// Called on every register write to let the simulated controller react to it
+pub fn on_register_write(addr: usize) {
+    let mock = ACTIVE.with(|active| active.get());
+    if !mock.is_null() {
+        unsafe { &mut *mock }.on_write(addr);
+    }
+}
+
+const MMIO_BYTES: usize = 0x3000;
+const CAPLENGTH: usize = 0x20;
+const RUNTIME_OFFSET: usize = 0x1000;
+const DOORBELL_OFFSET: usize = 0x2000;
+const USBCMD: usize = CAPLENGTH;
+const USBSTS: usize = CAPLENGTH + 0x04;
+const PAGESIZE: usize = CAPLENGTH + 0x08;
+const CRCR: usize = CAPLENGTH + 0x18;
+const DCBAAP: usize = CAPLENGTH + 0x30;
+const PORT_REGISTERS: usize = CAPLENGTH + 0x400;
+const INTERRUPTERS: usize = RUNTIME_OFFSET + 0x20;
+
+const MAX_SLOTS: usize = 8;
+const MAX_INTERRUPTERS: usize = 4;
+const MAX_PORTS: usize = 4;
+/// ERST Max. 4 segments
+const ERST_MAX: u32 = 2;
+const CONTEXT_BYTES: u64 = 32;
+
+/// Command Abort and Command Ring Running in CRCR
+const CRCR_CA: u64 = 1 << 2;
+const CRCR_CRR: u64 = 1 << 3;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+/// Change bits, which are cleared by writing 1
+const PORTSC_RW1C: u32 = 0x00fe_0000;
+
+// completion codes
+const SUCCESS: u8 = 1;
+const TRB_ERROR: u8 = 5;
+const STALL_ERROR: u8 = 6;
+const NO_SLOTS_AVAILABLE: u8 = 9;
+const SLOT_NOT_ENABLED: u8 = 11;
+const SHORT_PACKET: u8 = 13;
+const CONTEXT_STATE_ERROR: u8 = 19;
+const COMMAND_RING_STOPPED: u8 = 24;
+const COMMAND_ABORTED: u8 = 25;
+
+/// Control request received by [`MockDevice`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ControlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+    /// Data stage of the request from the host
+    pub data: Vec<u8>,
+}
+
+/// USB device attached to a root hub port of [`MockXhc`]
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    /// Port Speed in PORTSC
+    pub speed: u8,
+    pub device_descriptor: Vec<u8>,
+    /// Configuration descriptors followed by the interface and endpoint descriptors, in the order of index
+    pub configurations: Vec<Vec<u8>>,
+    /// String descriptors from index 1. English (US) is the only language
+    pub strings: Vec<&'static str>,
+    /// Requests answered with STALL, as (bRequest, wValue). Each of them stalls only once
+    pub stalls: Vec<(u8, u16)>,
+    /// Control requests received so far
+    pub requests: Vec<ControlRequest>,
+    /// Data received by OUT transfers other than control ones
+    pub received: Vec<u8>,
+}
+
+impl MockDevice {
+    pub fn new(speed: u8, device_descriptor: &[u8], configuration: &[u8]) -> Self {
+        Self {
+            speed,
+            device_descriptor: device_descriptor.to_vec(),
+            configurations: vec![configuration.to_vec()],
+            strings: Vec::new(),
+            stalls: Vec::new(),
+            requests: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// The response to the control request, or `None` to stall it
+    fn respond(&mut self, request: &ControlRequest) -> Option<Vec<u8>> {
+        let stall = (request.request, request.value);
+        if let Some(pos) = self.stalls.iter().position(|&s| s == stall) {
+            self.stalls.remove(pos);
+            return None;
+        }
+        if request.request != SetupData::REQUEST_GET_DESCRIPTOR {
+            return Some(Vec::new());
+        }
+        let desc_index = request.value as u8 as usize;
+        let mut response = match (request.value >> 8) as u8 {
+            1 => self.device_descriptor.clone(),
+            2 => self.configurations.get(desc_index)?.clone(),
+            3 if desc_index == 0 => vec![4, 3, 0x09, 0x04],
+            3 => {
+                let s = self.strings.get(desc_index - 1)?;
+                let mut desc = vec![0, 3];
+                for c in s.encode_utf16() {
+                    desc.extend_from_slice(&c.to_le_bytes());
+                }
+                desc[0] = desc.len() as u8;
+                desc
+            }
+            _ => return None,
+        };
+        response.truncate(request.length as usize);
+        Some(response)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct RingCursor {
+    ptr: u64,
+    cycle: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct MockEndpoint {
+    enabled: bool,
+    halted: bool,
+    ring: RingCursor,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ControlTransfer {
+    /// `None` if the request is stalled
+    response: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct MockSlot {
+    port_id: u8,
+    endpoints: [MockEndpoint; 32],
+    control: Option<ControlTransfer>,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct EventProducer {
+    segment: usize,
+    index: usize,
+    cycle: bool,
+}
+
+/// Simulated xHC. Dropping it detaches the registers from the thread
+pub struct MockXhc {
+    mmio: Box<[u64]>,
+    devices: [Option<MockDevice>; MAX_PORTS],
+    /// PORTSC as the controller sees it. Writes from the driver don't clobber the read-only bits
+    portsc: [u32; MAX_PORTS],
+    command_ring: RingCursor,
+    slots: [Option<MockSlot>; MAX_SLOTS + 1],
+    event_producers: [EventProducer; MAX_INTERRUPTERS],
+    /// Commands completed with an error, as (TRB type, completion code). Each of them fails only once
+    pub command_failures: Vec<(u8, u8)>,
+    /// Number of the following commands which never complete until they are aborted
+    pub stuck_commands: usize,
+}
+
+impl MockXhc {
+    pub fn new() -> Box<Self> {
+        let mut mock = Box::new(Self {
+            mmio: vec![0u64; MMIO_BYTES / 8].into_boxed_slice(),
+            devices: Default::default(),
+            portsc: [0; MAX_PORTS],
+            command_ring: RingCursor::default(),
+            slots: Default::default(),
+            event_producers: [EventProducer::default(); MAX_INTERRUPTERS],
+            command_failures: Vec::new(),
+            stuck_commands: 0,
+        });
+        mock.write32(0x00, CAPLENGTH as u32 | (0x0110 << 16));
+        mock.write32(
+            0x04,
+            MAX_SLOTS as u32 | ((MAX_INTERRUPTERS as u32) << 8) | ((MAX_PORTS as u32) << 24),
+        );
+        mock.write32(0x08, ERST_MAX << 4);
+        mock.write32(0x14, DOORBELL_OFFSET as u32);
+        mock.write32(0x18, RUNTIME_OFFSET as u32);
+        mock.write32(USBSTS, 1);
+        mock.write32(PAGESIZE, 1);
+        for i in 0..MAX_PORTS {
+            mock.set_portsc(i, PORTSC_PP);
+        }
+
+        let ptr = &mut *mock as *mut Self;
+        ACTIVE.with(|active| active.set(ptr));
+        mock
+    }
+
+    pub fn mmio_base(&self) -> usize {
+        self.mmio.as_ptr() as usize
+    }
+
+    /// Max Scratchpad Buffers in HCSPARAMS2, which xHC reads before it's initialized
+    pub fn set_max_scratchpad_buffers(&mut self, num_buffers: u32) {
+        let mut hcsparams2 = self.read32(0x08);
+        hcsparams2.set_bits(21..26, num_buffers >> 5);
+        hcsparams2.set_bits(27..32, num_buffers & 0x1f);
+        self.write32(0x08, hcsparams2);
+    }
+
+    /// Plug the device into the port. Port Status Change Event is generated if xHC is running
+    pub fn attach(&mut self, port_id: u8, device: MockDevice) {
+        let i = port_id as usize - 1;
+        let speed = (device.speed as u32) << 10;
+        self.devices[i] = Some(device);
+        self.set_portsc(i, PORTSC_CCS | PORTSC_PP | PORTSC_CSC | speed);
+        self.on_port_status_changed(port_id);
+    }
+
+    pub fn detach(&mut self, port_id: u8) {
+        let i = port_id as usize - 1;
+        self.devices[i] = None;
+        self.set_portsc(i, PORTSC_PP | PORTSC_CSC);
+        self.on_port_status_changed(port_id);
+    }
+
+    pub fn device(&self, port_id: u8) -> &MockDevice {
+        self.devices[port_id as usize - 1]
+            .as_ref()
+            .expect("No device is attached")
+    }
+
+    /// Number of device slots enabled
+    pub fn num_slots(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// Length of the IN transfer the device on the port is waiting to complete on the endpoint
+    /// Whether the endpoint of the device on the port has been added by Configure Endpoint Command
+    pub fn is_enabled(&self, port_id: u8, dci: u8) -> bool {
+        self.endpoint_of_port(port_id, dci).is_some()
+    }
+
+    pub fn pending_in(&self, port_id: u8, dci: u8) -> Option<u32> {
+        let ep = self.endpoint_of_port(port_id, dci)?;
+        let (_, trb) = self.next_trb(ep.ring)?;
+        Some(trb.get_bits(64..81) as u32)
+    }
+
+    /// Complete the pending IN transfer on the endpoint with `data`.
+    /// A TD must consist of a single TRB.
+    /// Returns `false` if no transfer is pending
+    pub fn complete_in(&mut self, port_id: u8, dci: u8, data: &[u8]) -> bool {
+        let slot_id = match self.slot_of_port(port_id) {
+            Some(slot_id) => slot_id,
+            None => return false,
+        };
+        let ep = match self.endpoint_of_port(port_id, dci) {
+            Some(ep) if !ep.halted => ep,
+            _ => return false,
+        };
+        let (cursor, trb) = match self.next_trb(ep.ring) {
+            Some(next) => next,
+            None => return false,
+        };
+        let len = trb.get_bits(64..81) as usize;
+        let copied = data.len().min(len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), trb.get_bits(0..64) as *mut u8, copied);
+        }
+        self.complete_trb(slot_id, dci, cursor, trb, (len - copied) as u32);
+        self.process_transfer_ring(slot_id, dci);
+        true
+    }
+
+    fn on_write(&mut self, addr: usize) {
+        let offset = match addr.checked_sub(self.mmio_base()) {
+            Some(offset) if offset < MMIO_BYTES => offset,
+            _ => return,
+        };
+        match offset {
+            USBCMD => self.on_usbcmd_written(),
+            CRCR => self.on_crcr_written(),
+            o if (PORT_REGISTERS..PORT_REGISTERS + 16 * MAX_PORTS).contains(&o)
+                && (o - PORT_REGISTERS) % 16 == 0 =>
+            {
+                self.on_portsc_written((o - PORT_REGISTERS) / 16)
+            }
+            o if (INTERRUPTERS..INTERRUPTERS + 32 * MAX_INTERRUPTERS).contains(&o)
+                && (o - INTERRUPTERS) % 32 == 0x10 =>
+            {
+                // the event ring starts over when ERSTBA is set
+                self.event_producers[(o - INTERRUPTERS) / 32] = EventProducer {
+                    segment: 0,
+                    index: 0,
+                    cycle: true,
+                };
+            }
+            o if (DOORBELL_OFFSET..DOORBELL_OFFSET + 4 * (MAX_SLOTS + 1)).contains(&o) => {
+                let target = self.read32(o) as u8;
+                match (o - DOORBELL_OFFSET) / 4 {
+                    0 => self.process_command_ring(),
+                    slot_id => self.process_transfer_ring(slot_id as u8, target),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_usbcmd_written(&mut self) {
+        let mut usbcmd = self.read32(USBCMD);
+        if usbcmd.get_bit(1) {
+            // reset completes immediately
+            usbcmd.set_bit(1, false);
+            self.write32(USBCMD, usbcmd);
+        }
+        let halted = !usbcmd.get_bit(0);
+        self.write32(USBSTS, halted as u32);
+    }
+
+    fn on_crcr_written(&mut self) {
+        let crcr = self.read64(CRCR);
+        // Command Stop and Command Abort keep the ring pointer
+        if crcr & 0b110 == 0 {
+            self.command_ring = RingCursor {
+                ptr: crcr & !0x3f,
+                cycle: crcr.get_bit(0),
+            };
+        }
+        if crcr & CRCR_CA != 0 && self.stuck_commands > 0 {
+            self.abort_command();
+        }
+        // the pointer always reads as 0
+        let running = self.read64(CRCR) & CRCR_CRR;
+        self.write64(CRCR, running);
+    }
+
+    /// Complete the stuck command with Command Aborted and stop the ring
+    fn abort_command(&mut self) {
+        if let Some((cursor, _)) = self.next_trb(self.command_ring) {
+            self.stuck_commands -= 1;
+            self.command_ring = RingCursor {
+                ptr: cursor.ptr + 16,
+                cycle: cursor.cycle,
+            };
+            let mut event = cursor.ptr as u128;
+            event.set_bits(88..96, COMMAND_ABORTED as u128);
+            self.post_event(0, CommandCompletionEventTrb::TYPE, event);
+        }
+        let mut event = self.command_ring.ptr as u128;
+        event.set_bits(88..96, COMMAND_RING_STOPPED as u128);
+        self.post_event(0, CommandCompletionEventTrb::TYPE, event);
+        self.write64(CRCR, 0);
+    }
+
+    fn on_portsc_written(&mut self, i: usize) {
+        let written = self.read32(PORT_REGISTERS + 16 * i);
+        let mut portsc = self.portsc[i] & !(written & PORTSC_RW1C);
+        let reset = written & PORTSC_PR != 0 && portsc & PORTSC_CCS != 0;
+        if reset {
+            portsc |= PORTSC_PED | PORTSC_PRC;
+        }
+        self.set_portsc(i, portsc);
+        if reset {
+            self.on_port_status_changed(i as u8 + 1);
+        }
+    }
+
+    fn set_portsc(&mut self, i: usize, portsc: u32) {
+        self.portsc[i] = portsc;
+        self.write32(PORT_REGISTERS + 16 * i, portsc);
+    }
+
+    fn on_port_status_changed(&mut self, port_id: u8) {
+        if !self.read32(USBSTS).get_bit(0) {
+            let mut event = 0u128;
+            event.set_bits(24..32, port_id as u128);
+            self.post_event(0, PortStatusChangeEventTrb::TYPE, event);
+        }
+    }
+
+    fn process_command_ring(&mut self) {
+        while let Some((cursor, trb)) = self.next_trb(self.command_ring) {
+            if self.stuck_commands > 0 {
+                self.write64(CRCR, CRCR_CRR);
+                return;
+            }
+            self.command_ring = RingCursor {
+                ptr: cursor.ptr + 16,
+                cycle: cursor.cycle,
+            };
+            let trb_type = trb.get_bits(106..112) as u8;
+            let slot_id = trb.get_bits(120..128) as u8;
+            let (code, slot_id) = match self
+                .command_failures
+                .iter()
+                .position(|&(t, _)| t == trb_type)
+            {
+                Some(pos) => (self.command_failures.remove(pos).1, slot_id),
+                None => self.execute_command(trb_type, trb),
+            };
+
+            let mut event = cursor.ptr as u128;
+            event.set_bits(88..96, code as u128);
+            event.set_bits(120..128, slot_id as u128);
+            self.post_event(0, CommandCompletionEventTrb::TYPE, event);
+        }
+    }
+
+    /// Returns the completion code and the slot ID
+    fn execute_command(&mut self, trb_type: u8, trb: u128) -> (u8, u8) {
+        let slot_id = trb.get_bits(120..128) as u8;
+        if trb_type == EnableSlotCommandTrb::TYPE {
+            return match (1..=MAX_SLOTS).find(|&i| self.slots[i].is_none()) {
+                Some(i) => {
+                    self.slots[i] = Some(MockSlot::default());
+                    (SUCCESS, i as u8)
+                }
+                None => (NO_SLOTS_AVAILABLE, 0),
+            };
+        }
+        if self.slot(slot_id).is_none() {
+            return (SLOT_NOT_ENABLED, slot_id);
+        }
+
+        let input_context = (trb.get_bits(4..64) << 4) as u64;
+        let dci = trb.get_bits(112..117) as usize;
+        let code = match trb_type {
+            DisableSlotCommandTrb::TYPE => {
+                self.slots[slot_id as usize] = None;
+                SUCCESS
+            }
+            AddressDeviceCommandTrb::TYPE | ConfigureEndpointCommandTrb::TYPE => {
+                self.load_input_context(slot_id, input_context);
+                SUCCESS
+            }
+            ResetEndpointCommandTrb::TYPE => {
+                let ep = &mut self.slot(slot_id).unwrap().endpoints[dci];
+                if ep.halted {
+                    ep.halted = false;
+                    SUCCESS
+                } else {
+                    CONTEXT_STATE_ERROR
+                }
+            }
+            SetTrDequeuePointerCommandTrb::TYPE => {
+                let ep = &mut self.slot(slot_id).unwrap().endpoints[dci];
+                ep.ring = RingCursor {
+                    ptr: (trb.get_bits(4..64) << 4) as u64,
+                    cycle: trb.get_bit(0),
+                };
+                SUCCESS
+            }
+            _ => TRB_ERROR,
+        };
+        (code, slot_id)
+    }
+
+    /// Copy the added contexts to the output device context
+    fn load_input_context(&mut self, slot_id: u8, input_context: u64) {
+        let output_context =
+            unsafe { ((self.read64(DCBAAP) + 8 * slot_id as u64) as *const u64).read_volatile() };
+        let drop_flags = unsafe { (input_context as *const u32).read_volatile() };
+        let add_flags = unsafe { ((input_context + 4) as *const u32).read_volatile() };
+        let slot = self.slots[slot_id as usize].as_mut().unwrap();
+        // the slot context and the default control endpoint can't be dropped
+        for i in 2..32 {
+            if drop_flags.get_bit(i) {
+                slot.endpoints[i] = MockEndpoint::default();
+            }
+        }
+        for i in 0..32 {
+            if !add_flags.get_bit(i) {
+                continue;
+            }
+            let src = input_context + (i as u64 + 1) * CONTEXT_BYTES;
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    src as *const u8,
+                    (output_context + i as u64 * CONTEXT_BYTES) as *mut u8,
+                    CONTEXT_BYTES as usize,
+                );
+            }
+            if i == 0 {
+                let dword1 = unsafe { ((src + 4) as *const u32).read_volatile() };
+                slot.port_id = dword1.get_bits(16..24) as u8;
+            } else {
+                let dequeue = unsafe { ((src + 8) as *const u64).read_volatile() };
+                slot.endpoints[i] = MockEndpoint {
+                    enabled: true,
+                    halted: false,
+                    ring: RingCursor {
+                        ptr: dequeue & !0xf,
+                        cycle: dequeue.get_bit(0),
+                    },
+                };
+            }
+        }
+    }
+
+    fn process_transfer_ring(&mut self, slot_id: u8, dci: u8) {
+        loop {
+            let ep = match self.slot(slot_id) {
+                Some(slot) => slot.endpoints[dci as usize],
+                None => return,
+            };
+            if !ep.enabled || ep.halted {
+                return;
+            }
+            let (cursor, trb) = match self.next_trb(ep.ring) {
+                Some(next) => next,
+                None => return,
+            };
+            let port_id = self.slot(slot_id).unwrap().port_id;
+            if self.devices[port_id as usize - 1].is_none() {
+                // nobody answers
+                return;
+            }
+            match trb.get_bits(106..112) as u8 {
+                SetupStageTrb::TYPE => self.on_setup_stage(slot_id, port_id, trb),
+                DataStageTrb::TYPE => {
+                    if !self.on_data_stage(slot_id, port_id, cursor, trb) {
+                        return;
+                    }
+                    continue;
+                }
+                StatusStageTrb::TYPE => {
+                    let stalled = self
+                        .slot(slot_id)
+                        .unwrap()
+                        .control
+                        .take()
+                        .map_or(false, |control| control.response.is_none());
+                    if stalled {
+                        self.halt(slot_id, dci, cursor, trb);
+                        return;
+                    }
+                    self.complete_trb(slot_id, dci, cursor, trb, 0);
+                    continue;
+                }
+                NormalTrb::TYPE | IsochTrb::TYPE if dci % 2 == 0 => {
+                    let len = trb.get_bits(64..81) as usize;
+                    let data = unsafe {
+                        std::slice::from_raw_parts(trb.get_bits(0..64) as *const u8, len)
+                    };
+                    self.devices[port_id as usize - 1]
+                        .as_mut()
+                        .unwrap()
+                        .received
+                        .extend_from_slice(data);
+                    self.complete_trb(slot_id, dci, cursor, trb, 0);
+                    continue;
+                }
+                // IN transfers wait for `complete_in`
+                NormalTrb::TYPE | IsochTrb::TYPE => return,
+                _ => {}
+            }
+            self.advance(slot_id, dci, cursor);
+        }
+    }
+
+    fn on_setup_stage(&mut self, slot_id: u8, port_id: u8, trb: u128) {
+        let request = ControlRequest {
+            request_type: trb.get_bits(0..8) as u8,
+            request: trb.get_bits(8..16) as u8,
+            value: trb.get_bits(16..32) as u16,
+            index: trb.get_bits(32..48) as u16,
+            length: trb.get_bits(48..64) as u16,
+            data: Vec::new(),
+        };
+        let device = self.devices[port_id as usize - 1].as_mut().unwrap();
+        let response = device.respond(&request);
+        device.requests.push(request);
+        self.slot(slot_id).unwrap().control = Some(ControlTransfer { response });
+    }
+
+    /// Returns `false` if the transfer is stalled
+    fn on_data_stage(&mut self, slot_id: u8, port_id: u8, cursor: RingCursor, trb: u128) -> bool {
+        let dci = 1;
+        let response = self
+            .slot(slot_id)
+            .unwrap()
+            .control
+            .as_ref()
+            .and_then(|control| control.response.clone());
+        let response = match response {
+            Some(response) => response,
+            None => {
+                self.slot(slot_id).unwrap().control = None;
+                self.halt(slot_id, dci, cursor, trb);
+                return false;
+            }
+        };
+
+        let buf = trb.get_bits(0..64) as *mut u8;
+        let len = trb.get_bits(64..81) as usize;
+        let device = self.devices[port_id as usize - 1].as_mut().unwrap();
+        let residual = if trb.get_bit(112) {
+            let copied = response.len().min(len);
+            unsafe { std::ptr::copy_nonoverlapping(response.as_ptr(), buf, copied) };
+            len - copied
+        } else {
+            let data = unsafe { std::slice::from_raw_parts(buf, len) };
+            device.requests.last_mut().unwrap().data = data.to_vec();
+            0
+        };
+        self.complete_trb(slot_id, dci, cursor, trb, residual as u32);
+        true
+    }
+
+    /// Finish the TRB and generate an event if it's requested
+    fn complete_trb(&mut self, slot_id: u8, dci: u8, cursor: RingCursor, trb: u128, residual: u32) {
+        self.advance(slot_id, dci, cursor);
+        let short = residual > 0;
+        // IOC or ISP
+        if trb.get_bit(101) || (short && trb.get_bit(98)) {
+            let code = if short { SHORT_PACKET } else { SUCCESS };
+            self.post_transfer_event(slot_id, dci, cursor, trb, code, residual);
+        }
+    }
+
+    /// Stall the endpoint on the TRB
+    fn halt(&mut self, slot_id: u8, dci: u8, cursor: RingCursor, trb: u128) {
+        let ep = &mut self.slot(slot_id).unwrap().endpoints[dci as usize];
+        ep.halted = true;
+        ep.ring = cursor;
+        let residual = trb.get_bits(64..81) as u32;
+        self.post_transfer_event(slot_id, dci, cursor, trb, STALL_ERROR, residual);
+    }
+
+    fn post_transfer_event(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        cursor: RingCursor,
+        trb: u128,
+        code: u8,
+        residual: u32,
+    ) {
+        let mut event = cursor.ptr as u128;
+        event.set_bits(64..88, residual as u128);
+        event.set_bits(88..96, code as u128);
+        event.set_bits(112..117, dci as u128);
+        event.set_bits(120..128, slot_id as u128);
+        let interrupter = trb.get_bits(86..96) as usize;
+        self.post_event(interrupter, TransferEventTrb::TYPE, event);
+    }
+
+    fn advance(&mut self, slot_id: u8, dci: u8, cursor: RingCursor) {
+        self.slot(slot_id).unwrap().endpoints[dci as usize].ring = RingCursor {
+            ptr: cursor.ptr + 16,
+            cycle: cursor.cycle,
+        };
+    }
+
+    /// The TRB to be processed next and its position, following Link TRBs
+    fn next_trb(&self, mut cursor: RingCursor) -> Option<(RingCursor, u128)> {
+        if cursor.ptr == 0 {
+            return None;
+        }
+        loop {
+            let trb = unsafe { (cursor.ptr as *const u128).read_volatile() };
+            if trb.get_bit(96) != cursor.cycle {
+                return None;
+            }
+            if trb.get_bits(106..112) as u8 != LinkTrb::TYPE {
+                return Some((cursor, trb));
+            }
+            cursor.ptr = (trb.get_bits(4..64) << 4) as u64;
+            if trb.get_bit(97) {
+                cursor.cycle = !cursor.cycle;
+            }
+        }
+    }
+
+    fn post_event(&mut self, interrupter: usize, trb_type: u8, mut event: u128) {
+        let base = INTERRUPTERS + 32 * interrupter;
+        let table = self.read64(base + 0x10);
+        assert_ne!(table, 0, "Event ring {} isn't initialized", interrupter);
+        let table_size = self.read32(base + 0x08) as usize;
+
+        let producer = &mut self.event_producers[interrupter];
+        let entry = table + 16 * producer.segment as u64;
+        let (segment, segment_len) = unsafe {
+            (
+                (entry as *const u64).read_volatile(),
+                ((entry + 8) as *const u16).read_volatile() as usize,
+            )
+        };
+        event.set_bits(106..112, trb_type as u128);
+        event.set_bit(96, producer.cycle);
+        unsafe {
+            ((segment + 16 * producer.index as u64) as *mut u128).write_volatile(event);
+        }
+
+        producer.index += 1;
+        if producer.index == segment_len {
+            producer.index = 0;
+            producer.segment += 1;
+            if producer.segment == table_size {
+                producer.segment = 0;
+                producer.cycle = !producer.cycle;
+            }
+        }
+    }
+
+    fn slot(&mut self, slot_id: u8) -> Option<&mut MockSlot> {
+        self.slots.get_mut(slot_id as usize)?.as_mut()
+    }
+
+    fn slot_of_port(&self, port_id: u8) -> Option<u8> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(s) if s.port_id == port_id))
+            .map(|i| i as u8)
+    }
+
+    fn endpoint_of_port(&self, port_id: u8, dci: u8) -> Option<MockEndpoint> {
+        let slot = self.slots[self.slot_of_port(port_id)? as usize].as_ref()?;
+        Some(slot.endpoints[dci as usize]).filter(|ep| ep.enabled)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.mmio_base() + offset) as *const u32).read_volatile() }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        unsafe { ((self.mmio_base() + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { ((self.mmio_base() + offset) as *const u64).read_volatile() }
+    }
+
+    fn write64(&mut self, offset: usize, value: u64) {
+        unsafe { ((self.mmio_base() + offset) as *mut u64).write_volatile(value) }
+    }
+}
+
+impl Drop for MockXhc {
+    fn drop(&mut self) {
+        ACTIVE.with(|active| active.set(null_mut()));
+    }
+}
@@ -1,23 +1,41 @@
 use crate::error::ErrorContext;
+use crate::timer::{current_tick, TICKS_PER_SECOND};
+use crate::usb::command::{Command, CommandCompletion, CommandRing};
+use crate::usb::context::ContextSize;
 use crate::usb::devmgr::DeviceManager;
+use crate::usb::endpoint::EndpointId;
 use crate::usb::port::Port;
-use crate::usb::trb::ring::{EventRing, Ring};
+use crate::usb::trb::ring::EventRing;
 use crate::usb::trb::{
-    AddressDeviceCommandTrb, CommandCompletionEventTrb, ConfigureEndpointCommandTrb,
-    EnableSlotCommandTrb, PortStatusChangeEventTrb, TransferEventTrb,
+    AddressDeviceCommandTrb, CommandCompletionEventTrb, CompletionCode,
+    ConfigureEndpointCommandTrb, DisableSlotCommandTrb, EnableSlotCommandTrb,
+    PortStatusChangeEventTrb, ResetEndpointCommandTrb, SetTrDequeuePointerCommandTrb,
+    TransferEventTrb, Trb,
 };
 use crate::usb::xhci::{ExtendedCapability, Registers};
+use crate::util::collection::ArrayVec;
 
 pub mod classdriver;
+mod command;
+pub mod configuration;
 mod context;
-mod descriptor;
+pub mod descriptor;
 mod devmgr;
 mod endpoint;
-mod mem;
+mod inventory;
+pub mod mem;
+#[cfg(test)]
+mod mock;
 mod port;
 mod trb;
 mod xhci;
 
+pub use descriptor::{DeviceDescriptor, UsbString};
+pub use devmgr::{IsochFrame, TransferCallback, TransferCompletion, TransferResult, UsbDevice};
+pub use inventory::{inventory, DeviceInfo};
+pub use port::PortSpeed;
+pub use trb::{RequestType, SetupData};
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SlotId(u8);
 impl SlotId {
@@ -35,18 +53,42 @@ pub enum ErrorType {
     InvalidPhase,
     NotImplemented,
     InvalidSlotId,
+    InvalidPortId,
+    InvalidInterrupter,
     DeviceError(devmgr::Error),
+    CommandError(command::Error),
+    TrbError(trb::Error),
+    CommandFailed(Command, CompletionCode),
 }
 
 pub type Error = ErrorContext<ErrorType>;
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// MaxPorts in HCSPARAMS1 is 8 bits wide
+const MAX_PORTS: usize = 255;
+
+/// Maximum number of interrupters to be enabled.
+/// The primary interrupter receives command completions, port status changes and
+/// control transfers, and the others are dedicated to transfers of specific endpoint types
+pub const MAX_INTERRUPTERS: usize = 3;
+
+/// A port is given up after enumeration fails this number of times in a row
+const MAX_ENUMERATION_ATTEMPTS: u8 = 4;
+/// Wait before the first retry. Doubled on each failure
+const RETRY_BACKOFF_TICKS: u64 = TICKS_PER_SECOND / 10;
+/// Enumeration is considered failed if a port stays in a single phase longer than this
+const ENUMERATION_TIMEOUT_TICKS: u64 = 3 * TICKS_PER_SECOND;
+
 pub struct Xhc {
     registers: Registers,
     device_manager: DeviceManager,
-    command_ring: Ring,
-    event_ring: EventRing,
-    port_config_phase: [ConfigPhase; 256],
+    command_ring: CommandRing,
+    /// Event ring of each interrupter
+    event_rings: ArrayVec<EventRing, MAX_INTERRUPTERS>,
+    /// State of each root hub port, indexed by port number - 1
+    ports: ArrayVec<PortState, MAX_PORTS>,
+    /// The USB2 port whose device is using the default address.
+    /// Only one device can be between port reset and Address Device Command on USB2
     addressing_port: Option<u8>,
 }
 
@@ -55,25 +97,37 @@ impl Xhc {
         Xhc {
             registers: Registers::new(mmio_base),
             device_manager: DeviceManager::new(),
-            command_ring: Ring::new(),
-            event_ring: EventRing::new(),
-            port_config_phase: [ConfigPhase::NotConnected; 256],
+            command_ring: CommandRing::new(),
+            event_rings: ArrayVec::new(),
+            ports: ArrayVec::new(),
             addressing_port: None,
         }
     }
 
-    pub fn initialize(&mut self) {
+    /// Initialize xHC with interrupters configured by `interrupters`.
+    /// The number of interrupters is limited by xHC and [`MAX_INTERRUPTERS`].
+    /// Fails if the memory for the data structures xHC requires runs out
+    pub fn initialize(&mut self, interrupters: &[InterrupterConfig]) -> Result<()> {
+        let context_size = ContextSize::new(
+            self.registers
+                .capability
+                .as_ref()
+                .hccparams1
+                .read()
+                .context_size(),
+        );
+        debug!("Context size: {} bytes", context_size.bytes());
         self.device_manager
-            .initialize()
-            .expect("Failed to initialize device manager");
+            .initialize(context_size)
+            .map_err(|e| mkerror!(ErrorType::DeviceError(e)))?;
+        self.init_port_states();
         self.request_hc_ownership();
         self.initialize_host_controller();
         self.set_enabled_device_slots();
-        // TODO: initialize scratchpad buffer
+        self.initialize_scratchpad_buffers()?;
         self.set_dcbaap();
-        self.init_command_ring();
-        self.init_event_ring();
-        self.init_interrupter();
+        self.init_command_ring()?;
+        self.init_interrupters(interrupters)
     }
 
     pub fn run(&mut self) {
@@ -101,6 +155,15 @@ impl Xhc {
             .max_ports()
     }
 
+    /// Returns the current frame number to schedule isochronous transfers at
+    pub fn current_frame_index(&self) -> u16 {
+        self.registers
+            .microframe_index
+            .as_ref()
+            .read()
+            .frame_index()
+    }
+
     pub fn port_at(&self, num: u8) -> Port {
         Port::new(
             num,
@@ -112,15 +175,35 @@ impl Xhc {
     }
 
     pub fn configure_port(&mut self, port: &mut Port) -> Result<()> {
-        if self.port_config_phase[port.port_num() as usize] == ConfigPhase::NotConnected {
+        if self.phase(port.port_num())? == ConfigPhase::NotConnected {
             self.reset_port(port)
         } else {
             Ok(())
         }
     }
 
+    /// Number of interrupters enabled
+    pub fn num_interrupters(&self) -> usize {
+        self.event_rings.len()
+    }
+
+    /// Process an event from any of the interrupters
     pub fn poll(&mut self) -> Result<Option<()>> {
-        if let Some(trb) = self.event_ring.poll() {
+        for i in 0..self.event_rings.len() {
+            if let Some(()) = self.poll_interrupter(i)? {
+                return Ok(Some(()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Process an event in the event ring of the interrupter.
+    /// Returns `None` if there's no event
+    pub fn poll_interrupter(&mut self, index: usize) -> Result<Option<()>> {
+        if index >= self.event_rings.len() {
+            return Err(mkerror!(ErrorType::InvalidInterrupter));
+        }
+        if let Some(trb) = self.event_rings.as_mut_slice()[index].poll() {
             if let Some(trb) = trb.specialize::<TransferEventTrb>() {
                 self.on_transfer_event(trb).map(|_| Some(()))
             } else if let Some(trb) = trb.specialize::<CommandCompletionEventTrb>() {
@@ -148,85 +231,278 @@ impl Xhc {
             .device_manager
             .find_by_slot(slot_id)
             .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?;
-        dev.on_transfer_event_received(trb)
-            .map_err(ErrorType::DeviceError)
-            .map_err(|e| mkerror!(e))?;
+        let port_id = dev.port_id();
+        let result = dev
+            .on_transfer_event_received(trb)
+            .map_err(|e| mkerror!(ErrorType::DeviceError(e)));
+        // the endpoint has to be recovered even if the event couldn't be handled
+        self.reset_halted_endpoints(slot_id)?;
+        if result.is_err() && self.phase(port_id)? == ConfigPhase::InitializingDevice {
+            self.on_enumeration_failed(port_id)?;
+        }
+        result?;
 
-        let port_id = dev.device_context().slot_context.root_hub_port_num();
-        if dev.is_initialized()
-            && self.port_config_phase[port_id as usize] == ConfigPhase::InitializingDevice
-        {
+        let dev = self
+            .device_manager
+            .find_by_slot(slot_id)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?;
+        if dev.is_initialized() && self.phase(port_id)? == ConfigPhase::InitializingDevice {
             self.configure_endpoints(slot_id, port_id)
         } else {
-            Ok(())
+            self.reconfigure_endpoints(slot_id)
         }
     }
 
     fn on_command_completion_event(&mut self, trb: &CommandCompletionEventTrb) -> Result<()> {
         debug!(
-            "CommandCompletionEvent: slot_id = {}, issuer = {:?}",
+            "CommandCompletionEvent: slot_id = {}, issuer = {:?}, code = {:?}",
             trb.slot_id().value(),
-            trb.issuer().trb_type()
+            trb.issuer().trb_type(),
+            trb.completion_code()
         );
-
-        if trb.issuer().specialize::<EnableSlotCommandTrb>().is_some() {
-            if let Some(addressing_port) = self.addressing_port {
-                if self.port_config_phase[addressing_port as usize] == ConfigPhase::EnablingSlot {
-                    return self.address_device(addressing_port, trb.slot_id());
-                }
-            }
-        } else if trb
-            .issuer()
-            .specialize::<AddressDeviceCommandTrb>()
-            .is_some()
+        let completion = match self
+            .command_ring
+            .on_completion(trb)
+            .map_err(|e| mkerror!(ErrorType::CommandError(e)))?
         {
-            let port_id = {
-                let dev = self
-                    .device_manager
-                    .find_by_slot(trb.slot_id())
-                    .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?;
-                dev.device_context().slot_context.root_hub_port_num()
-            };
+            Some(completion) => completion,
+            None => return Ok(()),
+        };
 
-            if self.addressing_port == Some(port_id)
-                && self.port_config_phase[port_id as usize] == ConfigPhase::AddressingDevice
+        match completion.command {
+            // the slot has to be released even if the command failed
+            Command::DisableSlot { slot_id } => self.on_slot_disabled(slot_id),
+            Command::EnableSlot { port_id }
+            | Command::AddressDevice { port_id, .. }
+            | Command::ConfigureEndpoint { port_id, .. }
+                if !completion.is_success() =>
             {
-                self.addressing_port = None;
-                for i in 0..self.port_config_phase.len() {
-                    if self.port_config_phase[i] == ConfigPhase::WaitingAddressed {
-                        let mut port = self.port_at(i as u8);
-                        self.reset_port(&mut port)?;
-                        break;
-                    }
+                self.on_command_failed(port_id, completion)
+            }
+            Command::ResetEndpoint {
+                slot_id,
+                endpoint_id,
+            }
+            | Command::SetTrDequeuePointer {
+                slot_id,
+                endpoint_id,
+            } if !completion.is_success() => {
+                error!(
+                    "Failed to recover endpoint {:?} of slot {}: {:?}",
+                    endpoint_id,
+                    slot_id.value(),
+                    completion.completion_code
+                );
+                if let Some(dev) = self.device_manager.find_by_slot(slot_id) {
+                    dev.on_endpoint_recovery_failed(endpoint_id);
+                }
+                Err(mkerror!(ErrorType::CommandFailed(
+                    completion.command,
+                    completion.completion_code
+                )))
+            }
+            Command::ResetEndpoint {
+                slot_id,
+                endpoint_id,
+            } => self.on_endpoint_reset(slot_id, endpoint_id),
+            Command::SetTrDequeuePointer {
+                slot_id,
+                endpoint_id,
+            } => {
+                // the device may have been detached in the meantime
+                if let Some(dev) = self.device_manager.find_by_slot(slot_id) {
+                    dev.on_endpoint_recovered(endpoint_id)
+                        .map_err(|e| mkerror!(ErrorType::DeviceError(e)))?;
+                    self.reset_halted_endpoints(slot_id)?;
+                }
+                Ok(())
+            }
+            Command::EnableSlot { port_id } => self.on_slot_enabled(port_id, completion.slot_id),
+            Command::AddressDevice { port_id, slot_id } => {
+                self.on_device_addressed(port_id, slot_id)
+            }
+            Command::ConfigureEndpoint { port_id, slot_id } => {
+                self.on_endpoints_configured(port_id, slot_id)
+            }
+            Command::Reconfigure { slot_id } => {
+                let dev = match self.device_manager.find_by_slot(slot_id) {
+                    Some(dev) => dev,
+                    // the device has been detached in the meantime
+                    None => return Ok(()),
+                };
+                if !completion.is_success() {
+                    error!(
+                        "Failed to switch alternate setting of slot {}: {:?}",
+                        slot_id.value(),
+                        completion.completion_code
+                    );
+                    dev.on_reconfiguration_failed();
+                    return Err(mkerror!(ErrorType::CommandFailed(
+                        completion.command,
+                        completion.completion_code
+                    )));
                 }
+                dev.on_endpoints_reconfigured()
+                    .map_err(|e| mkerror!(ErrorType::DeviceError(e)))?;
+                self.reconfigure_endpoints(slot_id)
+            }
+        }
+    }
 
-                self.port_config_phase[port_id as usize] = ConfigPhase::InitializingDevice;
-                return self
-                    .device_manager
-                    .find_by_slot(trb.slot_id())
-                    .expect("Existence is guaranteed here")
-                    .start_initialize()
-                    .map_err(|e| mkerror!(ErrorType::DeviceError(e)));
+    fn on_slot_enabled(&mut self, port_id: u8, slot_id: SlotId) -> Result<()> {
+        if self.phase(port_id)? != ConfigPhase::EnablingSlot {
+            // the port has been disconnected or given up while enabling the slot
+            return self.disable_slot(slot_id);
+        }
+        let result = self.address_device(port_id, slot_id);
+        if result.is_err() {
+            if self.device_manager.find_by_slot(slot_id).is_none() {
+                self.disable_slot(slot_id)?;
             }
-        } else if trb
-            .issuer()
-            .specialize::<ConfigureEndpointCommandTrb>()
-            .is_some()
+            self.on_enumeration_failed(port_id)?;
+        }
+        result
+    }
+
+    fn on_device_addressed(&mut self, port_id: u8, slot_id: SlotId) -> Result<()> {
+        if self.phase(port_id)? != ConfigPhase::AddressingDevice {
+            debug!("Port {} is no longer addressing the device", port_id);
+            return Ok(());
+        }
+        self.release_default_address(port_id)?;
+        self.set_phase(port_id, ConfigPhase::InitializingDevice)?;
+        let result = self
+            .device_manager
+            .find_by_slot(slot_id)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?
+            .start_initialize()
+            .map_err(|e| mkerror!(ErrorType::DeviceError(e)));
+        if result.is_err() {
+            self.on_enumeration_failed(port_id)?;
+        }
+        result
+    }
+
+    fn on_endpoints_configured(&mut self, port_id: u8, slot_id: SlotId) -> Result<()> {
+        if self.phase(port_id)? != ConfigPhase::ConfiguringEndpoints {
+            debug!("Port {} is no longer configuring endpoints", port_id);
+            return Ok(());
+        }
+        self.set_phase(port_id, ConfigPhase::Configured)?;
+        self.port_state(port_id)?.failures = 0;
+        self.device_manager
+            .find_by_slot(slot_id)
+            .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?
+            .on_endpoints_configured()
+            .map_err(|e| mkerror!(ErrorType::DeviceError(e)))
+    }
+
+    fn on_endpoint_reset(&mut self, slot_id: SlotId, endpoint_id: EndpointId) -> Result<()> {
+        let dequeue_pointer = self
+            .device_manager
+            .find_by_slot(slot_id)
+            .and_then(|dev| dev.recovery_dequeue_pointer(endpoint_id));
+        match dequeue_pointer {
+            Some((ptr, cycle_state)) => self.push_command(
+                SetTrDequeuePointerCommandTrb::new(slot_id, endpoint_id, ptr, cycle_state),
+                Command::SetTrDequeuePointer {
+                    slot_id,
+                    endpoint_id,
+                },
+            ),
+            // the device has been detached in the meantime
+            None => Ok(()),
+        }
+    }
+
+    fn on_command_failed(&mut self, port_id: u8, completion: CommandCompletion) -> Result<()> {
+        error!(
+            "Port {}: {:?} failed with completion code {:?}",
+            port_id, completion.command, completion.completion_code
+        );
+        self.on_enumeration_failed(port_id)?;
+        Err(mkerror!(ErrorType::CommandFailed(
+            completion.command,
+            completion.completion_code
+        )))
+    }
+
+    /// Stop enumerating the port and schedule a retry. Other ports are not affected
+    fn on_enumeration_failed(&mut self, port_id: u8) -> Result<()> {
+        let state = self.port_state(port_id)?;
+        if state.phase == ConfigPhase::Failed {
+            return Ok(());
+        }
+        state.failures += 1;
+        state.retry_at = retry_delay(state.failures).map(|delay| current_tick() + delay);
+        match state.retry_at {
+            Some(_) => warn!(
+                "Enumeration of port {} failed ({} times). Retrying later",
+                port_id, state.failures
+            ),
+            None => error!("Enumeration of port {} failed. Giving up", port_id),
+        }
+        self.set_phase(port_id, ConfigPhase::Failed)?;
+
+        if let Some(slot_id) = self.device_manager.find_slot_by_port(port_id) {
+            self.disable_slot(slot_id)?;
+        }
+        self.release_default_address(port_id)
+    }
+
+    /// Retry the enumeration of the failed port if its backoff has elapsed
+    fn retry_enumeration(&mut self, port_id: u8) -> Result<()> {
+        let state = *self.port_state(port_id)?;
+        let due = matches!(state.retry_at, Some(at) if at <= current_tick());
+        // wait for the slot to be disabled
+        if !due || self.device_manager.find_slot_by_port(port_id).is_some() {
+            return Ok(());
+        }
+        self.port_state(port_id)?.retry_at = None;
+        self.set_phase(port_id, ConfigPhase::NotConnected)?;
+        let mut port = self.port_at(port_id);
+        self.configure_port(&mut port)
+    }
+
+    /// Issue Reset Endpoint Command for each endpoint of the device halted by an error
+    fn reset_halted_endpoints(&mut self, slot_id: SlotId) -> Result<()> {
+        while let Some(endpoint_id) = self
+            .device_manager
+            .find_by_slot(slot_id)
+            .and_then(|dev| dev.next_halted_endpoint())
         {
-            let dev = self
-                .device_manager
-                .find_by_slot(trb.slot_id())
-                .ok_or_else(|| mkerror!(ErrorType::InvalidSlotId))?;
-            let port_id = dev.device_context().slot_context.root_hub_port_num();
-            if self.port_config_phase[port_id as usize] == ConfigPhase::ConfiguringEndpoints {
-                self.port_config_phase[port_id as usize] = ConfigPhase::Configured;
-                return dev
-                    .on_endpoints_configured()
-                    .map_err(ErrorType::DeviceError)
-                    .map_err(|e| mkerror!(e));
+            self.push_command(
+                ResetEndpointCommandTrb::new(slot_id, endpoint_id),
+                Command::ResetEndpoint {
+                    slot_id,
+                    endpoint_id,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Must be called periodically to abort commands which xHC doesn't complete
+    pub fn on_timer(&mut self) {
+        self.command_ring.check_timeout();
+
+        for port_id in 1..=self.ports.len() as u8 {
+            let result = match self.port_state(port_id) {
+                Ok(state) if state.phase == ConfigPhase::Failed => self.retry_enumeration(port_id),
+                Ok(state)
+                    if state.phase.is_enumerating()
+                        && current_tick() - state.since > ENUMERATION_TIMEOUT_TICKS =>
+                {
+                    warn!("Port {} timed out in {:?}", port_id, state.phase);
+                    self.on_enumeration_failed(port_id)
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = result {
+                error!("Failed to handle port {}: {:?}", port_id, err);
             }
         }
-        Err(mkerror!(ErrorType::InvalidPhase))
+        self.device_manager.on_timer();
     }
 
     fn address_device(&mut self, port_id: u8, slot_id: SlotId) -> Result<()> {
@@ -246,15 +522,13 @@ impl Xhc {
             .expect("Existence is guaranteed here");
         dev.address_device(port)
             .map_err(|e| mkerror!(ErrorType::DeviceError(e)))?;
+        let input_context_ptr = dev.input_context_ptr();
 
-        self.port_config_phase[port_id as usize] = ConfigPhase::AddressingDevice;
-        self.command_ring.push(AddressDeviceCommandTrb::new(
-            slot_id,
-            dev.input_context_ptr(),
-        ));
-        self.registers.doorbell.at(0).unwrap().as_mut().ring(0, 0);
-
-        Ok(())
+        self.set_phase(port_id, ConfigPhase::AddressingDevice)?;
+        self.push_command(
+            AddressDeviceCommandTrb::new(slot_id, input_context_ptr),
+            Command::AddressDevice { port_id, slot_id },
+        )
     }
 
     fn on_port_status_change_event(&mut self, trb: &PortStatusChangeEventTrb) -> Result<()> {
@@ -262,13 +536,17 @@ impl Xhc {
         debug!("PortStatusChangeEvent: port_id = {}", port_id);
 
         let mut port = self.port_at(port_id);
+        if port.is_connect_status_changed() && !port.is_connected() {
+            port.clear_connect_status_change();
+            return self.on_port_disconnected(port_id);
+        }
 
-        match self.port_config_phase[port_id as usize] {
+        match self.phase(port_id)? {
             ConfigPhase::NotConnected => self.reset_port(&mut port),
-            ConfigPhase::ResettingPort => {
-                self.enable_slot(&mut port);
-                Ok(())
-            }
+            ConfigPhase::ResettingPort => self.enable_slot(&mut port),
+            // changes left over from the failed attempt, such as the completion of a port reset
+            // in flight, are ignored. The retry starts over from resetting the port
+            ConfigPhase::Failed => Ok(()),
             phase => {
                 debug!("port = {}, phase: {:?}", port_id, phase);
                 Err(mkerror!(ErrorType::InvalidPhase))
@@ -276,6 +554,82 @@ impl Xhc {
         }
     }
 
+    fn on_port_disconnected(&mut self, port_id: u8) -> Result<()> {
+        debug!("Port {} has been disconnected", port_id);
+        let state = self.port_state(port_id)?;
+        state.failures = 0;
+        state.retry_at = None;
+        match self.device_manager.find_slot_by_port(port_id) {
+            Some(slot_id) => {
+                self.set_phase(port_id, ConfigPhase::DisablingSlot)?;
+                self.disable_slot(slot_id)?;
+            }
+            None => self.set_phase(port_id, ConfigPhase::NotConnected)?,
+        }
+        self.release_default_address(port_id)
+    }
+
+    fn on_slot_disabled(&mut self, slot_id: SlotId) -> Result<()> {
+        let port_id = self.device_manager.find_port_by_slot(slot_id);
+        self.device_manager.remove_device(slot_id);
+
+        if let Some(port_id) = port_id {
+            if self.phase(port_id)? == ConfigPhase::DisablingSlot {
+                self.set_phase(port_id, ConfigPhase::NotConnected)?;
+                // the device may have been plugged again while disabling the slot
+                let mut port = self.port_at(port_id);
+                return self.configure_port(&mut port);
+            }
+        }
+        Ok(())
+    }
+
+    fn disable_slot(&mut self, slot_id: SlotId) -> Result<()> {
+        self.push_command(
+            DisableSlotCommandTrb::new(slot_id),
+            Command::DisableSlot { slot_id },
+        )
+    }
+
+    fn push_command<T: Trb>(&mut self, trb: T, command: Command) -> Result<()> {
+        self.command_ring
+            .push(trb, command)
+            .map_err(|e| mkerror!(ErrorType::CommandError(e)))
+    }
+
+    /// Let the next USB2 port use the default address if `port_id` has been using it
+    fn release_default_address(&mut self, port_id: u8) -> Result<()> {
+        if self.addressing_port != Some(port_id) {
+            return Ok(());
+        }
+        self.addressing_port = None;
+        for port_id in 1..=self.ports.len() as u8 {
+            if self.phase(port_id)? == ConfigPhase::WaitingAddressed {
+                let mut port = self.port_at(port_id);
+                return self.reset_port(&mut port);
+            }
+        }
+        Ok(())
+    }
+
+    fn port_state(&mut self, port_id: u8) -> Result<&mut PortState> {
+        (port_id as usize)
+            .checked_sub(1)
+            .and_then(move |i| self.ports.as_mut_slice().get_mut(i))
+            .ok_or_else(|| mkerror!(ErrorType::InvalidPortId))
+    }
+
+    fn phase(&mut self, port_id: u8) -> Result<ConfigPhase> {
+        Ok(self.port_state(port_id)?.phase)
+    }
+
+    fn set_phase(&mut self, port_id: u8, phase: ConfigPhase) -> Result<()> {
+        let state = self.port_state(port_id)?;
+        state.phase = phase;
+        state.since = current_tick();
+        Ok(())
+    }
+
     fn configure_endpoints(&mut self, slot_id: SlotId, port_id: u8) -> Result<()> {
         let port = self.port_at(port_id);
         let dev = self
@@ -286,42 +640,63 @@ impl Xhc {
         dev.configure_endpoints(port)
             .map_err(|e| mkerror!(ErrorType::DeviceError(e)))?;
 
-        self.port_config_phase[port_id as usize] = ConfigPhase::ConfiguringEndpoints;
-        self.command_ring
-            .push(ConfigureEndpointCommandTrb::new(slot_id, input_context_ptr));
-        self.registers.doorbell.at(0).unwrap().as_mut().ring(0, 0);
+        self.set_phase(port_id, ConfigPhase::ConfiguringEndpoints)?;
+        self.push_command(
+            ConfigureEndpointCommandTrb::new(slot_id, input_context_ptr),
+            Command::ConfigureEndpoint { port_id, slot_id },
+        )
+    }
 
-        Ok(())
+    /// Issue Configure Endpoint Command if the device is switching an alternate setting
+    fn reconfigure_endpoints(&mut self, slot_id: SlotId) -> Result<()> {
+        let dev = match self.device_manager.find_by_slot(slot_id) {
+            Some(dev) => dev,
+            None => return Ok(()),
+        };
+        if !dev.take_reconfiguration_request() {
+            return Ok(());
+        }
+        let input_context_ptr = dev.input_context_ptr();
+        self.push_command(
+            ConfigureEndpointCommandTrb::new(slot_id, input_context_ptr),
+            Command::Reconfigure { slot_id },
+        )
     }
 
-    fn enable_slot(&mut self, port: &mut Port) {
+    fn enable_slot(&mut self, port: &mut Port) -> Result<()> {
         if port.is_enabled() && port.is_port_reset_changed() {
             port.clear_port_reset_change();
-            self.port_config_phase[port.port_num() as usize] = ConfigPhase::EnablingSlot;
-
-            self.command_ring.push(EnableSlotCommandTrb::new());
-            self.registers.doorbell.at(0).unwrap().as_mut().ring(0, 0);
+            self.set_phase(port.port_num(), ConfigPhase::EnablingSlot)?;
+            self.push_command(
+                EnableSlotCommandTrb::new(),
+                Command::EnableSlot {
+                    port_id: port.port_num(),
+                },
+            )?;
         }
+        Ok(())
     }
 
     fn reset_port(&mut self, port: &mut Port) -> Result<()> {
-        if port.is_connected() {
+        if !port.is_connected() {
+            return Ok(());
+        }
+        let port_id = port.port_num();
+        match self.phase(port_id)? {
+            ConfigPhase::NotConnected | ConfigPhase::WaitingAddressed => {}
+            _ => return Err(mkerror!(ErrorType::InvalidPhase)),
+        }
+        // SuperSpeed links are point-to-point, so the devices never conflict on the default address
+        if !port.port_speed().map_or(false, |s| s.is_super_speed()) {
             match self.addressing_port {
-                Some(_) => {
-                    self.port_config_phase[port.port_num() as usize] =
-                        ConfigPhase::WaitingAddressed;
+                Some(other) if other != port_id => {
+                    return self.set_phase(port_id, ConfigPhase::WaitingAddressed);
                 }
-                None => match self.port_config_phase[port.port_num() as usize] {
-                    ConfigPhase::NotConnected | ConfigPhase::WaitingAddressed => {
-                        self.addressing_port = Some(port.port_num());
-                        self.port_config_phase[port.port_num() as usize] =
-                            ConfigPhase::ResettingPort;
-                        port.reset();
-                    }
-                    _ => return Err(mkerror!(ErrorType::InvalidPhase)),
-                },
+                _ => self.addressing_port = Some(port_id),
             }
         }
+        self.set_phase(port_id, ConfigPhase::ResettingPort)?;
+        port.reset();
         Ok(())
     }
 
@@ -383,6 +758,24 @@ impl Xhc {
             .update(|c| c.set_max_device_slots_enabled(max_slots));
     }
 
+    fn initialize_scratchpad_buffers(&mut self) -> Result<()> {
+        let num_buffers = self
+            .registers
+            .capability
+            .as_ref()
+            .hcsparams2
+            .read()
+            .max_scratchpad_buffers();
+        let page_size = self.registers.operational.as_ref().pagesize.read().bytes();
+        debug!(
+            "Scratchpad buffers: {}, page size: {}",
+            num_buffers, page_size
+        );
+        self.device_manager
+            .initialize_scratchpad_buffers(num_buffers as usize, page_size)
+            .map_err(|e| mkerror!(ErrorType::DeviceError(e)))
+    }
+
     fn set_dcbaap(&mut self) {
         let ptr = self.device_manager.dcbaa_ptr();
         debug!("DCBAA ptr: 0x{:x}", ptr);
@@ -393,43 +786,102 @@ impl Xhc {
             .update(|d| d.set_device_context_base_address_array_pointer(ptr));
     }
 
-    fn init_command_ring(&mut self) {
+    fn init_command_ring(&mut self) -> Result<()> {
         self.command_ring
-            .initialize(32)
-            .expect("Failed to initialize command ring");
-        let ptr = self.command_ring.buffer_pointer();
-        self.registers.operational.as_mut().crcr.update(|c| {
-            c.set_ring_cycle_state(true);
-            c.set_command_stop(false);
-            c.set_command_abort(false);
-            c.set_command_ring_pointer(ptr);
-        });
+            .initialize(
+                self.registers.operational,
+                self.registers.doorbell.at(0).unwrap(),
+            )
+            .map_err(|e| mkerror!(ErrorType::CommandError(e)))
     }
 
-    fn init_event_ring(&mut self) {
-        self.event_ring
-            .initialize(32, self.registers.interrupter_register_set.at(0).unwrap())
-            .expect("Failed to initialize event ring");
+    fn init_port_states(&mut self) {
+        self.ports = ArrayVec::new();
+        for _ in 0..self.max_ports() {
+            // MAX_PORTS covers every port number
+            let _ = self.ports.push(PortState::default());
+        }
     }
 
-    fn init_interrupter(&mut self) {
-        self.registers
-            .interrupter_register_set
-            .at(0)
-            .unwrap()
-            .as_mut()
-            .iman
-            .update(|iman| {
+    fn init_interrupters(&mut self, configs: &[InterrupterConfig]) -> Result<()> {
+        let capability = self.registers.capability.as_ref();
+        let max_interrupters = capability.hcsparams1.read().max_interrupters() as usize;
+        let max_segments = capability.hcsparams2.read().max_event_ring_segments();
+        let num_interrupters = configs
+            .len()
+            .min(max_interrupters)
+            .min(MAX_INTERRUPTERS)
+            .max(1);
+        debug!(
+            "Interrupters: {} (max {}), max event ring segments: {}",
+            num_interrupters, max_interrupters, max_segments
+        );
+
+        for i in 0..num_interrupters {
+            let config = configs.get(i).copied().unwrap_or_default();
+            let mut interrupter = self.registers.interrupter_register_set.at(i).unwrap();
+            let mut event_ring = EventRing::new();
+            event_ring
+                .initialize(
+                    config.num_segments.min(max_segments).max(1),
+                    config.segment_len,
+                    interrupter,
+                )
+                .map_err(|e| mkerror!(ErrorType::TrbError(e)))?;
+            // capacity is checked above so never fails
+            let _ = self.event_rings.push(event_ring);
+
+            let reg = interrupter.as_mut();
+            reg.imod.update(|imod| {
+                imod.set_interrupt_moderation_interval(config.moderation_interval);
+                imod.set_interrupt_moderation_counter(0);
+            });
+            reg.iman.update(|iman| {
                 iman.set_interrupt_pending(true);
                 iman.set_interrupt_enable(true);
             });
+        }
+        self.device_manager.set_num_interrupters(num_interrupters);
 
         self.registers.operational.as_mut().usbcmd.update(|u| {
             u.set_interrupter_enable(true);
         });
+        Ok(())
+    }
+}
+
+/// Configuration of an interrupter and its event ring
+#[derive(Debug, Copy, Clone)]
+pub struct InterrupterConfig {
+    /// Number of event ring segments. Limited by ERST Max of xHC
+    pub num_segments: usize,
+    /// Number of TRBs in each segment, from 16 to 4096
+    pub segment_len: usize,
+    /// Minimum interval between interrupts in 250ns units. 0 disables the moderation
+    pub moderation_interval: u16,
+}
+
+impl Default for InterrupterConfig {
+    fn default() -> Self {
+        Self {
+            num_segments: 2,
+            segment_len: 64,
+            // 1ms
+            moderation_interval: 4000,
+        }
     }
 }
 
+#[derive(Debug, Copy, Clone, Default)]
+struct PortState {
+    phase: ConfigPhase,
+    /// The tick when the port entered the phase
+    since: u64,
+    /// Number of failed enumeration attempts in a row
+    failures: u8,
+    retry_at: Option<u64>,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum ConfigPhase {
     NotConnected,
@@ -440,4 +892,481 @@ enum ConfigPhase {
     InitializingDevice,
     ConfiguringEndpoints,
     Configured,
+    DisablingSlot,
+    /// Enumeration has failed. Waiting for the retry or the device to be detached
+    Failed,
+}
+
+impl Default for ConfigPhase {
+    fn default() -> Self {
+        ConfigPhase::NotConnected
+    }
+}
+
+impl ConfigPhase {
+    /// Whether the port is expected to proceed to the next phase by itself
+    fn is_enumerating(&self) -> bool {
+        matches!(
+            self,
+            ConfigPhase::ResettingPort
+                | ConfigPhase::EnablingSlot
+                | ConfigPhase::AddressingDevice
+                | ConfigPhase::InitializingDevice
+                | ConfigPhase::ConfiguringEndpoints
+        )
+    }
+}
+
+/// Backoff before the next enumeration attempt, or `None` if the port should be given up
+fn retry_delay(failures: u8) -> Option<u64> {
+    if failures >= MAX_ENUMERATION_ATTEMPTS {
+        None
+    } else {
+        Some(RETRY_BACKOFF_TICKS << (failures - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::classdriver::{cdc, set_default_mouse_observer, MouseEvent};
+    use crate::usb::command::COMMAND_TIMEOUT_TICKS;
+    use crate::usb::mem::{self, exclusive_pool};
+    use crate::usb::mock::{MockDevice, MockXhc};
+    use crate::usb::trb::{AddressDeviceCommandTrb, SetupData, Trb};
+    use crate::usb::{
+        retry_delay, ConfigPhase, InterrupterConfig, SlotId, Xhc, RETRY_BACKOFF_TICKS,
+    };
+    use core::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+
+    const HIGH_SPEED: u8 = 3;
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x27, 0x06, 0x01, 0x00, 0x00, 0x01, 1, 2, 0, 1,
+    ];
+
+    const MOUSE_CONFIGURATION: [u8; 34] = [
+        9, 2, 34, 0, 1, 1, 0, 0xa0, 50, // configuration
+        9, 4, 0, 0, 1, 3, 1, 2, 0, // interface: HID boot mouse
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 52, 0, // HID
+        7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN, interrupt
+    ];
+
+    const SERIAL_CONFIGURATION: [u8; 67] = [
+        9, 2, 67, 0, 2, 1, 0, 0x80, 50, // configuration
+        9, 4, 0, 0, 1, 2, 2, 1, 0, // interface: CDC-ACM
+        5, 0x24, 0, 0x10, 0x01, // header
+        5, 0x24, 1, 0, 1, // call management
+        4, 0x24, 2, 2, // ACM
+        5, 0x24, 6, 0, 1, // union
+        7, 5, 0x83, 3, 16, 0, 16, // endpoint 3 IN, interrupt
+        9, 4, 1, 0, 2, 0x0a, 0, 0, 0, // interface: CDC data
+        7, 5, 0x02, 2, 0x00, 0x02, 0, // endpoint 2 OUT, bulk
+        7, 5, 0x81, 2, 0x00, 0x02, 0, // endpoint 1 IN, bulk
+    ];
+
+    const KEYBOARD_CONFIGURATION: [u8; 34] = [
+        9, 2, 34, 0, 1, 1, 0, 0xa0, 50, // configuration
+        9, 4, 0, 0, 1, 3, 1, 1, 0, // interface: HID boot keyboard
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0, // HID
+        7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN, interrupt
+    ];
+
+    fn mouse() -> MockDevice {
+        let mut device = MockDevice::new(HIGH_SPEED, &DEVICE_DESCRIPTOR, &MOUSE_CONFIGURATION);
+        device.strings = vec!["rumikan", "Mouse"];
+        device
+    }
+
+    fn start(mock: &MockXhc, num_interrupters: usize) -> Xhc {
+        let mut xhc = Xhc::new(mock.mmio_base());
+        xhc.initialize(&vec![InterrupterConfig::default(); num_interrupters])
+            .unwrap();
+        xhc.run();
+        xhc
+    }
+
+    /// Process the events until all interrupters get empty. Returns the number of errors
+    fn run_until_idle(xhc: &mut Xhc) -> usize {
+        let mut errors = 0;
+        loop {
+            match xhc.poll() {
+                Ok(Some(())) => {}
+                Ok(None) => return errors,
+                Err(_) => errors += 1,
+            }
+        }
+    }
+
+    static MOUSE_X: AtomicI32 = AtomicI32::new(0);
+    static MOUSE_Y: AtomicI32 = AtomicI32::new(0);
+    static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+    fn on_mouse_event(event: MouseEvent) {
+        MOUSE_X.fetch_add(event.dx as i32, Ordering::SeqCst);
+        MOUSE_Y.fetch_add(event.dy as i32, Ordering::SeqCst);
+        MOUSE_BUTTONS.store(event.buttons, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn retry_backoff() {
+        assert_eq!(retry_delay(1), Some(100));
+        assert_eq!(retry_delay(2), Some(200));
+        assert_eq!(retry_delay(3), Some(400));
+        assert_eq!(retry_delay(4), None);
+    }
+
+    #[repr(C, align(4096))]
+    struct Page([u8; 4096]);
+
+    #[test]
+    fn scratchpad_buffers_from_added_region() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        // more pages than the built-in pool has
+        mock.set_max_scratchpad_buffers(64);
+        let mut xhc = Xhc::new(mock.mmio_base());
+        assert!(xhc.initialize(&[InterrupterConfig::default()]).is_err());
+        // the buffers allocated until the pool runs out are freed
+        let used_bytes = mem::stats().used_bytes;
+        assert!(xhc
+            .device_manager
+            .initialize_scratchpad_buffers(64, 4096)
+            .is_err());
+        assert_eq!(mem::stats().used_bytes, used_bytes);
+
+        // as the kernel adds a region at boot
+        let region = (0..128).map(|_| Page([0; 4096])).collect::<Vec<_>>().leak();
+        mem::add_region(region.as_mut_ptr() as *mut u8, 4096 * 128).unwrap();
+        let mut mock = MockXhc::new();
+        mock.set_max_scratchpad_buffers(64);
+        let mut xhc = start(&mock, 1);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        mock.attach(1, mouse());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+    }
+
+    #[test]
+    fn enumerate_mouse() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        mock.attach(1, mouse());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+
+        let requests: Vec<(u8, u16)> = mock
+            .device(1)
+            .requests
+            .iter()
+            .map(|r| (r.request, r.value))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0100),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0300),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0301),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0302),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0200),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0200),
+                (SetupData::REQUEST_SET_CONFIGURATION, 1),
+                (SetupData::REQUEST_SET_PROTOCOL, 0),
+            ]
+        );
+        let info = crate::usb::inventory::inventory()
+            .find(|info| info.port_id == 1)
+            .unwrap();
+        assert_eq!(info.product.to_string(), "Mouse");
+
+        // endpoint 1 IN
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+        set_default_mouse_observer(on_mouse_event);
+        let (x, y) = (
+            MOUSE_X.load(Ordering::SeqCst),
+            MOUSE_Y.load(Ordering::SeqCst),
+        );
+        assert!(mock.complete_in(1, 3, &[MouseEvent::BUTTON_LEFT, 5, -3i8 as u8]));
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(MOUSE_X.load(Ordering::SeqCst) - x, 5);
+        assert_eq!(MOUSE_Y.load(Ordering::SeqCst) - y, -3);
+        assert_eq!(
+            MOUSE_BUTTONS.load(Ordering::SeqCst),
+            MouseEvent::BUTTON_LEFT
+        );
+        // re-armed
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+
+        mock.detach(1);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::NotConnected);
+        assert_eq!(mock.num_slots(), 0);
+    }
+
+    #[test]
+    fn reattach_after_disconnect() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        let used_bytes = mem::stats().used_bytes;
+
+        mock.attach(1, mouse());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert!(mem::stats().used_bytes > used_bytes);
+
+        mock.detach(1);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::NotConnected);
+        assert_eq!(mock.num_slots(), 0);
+        assert!(xhc.device_manager.find_by_slot(SlotId::new(1)).is_none());
+        assert_eq!(mem::stats().used_bytes, used_bytes);
+
+        mock.attach(1, mouse());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert_eq!(mock.num_slots(), 1);
+        // the new device has been enumerated from the device descriptor
+        let requests = &mock.device(1).requests;
+        assert_eq!(
+            (requests[0].request, requests[0].value),
+            (SetupData::REQUEST_GET_DESCRIPTOR, 0x0100)
+        );
+        assert!(requests
+            .iter()
+            .any(|r| r.request == SetupData::REQUEST_SET_CONFIGURATION));
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+    }
+
+    #[test]
+    fn long_configuration() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        // the mouse with vendor specific descriptors, longer than the initial data buffer
+        let mut configuration = MOUSE_CONFIGURATION[..27].to_vec();
+        for _ in 0..28 {
+            configuration.extend_from_slice(&[9, 0x41, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        configuration.extend_from_slice(&MOUSE_CONFIGURATION[27..]);
+        let total_length = configuration.len() as u16;
+        assert!(total_length > 256);
+        configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+        let mut device = MockDevice::new(HIGH_SPEED, &DEVICE_DESCRIPTOR, &configuration);
+        device.strings = vec!["rumikan", "Mouse"];
+
+        mock.attach(1, device);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+        // the header first, then the whole of the configuration
+        let lengths: Vec<u16> = mock
+            .device(1)
+            .requests
+            .iter()
+            .filter(|r| r.request == SetupData::REQUEST_GET_DESCRIPTOR && r.value == 0x0200)
+            .map(|r| r.length)
+            .collect();
+        assert_eq!(lengths, vec![9, total_length]);
+    }
+
+    #[test]
+    fn retry_after_stall() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        let mut device = mouse();
+        device
+            .stalls
+            .push((SetupData::REQUEST_GET_DESCRIPTOR, 0x0200));
+        mock.attach(1, device);
+        assert!(run_until_idle(&mut xhc) > 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Failed);
+        assert_eq!(mock.num_slots(), 0);
+
+        // not retried until the backoff elapses
+        xhc.on_timer();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Failed);
+
+        for _ in 0..RETRY_BACKOFF_TICKS {
+            crate::timer::on_interrupt();
+        }
+        xhc.on_timer();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert_eq!(xhc.port_state(1).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn abort_timed_out_command() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        // Enable Slot Command
+        mock.stuck_commands = 1;
+        mock.attach(1, mouse());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::EnablingSlot);
+
+        // not aborted until the timeout elapses
+        for _ in 0..COMMAND_TIMEOUT_TICKS - 1 {
+            crate::timer::on_interrupt();
+        }
+        xhc.on_timer();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::EnablingSlot);
+
+        crate::timer::on_interrupt();
+        xhc.on_timer();
+        assert!(run_until_idle(&mut xhc) > 0);
+        assert_eq!(mock.stuck_commands, 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Failed);
+
+        // the ring has been resumed, so the retry goes through
+        for _ in 0..RETRY_BACKOFF_TICKS {
+            crate::timer::on_interrupt();
+        }
+        xhc.on_timer();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+    }
+
+    #[test]
+    fn command_failure_doesnt_block_other_ports() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        // USB transaction error
+        mock.command_failures
+            .push((AddressDeviceCommandTrb::TYPE, 4));
+        mock.attach(1, mouse());
+        mock.attach(2, mouse());
+        assert!(run_until_idle(&mut xhc) > 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Failed);
+        assert_eq!(xhc.phase(2).unwrap(), ConfigPhase::Configured);
+        assert_eq!(mock.num_slots(), 1);
+    }
+
+    #[test]
+    fn select_configuration() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        let mut device = MockDevice::new(HIGH_SPEED, &DEVICE_DESCRIPTOR, &KEYBOARD_CONFIGURATION);
+        // bNumConfigurations
+        device.device_descriptor[17] = 2;
+        let mut second = MOUSE_CONFIGURATION;
+        // bConfigurationValue
+        second[5] = 2;
+        device.configurations.push(second.to_vec());
+        mock.attach(1, device);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+
+        let requests: Vec<(u8, u16)> = mock
+            .device(1)
+            .requests
+            .iter()
+            .map(|r| (r.request, r.value))
+            .skip_while(|&(_, value)| value >> 8 != 2)
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0200),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0200),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0201),
+                (SetupData::REQUEST_GET_DESCRIPTOR, 0x0201),
+                (SetupData::REQUEST_SET_CONFIGURATION, 2),
+                (SetupData::REQUEST_SET_PROTOCOL, 0),
+            ]
+        );
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+    }
+
+    #[test]
+    fn switch_alternate_setting() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 1);
+        let mut device = mouse();
+        let mut configuration = MOUSE_CONFIGURATION.to_vec();
+        configuration.extend_from_slice(&[
+            9, 4, 0, 1, 2, 3, 1, 2, 0, // alternate setting 1
+            7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN, interrupt
+            7, 5, 0x02, 3, 8, 0, 10, // endpoint 2 OUT, interrupt
+        ]);
+        configuration[2] = configuration.len() as u8;
+        device.configurations[0] = configuration;
+        mock.attach(1, device);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert!(!mock.is_enabled(1, 4));
+
+        // the first slot enabled by the mock
+        let slot_id = SlotId::new(1);
+        let dev = xhc.device_manager.find_by_slot(slot_id).unwrap();
+        assert!(dev.set_interface(0, 2).is_err());
+        dev.set_interface(0, 1).unwrap();
+        assert!(dev.set_interface(0, 0).is_err());
+        assert_eq!(run_until_idle(&mut xhc), 0);
+
+        let set_interface = mock.device(1).requests.last().unwrap();
+        assert_eq!(
+            (
+                set_interface.request,
+                set_interface.value,
+                set_interface.index
+            ),
+            (SetupData::REQUEST_SET_INTERFACE, 1, 0)
+        );
+        // endpoint 1 IN has been re-added and re-armed, and endpoint 2 OUT has been added
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+        assert!(mock.is_enabled(1, 4));
+
+        // back to the default setting
+        let dev = xhc.device_manager.find_by_slot(slot_id).unwrap();
+        dev.set_interface(0, 0).unwrap();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(mock.pending_in(1, 3), Some(3));
+        assert!(!mock.is_enabled(1, 4));
+    }
+
+    #[test]
+    fn serial_transfer() {
+        let _pool = exclusive_pool();
+        let mut mock = MockXhc::new();
+        let mut xhc = start(&mock, 3);
+        mock.attach(
+            1,
+            MockDevice::new(HIGH_SPEED, &DEVICE_DESCRIPTOR, &SERIAL_CONFIGURATION),
+        );
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(xhc.phase(1).unwrap(), ConfigPhase::Configured);
+        assert!(cdc::is_ready());
+        let requests = &mock.device(1).requests;
+        let line_coding = requests
+            .iter()
+            .find(|r| r.request == SetupData::REQUEST_SET_LINE_CODING)
+            .unwrap();
+        assert_eq!(line_coding.data, vec![0x00, 0xc2, 0x01, 0x00, 0, 0, 8]);
+
+        // endpoint 1 IN
+        assert!(mock.complete_in(1, 3, b"hello"));
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        let mut buf = [0u8; 16];
+        assert_eq!(cdc::read(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        assert_eq!(cdc::write(b"world"), 5);
+        xhc.on_timer();
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert_eq!(mock.device(1).received, b"world");
+
+        mock.detach(1);
+        assert_eq!(run_until_idle(&mut xhc), 0);
+        assert!(!cdc::is_ready());
+    }
 }
@@ -19,6 +19,10 @@ impl PortSpeed {
     const SUPER_SPEED: u8 = 4;
     const SUPER_SPEED_PLUS: u8 = 5;
 
+    pub fn is_super_speed(&self) -> bool {
+        matches!(self, PortSpeed::SuperSpeed | PortSpeed::_SuperSpeedPlus)
+    }
+
     pub fn convert_interval(&self, endpoint_type: EndpointType, interval: u32) -> u32 {
         match &self {
             PortSpeed::FullSpeed | PortSpeed::LowSpeed => match endpoint_type {
@@ -82,6 +86,10 @@ impl Port {
         self.reg.as_ref().portsc.read().port_enabled_disabled()
     }
 
+    pub fn is_connect_status_changed(&self) -> bool {
+        self.reg.as_ref().portsc.read().connect_status_change()
+    }
+
     pub fn is_port_reset_changed(&self) -> bool {
         self.reg.as_ref().portsc.read().port_reset_change()
     }
@@ -98,6 +106,13 @@ impl Port {
     pub fn clear_port_reset_change(&mut self) {
         self.reg.as_mut().portsc.update(|s| s.clear_status_bit());
     }
+
+    pub fn clear_connect_status_change(&mut self) {
+        self.reg
+            .as_mut()
+            .portsc
+            .update(|s| s.clear_connect_status_change());
+    }
 }
 
 #[cfg(test)]
@@ -11,6 +11,8 @@ pub type Result<T> = core::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum ErrorType {
     AllocError(crate::usb::mem::Error),
+    CollectionError(crate::util::collection::CollectionError),
+    RingFull,
 }
 
 /// A trait must be implemented by all TRB structs
@@ -37,6 +39,7 @@ pub struct GenericTrb {
 impl GenericTrb {
     getbit!(pub cycle_bit; data; 96);
     setbit!(pub set_cycle_bit; data; 96);
+    getbit!(pub chain_bit; data; 100);
     getbits!(pub trb_type: u8; data; 106; 6);
 
     pub fn specialize<T: Trb>(&self) -> Option<&T> {
@@ -66,7 +69,10 @@ impl NormalTrb {
     withbits!(pub with_pointer: u64; data; 0; 64);
     getbits!(pub transfer_length: u32; data; 64; 17);
     withbits!(pub with_transfer_length: u32; data; 64; 17);
+    withbits!(pub with_td_size: u8; data; 81; 5);
+    withbits!(pub with_interrupter_target: u16; data; 86; 10);
     withbit!(pub with_interrupt_on_short_packet; data; 98);
+    withbit!(pub with_chain_bit; data; 100);
     withbit!(pub with_interrupt_on_completion; data; 101);
     setbits!(set_trb_type: u8; data; 106; 6);
 
@@ -77,6 +83,37 @@ impl NormalTrb {
     }
 }
 
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct IsochTrb {
+    data: u128,
+}
+
+impl Trb for IsochTrb {
+    const TYPE: u8 = 5;
+}
+
+impl IsochTrb {
+    withbits!(pub with_pointer: u64; data; 0; 64);
+    withbits!(pub with_transfer_length: u32; data; 64; 17);
+    withbits!(pub with_td_size: u8; data; 81; 5);
+    withbits!(pub with_interrupter_target: u16; data; 86; 10);
+    withbit!(pub with_interrupt_on_short_packet; data; 98);
+    withbit!(pub with_chain_bit; data; 100);
+    withbit!(pub with_interrupt_on_completion; data; 101);
+    withbits!(pub with_transfer_burst_count: u8; data; 103; 2);
+    setbits!(set_trb_type: u8; data; 106; 6);
+    withbits!(pub with_transfer_last_burst_packet_count: u8; data; 112; 4);
+    withbits!(pub with_frame_id: u16; data; 116; 11);
+    withbit!(pub with_start_isoch_asap; data; 127);
+
+    pub fn new() -> Self {
+        let mut trb = Self { data: 0 };
+        trb.set_trb_type(Self::TYPE);
+        trb
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct SetupStageTrb {
@@ -92,15 +129,10 @@ impl SetupStageTrb {
     pub const TRANSFER_TYPE_OUT_DATA_STAGE: u8 = 2;
     pub const TRANSFER_TYPE_IN_DATA_STAGE: u8 = 3;
 
-    getbits!(request_type: u8; data; 0; 8);
     setbits!(set_request_type: u8; data; 0; 8);
-    getbits!(request: u8; data; 8; 8);
     setbits!(set_request: u8; data; 8; 8);
-    getbits!(value: u16; data; 16; 16);
     setbits!(set_value: u16; data; 16; 16);
-    getbits!(index: u16; data; 32; 16);
     setbits!(set_index: u16; data; 32; 16);
-    getbits!(length: u16; data; 48; 16);
     setbits!(set_length: u16; data; 48; 16);
     setbits!(set_trb_transfer_length: u32; data; 64; 17);
     setbit!(set_immediate_data; data; 102);
@@ -112,17 +144,6 @@ impl SetupStageTrb {
         trb.set_trb_type(Self::TYPE);
         trb
     }
-
-    pub fn setup_data(&self) -> SetupData {
-        SetupData::new()
-            .with_request_type(RequestType {
-                data: self.request_type(),
-            })
-            .with_request(self.request())
-            .with_value(self.value())
-            .with_index(self.index())
-            .with_length(self.length())
-    }
 }
 
 #[repr(transparent)]
@@ -136,11 +157,9 @@ impl Trb for DataStageTrb {
 }
 
 impl DataStageTrb {
-    getbits!(pub data_buffer_pointer: u64; data; 0; 64);
     withbits!(pub with_data_buffer_pointer: u64; data; 0; 64);
-    getbits!(pub trb_transfer_length: u32; data; 64; 17);
     withbits!(pub with_trb_transfer_length: u32; data; 64; 17);
-    withbit!(pub with_interrupt_on_completion; data; 101);
+    withbit!(pub with_interrupt_on_short_packet; data; 98);
     setbits!(set_trb_type: u8; data; 106; 6);
     withbit!(pub with_direction_in; data; 112);
 
@@ -185,14 +204,14 @@ impl Trb for LinkTrb {
 
 impl LinkTrb {
     setbits!(set_ring_segment_pointer: u64; data; 4; 60);
-    setbit!(set_toggle_cycle; data; 97);
+    withbit!(pub with_toggle_cycle; data; 97);
+    withbit!(pub with_chain_bit; data; 100);
     setbits!(set_trb_type: u8; data; 106; 6);
 
     pub fn new(ring_segment_pointer: u64) -> Self {
         let mut trb = Self { data: 0 };
         trb.set_trb_type(Self::TYPE);
         trb.set_ring_segment_pointer(ring_segment_pointer >> 4);
-        trb.set_toggle_cycle(true);
         trb
     }
 }
@@ -217,6 +236,28 @@ impl EnableSlotCommandTrb {
     }
 }
 
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct DisableSlotCommandTrb {
+    data: u128,
+}
+
+impl Trb for DisableSlotCommandTrb {
+    const TYPE: u8 = 10;
+}
+
+impl DisableSlotCommandTrb {
+    setbits!(set_trb_type: u8; data; 106; 6);
+    setbits!(set_slot_id: u8; data; 120; 8);
+
+    pub fn new(slot_id: SlotId) -> Self {
+        let mut trb = Self { data: 0 };
+        trb.set_trb_type(Self::TYPE);
+        trb.set_slot_id(slot_id.value());
+        trb
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct AddressDeviceCommandTrb {
@@ -265,6 +306,63 @@ impl ConfigureEndpointCommandTrb {
     }
 }
 
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct ResetEndpointCommandTrb {
+    data: u128,
+}
+
+impl Trb for ResetEndpointCommandTrb {
+    const TYPE: u8 = 14;
+}
+
+impl ResetEndpointCommandTrb {
+    setbits!(set_trb_type: u8; data; 106; 6);
+    setbits!(set_endpoint_id: u8; data; 112; 5);
+    setbits!(set_slot_id: u8; data; 120; 8);
+
+    pub fn new(slot_id: SlotId, endpoint_id: EndpointId) -> Self {
+        let mut trb = Self { data: 0 };
+        trb.set_trb_type(Self::TYPE);
+        trb.set_endpoint_id(endpoint_id.address());
+        trb.set_slot_id(slot_id.value());
+        trb
+    }
+}
+
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct SetTrDequeuePointerCommandTrb {
+    data: u128,
+}
+
+impl Trb for SetTrDequeuePointerCommandTrb {
+    const TYPE: u8 = 16;
+}
+
+impl SetTrDequeuePointerCommandTrb {
+    setbit!(set_dequeue_cycle_state; data; 0);
+    setbits!(set_dequeue_pointer: u64; data; 4; 60);
+    setbits!(set_trb_type: u8; data; 106; 6);
+    setbits!(set_endpoint_id: u8; data; 112; 5);
+    setbits!(set_slot_id: u8; data; 120; 8);
+
+    pub fn new(
+        slot_id: SlotId,
+        endpoint_id: EndpointId,
+        dequeue_pointer: u64,
+        cycle_state: bool,
+    ) -> Self {
+        let mut trb = Self { data: 0 };
+        trb.set_trb_type(Self::TYPE);
+        trb.set_dequeue_pointer(dequeue_pointer >> 4);
+        trb.set_dequeue_cycle_state(cycle_state);
+        trb.set_endpoint_id(endpoint_id.address());
+        trb.set_slot_id(slot_id.value());
+        trb
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct TransferEventTrb {
@@ -278,7 +376,7 @@ impl Trb for TransferEventTrb {
 impl TransferEventTrb {
     getbits!(pub issuer_pointer: u64; data; 0; 64);
     getbits!(pub transfer_length: u32; data; 64; 24);
-    getbits!(pub completion_code: u8; data; 88; 8);
+    getbits!(_completion_code: u8; data; 88; 8);
     getbits!(_endpoint_id: u8; data; 112; 5);
     getbits!(_slot_id: u8; data; 120; 8);
 
@@ -286,6 +384,10 @@ impl TransferEventTrb {
         SlotId::new(self._slot_id())
     }
 
+    pub fn completion_code(&self) -> CompletionCode {
+        CompletionCode::from(self._completion_code())
+    }
+
     pub fn issuer_trb(&self) -> &GenericTrb {
         unsafe { &*(self.issuer_pointer() as *const GenericTrb) }
     }
@@ -307,10 +409,19 @@ impl Trb for CommandCompletionEventTrb {
 
 impl CommandCompletionEventTrb {
     getbits!(_trb_pointer: u64; data; 4; 60);
+    getbits!(_completion_code: u8; data; 88; 8);
     getbits!(_slot_id: u8; data; 120; 8);
 
+    pub fn issuer_pointer(&self) -> u64 {
+        self._trb_pointer() << 4
+    }
+
+    pub fn completion_code(&self) -> CompletionCode {
+        CompletionCode::from(self._completion_code())
+    }
+
     pub fn issuer(&self) -> &GenericTrb {
-        unsafe { &*((self._trb_pointer() << 4) as *const GenericTrb) }
+        unsafe { &*(self.issuer_pointer() as *const GenericTrb) }
     }
 
     pub fn slot_id(&self) -> SlotId {
@@ -332,33 +443,146 @@ impl PortStatusChangeEventTrb {
     getbits!(pub port_id: u8; data; 24; 8);
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+/// Completion status of Transfer Event and Command Completion Event
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CompletionCode {
+    Invalid,
+    Success,
+    DataBufferError,
+    BabbleDetected,
+    UsbTransactionError,
+    TrbError,
+    StallError,
+    ResourceError,
+    BandwidthError,
+    NoSlotsAvailable,
+    InvalidStreamType,
+    SlotNotEnabled,
+    EndpointNotEnabled,
+    ShortPacket,
+    RingUnderrun,
+    RingOverrun,
+    VfEventRingFull,
+    ParameterError,
+    BandwidthOverrun,
+    ContextStateError,
+    NoPingResponse,
+    EventRingFull,
+    IncompatibleDevice,
+    MissedService,
+    CommandRingStopped,
+    CommandAborted,
+    Stopped,
+    StoppedLengthInvalid,
+    StoppedShortPacket,
+    MaxExitLatencyTooLarge,
+    IsochBufferOverrun,
+    EventLost,
+    UndefinedError,
+    InvalidStreamId,
+    SecondaryBandwidthError,
+    SplitTransactionError,
+    /// Reserved or vendor defined code
+    Other(u8),
+}
+
+impl CompletionCode {
+    /// Short packet is also a success for transfers
+    pub fn is_success(&self) -> bool {
+        matches!(self, CompletionCode::Success | CompletionCode::ShortPacket)
+    }
+
+    /// xHC transitions the endpoint to Halted state on these errors.
+    /// The endpoint doesn't work until it is reset by Reset Endpoint Command
+    pub fn halts_endpoint(&self) -> bool {
+        matches!(
+            self,
+            CompletionCode::BabbleDetected
+                | CompletionCode::UsbTransactionError
+                | CompletionCode::StallError
+                | CompletionCode::SplitTransactionError
+        )
+    }
+}
+
+impl From<u8> for CompletionCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => CompletionCode::Invalid,
+            1 => CompletionCode::Success,
+            2 => CompletionCode::DataBufferError,
+            3 => CompletionCode::BabbleDetected,
+            4 => CompletionCode::UsbTransactionError,
+            5 => CompletionCode::TrbError,
+            6 => CompletionCode::StallError,
+            7 => CompletionCode::ResourceError,
+            8 => CompletionCode::BandwidthError,
+            9 => CompletionCode::NoSlotsAvailable,
+            10 => CompletionCode::InvalidStreamType,
+            11 => CompletionCode::SlotNotEnabled,
+            12 => CompletionCode::EndpointNotEnabled,
+            13 => CompletionCode::ShortPacket,
+            14 => CompletionCode::RingUnderrun,
+            15 => CompletionCode::RingOverrun,
+            16 => CompletionCode::VfEventRingFull,
+            17 => CompletionCode::ParameterError,
+            18 => CompletionCode::BandwidthOverrun,
+            19 => CompletionCode::ContextStateError,
+            20 => CompletionCode::NoPingResponse,
+            21 => CompletionCode::EventRingFull,
+            22 => CompletionCode::IncompatibleDevice,
+            23 => CompletionCode::MissedService,
+            24 => CompletionCode::CommandRingStopped,
+            25 => CompletionCode::CommandAborted,
+            26 => CompletionCode::Stopped,
+            27 => CompletionCode::StoppedLengthInvalid,
+            28 => CompletionCode::StoppedShortPacket,
+            29 => CompletionCode::MaxExitLatencyTooLarge,
+            31 => CompletionCode::IsochBufferOverrun,
+            32 => CompletionCode::EventLost,
+            33 => CompletionCode::UndefinedError,
+            34 => CompletionCode::InvalidStreamId,
+            35 => CompletionCode::SecondaryBandwidthError,
+            36 => CompletionCode::SplitTransactionError,
+            code => CompletionCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
 #[repr(transparent)]
 pub struct SetupData {
     data: u64,
 }
 
 impl SetupData {
+    pub const REQUEST_CLEAR_FEATURE: u8 = 1;
     pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
     pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+    pub const REQUEST_SET_INTERFACE: u8 = 11;
     pub const REQUEST_SET_PROTOCOL: u8 = 11;
+    pub const REQUEST_SET_LINE_CODING: u8 = 0x20;
+    pub const REQUEST_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+    pub const FEATURE_ENDPOINT_HALT: u16 = 0;
 
     getbits!(request_type: u8; data; 0; 8);
     withbits!(_with_request_type: u8; data; 0; 8);
+    getbit!(pub is_device_to_host; data; 7);
     getbits!(pub request: u8; data; 8; 8);
     withbits!(pub with_request: u8; data; 8; 8);
-    getbits!(value: u16; data; 16; 16);
+    getbits!(pub value: u16; data; 16; 16);
     withbits!(pub with_value: u16; data; 16; 16);
-    getbits!(index: u16; data; 32; 16);
+    getbits!(pub index: u16; data; 32; 16);
     withbits!(pub with_index: u16; data; 32; 16);
-    getbits!(length: u16; data; 48; 16);
+    getbits!(pub length: u16; data; 48; 16);
     withbits!(pub with_length: u16; data; 48; 16);
 
     pub fn new() -> Self {
-        Self { data: 0 }
+        Self::default()
     }
 
-    pub fn trb(&self, transfer_type: u8) -> SetupStageTrb {
+    pub(crate) fn trb(&self, transfer_type: u8) -> SetupStageTrb {
         let mut trb = SetupStageTrb::new();
 
         trb.set_trb_transfer_length(8);
@@ -378,7 +602,7 @@ impl SetupData {
 }
 
 #[repr(transparent)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Default)]
 pub struct RequestType {
     data: u8,
 }
@@ -389,6 +613,7 @@ impl RequestType {
 
     pub const RECIPIENT_DEVICE: u8 = 0;
     pub const RECIPIENT_INTERFACE: u8 = 1;
+    pub const RECIPIENT_ENDPOINT: u8 = 2;
 
     pub const DIRECTION_HOST_TO_DEVICE: bool = false;
     pub const DIRECTION_DEVICE_TO_HOST: bool = true;
@@ -398,6 +623,48 @@ impl RequestType {
     withbit!(pub with_direction; data; 7);
 
     pub fn new() -> Self {
-        Self { data: 0 }
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::endpoint::EndpointId;
+    use crate::usb::trb::{CompletionCode, SetTrDequeuePointerCommandTrb, Trb};
+    use crate::usb::SlotId;
+
+    #[test]
+    fn completion_code() {
+        assert_eq!(CompletionCode::from(1), CompletionCode::Success);
+        assert_eq!(CompletionCode::from(6), CompletionCode::StallError);
+        assert_eq!(
+            CompletionCode::from(36),
+            CompletionCode::SplitTransactionError
+        );
+        assert_eq!(CompletionCode::from(30), CompletionCode::Other(30));
+        assert_eq!(CompletionCode::from(192), CompletionCode::Other(192));
+
+        assert!(CompletionCode::ShortPacket.is_success());
+        assert!(!CompletionCode::StallError.is_success());
+        assert!(CompletionCode::BabbleDetected.halts_endpoint());
+        assert!(!CompletionCode::DataBufferError.halts_endpoint());
+    }
+
+    #[test]
+    fn set_tr_dequeue_pointer_command() {
+        let trb = SetTrDequeuePointerCommandTrb::new(
+            SlotId::new(3),
+            EndpointId::new(5),
+            0x1234_5670,
+            true,
+        );
+        let data = trb.generalize().data();
+        assert_eq!(data as u64, 0x1234_5671);
+        assert_eq!(
+            (data >> 106) as u8 & 0x3f,
+            SetTrDequeuePointerCommandTrb::TYPE
+        );
+        assert_eq!((data >> 112) as u8 & 0x1f, 5);
+        assert_eq!((data >> 120) as u8, 3);
     }
 }
@@ -1,84 +1,245 @@
 use super::{ErrorType, Result};
-use crate::usb::mem::allocate_array;
+use crate::usb::mem::{allocate_array, free};
 use crate::usb::trb::{GenericTrb, LinkTrb, Trb};
 use crate::usb::xhci::{Accessor, InterrupterRegisterSet};
+use crate::util::collection::ArrayVec;
 use bit_field::BitField;
+use core::mem::size_of;
 use core::ptr::null_mut;
 
+/// Maximum number of segments a ring can grow to
+const MAX_SEGMENTS: usize = 8;
+
 #[derive(Debug)]
 pub struct PushResult<T> {
     pub ptr: u64,
     pub trb: T,
 }
 
+/// Producer side of a transfer ring or the command ring.
+/// The ring consists of segments chained by Link TRBs, and a new segment is linked in
+/// when the producer would otherwise catch up with TRBs which xHC hasn't processed yet.
 #[derive(Debug)]
 pub struct Ring {
-    buffer: *mut GenericTrb,
-    len: usize,
+    /// Segments in the order xHC follows them. The last one links back to the first
+    segments: ArrayVec<*mut GenericTrb, MAX_SEGMENTS>,
+    /// Number of TRBs in each segment including the Link TRB
+    segment_len: usize,
     cycle_bit: bool,
+    write_segment: usize,
     write_index: usize,
+    /// The position next to the last TRB which xHC has processed
+    dequeue_segment: usize,
+    dequeue_index: usize,
 }
 
 impl Ring {
     pub fn new() -> Self {
         Self {
-            buffer: null_mut(),
-            len: 0,
+            segments: ArrayVec::new(),
+            segment_len: 0,
             cycle_bit: false,
+            write_segment: 0,
             write_index: 0,
+            dequeue_segment: 0,
+            dequeue_index: 0,
         }
     }
 
-    pub fn initialize(&mut self, len: usize) -> Result<()> {
+    /// Allocate the first segment of `segment_len` TRBs
+    pub fn initialize(&mut self, segment_len: usize) -> Result<()> {
+        self.free();
         self.cycle_bit = true;
+        self.segment_len = segment_len;
+        self.write_segment = 0;
         self.write_index = 0;
-        self.len = len;
+        self.dequeue_segment = 0;
+        self.dequeue_index = 0;
+        self.grow()
+    }
 
-        self.buffer = allocate_array::<GenericTrb>(len, Some(64), Some(64 * 1024))
-            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+    pub fn buffer_pointer(&self) -> u64 {
+        self.segments[0] as u64
+    }
+
+    /// Number of TRBs which can be pushed without linking in a new segment
+    pub fn free_trbs(&self) -> usize {
+        let capacity = self.segments.len() * (self.segment_len - 1);
+        let used = (self.position(self.write_segment, self.write_index) + capacity
+            - self.position(self.dequeue_segment, self.dequeue_index))
+            % capacity;
+        // a slot is kept empty to distinguish a full ring from an empty one
+        capacity - used - 1
+    }
+
+    /// Make sure that `num_trbs` TRBs can be pushed, linking in new segments as needed
+    pub fn reserve(&mut self, num_trbs: usize) -> Result<()> {
+        while self.free_trbs() < num_trbs {
+            if self.dequeue_segment == self.write_segment && !self.is_empty() && !self.is_behind() {
+                // xHC is processing the TRBs in front of the producer in the same segment,
+                // so a segment can't be inserted before them
+                return Err(mkerror!(ErrorType::RingFull));
+            }
+            self.grow()?;
+        }
         Ok(())
     }
 
-    pub fn buffer_pointer(&self) -> u64 {
-        self.buffer as u64
+    /// Must be called with the TRB pointer of a Transfer Event or Command Completion Event.
+    /// The TRBs up to `ptr` can be reused
+    pub fn on_trb_completed(&mut self, ptr: u64) {
+        if let Some((segment, index)) = self.locate(ptr) {
+            let (segment, index) = self.next_position(segment, index);
+            self.dequeue_segment = segment;
+            self.dequeue_index = index;
+        }
+    }
+
+    /// Dequeue pointer and its cycle state right after the TRB at `ptr`,
+    /// which is used to skip a TD that has halted the endpoint
+    pub fn dequeue_pointer_after(&self, ptr: u64) -> (u64, bool) {
+        let cycle_bit = unsafe { (ptr as *const GenericTrb).read_volatile() }.cycle_bit();
+        match self.locate(ptr) {
+            Some((segment, index)) => {
+                let (next_segment, next_index) = self.next_position(segment, index);
+                let next_ptr = unsafe { self.segments[next_segment].add(next_index) } as u64;
+                // the Link TRB of the last segment toggles the cycle
+                let toggled = next_index == 0 && segment == self.segments.len() - 1;
+                (next_ptr, cycle_bit ^ toggled)
+            }
+            None => (ptr + size_of::<GenericTrb>() as u64, cycle_bit),
+        }
+    }
+
+    /// Release the buffer. The ring must not be used by xHC anymore.
+    pub fn free(&mut self) {
+        for &segment in self.segments.as_slice() {
+            free(segment);
+        }
+        self.segments = ArrayVec::new();
     }
 
     /// Push the TRB to the ring, return the trb with
     /// the pointer to the trb in the buffer
-    pub fn push<T: Trb>(&mut self, mut trb: T) -> PushResult<T> {
-        let ptr = unsafe { self.buffer.add(self.write_index) };
+    pub fn push<T: Trb>(&mut self, mut trb: T) -> Result<PushResult<T>> {
+        if self.free_trbs() == 0 {
+            self.reserve(1)?;
+        }
+        let ptr = unsafe { self.segments[self.write_segment].add(self.write_index) };
+        let chain = trb.generalize().chain_bit();
         self.copy_to_last(trb.generalize_mut());
 
         self.write_index += 1;
-        if self.write_index == self.len - 1 {
-            let mut link = LinkTrb::new(self.buffer as u64);
+        if self.write_index == self.segment_len - 1 {
+            // don't let the producer enter the segment which xHC is still processing
+            let next = (self.write_segment + 1) % self.segments.len();
+            if self.dequeue_segment == next && !self.is_empty() && self.grow().is_err() {
+                debug!("Failed to grow the ring. Sharing the segment with xHC");
+            }
+            let next = (self.write_segment + 1) % self.segments.len();
+            let toggle_cycle = next == 0;
+
+            let mut link = LinkTrb::new(self.segments[next] as u64)
+                .with_toggle_cycle(toggle_cycle)
+                .with_chain_bit(chain);
             self.copy_to_last(link.generalize_mut());
 
+            self.write_segment = next;
             self.write_index = 0;
-            self.cycle_bit = !self.cycle_bit;
+            if toggle_cycle {
+                self.cycle_bit = !self.cycle_bit;
+            }
         }
 
-        PushResult {
+        Ok(PushResult {
             trb,
             ptr: ptr as u64,
+        })
+    }
+
+    /// Link in a new segment right after the one the producer is writing to
+    fn grow(&mut self) -> Result<()> {
+        if self.segments.len() == MAX_SEGMENTS {
+            return Err(mkerror!(ErrorType::RingFull));
+        }
+        let segment = allocate_array::<GenericTrb>(self.segment_len, Some(64), Some(64 * 1024))
+            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+
+        let at = if self.segments.len() == 0 {
+            0
+        } else {
+            self.write_segment + 1
+        };
+        self.segments
+            .push(segment)
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        self.segments.as_mut_slice()[at..].rotate_right(1);
+        if self.dequeue_segment >= at && self.segments.len() > 1 {
+            self.dequeue_segment += 1;
+        }
+        if at > 0 {
+            // xHC enters the new segment without toggling the cycle,
+            // so TRBs which haven't been written yet must look consumed
+            let stale_cycle = !self.cycle_bit;
+            for i in 0..self.segment_len {
+                let mut trb = GenericTrb::default();
+                trb.set_cycle_bit(stale_cycle);
+                unsafe { segment.add(i).write_volatile(trb) };
+            }
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.write_segment == self.dequeue_segment && self.write_index == self.dequeue_index
+    }
+
+    /// Whether xHC is behind the producer in the same segment
+    fn is_behind(&self) -> bool {
+        self.dequeue_index <= self.write_index
+    }
+
+    fn position(&self, segment: usize, index: usize) -> usize {
+        segment * (self.segment_len - 1) + index
+    }
+
+    fn next_position(&self, segment: usize, index: usize) -> (usize, usize) {
+        if index + 1 == self.segment_len - 1 {
+            ((segment + 1) % self.segments.len(), 0)
+        } else {
+            (segment, index + 1)
         }
     }
 
+    fn locate(&self, ptr: u64) -> Option<(usize, usize)> {
+        let trb_size = size_of::<GenericTrb>() as u64;
+        self.segments
+            .as_slice()
+            .iter()
+            .position(|&segment| {
+                let begin = segment as u64;
+                begin <= ptr && ptr < begin + self.segment_len as u64 * trb_size
+            })
+            .map(|segment| {
+                let index = (ptr - self.segments[segment] as u64) / trb_size;
+                (segment, index as usize)
+            })
+    }
+
     fn copy_to_last(&mut self, trb: &mut GenericTrb) {
         trb.set_cycle_bit(self.cycle_bit);
+        let dest = unsafe { self.segments[self.write_segment].add(self.write_index) } as *mut u32;
 
         // write lower 96 bits first, then write higher 32 bits in single instruction which
         // includes cycle_bit next to prevent the TRB is dequeued by  xHC unexpectedly early
         for i in 0..3 {
             unsafe {
-                (self.buffer.add(self.write_index) as *mut u32)
-                    .add(i)
+                dest.add(i)
                     .write_volatile(trb.data().get_bits((i * 32)..(i * 32 + 32)) as u32);
             }
         }
         unsafe {
-            (self.buffer.add(self.write_index) as *mut u32)
-                .add(3)
+            dest.add(3)
                 .write_volatile(trb.data.get_bits(96..128) as u32);
         }
     }
@@ -93,61 +254,71 @@ pub struct EventRingSegmentTableEntry {
 impl EventRingSegmentTableEntry {
     getbits!(pub ring_segment_base_address: u64; data; 0; 64);
     setbits!(pub set_ring_segment_base_address: u64; data; 0; 64);
-    getbits!(pub ring_segment_size: u16; data; 64; 16);
     setbits!(pub set_ring_segment_size: u16; data; 64; 16);
 }
 
 #[derive(Debug)]
 pub struct EventRing {
-    buffer: *mut GenericTrb,
     segment_table: *mut EventRingSegmentTableEntry,
+    num_segments: usize,
+    segment_len: usize,
     interrupter: Accessor<InterrupterRegisterSet>,
-    len: usize,
+    /// Position of the next event to be dequeued
+    segment_index: usize,
+    dequeue_index: usize,
     cycle_bit: bool,
 }
 
 impl EventRing {
     pub fn new() -> Self {
         Self {
-            buffer: null_mut(),
             segment_table: null_mut(),
+            num_segments: 0,
+            segment_len: 0,
             interrupter: Accessor::null(),
-            len: 0,
+            segment_index: 0,
+            dequeue_index: 0,
             cycle_bit: false,
         }
     }
 
+    /// Allocate `num_segments` segments of `segment_len` TRBs each and
+    /// register them to the interrupter
     pub fn initialize(
         &mut self,
-        len: usize,
+        num_segments: usize,
+        segment_len: usize,
         interrupter: Accessor<InterrupterRegisterSet>,
     ) -> Result<()> {
         self.interrupter = interrupter;
         self.cycle_bit = true;
-        self.len = len;
-
-        self.buffer = allocate_array::<GenericTrb>(len, Some(64), Some(64 * 1024))
-            .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+        self.num_segments = num_segments;
+        self.segment_len = segment_len;
+        self.segment_index = 0;
+        self.dequeue_index = 0;
 
         self.segment_table =
-            allocate_array::<EventRingSegmentTableEntry>(1, Some(64), Some(64 * 1024))
+            allocate_array::<EventRingSegmentTableEntry>(num_segments, Some(64), Some(64 * 1024))
                 .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
-        let mut table_entry = EventRingSegmentTableEntry::default();
-        table_entry.set_ring_segment_size(len as u16);
-        table_entry.set_ring_segment_base_address(self.buffer as u64);
-
-        unsafe {
-            self.segment_table.write_volatile(table_entry);
+        for i in 0..num_segments {
+            let buffer = allocate_array::<GenericTrb>(segment_len, Some(64), Some(64 * 1024))
+                .map_err(|e| mkerror!(ErrorType::AllocError(e)))?;
+            let mut table_entry = EventRingSegmentTableEntry::default();
+            table_entry.set_ring_segment_size(segment_len as u16);
+            table_entry.set_ring_segment_base_address(buffer as u64);
+            unsafe {
+                self.segment_table.add(i).write_volatile(table_entry);
+            }
         }
 
-        let buffer_ptr = self.buffer as u64;
+        let dequeue_pointer = self.dequeue_pointer() as u64;
         let segment_table_ptr = self.segment_table as u64;
 
         let reg = self.interrupter.as_mut();
         reg.erstsz
-            .update(|r| r.set_event_ring_segment_table_size(1));
+            .update(|r| r.set_event_ring_segment_table_size(num_segments as u16));
         reg.erdp
-            .update(|r| r.set_event_ring_dequeue_pointer(buffer_ptr));
+            .update(|r| r.set_event_ring_dequeue_pointer(dequeue_pointer));
         reg.erstba
             .update(|r| r.set_event_ring_segment_table_base_address(segment_table_ptr));
 
@@ -155,45 +326,171 @@ impl EventRing {
     }
 
     pub fn poll(&mut self) -> Option<GenericTrb> {
-        let trb = unsafe {
-            &*(self
-                .interrupter
-                .as_ref()
-                .erdp
-                .read()
-                .event_ring_dequeue_pointer() as *const GenericTrb)
-        };
+        let trb = unsafe { self.dequeue_pointer().read_volatile() };
 
         if trb.cycle_bit() == self.cycle_bit {
             self.pop();
-            Some(*trb)
+            Some(trb)
         } else {
             None
         }
     }
 
-    fn pop(&mut self) {
-        let mut ptr = unsafe {
-            (self
-                .interrupter
-                .as_ref()
-                .erdp
-                .read()
-                .event_ring_dequeue_pointer() as *const GenericTrb)
-                .add(1)
-        };
-
-        let segment_begin: *const GenericTrb =
-            unsafe { (self.segment_table.read()).ring_segment_base_address() as *const GenericTrb };
-        let segment_end =
-            unsafe { segment_begin.add((self.segment_table.read()).ring_segment_size() as usize) };
+    fn dequeue_pointer(&self) -> *const GenericTrb {
+        let segment_begin = unsafe { self.segment_table.add(self.segment_index).read_volatile() }
+            .ring_segment_base_address() as *const GenericTrb;
+        unsafe { segment_begin.add(self.dequeue_index) }
+    }
 
-        if ptr == segment_end {
-            ptr = segment_begin;
-            self.cycle_bit = !self.cycle_bit;
+    fn pop(&mut self) {
+        self.dequeue_index += 1;
+        if self.dequeue_index == self.segment_len {
+            self.dequeue_index = 0;
+            self.segment_index += 1;
+            if self.segment_index == self.num_segments {
+                self.segment_index = 0;
+                self.cycle_bit = !self.cycle_bit;
+            }
         }
+
+        let ptr = self.dequeue_pointer() as u64;
+        let segment_index = self.segment_index as u8;
         self.interrupter.as_mut().erdp.update(|r| {
-            r.set_event_ring_dequeue_pointer(ptr as u64);
+            r.set_event_ring_dequeue_pointer(ptr);
+            r.set_dequeue_erst_segment_index(segment_index);
+            r.set_event_handler_busy(true);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::mem::exclusive_pool;
+    use crate::usb::trb::ring::{EventRing, Ring, MAX_SEGMENTS};
+    use crate::usb::trb::{GenericTrb, LinkTrb, NormalTrb, Trb};
+    use crate::usb::xhci::{Accessor, InterrupterRegisterSet};
+    use bit_field::BitField;
+
+    #[test]
+    fn dequeue_pointer_after() {
+        let _pool = exclusive_pool();
+        let mut ring = Ring::new();
+        ring.initialize(4).unwrap();
+        let base = ring.buffer_pointer();
+
+        let first = ring.push(NormalTrb::new()).unwrap().ptr;
+        assert_eq!(ring.dequeue_pointer_after(first), (base + 16, true));
+        let second = ring.push(NormalTrb::new()).unwrap().ptr;
+        let third = ring.push(NormalTrb::new()).unwrap().ptr;
+        assert_eq!(ring.dequeue_pointer_after(second), (third, true));
+        // the fourth is Link TRB to the second segment
+        let second_segment = ring.segments[1] as u64;
+        assert_eq!(ring.dequeue_pointer_after(third), (second_segment, true));
+        ring.on_trb_completed(third);
+
+        ring.push(NormalTrb::new()).unwrap();
+        let fifth = ring.push(NormalTrb::new()).unwrap().ptr;
+        ring.on_trb_completed(fifth);
+        let sixth = ring.push(NormalTrb::new()).unwrap().ptr;
+        // the Link TRB of the last segment toggles the cycle
+        assert_eq!(ring.dequeue_pointer_after(sixth), (base, false));
+
+        let wrapped = ring.push(NormalTrb::new()).unwrap().ptr;
+        assert_eq!(wrapped, base);
+        assert_eq!(ring.segments.len(), 2);
+        assert_eq!(ring.dequeue_pointer_after(wrapped), (base + 16, false));
+        ring.free();
+    }
+
+    #[test]
+    fn track_free_trbs() {
+        let _pool = exclusive_pool();
+        let mut ring = Ring::new();
+        ring.initialize(4).unwrap();
+        assert_eq!(ring.free_trbs(), 2);
+
+        let first = ring.push(NormalTrb::new()).unwrap().ptr;
+        let second = ring.push(NormalTrb::new()).unwrap().ptr;
+        assert_eq!(ring.free_trbs(), 0);
+        ring.on_trb_completed(first);
+        assert_eq!(ring.free_trbs(), 1);
+        ring.on_trb_completed(second);
+        assert_eq!(ring.free_trbs(), 2);
+
+        ring.reserve(4).unwrap();
+        assert_eq!(ring.segments.len(), 2);
+        assert_eq!(ring.free_trbs(), 5);
+        ring.free();
+    }
+
+    #[test]
+    fn grow_when_full() {
+        let _pool = exclusive_pool();
+        let mut ring = Ring::new();
+        ring.initialize(4).unwrap();
+        let base = ring.buffer_pointer();
+
+        for _ in 0..3 {
+            ring.push(NormalTrb::new()).unwrap();
+        }
+        // xHC hasn't processed any TRB, so a new segment is linked in
+        assert_eq!(ring.segments.len(), 2);
+        let link = unsafe { (base as *const GenericTrb).add(3).read() };
+        let next = ring.push(NormalTrb::new()).unwrap().ptr;
+        assert_eq!(link.trb_type(), LinkTrb::TYPE);
+        assert_eq!(link.data().get_bits(0..64), next as u128);
+        // the Link TRB into the new segment doesn't toggle the cycle
+        assert!(!link.data().get_bit(97));
+        assert_eq!(ring.dequeue_pointer_after(next), (next + 16, true));
+        ring.free();
+    }
+
+    #[test]
+    fn refuse_when_segments_exhausted() {
+        let _pool = exclusive_pool();
+        let mut ring = Ring::new();
+        ring.initialize(4).unwrap();
+        let first = ring.push(NormalTrb::new()).unwrap().ptr;
+
+        for _ in 1..(MAX_SEGMENTS * 3 - 1) {
+            ring.push(NormalTrb::new()).unwrap();
+        }
+        assert_eq!(ring.segments.len(), MAX_SEGMENTS);
+        assert_eq!(ring.free_trbs(), 0);
+        assert!(ring.push(NormalTrb::new()).is_err());
+        assert_eq!(ring.segments.len(), MAX_SEGMENTS);
+
+        ring.on_trb_completed(first);
+        assert!(ring.push(NormalTrb::new()).is_ok());
+        ring.free();
+    }
+
+    #[test]
+    fn event_ring_over_segments() {
+        let _pool = exclusive_pool();
+        let mut registers: InterrupterRegisterSet = unsafe { core::mem::zeroed() };
+        let mut ring = EventRing::new();
+        ring.initialize(2, 16, Accessor::new(&mut registers))
+            .unwrap();
+
+        // xHC writes the events with the producer cycle state
+        let table = ring.segment_table;
+        for pass in 0..2 {
+            let cycle_bit = pass == 0;
+            for segment in 0..2 {
+                let base = unsafe { table.add(segment).read() }.ring_segment_base_address()
+                    as *mut GenericTrb;
+                for i in 0..16 {
+                    let mut trb = GenericTrb::default();
+                    trb.set_cycle_bit(cycle_bit);
+                    unsafe { base.add(i).write(trb) };
+                }
+            }
+            for _ in 0..32 {
+                assert!(ring.poll().is_some());
+            }
+            // the whole ring is consumed and the cycle state is toggled
+            assert!(ring.poll().is_none());
+        }
+    }
+}
@@ -13,6 +13,8 @@ impl<T> MemMapRegister<T> {
     pub fn write(&mut self, value: T) {
         let ptr = (self as *mut Self) as *mut T;
         unsafe { ptr.write_volatile(value) };
+        #[cfg(test)]
+        crate::usb::mock::on_register_write(ptr as usize);
     }
 
     pub fn update<F>(&mut self, op: F)
@@ -25,15 +27,26 @@ impl<T> MemMapRegister<T> {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 pub struct Accessor<T> {
     ptr: *mut T,
 }
+impl<T> Clone for Accessor<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Accessor<T> {}
 impl<T> Accessor<T> {
     pub fn null() -> Self {
         Self { ptr: null_mut() }
     }
 
+    #[cfg(test)]
+    pub fn new(value: &mut T) -> Self {
+        Self { ptr: value }
+    }
+
     pub fn as_ref(&self) -> &T {
         unsafe { &*self.ptr }
     }
@@ -67,6 +80,7 @@ pub struct HCSPARAMS1 {
 }
 impl HCSPARAMS1 {
     getbits!(pub max_device_slots: u8; data; 0; 8);
+    getbits!(pub max_interrupters: u16; data; 8; 11);
     getbits!(pub max_ports: u8; data; 24; 8);
 }
 
@@ -75,6 +89,20 @@ impl HCSPARAMS1 {
 pub struct HCSPARAMS2 {
     data: u32,
 }
+impl HCSPARAMS2 {
+    getbits!(event_ring_segment_table_max: u8; data; 4; 4);
+    getbits!(max_scratchpad_buffers_hi: u16; data; 21; 5);
+    getbits!(max_scratchpad_buffers_lo: u16; data; 27; 5);
+
+    pub fn max_scratchpad_buffers(&self) -> u16 {
+        (self.max_scratchpad_buffers_hi() << 5) | self.max_scratchpad_buffers_lo()
+    }
+
+    /// Maximum number of entries in an event ring segment table
+    pub fn max_event_ring_segments(&self) -> usize {
+        1 << self.event_ring_segment_table_max()
+    }
+}
 
 #[derive(Debug)]
 #[repr(transparent)]
@@ -88,6 +116,7 @@ pub struct HCCPARAMS1 {
     data: u32,
 }
 impl HCCPARAMS1 {
+    getbit!(pub context_size; data; 2);
     getbits!(pub xhci_extended_capabilities_pointer: u16; data; 16; 16);
 }
 
@@ -171,6 +200,7 @@ impl CRCR {
     setbit!(pub set_ring_cycle_state; data; 0);
     setbit!(pub set_command_stop; data; 1);
     setbit!(pub set_command_abort; data; 2);
+    getbit!(pub command_ring_running; data; 3);
     setbits!(set_pointer: u64; data; 6; 58);
 
     pub fn set_command_ring_pointer(&mut self, ptr: u64) {
@@ -178,6 +208,20 @@ impl CRCR {
     }
 }
 
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct PAGESIZE {
+    data: u32,
+}
+impl PAGESIZE {
+    getbits!(page_size: u16; data; 0; 16);
+
+    /// Page size in bytes. Bit n of the register means 2^(n+12) bytes
+    pub fn bytes(&self) -> usize {
+        1 << (self.page_size().trailing_zeros() + 12)
+    }
+}
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct DCBAAP {
@@ -205,7 +249,7 @@ impl CONFIG {
 pub struct OperationalRegisters {
     pub usbcmd: MemMapRegister<USBCMD>,
     pub usbsts: MemMapRegister<USBSTS>,
-    _pagesize: MemMapRegister<u32>,
+    pub pagesize: MemMapRegister<PAGESIZE>,
     _reserved1: [u32; 2],
     _dnctrl: MemMapRegister<u32>,
     pub crcr: MemMapRegister<CRCR>,
@@ -224,6 +268,8 @@ impl PORTSC {
     getbit!(pub port_enabled_disabled; data; 1);
     getbit!(pub port_reset; data; 4);
     getbits!(pub port_speed: u8; data; 10; 4);
+    getbit!(pub connect_status_change; data; 17);
+    setbit!(set_connect_status_change; data; 17);
     getbit!(pub port_reset_change; data; 21);
     setbit!(set_port_reset_change; data; 21);
 
@@ -236,6 +282,11 @@ impl PORTSC {
         self.data &= 0x0e01c3e0;
         self.set_port_reset_change(true);
     }
+
+    pub fn clear_connect_status_change(&mut self) {
+        self.data &= 0x0e01c3e0;
+        self.set_connect_status_change(true);
+    }
 }
 
 #[derive(Debug)]
@@ -265,6 +316,20 @@ pub struct PortRegisterSet {
     _porthlpmc: MemMapRegister<PORTHLPMC>,
 }
 
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct MFINDEX {
+    data: u32,
+}
+impl MFINDEX {
+    getbits!(pub microframe_index: u16; data; 0; 14);
+
+    /// The current 1ms frame number, which is what the Frame ID field of Isoch TRB refers to
+    pub fn frame_index(&self) -> u16 {
+        (self.microframe_index() >> 3) & 0x7ff
+    }
+}
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct IMAN {
@@ -280,6 +345,11 @@ impl IMAN {
 pub struct IMOD {
     data: u32,
 }
+impl IMOD {
+    // minimum interval between interrupts in 250ns units
+    setbits!(pub set_interrupt_moderation_interval: u16; data; 0; 16);
+    setbits!(pub set_interrupt_moderation_counter: u16; data; 16; 16);
+}
 
 #[derive(Debug)]
 #[repr(transparent)]
@@ -309,13 +379,11 @@ pub struct ERDP {
     data: u64,
 }
 impl ERDP {
-    getbits!(erdp: u64; data; 4; 60);
+    setbits!(pub set_dequeue_erst_segment_index: u8; data; 0; 3);
+    // write 1 to clear after the events are handled
+    setbit!(pub set_event_handler_busy; data; 3);
     setbits!(set_erdp: u64; data; 4; 60);
 
-    pub fn event_ring_dequeue_pointer(&self) -> u64 {
-        self.erdp() << 4
-    }
-
     pub fn set_event_ring_dequeue_pointer(&mut self, ptr: u64) {
         self.set_erdp(ptr >> 4);
     }
@@ -430,6 +498,7 @@ pub struct Registers {
     pub operational: Accessor<OperationalRegisters>,
     pub doorbell: ArrayAccessor<DoorbellRegister>,
     pub port_register_set: ArrayAccessor<PortRegisterSet>,
+    pub microframe_index: Accessor<MemMapRegister<MFINDEX>>,
     pub interrupter_register_set: ArrayAccessor<InterrupterRegisterSet>,
     pub extended_register_list: Option<ExtendedRegisterList>,
 }
@@ -441,9 +510,9 @@ impl Registers {
         };
         let operational_base = mmio_base + cap.as_ref().caplength.read() as usize;
         let doorbell_base = mmio_base + cap.as_ref().dboff.read().doorbell_array_offset() as usize;
-        let interrupter_register_set_base = mmio_base
-            + (cap.as_ref().rtsoff.read().runtime_register_space_offset() as usize)
-            + 0x20;
+        let runtime_register_base =
+            mmio_base + (cap.as_ref().rtsoff.read().runtime_register_space_offset() as usize);
+        let interrupter_register_set_base = runtime_register_base + 0x20;
         let port_register_set_base = operational_base + 0x400;
         let max_ports = cap.as_ref().hcsparams1.read().max_ports();
 
@@ -471,6 +540,9 @@ impl Registers {
                 ptr: port_register_set_base as *mut PortRegisterSet,
                 len: max_ports as usize,
             },
+            microframe_index: Accessor {
+                ptr: runtime_register_base as *mut MemMapRegister<MFINDEX>,
+            },
             interrupter_register_set: ArrayAccessor {
                 ptr: interrupter_register_set_base as *mut InterrupterRegisterSet,
                 len: 1024,
@@ -481,3 +553,43 @@ impl Registers {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::usb::xhci::{ERDP, HCSPARAMS1, HCSPARAMS2, PAGESIZE};
+
+    #[test]
+    fn max_scratchpad_buffers() {
+        let params = HCSPARAMS2 {
+            data: (0b10 << 21) | (0b00011 << 27),
+        };
+        assert_eq!(params.max_scratchpad_buffers(), (0b10 << 5) | 0b11);
+        assert_eq!(HCSPARAMS2 { data: 0xf1 }.max_scratchpad_buffers(), 0);
+    }
+
+    #[test]
+    fn interrupter_parameters() {
+        let params = HCSPARAMS1 {
+            data: (4 << 24) | (8 << 8) | 64,
+        };
+        assert_eq!(params.max_interrupters(), 8);
+        assert_eq!(params.max_ports(), 4);
+        assert_eq!(HCSPARAMS2 { data: 3 << 4 }.max_event_ring_segments(), 8);
+        assert_eq!(HCSPARAMS2 { data: 0 }.max_event_ring_segments(), 1);
+    }
+
+    #[test]
+    fn dequeue_pointer_with_flags() {
+        let mut erdp = ERDP { data: 0 };
+        erdp.set_event_ring_dequeue_pointer(0x1000_0040);
+        erdp.set_dequeue_erst_segment_index(2);
+        erdp.set_event_handler_busy(true);
+        assert_eq!(erdp.data, 0x1000_004a);
+    }
+
+    #[test]
+    fn page_size_bytes() {
+        assert_eq!(PAGESIZE { data: 1 }.bytes(), 4096);
+        assert_eq!(PAGESIZE { data: 0b100 }.bytes(), 16384);
+    }
+}
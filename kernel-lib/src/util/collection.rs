@@ -49,6 +49,50 @@ impl<T, const N: usize> ArrayVec<T, N> {
     pub fn as_mut_slice(&mut self) -> &mut [T] {
         &mut self.buf[..self.len]
     }
+
+    /// Insert the element at `index`, shifting the following ones
+    pub fn insert(&mut self, index: usize, value: T) -> Result<()> {
+        assert!(index <= self.len);
+        self.push(value)?;
+        self.buf[index..self.len].rotate_right(1);
+        Ok(())
+    }
+
+    /// Remove the element at `index`, which is replaced with the last one
+    pub fn swap_remove(&mut self, index: usize) -> T
+    where
+        T: Copy,
+    {
+        assert!(index < self.len);
+        self.len -= 1;
+        self.buf.swap(index, self.len);
+        self.buf[self.len]
+    }
+
+    /// Remove the last element
+    pub fn pop(&mut self) -> Option<T>
+    where
+        T: Copy,
+    {
+        if self.len > 0 {
+            self.len -= 1;
+            Some(self.buf[self.len])
+        } else {
+            None
+        }
+    }
+
+    /// Keep only the elements `f` returns true for, preserving their order
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if f(&self.buf[i]) {
+                self.buf.swap(kept, i);
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
 }
 
 impl<T, const N: usize> Index<usize> for ArrayVec<T, N> {
@@ -132,6 +176,18 @@ where
         None
     }
 
+    pub fn len(&self) -> usize {
+        self.buf.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buf.iter().flatten().map(|(k, v)| (k, v))
+    }
+
     pub fn iter_mut(&mut self) -> IterMut<K, V, N> {
         IterMut {
             inner: self.buf.as_mut(),
@@ -228,6 +284,36 @@ mod tests {
         assert!(v.push(44).is_err());
     }
 
+    #[test]
+    fn array_vec_pop() {
+        let mut v = ArrayVec::<u32, 4>::new();
+        assert_eq!(v.pop(), None);
+        v.push(1).unwrap();
+        v.push(2).unwrap();
+        assert_eq!(v.pop(), Some(2));
+        assert_eq!(v.as_slice(), &[1]);
+        assert_eq!(v.pop(), Some(1));
+        assert_eq!(v.pop(), None);
+        v.push(3).unwrap();
+        assert_eq!(v.as_slice(), &[3]);
+    }
+
+    #[test]
+    fn array_vec_retain() {
+        let mut v = ArrayVec::<u32, 8>::new();
+        for i in 0..6 {
+            v.push(i).unwrap();
+        }
+        v.retain(|&i| i % 3 != 1);
+        assert_eq!(v.as_slice(), &[0, 2, 3, 5]);
+        v.push(6).unwrap();
+        assert_eq!(v.as_slice(), &[0, 2, 3, 5, 6]);
+        v.insert(1, 1).unwrap();
+        assert_eq!(v.as_slice(), &[0, 1, 2, 3, 5, 6]);
+        assert_eq!(v.swap_remove(0), 0);
+        assert_eq!(v.as_slice(), &[6, 1, 2, 3, 5]);
+    }
+
     #[test]
     fn array_map_insert_get() {
         let mut m: ArrayMap<i32, &str, 3> = ArrayMap::new();
@@ -258,6 +344,18 @@ mod tests {
         assert_eq!(m.get(&3), Some(&"three"));
     }
 
+    #[test]
+    fn array_map_len() {
+        let mut m: ArrayMap<i32, &str, 3> = ArrayMap::new();
+        assert!(m.is_empty());
+        m.insert(1, "one").unwrap();
+        m.insert(2, "two").unwrap();
+        m.insert(1, "uno").unwrap();
+        assert_eq!(m.len(), 2);
+        m.remove(&1);
+        assert_eq!(m.len(), 1);
+    }
+
     #[test]
     fn array_map_iter_mut() {
         let mut m: ArrayMap<i32, &str, 3> = ArrayMap::new();
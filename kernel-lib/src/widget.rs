@@ -0,0 +1,687 @@
+//! Minimal widget toolkit for windows drawn by the kernel.
+//!
+//! A [`Window`] owns its widgets and translates the mouse and keyboard events,
+//! given in the window coordinates, into [`Action`]s for the application.
+//! Drawing goes to any [`PixelWriter`], typically the buffer of a layer.
+
+use crate::error::ErrorContext;
+use crate::graphics::fonts;
+use crate::graphics::{PixelColor, PixelWriter, Rect};
+use crate::util::collection::{ArrayMap, ArrayVec, CollectionError};
+
+#[derive(Debug)]
+pub enum ErrorType {
+    CollectionError(CollectionError),
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+const MAX_WIDGETS: usize = 16;
+const TEXT_BOX_CAPACITY: usize = 64;
+
+const WHITE: PixelColor = PixelColor::new(0xff, 0xff, 0xff);
+const BLACK: PixelColor = PixelColor::new(0, 0, 0);
+const FACE: PixelColor = PixelColor::new(0xc6, 0xc6, 0xc6);
+const SHADOW: PixelColor = PixelColor::new(0x84, 0x84, 0x84);
+const DARK: PixelColor = PixelColor::new(0x44, 0x44, 0x44);
+const ACTIVE_TITLE: PixelColor = PixelColor::new(0, 0, 0x84);
+
+/// Input to a window. The coordinates are relative to the top-left corner of the window
+/// and may be out of it while the button is held
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// The left button is pressed
+    MouseDown {
+        x: isize,
+        y: isize,
+    },
+    MouseUp {
+        x: isize,
+        y: isize,
+    },
+    MouseMove {
+        x: isize,
+        y: isize,
+    },
+    Key(char),
+}
+
+/// What the application is asked to do by an event
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// The close button is clicked
+    Close,
+    Clicked(WidgetId),
+    /// Enter is hit in the text box
+    Submitted(WidgetId),
+    /// The scroll bar is moved to the position
+    Scrolled(WidgetId, usize),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Response {
+    /// Whether the window has to be drawn again
+    pub redraw: bool,
+    pub action: Option<Action>,
+}
+
+impl Response {
+    fn redraw(action: Option<Action>) -> Response {
+        Response {
+            redraw: true,
+            action,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WidgetId(u8);
+
+/// Object under a point of a window
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Hit {
+    CloseButton,
+    /// The part of the title bar other than the close button, by which the window is dragged
+    TitleBar,
+    Widget(WidgetId),
+    Client,
+}
+
+/// Widgets are stored inline since there's no heap to box the text box into
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Widget {
+    Button(Button),
+    TextBox(TextBox),
+    ScrollBar(ScrollBar),
+}
+
+impl Widget {
+    pub fn rect(&self) -> Rect {
+        match self {
+            Widget::Button(button) => button.rect,
+            Widget::TextBox(text_box) => text_box.rect,
+            Widget::ScrollBar(scroll_bar) => scroll_bar.rect,
+        }
+    }
+
+    fn is_focusable(&self) -> bool {
+        matches!(self, Widget::TextBox(_))
+    }
+
+    fn draw<W: PixelWriter + ?Sized>(&self, writer: &mut W, origin: (usize, usize), focused: bool) {
+        let rect = self.rect();
+        let rect = Rect::new(
+            origin.0 + rect.x,
+            origin.1 + rect.y,
+            rect.width,
+            rect.height,
+        );
+        match self {
+            Widget::Button(button) => button.draw(writer, rect),
+            Widget::TextBox(text_box) => text_box.draw(writer, rect, focused),
+            Widget::ScrollBar(scroll_bar) => scroll_bar.draw(writer, rect),
+        }
+    }
+}
+
+/// Push button, which is clicked when the button is released on it
+#[derive(Debug)]
+pub struct Button {
+    rect: Rect,
+    label: &'static str,
+    pressed: bool,
+}
+
+impl Button {
+    pub fn new(rect: Rect, label: &'static str) -> Button {
+        Button {
+            rect,
+            label,
+            pressed: false,
+        }
+    }
+
+    fn draw<W: PixelWriter + ?Sized>(&self, writer: &mut W, rect: Rect) {
+        writer.fill_rect(rect, if self.pressed { SHADOW } else { FACE });
+        writer.draw_rect(rect, DARK);
+        let text_width = fonts::str_width(self.label);
+        writer.write_str(
+            rect.x + rect.width.saturating_sub(text_width) / 2,
+            rect.y + rect.height.saturating_sub(fonts::cell_size().1) / 2,
+            self.label,
+            BLACK,
+        );
+    }
+}
+
+/// Single-line text input which receives the keys while focused
+#[derive(Debug)]
+pub struct TextBox {
+    rect: Rect,
+    text: ArrayVec<char, TEXT_BOX_CAPACITY>,
+}
+
+impl TextBox {
+    pub fn new(rect: Rect) -> TextBox {
+        TextBox {
+            rect,
+            text: ArrayVec::new(),
+        }
+    }
+
+    pub fn text(&self) -> &[char] {
+        self.text.as_slice()
+    }
+
+    pub fn clear(&mut self) {
+        self.text = ArrayVec::new();
+    }
+
+    /// Characters beyond the capacity are dropped
+    fn on_key(&mut self, id: WidgetId, c: char) -> Response {
+        match c {
+            '\n' => Response::redraw(Some(Action::Submitted(id))),
+            '\x08' => {
+                self.text.pop();
+                Response::redraw(None)
+            }
+            c if !c.is_control() => {
+                let _ = self.text.push(c);
+                Response::redraw(None)
+            }
+            _ => Response::default(),
+        }
+    }
+
+    fn draw<W: PixelWriter + ?Sized>(&self, writer: &mut W, rect: Rect, focused: bool) {
+        writer.fill_rect(rect, WHITE);
+        writer.draw_rect(rect, if focused { BLACK } else { SHADOW });
+        let (cell_width, cell_height) = fonts::cell_size();
+        // the tail is shown if the text doesn't fit
+        let columns = rect.width.saturating_sub(8) / cell_width;
+        let text = self.text.as_slice();
+        let mut start = text.len();
+        let mut width = 0;
+        // leave a column for the caret
+        while start > 0 && width + fonts::char_width(text[start - 1]) < columns {
+            start -= 1;
+            width += fonts::char_width(text[start]);
+        }
+        let y = rect.y + rect.height.saturating_sub(cell_height) / 2;
+        let mut x = rect.x + 4;
+        for &c in &text[start..] {
+            writer.write_char(x, y, c, BLACK);
+            x += fonts::char_width(c) * cell_width;
+        }
+        if focused {
+            writer.fill_rect(Rect::new(x, y, 1, cell_height), BLACK);
+        }
+    }
+}
+
+/// Vertical scroll bar over `total` lines of which `visible` lines are shown
+#[derive(Debug)]
+pub struct ScrollBar {
+    rect: Rect,
+    total: usize,
+    visible: usize,
+    position: usize,
+    /// Offset from the top of the thumb where it's grabbed
+    grabbed: Option<usize>,
+}
+
+impl ScrollBar {
+    const MIN_THUMB_HEIGHT: usize = 8;
+
+    pub fn new(rect: Rect, total: usize, visible: usize) -> ScrollBar {
+        ScrollBar {
+            rect,
+            total,
+            visible,
+            position: 0,
+            grabbed: None,
+        }
+    }
+
+    /// The first line shown
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position.min(self.max_position());
+    }
+
+    pub fn set_total(&mut self, total: usize) {
+        self.total = total;
+        self.set_position(self.position);
+    }
+
+    fn max_position(&self) -> usize {
+        self.total.saturating_sub(self.visible)
+    }
+
+    /// Top and height of the thumb relative to the bar
+    fn thumb(&self) -> (usize, usize) {
+        let height = self.rect.height;
+        if self.total <= self.visible {
+            return (0, height);
+        }
+        let thumb = (height * self.visible / self.total)
+            .max(Self::MIN_THUMB_HEIGHT)
+            .min(height);
+        (
+            (height - thumb) * self.position / self.max_position(),
+            thumb,
+        )
+    }
+
+    fn scroll_to(&mut self, id: WidgetId, position: usize) -> Response {
+        let prev = self.position;
+        self.set_position(position);
+        if self.position == prev {
+            return Response::default();
+        }
+        Response::redraw(Some(Action::Scrolled(id, self.position)))
+    }
+
+    /// `y` is relative to the top of the bar
+    fn on_mouse_down(&mut self, id: WidgetId, y: usize) -> Response {
+        let (top, height) = self.thumb();
+        if y < top {
+            self.scroll_to(id, self.position.saturating_sub(self.visible))
+        } else if y >= top + height {
+            self.scroll_to(id, self.position + self.visible)
+        } else {
+            self.grabbed = Some(y - top);
+            Response::default()
+        }
+    }
+
+    fn on_mouse_move(&mut self, id: WidgetId, y: isize) -> Response {
+        let grabbed = match self.grabbed {
+            Some(grabbed) => grabbed as isize,
+            None => return Response::default(),
+        };
+        let (_, height) = self.thumb();
+        let range = self.rect.height - height;
+        if range == 0 {
+            return Response::default();
+        }
+        let top = (y - grabbed).max(0) as usize;
+        // round to the nearest line
+        let position = (top * self.max_position() + range / 2) / range;
+        self.scroll_to(id, position)
+    }
+
+    fn draw<W: PixelWriter + ?Sized>(&self, writer: &mut W, rect: Rect) {
+        writer.fill_rect(rect, SHADOW);
+        let (top, height) = self.thumb();
+        let thumb = Rect::new(rect.x, rect.y + top, rect.width, height);
+        writer.fill_rect(thumb, FACE);
+        writer.draw_rect(thumb, DARK);
+    }
+}
+
+/// What the left button has been pressed on
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Pressed {
+    CloseButton,
+    Widget(WidgetId),
+}
+
+/// Window with a title bar and a close button
+#[derive(Debug)]
+pub struct Window {
+    title: &'static str,
+    width: usize,
+    height: usize,
+    widgets: ArrayMap<WidgetId, Widget, MAX_WIDGETS>,
+    next_id: u8,
+    focus: Option<WidgetId>,
+    pressed: Option<Pressed>,
+}
+
+impl Window {
+    pub fn new(title: &'static str, width: usize, height: usize) -> Window {
+        Window {
+            title,
+            width,
+            height,
+            widgets: ArrayMap::new(),
+            next_id: 0,
+            focus: None,
+            pressed: None,
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Tall enough for the title in the selected font
+    pub fn title_bar_height() -> usize {
+        (fonts::cell_size().1 + 4).max(20)
+    }
+
+    /// The area below the title bar, where the widgets are placed
+    pub fn client_area(&self) -> Rect {
+        Rect::new(
+            1,
+            Self::title_bar_height(),
+            self.width.saturating_sub(2),
+            self.height.saturating_sub(Self::title_bar_height() + 1),
+        )
+    }
+
+    /// `widget` is placed in the window coordinates
+    pub fn add(&mut self, widget: Widget) -> Result<WidgetId> {
+        let id = WidgetId(self.next_id);
+        self.widgets
+            .insert(id, widget)
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    pub fn widget(&mut self, id: WidgetId) -> Option<&mut Widget> {
+        self.widgets.get_mut(&id)
+    }
+
+    pub fn focus(&self) -> Option<WidgetId> {
+        self.focus
+    }
+
+    fn close_button(&self) -> Rect {
+        let size = Self::title_bar_height() - 6;
+        Rect::new(self.width.saturating_sub(size + 4), 3, size, size)
+    }
+
+    /// Object at the point, or `None` if it's out of the window
+    pub fn hit_test(&self, x: isize, y: isize) -> Option<Hit> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        let point = Rect::new(x as usize, y as usize, 1, 1);
+        if !self.close_button().intersection(&point).is_empty() {
+            return Some(Hit::CloseButton);
+        }
+        if point.y < Self::title_bar_height() {
+            return Some(Hit::TitleBar);
+        }
+        let widget = self
+            .widgets
+            .iter()
+            .find(|(_, widget)| !widget.rect().intersection(&point).is_empty());
+        Some(match widget {
+            Some((&id, _)) => Hit::Widget(id),
+            None => Hit::Client,
+        })
+    }
+
+    pub fn on_event(&mut self, event: Event) -> Response {
+        match event {
+            Event::MouseDown { x, y } => self.on_mouse_down(x, y),
+            Event::MouseUp { x, y } => self.on_mouse_up(x, y),
+            Event::MouseMove { y, .. } => match self.pressed {
+                Some(Pressed::Widget(id)) => match self.widgets.get_mut(&id) {
+                    Some(Widget::ScrollBar(bar)) => bar.on_mouse_move(id, y - bar.rect.y as isize),
+                    _ => Response::default(),
+                },
+                _ => Response::default(),
+            },
+            Event::Key('\t') => {
+                self.focus_next();
+                Response::redraw(None)
+            }
+            Event::Key(c) => match self
+                .focus
+                .and_then(|id| Some((id, self.widgets.get_mut(&id)?)))
+            {
+                Some((id, Widget::TextBox(text_box))) => text_box.on_key(id, c),
+                _ => Response::default(),
+            },
+        }
+    }
+
+    fn on_mouse_down(&mut self, x: isize, y: isize) -> Response {
+        let hit = self.hit_test(x, y);
+        let prev_focus = self.focus;
+        self.focus = None;
+        let mut response = Response::default();
+        match hit {
+            Some(Hit::CloseButton) => self.pressed = Some(Pressed::CloseButton),
+            Some(Hit::Widget(id)) => {
+                self.pressed = Some(Pressed::Widget(id));
+                let widget = self.widgets.get_mut(&id).unwrap();
+                if widget.is_focusable() {
+                    self.focus = Some(id);
+                }
+                match widget {
+                    Widget::Button(button) => {
+                        button.pressed = true;
+                        response.redraw = true;
+                    }
+                    Widget::ScrollBar(bar) => {
+                        response = bar.on_mouse_down(id, y as usize - bar.rect.y);
+                    }
+                    Widget::TextBox(_) => {}
+                }
+            }
+            _ => {}
+        }
+        response.redraw |= self.focus != prev_focus;
+        response
+    }
+
+    fn on_mouse_up(&mut self, x: isize, y: isize) -> Response {
+        let hit = self.hit_test(x, y);
+        match self.pressed.take() {
+            Some(Pressed::CloseButton) if hit == Some(Hit::CloseButton) => {
+                Response::redraw(Some(Action::Close))
+            }
+            Some(Pressed::Widget(id)) => match self.widgets.get_mut(&id) {
+                Some(Widget::Button(button)) => {
+                    button.pressed = false;
+                    let clicked = hit == Some(Hit::Widget(id));
+                    Response::redraw(if clicked {
+                        Some(Action::Clicked(id))
+                    } else {
+                        None
+                    })
+                }
+                Some(Widget::ScrollBar(bar)) => {
+                    bar.grabbed = None;
+                    Response::default()
+                }
+                _ => Response::default(),
+            },
+            _ => Response::default(),
+        }
+    }
+
+    /// Move the focus to the next focusable widget in the order added
+    fn focus_next(&mut self) {
+        let focusable = || {
+            self.widgets
+                .iter()
+                .filter(|(_, widget)| widget.is_focusable())
+                .map(|(&id, _)| id)
+        };
+        let first = focusable().min_by_key(|id| id.0);
+        let next = self.focus.and_then(|current| {
+            focusable()
+                .filter(|id| id.0 > current.0)
+                .min_by_key(|id| id.0)
+        });
+        self.focus = next.or(first);
+    }
+
+    /// Draw the window at `(x, y)` of the writer. The title bar of the active window is highlighted
+    pub fn draw<W: PixelWriter + ?Sized>(&self, writer: &mut W, x: usize, y: usize, active: bool) {
+        let bounds = Rect::new(x, y, self.width, self.height);
+        writer.fill_rect(bounds, FACE);
+        writer.draw_rect(bounds, DARK);
+        writer.fill_rect(
+            Rect::new(
+                x + 1,
+                y + 1,
+                self.width.saturating_sub(2),
+                Self::title_bar_height() - 2,
+            ),
+            if active { ACTIVE_TITLE } else { SHADOW },
+        );
+        writer.write_str(
+            x + 6,
+            y + (Self::title_bar_height() - fonts::cell_size().1) / 2,
+            self.title,
+            WHITE,
+        );
+
+        let close = self.close_button();
+        let close = Rect::new(x + close.x, y + close.y, close.width, close.height);
+        writer.fill_rect(close, FACE);
+        writer.draw_rect(close, DARK);
+        let (left, top) = (close.x as isize + 3, close.y as isize + 3);
+        let (right, bottom) = (close.right() as isize - 4, close.bottom() as isize - 4);
+        writer.draw_line(left, top, right, bottom, BLACK);
+        writer.draw_line(left, bottom, right, top, BLACK);
+
+        for (&id, widget) in self.widgets.iter() {
+            widget.draw(writer, (x, y), self.focus == Some(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::{PixelColor, Rect, ShadowBuffer};
+    use crate::widget::{
+        Action, Button, Event, Hit, Response, ScrollBar, TextBox, Widget, Window, ACTIVE_TITLE,
+    };
+    use rumikan_shared::graphics::PixelFormat;
+
+    fn click(window: &mut Window, x: isize, y: isize) -> Option<Action> {
+        let pressed = window.on_event(Event::MouseDown { x, y }).action;
+        let released = window.on_event(Event::MouseUp { x, y }).action;
+        pressed.or(released)
+    }
+
+    #[test]
+    fn click_button() {
+        let mut window = Window::new("test", 200, 100);
+        let ok = window
+            .add(Widget::Button(Button::new(Rect::new(10, 30, 60, 20), "OK")))
+            .unwrap();
+        assert_eq!(window.hit_test(20, 40), Some(Hit::Widget(ok)));
+        assert_eq!(window.hit_test(20, 10), Some(Hit::TitleBar));
+        assert_eq!(window.hit_test(190, 10), Some(Hit::CloseButton));
+        assert_eq!(window.hit_test(100, 80), Some(Hit::Client));
+        assert_eq!(window.hit_test(-1, 80), None);
+
+        assert_eq!(click(&mut window, 20, 40), Some(Action::Clicked(ok)));
+        // released out of the button
+        window.on_event(Event::MouseDown { x: 20, y: 40 });
+        assert_eq!(
+            window.on_event(Event::MouseUp { x: 100, y: 40 }),
+            Response {
+                redraw: true,
+                action: None
+            }
+        );
+        assert_eq!(click(&mut window, 190, 10), Some(Action::Close));
+    }
+
+    #[test]
+    fn type_into_focused_text_box() {
+        let mut window = Window::new("test", 200, 100);
+        let first = window
+            .add(Widget::TextBox(TextBox::new(Rect::new(10, 30, 100, 20))))
+            .unwrap();
+        let second = window
+            .add(Widget::TextBox(TextBox::new(Rect::new(10, 60, 100, 20))))
+            .unwrap();
+
+        // not focused yet
+        assert!(!window.on_event(Event::Key('a')).redraw);
+        click(&mut window, 20, 40);
+        assert_eq!(window.focus(), Some(first));
+        for c in "abc\x08d".chars() {
+            window.on_event(Event::Key(c));
+        }
+        assert_eq!(
+            window.on_event(Event::Key('\n')).action,
+            Some(Action::Submitted(first))
+        );
+        match window.widget(first) {
+            Some(Widget::TextBox(text_box)) => assert_eq!(text_box.text(), &['a', 'b', 'd']),
+            _ => panic!(),
+        }
+
+        window.on_event(Event::Key('\t'));
+        assert_eq!(window.focus(), Some(second));
+        window.on_event(Event::Key('\t'));
+        assert_eq!(window.focus(), Some(first));
+        // clicking elsewhere releases the focus
+        click(&mut window, 150, 90);
+        assert_eq!(window.focus(), None);
+    }
+
+    #[test]
+    fn scroll() {
+        let mut window = Window::new("test", 200, 140);
+        // thumb is 20px high at first
+        let bar = window
+            .add(Widget::ScrollBar(ScrollBar::new(
+                Rect::new(180, 20, 16, 100),
+                50,
+                10,
+            )))
+            .unwrap();
+        // page down
+        assert_eq!(
+            click(&mut window, 185, 100),
+            Some(Action::Scrolled(bar, 10))
+        );
+        // drag the thumb, which is at 20..40 of the bar, to the bottom
+        window.on_event(Event::MouseDown { x: 185, y: 45 });
+        assert_eq!(
+            window.on_event(Event::MouseMove { x: 185, y: 85 }).action,
+            Some(Action::Scrolled(bar, 30))
+        );
+        assert_eq!(
+            window.on_event(Event::MouseMove { x: 300, y: 500 }).action,
+            Some(Action::Scrolled(bar, 40))
+        );
+        window.on_event(Event::MouseUp { x: 300, y: 500 });
+        assert_eq!(
+            window.on_event(Event::MouseMove { x: 185, y: 45 }).action,
+            None
+        );
+        // page up
+        assert_eq!(click(&mut window, 185, 30), Some(Action::Scrolled(bar, 30)));
+    }
+
+    #[test]
+    fn draw_window() {
+        let pixels = Box::leak(vec![0u32; 64 * 48].into_boxed_slice());
+        let mut buf = ShadowBuffer::new(pixels, 64, 48, PixelFormat::Rgb);
+        let mut window = Window::new("w", 60, 40);
+        window
+            .add(Widget::Button(Button::new(Rect::new(4, 22, 20, 16), "B")))
+            .unwrap();
+        window.draw(&mut buf, 2, 2, true);
+        assert_eq!(buf.read_pixel(10, 3), Some(ACTIVE_TITLE));
+        // title
+        assert_eq!(
+            buf.read_pixel(8, 4),
+            Some(PixelColor::new(0xff, 0xff, 0xff))
+        );
+        // label of the button
+        assert_eq!(buf.read_pixel(18, 30), Some(PixelColor::new(0, 0, 0)));
+        assert_eq!(buf.read_pixel(63, 47), Some(PixelColor::new(0, 0, 0)));
+        assert_eq!(buf.dirty_rects(), &[Rect::new(2, 2, 60, 40)]);
+    }
+}
@@ -0,0 +1,525 @@
+//! Windows placed on layers, which receive the mouse and keyboard events.
+//!
+//! Pressing the left button on a window focuses it and raises it to the top of the windows.
+//! The window is dragged by its title bar, and otherwise the mouse events go to the window
+//! until the button is released. The keys go to the focused window.
+//! The cursor turns into an I-beam over the text boxes unless a shape is forced, e.g. while busy.
+
+use crate::error::ErrorContext;
+use crate::graphics::cursor::{CursorShape, Palette, DEFAULT_PALETTE};
+use crate::layer::{LayerId, LayerManager};
+use crate::usb::classdriver::MouseEvent;
+use crate::util::collection::{ArrayMap, CollectionError};
+use crate::widget::{Action, Event, Hit, Widget, Window};
+
+#[derive(Debug)]
+pub enum ErrorType {
+    NoSuchWindow(LayerId),
+    LayerError(crate::layer::Error),
+    CollectionError(CollectionError),
+}
+
+pub type Error = ErrorContext<ErrorType>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+const MAX_WINDOWS: usize = 8;
+
+static mut WINDOW_MANAGER: Option<WindowManager> = None;
+
+pub fn init_global_window_manager(manager: WindowManager) {
+    unsafe { WINDOW_MANAGER = Some(manager) };
+}
+
+/// `None` until [`init_global_window_manager`] is called
+pub fn window_manager() -> Option<&'static mut WindowManager> {
+    unsafe { WINDOW_MANAGER.as_mut() }
+}
+
+/// Called with the actions of the window. The window is drawn again afterwards,
+/// or closed if the action is [`Action::Close`]
+pub type WindowHandler = fn(&mut Window, Action);
+
+struct Entry {
+    window: Window,
+    handler: WindowHandler,
+}
+
+/// What the mouse events go to while the left button is held
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Capture {
+    /// The window is dragged, grabbed at the offset from its top-left corner
+    Drag {
+        layer: LayerId,
+        dx: isize,
+        dy: isize,
+    },
+    Window(LayerId),
+}
+
+pub struct WindowManager {
+    windows: ArrayMap<LayerId, Entry, MAX_WINDOWS>,
+    /// Layer kept above every window if any
+    top: Option<LayerId>,
+    focus: Option<LayerId>,
+    capture: Option<Capture>,
+    buttons: u8,
+    theme: Palette,
+    /// Shape shown regardless of the point
+    forced_cursor: Option<CursorShape>,
+    /// Shape shown now, which is `None` until the cursor is shown
+    cursor: Option<CursorShape>,
+}
+
+impl WindowManager {
+    pub fn new(top: Option<LayerId>) -> WindowManager {
+        WindowManager {
+            windows: ArrayMap::new(),
+            top,
+            focus: None,
+            capture: None,
+            buttons: 0,
+            theme: DEFAULT_PALETTE,
+            forced_cursor: None,
+            cursor: None,
+        }
+    }
+
+    /// Show the shape of the cursor wherever it points, or let it follow the point by `None`
+    pub fn set_cursor(&mut self, layers: &mut LayerManager, shape: Option<CursorShape>) {
+        self.forced_cursor = shape;
+        let (x, y) = layers.cursor_position();
+        self.update_cursor(layers, x, y);
+    }
+
+    /// Draw the cursors in the colors from now on
+    pub fn set_cursor_theme(&mut self, layers: &mut LayerManager, palette: Palette) {
+        self.theme = palette;
+        self.cursor = None;
+        let (x, y) = layers.cursor_position();
+        self.update_cursor(layers, x, y);
+    }
+
+    /// Shape of the cursor shown now
+    pub fn cursor(&self) -> Option<CursorShape> {
+        self.cursor
+    }
+
+    /// Show the window on the layer, which must be as large as the window, and focus it
+    pub fn add(
+        &mut self,
+        layers: &mut LayerManager,
+        layer: LayerId,
+        window: Window,
+        handler: WindowHandler,
+    ) -> Result<()> {
+        layers
+            .layer(layer)
+            .map_err(|e| mkerror!(ErrorType::LayerError(e)))?;
+        self.windows
+            .insert(layer, Entry { window, handler })
+            .map_err(|e| mkerror!(ErrorType::CollectionError(e)))?;
+        self.activate(layers, layer)
+    }
+
+    /// Hide the window and forget it
+    pub fn close(&mut self, layers: &mut LayerManager, layer: LayerId) -> Result<()> {
+        self.windows
+            .remove(&layer)
+            .ok_or_else(|| mkerror!(ErrorType::NoSuchWindow(layer)))?;
+        if self.focus == Some(layer) {
+            self.focus = None;
+        }
+        match self.capture {
+            Some(Capture::Drag { layer: l, .. }) | Some(Capture::Window(l)) if l == layer => {
+                self.capture = None
+            }
+            _ => {}
+        }
+        layers
+            .set_z(layer, None)
+            .map_err(|e| mkerror!(ErrorType::LayerError(e)))
+    }
+
+    pub fn window(&mut self, layer: LayerId) -> Option<&mut Window> {
+        self.windows.get_mut(&layer).map(|entry| &mut entry.window)
+    }
+
+    pub fn focus(&self) -> Option<LayerId> {
+        self.focus
+    }
+
+    /// The topmost window at the point of the screen
+    pub fn window_at(&self, layers: &LayerManager, x: isize, y: isize) -> Option<LayerId> {
+        layers.z_order().iter().rev().copied().find(|id| {
+            match (self.windows.get(id), layers.position(*id)) {
+                (Some(entry), Ok((wx, wy))) => entry.window.hit_test(x - wx, y - wy).is_some(),
+                _ => false,
+            }
+        })
+    }
+
+    /// Focus the window and raise it just below the top layer
+    pub fn activate(&mut self, layers: &mut LayerManager, layer: LayerId) -> Result<()> {
+        if self.windows.get(&layer).is_none() {
+            return Err(mkerror!(ErrorType::NoSuchWindow(layer)));
+        }
+        let z = match (layers.z(layer), self.top.and_then(|top| layers.z(top))) {
+            // the top layer moves down once this one is taken out
+            (Some(z), Some(top)) if z < top => top - 1,
+            (_, Some(top)) => top,
+            (_, None) => usize::MAX,
+        };
+        if layers.z(layer) != Some(z) {
+            layers
+                .set_z(layer, Some(z))
+                .map_err(|e| mkerror!(ErrorType::LayerError(e)))?;
+        }
+        let prev = self.focus.replace(layer);
+        if let Some(prev) = prev.filter(|&prev| prev != layer) {
+            self.redraw(layers, prev)?;
+        }
+        self.redraw(layers, layer)
+    }
+
+    /// Dispatch the mouse event, where the cursor has moved to `(x, y)` on the screen
+    pub fn on_mouse_event(
+        &mut self,
+        layers: &mut LayerManager,
+        event: MouseEvent,
+        x: isize,
+        y: isize,
+    ) -> Result<()> {
+        let was_pressed = self.buttons & MouseEvent::BUTTON_LEFT != 0;
+        self.buttons = event.buttons;
+        let result = match (was_pressed, event.is_pressed(MouseEvent::BUTTON_LEFT)) {
+            (false, true) => self.on_press(layers, x, y),
+            (true, true) => match self.capture {
+                Some(Capture::Drag { layer, dx, dy }) => layers
+                    .move_to(layer, x - dx, y - dy)
+                    .map_err(|e| mkerror!(ErrorType::LayerError(e))),
+                Some(Capture::Window(layer)) => {
+                    self.dispatch(layers, layer, x, y, |x, y| Event::MouseMove { x, y })
+                }
+                None => Ok(()),
+            },
+            (true, false) => match self.capture.take() {
+                Some(Capture::Window(layer)) => {
+                    self.dispatch(layers, layer, x, y, |x, y| Event::MouseUp { x, y })
+                }
+                _ => Ok(()),
+            },
+            (false, false) => Ok(()),
+        };
+        self.update_cursor(layers, x, y);
+        result
+    }
+
+    /// Dispatch the key to the focused window
+    pub fn on_key(&mut self, layers: &mut LayerManager, c: char) -> Result<()> {
+        match self.focus {
+            Some(layer) => self.dispatch(layers, layer, 0, 0, |_, _| Event::Key(c)),
+            None => Ok(()),
+        }
+    }
+
+    fn on_press(&mut self, layers: &mut LayerManager, x: isize, y: isize) -> Result<()> {
+        let layer = match self.window_at(layers, x, y) {
+            Some(layer) => layer,
+            None => {
+                // clicking the desktop leaves no window focused
+                if let Some(prev) = self.focus.take() {
+                    self.redraw(layers, prev)?;
+                }
+                return Ok(());
+            }
+        };
+        self.activate(layers, layer)?;
+        let (wx, wy) = layers.position(layer).unwrap();
+        let window = &self.windows.get(&layer).unwrap().window;
+        if window.hit_test(x - wx, y - wy) == Some(Hit::TitleBar) {
+            self.capture = Some(Capture::Drag {
+                layer,
+                dx: x - wx,
+                dy: y - wy,
+            });
+            Ok(())
+        } else {
+            self.capture = Some(Capture::Window(layer));
+            self.dispatch(layers, layer, x, y, |x, y| Event::MouseDown { x, y })
+        }
+    }
+
+    fn update_cursor(&mut self, layers: &mut LayerManager, x: isize, y: isize) {
+        let shape = self
+            .forced_cursor
+            .unwrap_or_else(|| self.cursor_at(layers, x, y));
+        if self.cursor != Some(shape) {
+            self.cursor = Some(shape);
+            layers.set_cursor(Some(shape.cursor(self.theme)));
+        }
+    }
+
+    /// Shape of the cursor following the point of the screen
+    fn cursor_at(&mut self, layers: &LayerManager, x: isize, y: isize) -> CursorShape {
+        if let Some(Capture::Drag { .. }) = self.capture {
+            return CursorShape::Arrow;
+        }
+        let (layer, (wx, wy)) = match self.window_at(layers, x, y) {
+            Some(layer) => (layer, layers.position(layer).unwrap()),
+            None => return CursorShape::Arrow,
+        };
+        let window = &mut self.windows.get_mut(&layer).unwrap().window;
+        match window.hit_test(x - wx, y - wy) {
+            Some(Hit::Widget(id)) => match window.widget(id) {
+                Some(Widget::TextBox(_)) => CursorShape::Text,
+                _ => CursorShape::Arrow,
+            },
+            _ => CursorShape::Arrow,
+        }
+    }
+
+    /// Send the event made from the point relative to the window, and handle the response
+    fn dispatch<F: FnOnce(isize, isize) -> Event>(
+        &mut self,
+        layers: &mut LayerManager,
+        layer: LayerId,
+        x: isize,
+        y: isize,
+        event: F,
+    ) -> Result<()> {
+        let (wx, wy) = layers
+            .position(layer)
+            .map_err(|e| mkerror!(ErrorType::LayerError(e)))?;
+        let entry = self
+            .windows
+            .get_mut(&layer)
+            .ok_or_else(|| mkerror!(ErrorType::NoSuchWindow(layer)))?;
+        let response = entry.window.on_event(event(x - wx, y - wy));
+        if let Some(action) = response.action {
+            (entry.handler)(&mut entry.window, action);
+            if action == Action::Close {
+                return self.close(layers, layer);
+            }
+        }
+        if response.redraw || response.action.is_some() {
+            self.redraw(layers, layer)?;
+        }
+        Ok(())
+    }
+
+    fn redraw(&mut self, layers: &mut LayerManager, layer: LayerId) -> Result<()> {
+        let active = self.focus == Some(layer);
+        let entry = self
+            .windows
+            .get(&layer)
+            .ok_or_else(|| mkerror!(ErrorType::NoSuchWindow(layer)))?;
+        let buffer = layers
+            .layer(layer)
+            .map_err(|e| mkerror!(ErrorType::LayerError(e)))?
+            .buffer();
+        entry.window.draw(buffer, 0, 0, active);
+        layers
+            .draw(layer)
+            .map_err(|e| mkerror!(ErrorType::LayerError(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::cursor::{CursorShape, Palette, PaletteColor, DEFAULT_PALETTE};
+    use crate::graphics::{FrameBuffer, PixelColor, Rect};
+    use crate::layer::{LayerId, LayerManager};
+    use crate::usb::classdriver::MouseEvent;
+    use crate::widget::{Action, Button, TextBox, Widget, Window};
+    use crate::window::WindowManager;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use rumikan_shared::graphics::{FrameBufferInfo, PixelFormat};
+
+    const WIDTH: usize = 200;
+    const HEIGHT: usize = 150;
+
+    fn pixels(len: usize) -> &'static mut [u32] {
+        Box::leak(vec![0u32; len].into_boxed_slice())
+    }
+
+    /// Layer manager with a cursor layer on the top
+    fn layers() -> (LayerManager, LayerId) {
+        let vram = pixels(WIDTH * HEIGHT);
+        let frame_buffer = FrameBuffer::new(FrameBufferInfo::new(
+            vram.as_mut_ptr() as *mut u8,
+            WIDTH,
+            HEIGHT,
+            WIDTH,
+            PixelFormat::Rgb,
+        ));
+        let mut layers = LayerManager::new(frame_buffer, pixels(WIDTH * HEIGHT));
+        let cursor = layers.new_layer(pixels(4), 2, 2).unwrap();
+        layers.set_z(cursor, Some(0)).unwrap();
+        (layers, cursor)
+    }
+
+    fn open(
+        manager: &mut WindowManager,
+        layers: &mut LayerManager,
+        window: Window,
+        x: isize,
+        y: isize,
+    ) -> LayerId {
+        let (width, height) = window.size();
+        let layer = layers
+            .new_layer(pixels(width * height), width, height)
+            .unwrap();
+        layers.move_to(layer, x, y).unwrap();
+        manager.add(layers, layer, window, |_, _| {}).unwrap();
+        layer
+    }
+
+    fn click(manager: &mut WindowManager, layers: &mut LayerManager, x: isize, y: isize) {
+        let press = MouseEvent {
+            buttons: MouseEvent::BUTTON_LEFT,
+            ..MouseEvent::default()
+        };
+        manager.on_mouse_event(layers, press, x, y).unwrap();
+        manager
+            .on_mouse_event(layers, MouseEvent::default(), x, y)
+            .unwrap();
+    }
+
+    #[test]
+    fn click_to_focus_and_raise() {
+        let (mut layers, cursor) = layers();
+        let mut manager = WindowManager::new(Some(cursor));
+        let back = open(&mut manager, &mut layers, Window::new("a", 80, 60), 10, 10);
+        let front = open(&mut manager, &mut layers, Window::new("b", 80, 60), 50, 40);
+        assert_eq!(layers.z_order(), &[back, front, cursor]);
+        assert_eq!(manager.focus(), Some(front));
+
+        // overlapped area
+        assert_eq!(manager.window_at(&layers, 60, 50), Some(front));
+        assert_eq!(manager.window_at(&layers, 20, 20), Some(back));
+        assert_eq!(manager.window_at(&layers, 150, 20), None);
+
+        click(&mut manager, &mut layers, 20, 20);
+        assert_eq!(manager.focus(), Some(back));
+        assert_eq!(layers.z_order(), &[front, back, cursor]);
+        assert_eq!(manager.window_at(&layers, 60, 50), Some(back));
+
+        click(&mut manager, &mut layers, 150, 20);
+        assert_eq!(manager.focus(), None);
+        assert_eq!(layers.z_order(), &[front, back, cursor]);
+    }
+
+    #[test]
+    fn drag_by_title_bar() {
+        let (mut layers, cursor) = layers();
+        let mut manager = WindowManager::new(Some(cursor));
+        let window = open(&mut manager, &mut layers, Window::new("a", 80, 60), 10, 10);
+        let held = MouseEvent {
+            buttons: MouseEvent::BUTTON_LEFT,
+            ..MouseEvent::default()
+        };
+        manager.on_mouse_event(&mut layers, held, 30, 15).unwrap();
+        manager.on_mouse_event(&mut layers, held, 45, 35).unwrap();
+        assert_eq!(layers.position(window).unwrap(), (25, 30));
+        // above the screen
+        manager.on_mouse_event(&mut layers, held, 45, -5).unwrap();
+        assert_eq!(layers.position(window).unwrap(), (25, -10));
+        manager
+            .on_mouse_event(&mut layers, MouseEvent::default(), 100, 100)
+            .unwrap();
+        assert_eq!(layers.position(window).unwrap(), (25, -10));
+
+        // dragging in the client area doesn't move it
+        manager.on_mouse_event(&mut layers, held, 40, 30).unwrap();
+        manager.on_mouse_event(&mut layers, held, 60, 60).unwrap();
+        assert_eq!(layers.position(window).unwrap(), (25, -10));
+    }
+
+    #[test]
+    fn dispatch_to_focused_window() {
+        static CLICKS: AtomicUsize = AtomicUsize::new(0);
+        let (mut layers, cursor) = layers();
+        let mut manager = WindowManager::new(Some(cursor));
+        let mut window = Window::new("a", 100, 80);
+        window
+            .add(Widget::Button(Button::new(Rect::new(10, 30, 40, 20), "OK")))
+            .unwrap();
+        let text = window
+            .add(Widget::TextBox(TextBox::new(Rect::new(10, 55, 80, 20))))
+            .unwrap();
+        let (width, height) = window.size();
+        let layer = layers
+            .new_layer(pixels(width * height), width, height)
+            .unwrap();
+        manager
+            .add(&mut layers, layer, window, |_, action| {
+                if let Action::Clicked(_) = action {
+                    CLICKS.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .unwrap();
+
+        click(&mut manager, &mut layers, 20, 40);
+        assert_eq!(CLICKS.load(Ordering::Relaxed), 1);
+
+        // keys go nowhere until the text box is focused
+        click(&mut manager, &mut layers, 20, 60);
+        for c in "hi".chars() {
+            manager.on_key(&mut layers, c).unwrap();
+        }
+        match manager.window(layer).unwrap().widget(text) {
+            Some(Widget::TextBox(text_box)) => assert_eq!(text_box.text(), &['h', 'i']),
+            _ => panic!(),
+        }
+
+        // close button
+        click(&mut manager, &mut layers, 90, 10);
+        assert!(manager.window(layer).is_none());
+        assert_eq!(manager.focus(), None);
+        assert_eq!(layers.z_order(), &[cursor]);
+    }
+
+    #[test]
+    fn cursor_follows_widgets() {
+        let (mut layers, _) = layers();
+        let mut manager = WindowManager::new(None);
+        let mut window = Window::new("a", 100, 80);
+        window
+            .add(Widget::TextBox(TextBox::new(Rect::new(10, 55, 80, 20))))
+            .unwrap();
+        open(&mut manager, &mut layers, window, 0, 0);
+        let moved = |manager: &mut WindowManager, layers: &mut LayerManager, x, y| {
+            layers.move_cursor(x, y);
+            manager
+                .on_mouse_event(layers, MouseEvent::default(), x, y)
+                .unwrap();
+            manager.cursor()
+        };
+
+        assert_eq!(manager.cursor(), None);
+        assert_eq!(
+            moved(&mut manager, &mut layers, 20, 60),
+            Some(CursorShape::Text)
+        );
+        assert_eq!(
+            moved(&mut manager, &mut layers, 20, 40),
+            Some(CursorShape::Arrow)
+        );
+
+        // forced while busy
+        manager.set_cursor(&mut layers, Some(CursorShape::Busy));
+        assert_eq!(
+            moved(&mut manager, &mut layers, 20, 60),
+            Some(CursorShape::Busy)
+        );
+        manager.set_cursor(&mut layers, None);
+        assert_eq!(manager.cursor(), Some(CursorShape::Text));
+
+        let theme = Palette {
+            fill: PaletteColor::new(PixelColor::new(0, 0xff, 0), 0xff),
+            ..DEFAULT_PALETTE
+        };
+        manager.set_cursor_theme(&mut layers, theme);
+        assert_eq!(manager.cursor(), Some(CursorShape::Text));
+    }
+}
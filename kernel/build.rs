@@ -1,25 +1,256 @@
+//! Converts the fonts in `resources` into the glyph tables of `rumikan_kernel_lib::graphics::fonts`
+//! and generates `fonts.rs` listing them, which the kernel includes.
+//!
+//! - `*.psf`, `*.psfu`: PC Screen Font version 2. Code points come from the unicode table if any
+//! - `*.bdf`: Glyph Bitmap Distribution Format. Glyphs are grouped into tables by their advance width
+//! - `shinonome_halfwidth.bin`: 8x16 glyphs in the order of JIS X 0201
+//! - `shinonome_fullwidth.bin`: 16x16 glyphs in the order of JIS X 0208. The build fails without it
+
+use std::collections::BTreeMap;
 use std::env;
-use std::path::Path;
-use std::process::Command;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+const PSF2_MAGIC: &[u8] = &[0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+/// Number of the fonts the kernel can register, which is `MAX_FONTS` of `rumikan_kernel_lib::graphics::fonts`
+const MAX_FONTS: usize = 8;
+
+/// Glyphs of the same size by code point
+struct Table {
+    width: usize,
+    height: usize,
+    glyphs: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Table {
+    fn new(width: usize, height: usize) -> Table {
+        Table {
+            width,
+            height,
+            glyphs: BTreeMap::new(),
+        }
+    }
+
+    fn stride(&self) -> usize {
+        (self.width + 7) / 8
+    }
+
+    fn bitmap_size(&self) -> usize {
+        self.stride() * self.height
+    }
+
+    /// Glyphs appearing earlier take precedence
+    fn insert(&mut self, code_point: u32, bitmap: &[u8]) {
+        assert_eq!(bitmap.len(), self.bitmap_size());
+        self.glyphs
+            .entry(code_point)
+            .or_insert_with(|| bitmap.to_vec());
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"RMKF".to_vec();
+        bytes.extend_from_slice(&(self.width as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.height as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.glyphs.len() as u32).to_le_bytes());
+        for code_point in self.glyphs.keys() {
+            bytes.extend_from_slice(&code_point.to_le_bytes());
+        }
+        for bitmap in self.glyphs.values() {
+            bytes.extend_from_slice(bitmap);
+        }
+        bytes
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn parse_psf2(name: &str, data: &[u8]) -> Table {
+    assert!(
+        data.len() >= 32 && &data[..4] == PSF2_MAGIC,
+        "{} is not a PSF2 font",
+        name
+    );
+    let header_size = u32_at(data, 8) as usize;
+    let flags = u32_at(data, 12);
+    let length = u32_at(data, 16) as usize;
+    let char_size = u32_at(data, 20) as usize;
+    let mut table = Table::new(u32_at(data, 28) as usize, u32_at(data, 24) as usize);
+    assert_eq!(char_size, table.bitmap_size(), "{} has broken header", name);
+    let glyph = |i: usize| &data[header_size + i * char_size..header_size + (i + 1) * char_size];
+
+    if flags & PSF2_HAS_UNICODE_TABLE == 0 {
+        for i in 0..length {
+            table.insert(i as u32, glyph(i));
+        }
+        return table;
+    }
+    // for each glyph, UTF-8 characters terminated by 0xff.
+    // The sequences of the combining characters following 0xfe are skipped
+    let mut entries = data[header_size + length * char_size..].split(|&b| b == 0xff);
+    for i in 0..length {
+        let entry = entries.next().unwrap_or_default();
+        let singles = entry.split(|&b| b == 0xfe).next().unwrap_or_default();
+        let singles = std::str::from_utf8(singles)
+            .unwrap_or_else(|_| panic!("{} has broken unicode table", name));
+        for c in singles.chars() {
+            table.insert(c as u32, glyph(i));
+        }
+    }
+    table
+}
+
+/// Tables by the advance width. Each glyph is placed in the font bounding box on the baseline
+fn parse_bdf(name: &str, text: &str) -> Vec<Table> {
+    let numbers = |line: &str| -> Vec<i32> {
+        line.split_whitespace()
+            .skip(1)
+            .map(|n| {
+                n.parse()
+                    .unwrap_or_else(|_| panic!("{}: invalid number in {}", name, line))
+            })
+            .collect()
+    };
+    let mut lines = text.lines().map(str::trim);
+    let mut bounding_box = None;
+    let mut tables: BTreeMap<usize, Table> = BTreeMap::new();
+    let (mut encoding, mut advance, mut bbx) = (-1, 0, [0; 4]);
+    while let Some(line) = lines.next() {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        match keyword {
+            "FONTBOUNDINGBOX" => bounding_box = Some(numbers(line)),
+            "STARTCHAR" => {
+                encoding = -1;
+                advance = 0;
+                bbx = [0; 4];
+            }
+            "ENCODING" => encoding = numbers(line)[0],
+            "DWIDTH" => advance = numbers(line)[0],
+            "BBX" => bbx.copy_from_slice(&numbers(line)[..4]),
+            "BITMAP" => {
+                let font_box = bounding_box
+                    .as_ref()
+                    .unwrap_or_else(|| panic!("{} has no FONTBOUNDINGBOX", name));
+                let (height, descent) = (font_box[1], font_box[3]);
+                let rows: Vec<&str> = lines.by_ref().take_while(|&l| l != "ENDCHAR").collect();
+                if encoding < 0 || advance <= 0 {
+                    continue;
+                }
+                let table = tables
+                    .entry(advance as usize)
+                    .or_insert_with(|| Table::new(advance as usize, height as usize));
+                let stride = table.stride();
+                let mut bitmap = vec![0; table.bitmap_size()];
+                let [width, rows_count, x_offset, y_offset] = bbx;
+                // rows from the top of the cell, whose bottom is at `descent` below the baseline
+                let top = (height + descent) - (y_offset + rows_count);
+                for (y, row) in rows.iter().enumerate() {
+                    let is_set = |x: i32| {
+                        let digit = row.get(x as usize / 4..x as usize / 4 + 1);
+                        let digit = digit.and_then(|d| u8::from_str_radix(d, 16).ok());
+                        digit.map_or(false, |d| d & (0x8 >> (x % 4)) != 0)
+                    };
+                    for x in 0..width {
+                        let (cx, cy) = (x_offset + x, top + y as i32);
+                        if !is_set(x) || cx < 0 || cy < 0 || cx >= advance || cy >= height {
+                            continue;
+                        }
+                        let (cx, cy) = (cx as usize, cy as usize);
+                        bitmap[cy * stride + cx / 8] |= 0x80 >> (cx % 8);
+                    }
+                }
+                table.insert(encoding as u32, &bitmap);
+            }
+            _ => {}
+        }
+    }
+    tables.into_iter().map(|(_, table)| table).collect()
+}
+
+fn parse_halfwidth(data: &[u8]) -> Table {
+    let mut table = Table::new(8, 16);
+    let glyph = |index: usize| &data[index * 16..(index + 1) * 16];
+    for index in 0x20..=0x7e {
+        table.insert(index as u32, glyph(index));
+    }
+    // half-width katakana
+    for index in 0xa1..=0xdf {
+        table.insert((0xff61 + index - 0xa1) as u32, glyph(index));
+    }
+    table
+}
+
+/// `jis_x_0208` is the pairs of a code point and the index of the glyph, as the kernel library has
+fn parse_fullwidth(data: &[u8], jis_x_0208: &[u8]) -> Table {
+    let mut table = Table::new(16, 16);
+    for entry in jis_x_0208.chunks_exact(4) {
+        let code_point = u16::from_le_bytes([entry[0], entry[1]]) as u32;
+        let index = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+        if let Some(bitmap) = data.get(index * 32..(index + 1) * 32) {
+            table.insert(code_point, bitmap);
+        }
+    }
+    table
+}
 
 fn main() {
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let current_dir = env::current_dir().unwrap();
-
-    let font_obj = Path::new(&out_dir).join("shinonome_halfwidth.o");
-
-    Command::new("objcopy")
-        .current_dir(Path::new(&current_dir).join("resources"))
-        .args(&["-I", "binary"])
-        .args(&["-O", "elf64-x86-64"])
-        .args(&["-B", "i386:x86-64"])
-        .arg("shinonome_halfwidth.bin")
-        .arg(&font_obj)
-        .status()
-        .unwrap();
-
-    println!(
-        "cargo:rustc-link-arg={}",
-        font_obj.as_path().to_str().unwrap()
-    )
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let resources = manifest_dir.join("resources");
+    // the kernel library looks up the glyphs with the same map
+    let jis_x_0208 = manifest_dir.join("../kernel-lib/resources/jisx0208.bin");
+    println!("cargo:rerun-if-changed=resources");
+    println!("cargo:rerun-if-changed={}", jis_x_0208.display());
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&resources)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    // the fonts given are registered before the Shinonome fonts to take precedence
+    let mut tables = vec![];
+    for path in &paths {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("psf") | Some("psfu") => tables.push(parse_psf2(name, &fs::read(path).unwrap())),
+            Some("bdf") => tables.extend(parse_bdf(name, &fs::read_to_string(path).unwrap())),
+            _ => {}
+        }
+    }
+    tables.push(parse_halfwidth(
+        &fs::read(resources.join("shinonome_halfwidth.bin")).unwrap(),
+    ));
+    let fullwidth = fs::read(resources.join("shinonome_fullwidth.bin")).unwrap_or_else(|e| {
+        panic!(
+            "resources/shinonome_fullwidth.bin can't be read: {}. \
+             It must have the 16x16 glyphs of Shinonome font in the order of JIS X 0208",
+            e
+        )
+    });
+    tables.push(parse_fullwidth(&fullwidth, &fs::read(jis_x_0208).unwrap()));
+
+    assert!(
+        tables.len() <= MAX_FONTS,
+        "resources make {} glyph tables, but the kernel can register only {}. \
+         Note that a BDF font makes a table for each advance width",
+        tables.len(),
+        MAX_FONTS
+    );
+
+    let mut list = String::from("pub static FONTS: &[&[u8]] = &[\n");
+    for (i, table) in tables.iter().enumerate() {
+        let path = out_dir.join(format!("font{}.rmkf", i));
+        fs::write(&path, table.to_bytes()).unwrap();
+        writeln!(list, "    include_bytes!({:?}),", path.to_str().unwrap()).unwrap();
+    }
+    list.push_str("];\n");
+    fs::write(out_dir.join("fonts.rs"), list).unwrap();
 }
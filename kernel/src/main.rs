@@ -4,52 +4,114 @@
 #![feature(abi_x86_interrupt)]
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use rumikan_kernel_lib::console::{init_global_console, Console};
-use rumikan_kernel_lib::graphics::{FrameBuffer, PixelColor};
+use rumikan_kernel_lib::console::{console, init_global_console, Cell, Console};
+use rumikan_kernel_lib::graphics::fonts::{self, Font};
+use rumikan_kernel_lib::graphics::image;
+use rumikan_kernel_lib::graphics::{FrameBuffer, PixelColor, PixelWriter, Rect};
 use rumikan_kernel_lib::interrupt::{
     notify_end_interrupt, DescriptorType, InterruptDescriptorAttribute, InterruptDescriptorTable,
     InterruptEvent, InterruptFrame, InterruptVector,
 };
-use rumikan_kernel_lib::logger::{init_logger, LogLevel};
+use rumikan_kernel_lib::layer::{init_global_layer_manager, layer_manager, LayerId, LayerManager};
+use rumikan_kernel_lib::logger::{init_logger, set_log_sink, LogLevel};
 use rumikan_kernel_lib::pci::{ClassCode, MSIDeliveryMode, MSITriggerMode, Pci};
-use rumikan_kernel_lib::usb::Xhc;
+use rumikan_kernel_lib::timer::{initialize_lapic_timer, take_pending_tick};
+use rumikan_kernel_lib::usb::classdriver::{cdc, MouseEvent};
+use rumikan_kernel_lib::usb::{self, mem, InterrupterConfig, Xhc, MAX_INTERRUPTERS};
 use rumikan_kernel_lib::util::collection::ArrayQueue;
+use rumikan_kernel_lib::widget::{Action, Button, TextBox, Widget, WidgetId, Window};
+use rumikan_kernel_lib::window::{init_global_window_manager, window_manager, WindowManager};
+use rumikan_shared::boot::BootFile;
 use rumikan_shared::graphics::FrameBufferInfo;
 
 #[macro_use]
 extern crate rumikan_kernel_lib;
 
+/// Glyph tables converted from the fonts in `resources` by the build script
+mod font_data {
+    include!(concat!(env!("OUT_DIR"), "/fonts.rs"));
+}
+
+/// Largest screen the back buffer and the desktop can cover. Only the part of a larger screen is used
+const MAX_SCREEN_PIXELS: usize = 1920 * 1200;
+const MAX_CONSOLE_COLS: usize = 1920 / 8;
+
+static mut SCREEN_PIXELS: [u32; MAX_SCREEN_PIXELS] = [0; MAX_SCREEN_PIXELS];
+static mut DESKTOP_PIXELS: [u32; MAX_SCREEN_PIXELS] = [0; MAX_SCREEN_PIXELS];
+static mut CONSOLE_PIXELS: [u32; MAX_SCREEN_PIXELS] = [0; MAX_SCREEN_PIXELS];
+const CONSOLE_BG: PixelColor = PixelColor::new(0, 0, 0);
+/// Lines of the console kept including the screen
+const CONSOLE_LINES: usize = 500;
+static mut CONSOLE_CELLS: [Cell; MAX_CONSOLE_COLS * CONSOLE_LINES] =
+    [Cell::BLANK; MAX_CONSOLE_COLS * CONSOLE_LINES];
+const HELLO_WIDTH: usize = 200;
+const HELLO_HEIGHT: usize = 80;
+static mut HELLO_PIXELS: [u32; HELLO_WIDTH * HELLO_HEIGHT] = [0; HELLO_WIDTH * HELLO_HEIGHT];
+
 #[no_mangle]
 #[allow(clippy::fn_to_numeric_cast)]
-pub extern "C" fn _start(frame_buffer_info: FrameBufferInfo) -> ! {
-    let mut frame_buffer = FrameBuffer::new(frame_buffer_info);
+pub extern "C" fn _start(frame_buffer_info: FrameBufferInfo, wallpaper: BootFile) -> ! {
+    let frame_buffer = FrameBuffer::new(frame_buffer_info);
+    unsafe { FRAME_BUFFER = Some(frame_buffer) };
+    let (screen_width, screen_height) = frame_buffer.resolution();
+    init_global_layer_manager(LayerManager::new(frame_buffer, unsafe {
+        &mut SCREEN_PIXELS
+    }));
+    let layer_manager = layer_manager().unwrap();
+    let (width, height) = layer_manager.screen_size();
+
+    let desktop = layer_manager
+        .new_layer(unsafe { &mut DESKTOP_PIXELS }, width, height)
+        .unwrap();
+    let buffer = layer_manager.layer(desktop).unwrap().buffer();
+    buffer.fill_rect(buffer.bounds(), PixelColor::new(45, 118, 237));
+    layer_manager.set_z(desktop, Some(0)).unwrap();
+
+    // reported once the logger is ready
+    let mut font_errors = 0;
+    for &data in font_data::FONTS {
+        if Font::parse(data).and_then(fonts::register).is_err() {
+            font_errors += 1;
+        }
+    }
+    fonts::select_size(height);
+
+    let console_layer = layer_manager
+        .new_layer(unsafe { &mut CONSOLE_PIXELS }, width, height)
+        .unwrap();
+    layer_manager.set_z(console_layer, Some(1)).unwrap();
     let console = Console::new(
-        frame_buffer,
-        PixelColor::new(0, 0, 0),
+        console_layer,
+        unsafe { &mut CONSOLE_CELLS },
+        CONSOLE_BG,
         PixelColor::new(0xff, 0xff, 0xff),
     );
     init_global_console(console);
     init_logger(LogLevel::Info);
-
-    let mouse_cursor_info = MouseCursorInfo {
-        frame_buffer,
-        current_pos: (50, 50),
-        fill_color: PixelColor::new(0xff, 0, 0),
-        edge_color: PixelColor::new(0xff, 0xff, 0xff),
-        bgcolor: PixelColor::new(0, 0, 0),
-    };
-    unsafe {
-        MOUSE_CURSOR_INFO = Some(mouse_cursor_info);
+    if font_errors > 0 {
+        warn!(
+            "{} of the fonts are skipped as they can't be used",
+            font_errors
+        );
+    }
+    if (width, height) != (screen_width, screen_height) {
+        warn!(
+            "Screen {}x{} is too large. Only {}x{} of it is used",
+            screen_width, screen_height, width, height
+        );
     }
+    draw_wallpaper(desktop, wallpaper);
+
+    layer_manager.move_cursor(50, 50);
+    init_global_window_manager(WindowManager::new(None));
+    window_manager().unwrap().set_cursor(layer_manager, None);
+    open_hello_window();
     info!("Hello, world!");
-    frame_buffer.write_mouse_cursor(
-        mouse_cursor_info.current_pos.0,
-        mouse_cursor_info.current_pos.1,
-        mouse_cursor_info.edge_color,
-        mouse_cursor_info.fill_color,
-    );
     rumikan_kernel_lib::usb::classdriver::set_default_mouse_observer(on_mouse_event);
+    // mirror the log to a CDC-ACM serial console once it's attached
+    set_log_sink(cdc::print);
 
     let mut pci = Pci::new();
     if pci.scan_all_bus().is_err() {
@@ -103,23 +165,67 @@ pub extern "C" fn _start(frame_buffer_info: FrameBufferInfo) -> ! {
                 .with_descriptor_privilege_level(0),
             xhc_interrupt_handler as u64,
         );
+        idt.set(
+            InterruptVector::XHCIInterrupter1,
+            InterruptDescriptorAttribute::new()
+                .with_descriptor_type(DescriptorType::InterruptGate)
+                .with_descriptor_privilege_level(0),
+            xhc_interrupter1_handler as u64,
+        );
+        idt.set(
+            InterruptVector::XHCIInterrupter2,
+            InterruptDescriptorAttribute::new()
+                .with_descriptor_type(DescriptorType::InterruptGate)
+                .with_descriptor_privilege_level(0),
+            xhc_interrupter2_handler as u64,
+        );
+        idt.set(
+            InterruptVector::LAPICTimer,
+            InterruptDescriptorAttribute::new()
+                .with_descriptor_type(DescriptorType::InterruptGate)
+                .with_descriptor_privilege_level(0),
+            lapic_timer_interrupt_handler as u64,
+        );
         idt.load();
 
         let bsp_local_apic_id: u64 = 0xfee00020;
         let bsp_local_apic_id = (unsafe { *(bsp_local_apic_id as *const u32) } >> 24) as u8;
-        if let Err(err) = dev.configure_msi_fixed_destination(
+        // each interrupter of xHC has its own vector if MSI-X is available
+        let num_interrupters = match dev.configure_msix_fixed_destination(
             bsp_local_apic_id,
             MSITriggerMode::Level,
             MSIDeliveryMode::Fixed,
-            InterruptVector::XHCI,
-            0,
+            &[
+                InterruptVector::XHCI,
+                InterruptVector::XHCIInterrupter1,
+                InterruptVector::XHCIInterrupter2,
+            ],
         ) {
-            error!("Error during configuring MSI {:?}", err);
-        } else {
-            InterruptEventManager::init();
-            init_xhc(xhc_mmio_base);
-
-            InterruptEventManager::run();
+            Ok(num_vectors) => Ok(num_vectors),
+            Err(err) => {
+                debug!("MSI-X is not available {:?}", err);
+                dev.configure_msi_fixed_destination(
+                    bsp_local_apic_id,
+                    MSITriggerMode::Level,
+                    MSIDeliveryMode::Fixed,
+                    InterruptVector::XHCI,
+                    0,
+                )
+                .map(|_| 1)
+            }
+        };
+        match num_interrupters {
+            Ok(num_interrupters) => {
+                InterruptEventManager::init();
+                match init_xhc(xhc_mmio_base, num_interrupters) {
+                    Ok(()) => {
+                        initialize_lapic_timer(InterruptVector::LAPICTimer);
+                        InterruptEventManager::run();
+                    }
+                    Err(err) => error!("Failed to initialize xHC: {:?}", err),
+                }
+            }
+            Err(err) => error!("Error during configuring MSI {:?}", err),
         }
     }
 
@@ -130,34 +236,54 @@ pub extern "C" fn _start(frame_buffer_info: FrameBufferInfo) -> ! {
     }
 }
 
-#[derive(Copy, Clone)]
-struct MouseCursorInfo {
-    frame_buffer: FrameBuffer,
-    current_pos: (usize, usize),
-    fill_color: PixelColor,
-    edge_color: PixelColor,
-    bgcolor: PixelColor,
+fn on_mouse_event(event: MouseEvent) {
+    let layer_manager = layer_manager().unwrap();
+    let (width, height) = layer_manager.screen_size();
+    let (x, y) = layer_manager.cursor_position();
+    let x = (x + event.dx as isize).max(0).min(width as isize - 1);
+    let y = (y + event.dy as isize).max(0).min(height as isize - 1);
+
+    layer_manager.move_cursor(x, y);
+    if let Err(err) = window_manager()
+        .unwrap()
+        .on_mouse_event(layer_manager, event, x, y)
+    {
+        error!("Failed to dispatch the mouse event: {:?}", err);
+    }
+}
+
+static mut HELLO_TEXT: Option<WidgetId> = None;
+
+fn open_hello_window() {
+    let mut window = Window::new("Hello", HELLO_WIDTH, HELLO_HEIGHT);
+    let text = window
+        .add(Widget::TextBox(TextBox::new(Rect::new(8, 28, 120, 20))))
+        .unwrap();
+    unsafe { HELLO_TEXT = Some(text) };
+    window
+        .add(Widget::Button(Button::new(
+            Rect::new(136, 28, 56, 20),
+            "Clear",
+        )))
+        .unwrap();
+    let layer_manager = layer_manager().unwrap();
+    let layer = layer_manager
+        .new_layer(unsafe { &mut HELLO_PIXELS }, HELLO_WIDTH, HELLO_HEIGHT)
+        .unwrap();
+    layer_manager.move_to(layer, 300, 100).unwrap();
+    window_manager()
+        .unwrap()
+        .add(layer_manager, layer, window, on_hello_action)
+        .unwrap();
 }
 
-static mut MOUSE_CURSOR_INFO: Option<MouseCursorInfo> = None;
-
-fn on_mouse_event(delta: (i8, i8)) {
-    let mut info = unsafe { MOUSE_CURSOR_INFO }.unwrap();
-    info.frame_buffer
-        .erase_mouse_cursor(info.current_pos.0, info.current_pos.1, info.bgcolor);
-    let (x, y) = info.current_pos;
-    let (mut x, mut y) = (x as isize, y as isize);
-    x += delta.0 as isize;
-    y += delta.1 as isize;
-
-    x = x.max(0);
-    y = y.max(0);
-
-    let (x, y) = (x as usize, y as usize);
-    info.current_pos = (x, y);
-    info.frame_buffer
-        .write_mouse_cursor(x, y, info.edge_color, info.fill_color);
-    unsafe { MOUSE_CURSOR_INFO = Some(info) };
+fn on_hello_action(window: &mut Window, action: Action) {
+    if let Action::Clicked(_) = action {
+        let text = unsafe { HELLO_TEXT }.unwrap();
+        if let Some(Widget::TextBox(text_box)) = window.widget(text) {
+            text_box.clear();
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -194,8 +320,14 @@ impl InterruptEventManager {
                 }
                 match event {
                     InterruptEvent::Unknown => error!("Unknown interrupt event"),
-                    InterruptEvent::XHCI => Self::handle_xhci(),
+                    InterruptEvent::XHCI(index) => Self::handle_xhci(index),
+                }
+            } else if take_pending_tick() {
+                unsafe {
+                    asm!("sti");
                 }
+                unsafe { XHC.as_mut().unwrap() }.on_timer();
+                read_serial_input();
             } else {
                 unsafe {
                     asm!("sti\nhlt");
@@ -204,10 +336,10 @@ impl InterruptEventManager {
         }
     }
 
-    fn handle_xhci() {
+    fn handle_xhci(interrupter: usize) {
         let xhc = unsafe { XHC.as_mut().unwrap() };
         loop {
-            let ret = xhc.poll();
+            let ret = xhc.poll_interrupter(interrupter);
             match ret {
                 Ok(opt) => {
                     if opt.is_none() {
@@ -223,22 +355,95 @@ impl InterruptEventManager {
     }
 }
 
+/// Pass the keys typed on the serial console to the console, which scrolls back with PgUp and PgDn
+fn read_serial_input() {
+    let mut buf = [0; 64];
+    let len = cdc::read(&mut buf);
+    if let Some(console) = console() {
+        for &b in &buf[..len] {
+            console.on_input(b as char);
+        }
+    }
+}
+
+/// Draw the wallpaper handed over by the bootloader on the desktop,
+/// letting it show through the background of the console
+fn draw_wallpaper(desktop: LayerId, wallpaper: BootFile) {
+    if wallpaper.is_empty() {
+        return;
+    }
+    let data = unsafe { wallpaper.as_slice() };
+    let layer_manager = layer_manager().unwrap();
+    let buffer = layer_manager.layer(desktop).unwrap().buffer();
+    let bounds = buffer.bounds();
+    if let Err(e) = image::draw_fit(data, buffer, bounds) {
+        warn!("Failed to decode the wallpaper: {:?}", e);
+        return;
+    }
+    if let Some(console) = console() {
+        console.set_transparent_background();
+    }
+    layer_manager.draw(desktop).unwrap();
+}
+
+/// Drawn directly to report a panic while the console or the logger can't be used
+static mut FRAME_BUFFER: Option<FrameBuffer> = None;
+/// Number of the panics being handled, which is more than 1 if reporting a panic panics
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    match PANIC_DEPTH.fetch_add(1, Ordering::SeqCst) {
+        0 => error!("{}", info),
+        // the console or the logger panicked. The message isn't formatted, which may panic again
+        1 => {
+            if let Some(mut frame_buffer) = unsafe { FRAME_BUFFER } {
+                let (width, _) = frame_buffer.resolution();
+                let (_, cell_height) = fonts::cell_size();
+                frame_buffer.fill_rect(
+                    Rect::new(0, 0, width, cell_height),
+                    PixelColor::new(0xff, 0, 0),
+                );
+                frame_buffer.write_str(
+                    0,
+                    0,
+                    "Panicked while reporting a panic",
+                    PixelColor::new(0xff, 0xff, 0xff),
+                );
+            }
+        }
+        _ => {}
+    }
+    loop {
+        unsafe {
+            asm!("cli\nhlt");
+        }
+    }
 }
 
 static mut XHC: Option<Xhc> = None;
+/// Memory given to the xHC driver in addition to its built-in pool,
+/// which falls short of the scratchpad buffers some controllers ask for
+const XHC_MEMORY_BYTES: usize = 4 * 1024 * 1024;
+static mut XHC_MEMORY: XhcMemory = XhcMemory([0; XHC_MEMORY_BYTES]);
+
+#[repr(C, align(4096))]
+struct XhcMemory([u8; XHC_MEMORY_BYTES]);
 static mut INTERRUPT_EVENT_MANAGER: Option<InterruptEventManager> = None;
 
 #[allow(clippy::fn_to_numeric_cast)]
-fn init_xhc(mmio_base: usize) {
+fn init_xhc(mmio_base: usize, num_interrupters: usize) -> usb::Result<()> {
+    let memory = unsafe { XHC_MEMORY.0.as_mut_ptr() };
+    if let Err(err) = mem::add_region(memory, XHC_MEMORY_BYTES) {
+        warn!("Failed to add memory for xHC: {:?}", err);
+    }
     let xhc = Xhc::new(mmio_base);
     let xhc = unsafe {
         XHC = Some(xhc);
         XHC.as_mut().unwrap()
     };
-    xhc.initialize();
+    let interrupters = [InterrupterConfig::default(); MAX_INTERRUPTERS];
+    xhc.initialize(&interrupters[..num_interrupters.min(MAX_INTERRUPTERS)])?;
     xhc.run();
 
     unsafe {
@@ -259,10 +464,26 @@ fn init_xhc(mmio_base: usize) {
             }
         }
     }
+    Ok(())
 }
 
 extern "x86-interrupt" fn xhc_interrupt_handler(_frame: *mut InterruptFrame) {
     debug!("xhc interruption");
-    InterruptEventManager::push(InterruptEvent::XHCI);
+    InterruptEventManager::push(InterruptEvent::XHCI(0));
+    notify_end_interrupt();
+}
+
+extern "x86-interrupt" fn xhc_interrupter1_handler(_frame: *mut InterruptFrame) {
+    InterruptEventManager::push(InterruptEvent::XHCI(1));
+    notify_end_interrupt();
+}
+
+extern "x86-interrupt" fn xhc_interrupter2_handler(_frame: *mut InterruptFrame) {
+    InterruptEventManager::push(InterruptEvent::XHCI(2));
+    notify_end_interrupt();
+}
+
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(_frame: *mut InterruptFrame) {
+    rumikan_kernel_lib::timer::on_interrupt();
     notify_end_interrupt();
 }
@@ -0,0 +1,35 @@
+/// File on the ESP which the bootloader has read into memory for the kernel
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BootFile {
+    ptr: *const u8,
+    size: usize,
+}
+
+impl BootFile {
+    pub fn new(ptr: *const u8, size: usize) -> BootFile {
+        BootFile { ptr, size }
+    }
+
+    /// The file wasn't found
+    pub fn empty() -> BootFile {
+        BootFile {
+            ptr: core::ptr::null(),
+            size: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// # Safety
+    /// The memory must be left as the bootloader has read the file into
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.is_empty() {
+            &[]
+        } else {
+            core::slice::from_raw_parts(self.ptr, self.size)
+        }
+    }
+}
@@ -1,3 +1,4 @@
 #![no_std]
 
+pub mod boot;
 pub mod graphics;